@@ -3,6 +3,7 @@ use mlua::{FromLua, FromLuaMulti, Lua, LuaSerdeExt, RegistryKey, Table, ToLuaMul
 use serde::Serialize;
 use std::collections::VecDeque;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
@@ -30,6 +31,10 @@ lazy_static::lazy_static! {
     };
 }
 
+/// Incremented each time the policy is reloaded; lua contexts
+/// created for an earlier generation are not reused
+static CONFIG_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
 /// Maximum age of a lua context before we release it
 const MAX_AGE: Duration = Duration::from_secs(300);
 /// Maximum number of uses of a given lua context before we release it
@@ -58,7 +63,9 @@ impl Pool {
 
     pub fn expire(&mut self) {
         let len_before = self.pool.len();
-        self.pool.retain(|inner| inner.created.elapsed() < MAX_AGE);
+        let generation = get_generation();
+        self.pool
+            .retain(|inner| inner.created.elapsed() < MAX_AGE && inner.generation == generation);
         let len_after = self.pool.len();
         let diff = len_before - len_after;
         if diff > 0 {
@@ -70,7 +77,7 @@ impl Pool {
         loop {
             let mut item = self.pool.pop_front()?;
             LUA_SPARE_COUNT.decrement(1.);
-            if item.created.elapsed() > MAX_AGE {
+            if item.created.elapsed() > MAX_AGE || item.generation != get_generation() {
                 continue;
             }
             item.use_count += 1;
@@ -82,7 +89,10 @@ impl Pool {
         if self.pool.len() + 1 > MAX_SPARE {
             return;
         }
-        if config.created.elapsed() > MAX_AGE || config.use_count + 1 > MAX_USE {
+        if config.created.elapsed() > MAX_AGE
+            || config.use_count + 1 > MAX_USE
+            || config.generation != get_generation()
+        {
             return;
         }
         self.pool.push_back(config);
@@ -95,6 +105,7 @@ struct LuaConfigInner {
     lua: Lua,
     created: Instant,
     use_count: usize,
+    generation: usize,
 }
 
 impl Drop for LuaConfigInner {
@@ -130,11 +141,40 @@ fn get_funcs() -> Vec<RegisterFunc> {
     FUNCS.lock().unwrap().clone()
 }
 
+/// Returns the current policy generation
+pub fn get_generation() -> usize {
+    CONFIG_GENERATION.load(Ordering::SeqCst)
+}
+
 pub async fn load_config() -> anyhow::Result<LuaConfig> {
     if let Some(inner) = POOL.lock().unwrap().get() {
         return Ok(LuaConfig { inner: Some(inner) });
     }
 
+    let inner = load_config_inner(get_generation()).await?;
+    Ok(LuaConfig { inner: Some(inner) })
+}
+
+/// Loads the policy into a fresh lua context. If that succeeds,
+/// the policy generation is incremented so that the lua contexts
+/// that were loaded from the prior version of the policy are
+/// discarded rather than reused.
+/// If the policy fails to load, the current policy remains in
+/// effect and the error is returned.
+/// Returns the new generation.
+pub async fn reload_config() -> anyhow::Result<usize> {
+    let mut inner = load_config_inner(get_generation()).await?;
+    let generation = CONFIG_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    inner.generation = generation;
+
+    let mut pool = POOL.lock().unwrap();
+    pool.expire();
+    pool.put(inner);
+
+    Ok(generation)
+}
+
+async fn load_config_inner(generation: usize) -> anyhow::Result<LuaConfigInner> {
     LUA_LOAD_COUNT.increment(1);
     let lua = Lua::new();
     let created = Instant::now();
@@ -178,15 +218,32 @@ pub async fn load_config() -> anyhow::Result<LuaConfig> {
     }
     LUA_COUNT.increment(1.);
 
-    Ok(LuaConfig {
-        inner: Some(LuaConfigInner {
-            lua,
-            created,
-            use_count: 1,
-        }),
+    Ok(LuaConfigInner {
+        lua,
+        created,
+        use_count: 1,
+        generation,
     })
 }
 
+/// The name of the registry table that lists the events
+/// that have handlers registered via `on`
+const REGISTERED_EVENTS: &str = "kumomta-registered-events";
+
+/// Records that a handler has been registered via `on` for the named
+/// event, so that it is included in LuaConfig::registered_events
+pub fn record_event_handler(lua: &Lua, name: &str) -> mlua::Result<()> {
+    let events: Table = match lua.named_registry_value(REGISTERED_EVENTS)? {
+        Value::Table(events) => events,
+        _ => {
+            let events = lua.create_table()?;
+            lua.set_named_registry_value(REGISTERED_EVENTS, events.clone())?;
+            events
+        }
+    };
+    events.push(name)
+}
+
 pub fn register(func: RegisterFunc) {
     FUNCS.lock().unwrap().push(func);
 }
@@ -322,6 +379,16 @@ impl LuaConfig {
         future.await
     }
 
+    /// Returns the names of the events that have handlers
+    /// registered via `on`, in the order they were registered
+    pub fn registered_events(&self) -> anyhow::Result<Vec<String>> {
+        let lua = &self.inner.as_ref().unwrap().lua;
+        match lua.named_registry_value(REGISTERED_EVENTS)? {
+            Value::Table(events) => Ok(events.sequence_values().collect::<mlua::Result<_>>()?),
+            _ => Ok(vec![]),
+        }
+    }
+
     /// Call a callback registered via `on`.
     #[allow(unused)]
     pub fn call_callback<
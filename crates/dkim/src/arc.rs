@@ -0,0 +1,531 @@
+// Implementation of ARC: https://datatracker.ietf.org/doc/html/rfc8617
+use crate::header::{DKIMHeader, DKIMHeaderBuilder};
+use crate::sign::sign_hash;
+use crate::{
+    canonicalization, dns, hash, parser, public_key, verify_email_header, verify_signature,
+    DKIMError, ParsedEmail, Signer,
+};
+use base64::engine::general_purpose;
+use base64::Engine;
+use mailparse::MailHeader;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+pub const SEAL_HEADER: &str = "ARC-Seal";
+pub const MESSAGE_SIGNATURE_HEADER: &str = "ARC-Message-Signature";
+pub const AUTHENTICATION_RESULTS_HEADER: &str = "ARC-Authentication-Results";
+
+/// The maximum instance number; RFC 8617 section 4.2.1
+const MAX_INSTANCE: u32 = 50;
+const SEAL_REQUIRED_TAGS: &[&str] = &["i", "a", "b", "cv", "d", "s"];
+const MESSAGE_SIGNATURE_REQUIRED_TAGS: &[&str] = &["i", "a", "b", "bh", "d", "h", "s"];
+
+/// The chain validation status (cv=); RFC 8617 section 4.4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainValidationStatus {
+    None,
+    Pass,
+    Fail,
+}
+
+impl ChainValidationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "none" => Some(Self::None),
+            "pass" => Some(Self::Pass),
+            "fail" => Some(Self::Fail),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ChainValidationStatus {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+/// The outcome of validating the ARC chain of a message
+#[derive(Debug)]
+pub struct ArcChainResult {
+    pub status: ChainValidationStatus,
+    /// The highest instance number present in the message;
+    /// 0 if there are no ARC header fields
+    pub instance: u32,
+    /// Why the chain failed to validate
+    pub error: Option<DKIMError>,
+}
+
+/// The header fields that make up a single ARC Set
+struct ArcSet<'h, 'a> {
+    authentication_results: &'h MailHeader<'a>,
+    message_signature: &'h MailHeader<'a>,
+    seal: &'h MailHeader<'a>,
+}
+
+#[derive(Default)]
+struct PartialArcSet<'h, 'a> {
+    authentication_results: Option<&'h MailHeader<'a>>,
+    message_signature: Option<&'h MailHeader<'a>>,
+    seal: Option<&'h MailHeader<'a>>,
+}
+
+fn arc_error(err: impl Into<String>) -> DKIMError {
+    DKIMError::ArcChainInvalid(err.into())
+}
+
+/// Extracts the instance tag (i=) from an ARC header field value
+fn parse_instance(value: &[u8]) -> Result<u32, DKIMError> {
+    let value = String::from_utf8_lossy(value);
+    for tag in value.split(';') {
+        if let Some((name, value)) = tag.split_once('=') {
+            if name.trim() == "i" {
+                let value: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+                return match value.parse::<u32>() {
+                    Ok(i) if (1..=MAX_INSTANCE).contains(&i) => Ok(i),
+                    _ => Err(arc_error(format!("invalid instance i={value}"))),
+                };
+            }
+        }
+    }
+    Err(arc_error("missing instance tag"))
+}
+
+/// Returns the highest instance number of the ARC header
+/// fields in email, ignoring any that cannot be parsed
+fn max_instance(email: &ParsedEmail) -> u32 {
+    email
+        .get_headers()
+        .iter()
+        .filter(|header| is_arc_header(&header.get_key_ref()))
+        .filter_map(|header| parse_instance(header.get_value_raw()).ok())
+        .max()
+        .unwrap_or(0)
+}
+
+fn is_arc_header(name: &str) -> bool {
+    name.eq_ignore_ascii_case(SEAL_HEADER)
+        || name.eq_ignore_ascii_case(MESSAGE_SIGNATURE_HEADER)
+        || name.eq_ignore_ascii_case(AUTHENTICATION_RESULTS_HEADER)
+}
+
+/// Groups the ARC header fields of email into sets, ordered by
+/// instance. The sets must be complete, unique and numbered
+/// contiguously from 1.
+fn collect_sets<'h, 'a>(email: &'h ParsedEmail<'a>) -> Result<Vec<ArcSet<'h, 'a>>, DKIMError> {
+    let mut sets: BTreeMap<u32, PartialArcSet> = BTreeMap::new();
+
+    for header in email.get_headers() {
+        let key = header.get_key_ref();
+        if !is_arc_header(&key) {
+            continue;
+        }
+        let instance = parse_instance(header.get_value_raw())?;
+        let set = sets.entry(instance).or_default();
+        let field = if key.eq_ignore_ascii_case(SEAL_HEADER) {
+            &mut set.seal
+        } else if key.eq_ignore_ascii_case(MESSAGE_SIGNATURE_HEADER) {
+            &mut set.message_signature
+        } else {
+            &mut set.authentication_results
+        };
+        if field.replace(header).is_some() {
+            return Err(arc_error(format!("duplicate {key} for i={instance}")));
+        }
+    }
+
+    let mut result = vec![];
+    for (expected, (instance, set)) in (1..).zip(sets) {
+        if instance != expected {
+            return Err(arc_error(format!("missing ARC set i={expected}")));
+        }
+        match (set.authentication_results, set.message_signature, set.seal) {
+            (Some(authentication_results), Some(message_signature), Some(seal)) => {
+                result.push(ArcSet {
+                    authentication_results,
+                    message_signature,
+                    seal,
+                })
+            }
+            _ => return Err(arc_error(format!("incomplete ARC set i={instance}"))),
+        }
+    }
+    Ok(result)
+}
+
+fn parse_seal(header: &MailHeader) -> Result<DKIMHeader, DKIMError> {
+    let seal = DKIMHeader::parse_tags(&String::from_utf8_lossy(header.get_value_raw()))?;
+    seal.validate_tags(SEAL_REQUIRED_TAGS)?;
+    if seal.get_tag("h").is_some() {
+        return Err(arc_error("ARC-Seal must not have an h= tag"));
+    }
+    Ok(seal)
+}
+
+fn seal_status(seal: &DKIMHeader) -> Result<ChainValidationStatus, DKIMError> {
+    let cv = seal.get_required_tag("cv");
+    ChainValidationStatus::parse(cv).ok_or_else(|| arc_error(format!("invalid cv={cv}")))
+}
+
+/// Computes the hash signed by an ARC-Seal. headers are the ARC
+/// header fields that precede the seal, in instance order with
+/// each set ordered as ARC-Authentication-Results,
+/// ARC-Message-Signature, ARC-Seal; RFC 8617 section 5.1.1
+fn compute_seal_hash(
+    hash_algo: hash::HashAlgo,
+    headers: &[(&str, &[u8])],
+    seal: &DKIMHeader,
+) -> Vec<u8> {
+    let canonicalization_type = canonicalization::Type::Relaxed;
+    let mut input = vec![];
+    for (key, value) in headers {
+        canonicalization_type.canon_header_into(key, value, &mut input);
+    }
+
+    // The seal itself is hashed without its signature
+    // and without the trailing "\r\n"
+    let sign = seal.get_required_raw_tag("b");
+    let value = seal.raw_bytes.replace(sign, "");
+    let mut canonicalized_value = vec![];
+    canonicalization_type.canon_header_into(
+        SEAL_HEADER,
+        value.as_bytes(),
+        &mut canonicalized_value,
+    );
+    canonicalized_value.truncate(canonicalized_value.len() - 2);
+    input.extend_from_slice(&canonicalized_value);
+
+    let mut hasher = hash::HashImpl::from_algo(hash_algo);
+    hasher.hash(&input);
+    hasher.finalize_bytes()
+}
+
+fn set_headers<'h>(sets: &'h [ArcSet]) -> Vec<(&'h str, &'h [u8])> {
+    let mut headers = vec![];
+    for set in sets {
+        headers.push((
+            AUTHENTICATION_RESULTS_HEADER,
+            set.authentication_results.get_value_raw(),
+        ));
+        headers.push((
+            MESSAGE_SIGNATURE_HEADER,
+            set.message_signature.get_value_raw(),
+        ));
+        headers.push((SEAL_HEADER, set.seal.get_value_raw()));
+    }
+    headers
+}
+
+async fn verify_seal(
+    resolver: Arc<dyn dns::Lookup>,
+    sets: &[ArcSet<'_, '_>],
+    seal: &DKIMHeader,
+) -> Result<(), DKIMError> {
+    let hash_algo = parser::parse_hash_algo(seal.get_required_tag("a"))?;
+    let public_key = public_key::retrieve_public_key(
+        resolver,
+        seal.get_required_tag("d"),
+        seal.get_required_tag("s"),
+    )
+    .await?;
+
+    // The hash covers every set up to this one, except for the seal
+    // which is added by compute_seal_hash
+    let mut headers = set_headers(sets);
+    headers.pop();
+    let computed_hash = compute_seal_hash(hash_algo, &headers, seal);
+
+    let signature = general_purpose::STANDARD
+        .decode(seal.get_required_tag("b"))
+        .map_err(|err| {
+            DKIMError::SignatureSyntaxError(format!("failed to decode signature: {}", err))
+        })?;
+    if !verify_signature(hash_algo, &computed_hash, &signature, public_key)? {
+        return Err(DKIMError::SignatureDidNotVerify);
+    }
+    Ok(())
+}
+
+async fn validate_chain<'a>(
+    resolver: Arc<dyn dns::Lookup>,
+    sets: &[ArcSet<'_, 'a>],
+    email: &'a ParsedEmail<'a>,
+) -> Result<(), DKIMError> {
+    let seals = sets
+        .iter()
+        .map(|set| parse_seal(set.seal))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (instance, seal) in (1..).zip(&seals) {
+        let expected = if instance == 1 {
+            ChainValidationStatus::None
+        } else {
+            ChainValidationStatus::Pass
+        };
+        let status = seal_status(seal)?;
+        if status != expected {
+            return Err(arc_error(format!("ARC-Seal i={instance} has cv={status}")));
+        }
+    }
+
+    // Only the most recent ARC-Message-Signature is required to verify
+    let latest = &sets[sets.len() - 1];
+    let message_signature = DKIMHeader::parse_tags(&String::from_utf8_lossy(
+        latest.message_signature.get_value_raw(),
+    ))?;
+    message_signature.validate_tags(MESSAGE_SIGNATURE_REQUIRED_TAGS)?;
+    verify_email_header(
+        Arc::clone(&resolver),
+        MESSAGE_SIGNATURE_HEADER,
+        &message_signature,
+        email,
+    )
+    .await?;
+
+    for idx in (0..sets.len()).rev() {
+        verify_seal(Arc::clone(&resolver), &sets[..=idx], &seals[idx]).await?;
+    }
+
+    Ok(())
+}
+
+/// Validate the ARC chain of email; RFC 8617 section 5.2
+pub async fn verify_arc_chain<'a>(
+    email: &'a ParsedEmail<'a>,
+    resolver: Arc<dyn dns::Lookup>,
+) -> ArcChainResult {
+    let instance = max_instance(email);
+    let fail = |err| ArcChainResult {
+        status: ChainValidationStatus::Fail,
+        instance,
+        error: Some(err),
+    };
+
+    let sets = match collect_sets(email) {
+        Ok(sets) => sets,
+        Err(err) => return fail(err),
+    };
+    if sets.is_empty() {
+        return ArcChainResult {
+            status: ChainValidationStatus::None,
+            instance,
+            error: None,
+        };
+    }
+
+    match validate_chain(resolver, &sets, email).await {
+        Ok(()) => ArcChainResult {
+            status: ChainValidationStatus::Pass,
+            instance,
+            error: None,
+        },
+        Err(err) => fail(err),
+    }
+}
+
+/// ARC sealer. Constructed from a [Signer](crate::Signer) whose key,
+/// domain, selector, canonicalization and signed headers are
+/// used for the ARC-Message-Signature and ARC-Seal.
+pub struct ArcSigner {
+    signer: Signer,
+}
+
+impl ArcSigner {
+    pub fn new(signer: Signer) -> Result<Self, DKIMError> {
+        for name in [
+            SEAL_HEADER,
+            MESSAGE_SIGNATURE_HEADER,
+            AUTHENTICATION_RESULTS_HEADER,
+            "Authentication-Results",
+        ] {
+            if signer.signed_headers.contains(name) {
+                return Err(DKIMError::BuilderError(
+                    "ARC and Authentication-Results headers must not be signed",
+                ));
+            }
+        }
+        Ok(Self { signer })
+    }
+
+    /// Add a new ARC Set to email; RFC 8617 section 5.1.
+    /// chain is the result of validating the existing chain with
+    /// [verify_arc_chain], and authentication_results is the
+    /// payload of the ARC-Authentication-Results header, in the
+    /// same form as an Authentication-Results header value.
+    /// Returns the ARC-Seal, ARC-Message-Signature and
+    /// ARC-Authentication-Results header fields, separated by
+    /// CRLF, ready to be prepended to the message.
+    pub fn seal<'b>(
+        &self,
+        email: &'b ParsedEmail<'b>,
+        chain: &ArcChainResult,
+        authentication_results: &str,
+    ) -> Result<String, DKIMError> {
+        // A chain that has already been sealed as failed must not be extended
+        if chain.status == ChainValidationStatus::Fail {
+            if let Ok(sets) = collect_sets(email) {
+                if let Some(latest) = sets.last() {
+                    let seal = parse_seal(latest.seal)?;
+                    if seal_status(&seal)? == ChainValidationStatus::Fail {
+                        return Err(arc_error("the chain has already been sealed as failed"));
+                    }
+                }
+            }
+        }
+
+        let instance = chain.instance + 1;
+        if instance > MAX_INSTANCE {
+            return Err(arc_error(format!(
+                "cannot add more than {MAX_INSTANCE} ARC sets"
+            )));
+        }
+        let status = if chain.instance == 0 {
+            ChainValidationStatus::None
+        } else if chain.status == ChainValidationStatus::Pass {
+            ChainValidationStatus::Pass
+        } else {
+            ChainValidationStatus::Fail
+        };
+        let time = self.signer.time.unwrap_or_else(chrono::offset::Utc::now);
+
+        let authentication_results =
+            format!("i={instance}; {}", authentication_results.trim_start());
+
+        // ARC-Message-Signature: as for DKIM-Signature, without v=
+        let body_hash = hash::compute_body_hash(
+            self.signer.body_canonicalization,
+            None,
+            self.signer.hash_algo,
+            email,
+        )?;
+        let mut builder = DKIMHeaderBuilder::new()
+            .add_tag("i", &instance.to_string())
+            .add_tag("a", self.signer.hash_algo.algo_name())
+            .add_tag("d", &self.signer.signing_domain)
+            .add_tag("s", &self.signer.selector)
+            .add_tag(
+                "c",
+                &format!(
+                    "{}/{}",
+                    self.signer.header_canonicalization.canon_name(),
+                    self.signer.body_canonicalization.canon_name()
+                ),
+            )
+            .set_time(time);
+        if let Some(expiry) = self.signer.expiry {
+            builder = builder.set_expiry(expiry)?;
+        }
+        let builder = builder
+            .add_tag("bh", &body_hash)
+            .set_signed_headers(&self.signer.signed_headers);
+        let header_hash = hash::compute_headers_hash_for_header(
+            self.signer.header_canonicalization,
+            &self.signer.signed_headers,
+            self.signer.hash_algo,
+            MESSAGE_SIGNATURE_HEADER,
+            &builder.clone().add_tag("b", "").build()?,
+            email,
+        )?;
+        let signature = sign_hash(
+            &self.signer.private_key,
+            self.signer.hash_algo,
+            &header_hash,
+        )?;
+        let message_signature = builder
+            .add_tag("b", &general_purpose::STANDARD.encode(signature))
+            .build()?;
+
+        // ARC-Seal
+        let builder = DKIMHeaderBuilder::new()
+            .add_tag("i", &instance.to_string())
+            .add_tag("a", self.signer.hash_algo.algo_name())
+            .set_time(time)
+            .add_tag("cv", status.as_str())
+            .add_tag("d", &self.signer.signing_domain)
+            .add_tag("s", &self.signer.selector);
+
+        // When the chain has failed, the seal covers only its own set
+        let sets = if status == ChainValidationStatus::Fail {
+            vec![]
+        } else {
+            collect_sets(email)?
+        };
+        let mut headers = set_headers(&sets);
+        headers.push((
+            AUTHENTICATION_RESULTS_HEADER,
+            authentication_results.as_bytes(),
+        ));
+        headers.push((
+            MESSAGE_SIGNATURE_HEADER,
+            message_signature.raw_bytes.as_bytes(),
+        ));
+        let seal_hash = compute_seal_hash(
+            self.signer.hash_algo,
+            &headers,
+            &builder.clone().add_tag("b", "").build()?,
+        );
+        let signature = sign_hash(&self.signer.private_key, self.signer.hash_algo, &seal_hash)?;
+        let seal = builder
+            .add_tag("b", &general_purpose::STANDARD.encode(signature))
+            .build()?;
+
+        Ok(format!(
+            "{SEAL_HEADER}: {}\r\n{MESSAGE_SIGNATURE_HEADER}: {}\r\n{AUTHENTICATION_RESULTS_HEADER}: {}",
+            seal.raw_bytes, message_signature.raw_bytes, authentication_results
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_instance() {
+        assert_eq!(parse_instance(b"i=1; a=rsa-sha256").unwrap(), 1);
+        assert_eq!(
+            parse_instance(b"i = 12 ; mx.example.org; dkim=pass header.i=@example.com").unwrap(),
+            12
+        );
+        assert!(parse_instance(b"i=0; a=rsa-sha256").is_err());
+        assert!(parse_instance(b"i=51; a=rsa-sha256").is_err());
+        assert!(parse_instance(b"a=rsa-sha256").is_err());
+    }
+
+    #[test]
+    fn test_collect_sets() {
+        let raw = "ARC-Seal: i=1; cv=none\r\n\
+                   ARC-Message-Signature: i=1; a=rsa-sha256\r\n\
+                   ARC-Authentication-Results: i=1; mx.example.org; none\r\n\
+                   Subject: hello\r\n\r\nbody\r\n";
+        let email = ParsedEmail::parse_bytes(raw.as_bytes()).unwrap();
+        assert_eq!(collect_sets(&email).unwrap().len(), 1);
+        assert_eq!(max_instance(&email), 1);
+
+        let raw = "ARC-Seal: i=2; cv=pass\r\n\
+                   ARC-Message-Signature: i=2; a=rsa-sha256\r\n\
+                   ARC-Authentication-Results: i=2; mx.example.org; none\r\n\
+                   Subject: hello\r\n\r\nbody\r\n";
+        let email = ParsedEmail::parse_bytes(raw.as_bytes()).unwrap();
+        assert!(collect_sets(&email).is_err());
+        assert_eq!(max_instance(&email), 2);
+
+        let raw = "ARC-Seal: i=1; cv=none\r\n\
+                   ARC-Authentication-Results: i=1; mx.example.org; none\r\n\
+                   Subject: hello\r\n\r\nbody\r\n";
+        let email = ParsedEmail::parse_bytes(raw.as_bytes()).unwrap();
+        assert!(collect_sets(&email).is_err());
+
+        let raw = "Subject: hello\r\n\r\nbody\r\n";
+        let email = ParsedEmail::parse_bytes(raw.as_bytes()).unwrap();
+        assert!(collect_sets(&email).unwrap().is_empty());
+    }
+}
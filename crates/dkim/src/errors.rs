@@ -75,6 +75,9 @@ quick_error! {
         HeaderSerializeError(err: String) {
             display("failed to serialize DKIM header: {err}")
         }
+        ArcChainInvalid(err: String) {
+            display("invalid ARC chain: {err}")
+        }
     }
 }
 
@@ -98,7 +101,8 @@ impl DKIMError {
             | BodyHashDidNotVerify
             | MalformedBody
             | UnsupportedCanonicalizationType(_)
-            | UnsupportedHashAlgorithm(_) => Status::Permfail,
+            | UnsupportedHashAlgorithm(_)
+            | ArcChainInvalid(_) => Status::Permfail,
             KeyUnavailable(_)
             | UnknownInternalError(_)
             | BuilderError(_)
@@ -132,6 +132,14 @@ impl HeaderList {
         }
     }
 
+    pub fn contains(&self, name: &str) -> bool {
+        match self {
+            Self::MaybeMultiple(list) | Self::Unique(list) => {
+                list.iter().any(|h| h.eq_ignore_ascii_case(name))
+            }
+        }
+    }
+
     /// Build a header list.
     /// Analyzes the list to determine whether it is a unique list or not
     pub fn new(list: Vec<String>) -> Self {
@@ -241,6 +249,26 @@ pub(crate) fn compute_headers_hash<'a, 'b>(
     hash_algo: HashAlgo,
     dkim_header: &'b DKIMHeader,
     email: &'a ParsedEmail<'a>,
+) -> Result<Vec<u8>, DKIMError> {
+    compute_headers_hash_for_header(
+        canonicalization_type,
+        headers,
+        hash_algo,
+        HEADER,
+        dkim_header,
+        email,
+    )
+}
+
+/// Like compute_headers_hash, but for a signature header field
+/// named header_name, such as ARC-Message-Signature
+pub(crate) fn compute_headers_hash_for_header<'a, 'b>(
+    canonicalization_type: canonicalization::Type,
+    headers: &HeaderList,
+    hash_algo: HashAlgo,
+    header_name: &str,
+    dkim_header: &'b DKIMHeader,
+    email: &'a ParsedEmail<'a>,
 ) -> Result<Vec<u8>, DKIMError> {
     let mut input = Vec::new();
     let mut hasher = HashImpl::from_algo(hash_algo);
@@ -255,7 +283,11 @@ pub(crate) fn compute_headers_hash<'a, 'b>(
         let sign = dkim_header.get_required_raw_tag("b");
         let value = dkim_header.raw_bytes.replace(&sign, "");
         let mut canonicalized_value = vec![];
-        canonicalization_type.canon_header_into(HEADER, value.as_bytes(), &mut canonicalized_value);
+        canonicalization_type.canon_header_into(
+            header_name,
+            value.as_bytes(),
+            &mut canonicalized_value,
+        );
 
         // remove trailing "\r\n"
         canonicalized_value.truncate(canonicalized_value.len() - 2);
@@ -16,17 +16,7 @@ pub(crate) struct DKIMHeader {
 impl DKIMHeader {
     /// <https://datatracker.ietf.org/doc/html/rfc6376#section-6.1.1>
     pub fn parse(value: &str) -> Result<Self, DKIMError> {
-        let (_, tags) = parser::tag_list(value)
-            .map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?;
-
-        let mut tags_map = IndexMap::new();
-        for tag in &tags {
-            tags_map.insert(tag.name.clone(), tag.clone());
-        }
-        let header = DKIMHeader {
-            tags: tags_map,
-            raw_bytes: value.to_owned(),
-        };
+        let header = Self::parse_tags(value)?;
 
         header.validate_required_tags()?;
 
@@ -81,6 +71,23 @@ impl DKIMHeader {
         Ok(header)
     }
 
+    /// Parse the tag list without applying any of the DKIM-Signature
+    /// specific validation. Used for the ARC header fields, which share
+    /// the syntax but have different requirements.
+    pub fn parse_tags(value: &str) -> Result<Self, DKIMError> {
+        let (_, tags) = parser::tag_list(value)
+            .map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?;
+
+        let mut tags_map = IndexMap::new();
+        for tag in &tags {
+            tags_map.insert(tag.name.clone(), tag.clone());
+        }
+        Ok(DKIMHeader {
+            tags: tags_map,
+            raw_bytes: value.to_owned(),
+        })
+    }
+
     pub fn get_tag(&self, name: &str) -> Option<&str> {
         self.tags.get(name).map(|v| v.value.as_str())
     }
@@ -128,7 +135,11 @@ impl DKIMHeader {
     }
 
     fn validate_required_tags(&self) -> Result<(), DKIMError> {
-        for required in REQUIRED_TAGS {
+        self.validate_tags(REQUIRED_TAGS)
+    }
+
+    pub fn validate_tags(&self, required_tags: &[&'static str]) -> Result<(), DKIMError> {
+        for required in required_tags {
             if self.get_tag(required).is_none() {
                 return Err(DKIMError::SignatureMissingRequiredTag(required));
             }
@@ -14,6 +14,7 @@ use mailparse::MailHeaderMap;
 #[macro_use]
 extern crate quick_error;
 
+pub mod arc;
 pub mod canonicalization;
 pub mod dns;
 mod errors;
@@ -27,7 +28,8 @@ mod result;
 mod roundtrip_test;
 mod sign;
 
-pub use errors::DKIMError;
+pub use arc::{verify_arc_chain, ArcChainResult, ArcSigner, ChainValidationStatus};
+pub use errors::{DKIMError, Status};
 use header::{DKIMHeader, HEADER};
 pub use parsed_email::ParsedEmail;
 pub use parser::{tag_list as parse_tag_list, Tag};
@@ -79,6 +81,7 @@ fn verify_signature(
 
 async fn verify_email_header<'a>(
     resolver: Arc<dyn dns::Lookup>,
+    header_name: &str,
     dkim_header: &'a DKIMHeader,
     email: &'a ParsedEmail<'a>,
 ) -> Result<(canonicalization::Type, canonicalization::Type), DKIMError> {
@@ -105,10 +108,11 @@ async fn verify_email_header<'a>(
         .map(|s| s.trim().to_ascii_lowercase())
         .collect();
 
-    let computed_headers_hash = hash::compute_headers_hash(
+    let computed_headers_hash = hash::compute_headers_hash_for_header(
         header_canonicalization_type,
         &HeaderList::new(header_list),
         hash_algo,
+        header_name,
         dkim_header,
         email,
     )?;
@@ -158,7 +162,7 @@ pub async fn verify_email_with_resolver<'a>(
             continue;
         }
 
-        match verify_email_header(Arc::clone(&resolver), &dkim_header, email).await {
+        match verify_email_header(Arc::clone(&resolver), HEADER, &dkim_header, email).await {
             Ok((header_canonicalization_type, body_canonicalization_type)) => {
                 return Ok(DKIMResult::pass(
                     signing_domain,
@@ -181,6 +185,48 @@ pub async fn verify_email_with_resolver<'a>(
     }
 }
 
+/// Run the DKIM verification on each signature present in the email,
+/// regardless of the signing domain, returning a result per signature
+pub async fn verify_email_signatures_with_resolver<'a>(
+    email: &'a ParsedEmail<'a>,
+    resolver: Arc<dyn dns::Lookup>,
+) -> Vec<DKIMResult> {
+    let mut results = vec![];
+
+    for h in email.get_headers().get_all_headers(HEADER) {
+        let value = String::from_utf8_lossy(h.get_value_raw());
+        tracing::debug!("checking signature {:?}", value);
+
+        let dkim_header = match DKIMHeader::parse(&value) {
+            Ok(v) => v,
+            Err(err) => {
+                tracing::debug!("failed to verify: {}", err);
+                results.push(DKIMResult::fail(err, ""));
+                continue;
+            }
+        };
+
+        let signing_domain = dkim_header.get_required_tag("d");
+        let selector = dkim_header.get_required_tag("s");
+
+        let result =
+            match verify_email_header(Arc::clone(&resolver), HEADER, &dkim_header, email).await {
+                Ok((header_canonicalization_type, body_canonicalization_type)) => DKIMResult::pass(
+                    signing_domain,
+                    header_canonicalization_type,
+                    body_canonicalization_type,
+                ),
+                Err(err) => {
+                    tracing::debug!("failed to verify: {}", err);
+                    DKIMResult::fail(err, signing_domain)
+                }
+            };
+        results.push(result.with_selector(selector));
+    }
+
+    results
+}
+
 /// Run the DKIM verification on the email
 pub async fn verify_email<'a>(
     from_domain: &str,
@@ -351,6 +397,7 @@ Joe."#
 
         let dkim_verify_result = verify_email_header(
             Arc::clone(&resolver),
+            HEADER,
             &DKIMHeader::parse(&raw_header_dkim).unwrap(),
             &email,
         )
@@ -401,6 +448,7 @@ Joe.
 
         let dkim_verify_result = verify_email_header(
             Arc::clone(&resolver),
+            HEADER,
             &DKIMHeader::parse(&raw_header_rsa).unwrap(),
             &email,
         )
@@ -6,6 +6,7 @@ pub struct DKIMResult {
     value: &'static str,
     error: Option<DKIMError>,
     domain_used: String,
+    selector: Option<String>,
     header_canonicalization_type: Option<canonicalization::Type>,
     body_canonicalization_type: Option<canonicalization::Type>,
 }
@@ -20,6 +21,7 @@ impl DKIMResult {
             value: "pass",
             error: None,
             domain_used: domain_used.to_string().to_lowercase(),
+            selector: None,
             header_canonicalization_type: Some(header_canonicalization_type),
             body_canonicalization_type: Some(body_canonicalization_type),
         }
@@ -30,6 +32,7 @@ impl DKIMResult {
             value: "neutral",
             error: None,
             domain_used: domain_used.to_string().to_lowercase(),
+            selector: None,
             header_canonicalization_type: None,
             body_canonicalization_type: None,
         }
@@ -40,11 +43,18 @@ impl DKIMResult {
             value: "fail",
             error: Some(reason),
             domain_used: domain_used.to_string().to_lowercase(),
+            selector: None,
             header_canonicalization_type: None,
             body_canonicalization_type: None,
         }
     }
 
+    /// Records the selector of the signature that produced this result
+    pub fn with_selector(mut self, selector: &str) -> Self {
+        self.selector = Some(selector.to_string());
+        self
+    }
+
     pub fn error(&self) -> Option<DKIMError> {
         self.error.clone()
     }
@@ -54,6 +64,11 @@ impl DKIMResult {
         &self.domain_used
     }
 
+    /// Returns the selector of the signature, if known
+    pub fn selector(&self) -> Option<&str> {
+        self.selector.as_deref()
+    }
+
     /// Returns the verification result as a summary: fail, neutral or pass.
     pub fn summary(&self) -> &'static str {
         self.value
@@ -1,7 +1,7 @@
 #![cfg(test)]
 use crate::{
-    dns, verify_email_with_resolver, DKIMError, DKIMResult, DkimPrivateKey, ParsedEmail,
-    SignerBuilder,
+    dns, verify_arc_chain, verify_email_with_resolver, ArcSigner, ChainValidationStatus, DKIMError,
+    DKIMResult, DkimPrivateKey, ParsedEmail, SignerBuilder,
 };
 use chrono::TimeZone;
 use futures::future::BoxFuture;
@@ -172,3 +172,80 @@ sentation" style=3D"width:100%;">
         assert_eq!(res.with_detail(), "pass")
     }
 }
+
+async fn arc_seal(domain: &str, authentication_results: &str, raw_email: &str) -> String {
+    let email = ParsedEmail::parse_bytes(raw_email.as_bytes()).unwrap();
+
+    let private_key =
+        rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+    let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+
+    let signer = ArcSigner::new(
+        SignerBuilder::new()
+            .with_signed_headers(["From", "Subject", "DKIM-Signature"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("2022")
+            .with_signing_domain(domain)
+            .with_time(time)
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+
+    let resolver = test_resolver(map! {
+        "2022._domainkey.cloudflare.com" => dkim_record(),
+        "2022._domainkey.example.com" => dkim_record()
+    });
+    let chain = verify_arc_chain(&email, resolver).await;
+    let headers = signer.seal(&email, &chain, authentication_results).unwrap();
+
+    format!("{}\r\n{}", headers, raw_email)
+}
+
+#[tokio::test]
+async fn test_arc_roundtrip() {
+    let resolver = test_resolver(map! {
+        "2022._domainkey.cloudflare.com" => dkim_record(),
+        "2022._domainkey.example.com" => dkim_record()
+    });
+
+    let email = r#"Subject: subject
+From: Sven Sauleau <sven@cloudflare.com>
+
+Hello Alice
+"#
+    .replace("\n", "\r\n");
+
+    let unsealed = ParsedEmail::parse_bytes(email.as_bytes()).unwrap();
+    let res = verify_arc_chain(&unsealed, Arc::clone(&resolver)).await;
+    assert_eq!(res.status, ChainValidationStatus::None);
+    assert_eq!(res.instance, 0);
+
+    let sealed = arc_seal("cloudflare.com", "mx.cloudflare.com; spf=pass", &email).await;
+    assert!(sealed.starts_with("ARC-Seal: i=1; a=rsa-sha256; t=1609459201; cv=none;"));
+    let sealed = arc_seal("example.com", "mx.example.com;\r\n\tarc=pass", &sealed).await;
+    assert!(sealed.contains("ARC-Authentication-Results: i=2; mx.example.com;\r\n\tarc=pass"));
+
+    let email = ParsedEmail::parse_bytes(sealed.as_bytes()).unwrap();
+    let res = verify_arc_chain(&email, Arc::clone(&resolver)).await;
+    assert!(res.error.is_none(), "{:?}", res.error);
+    assert_eq!(res.status, ChainValidationStatus::Pass);
+    assert_eq!(res.instance, 2);
+
+    // Modifying the body breaks the most recent ARC-Message-Signature
+    let tampered = sealed.replace("Hello Alice", "Hello Mallory");
+    let email = ParsedEmail::parse_bytes(tampered.as_bytes()).unwrap();
+    let res = verify_arc_chain(&email, Arc::clone(&resolver)).await;
+    assert_eq!(res.status, ChainValidationStatus::Fail);
+
+    // Modifying an earlier set breaks the seals
+    let tampered = sealed.replace("spf=pass", "spf=fail");
+    let email = ParsedEmail::parse_bytes(tampered.as_bytes()).unwrap();
+    let res = verify_arc_chain(&email, Arc::clone(&resolver)).await;
+    assert_eq!(res.status, ChainValidationStatus::Fail);
+
+    // A failed chain is sealed with cv=fail
+    let sealed = arc_seal("example.com", "mx.example.com; arc=fail", &tampered).await;
+    assert!(sealed.contains("cv=fail"));
+}
@@ -135,15 +135,15 @@ impl Default for SignerBuilder {
 }
 
 pub struct Signer {
-    signed_headers: HeaderList,
-    private_key: DkimPrivateKey,
-    selector: String,
-    signing_domain: String,
-    header_canonicalization: canonicalization::Type,
-    body_canonicalization: canonicalization::Type,
-    expiry: Option<chrono::Duration>,
-    hash_algo: hash::HashAlgo,
-    time: Option<chrono::DateTime<chrono::offset::Utc>>,
+    pub(crate) signed_headers: HeaderList,
+    pub(crate) private_key: DkimPrivateKey,
+    pub(crate) selector: String,
+    pub(crate) signing_domain: String,
+    pub(crate) header_canonicalization: canonicalization::Type,
+    pub(crate) body_canonicalization: canonicalization::Type,
+    pub(crate) expiry: Option<chrono::Duration>,
+    pub(crate) hash_algo: hash::HashAlgo,
+    pub(crate) time: Option<chrono::DateTime<chrono::offset::Utc>>,
 }
 
 /// DKIM signer. Use the [SignerBuilder] to build an instance.
@@ -156,27 +156,7 @@ impl Signer {
 
         let header_hash = self.compute_header_hash(email, dkim_header_builder.clone())?;
 
-        let signature = match &self.private_key {
-            DkimPrivateKey::Rsa(private_key) => private_key
-                .sign(
-                    match &self.hash_algo {
-                        hash::HashAlgo::RsaSha1 => Pkcs1v15Sign::new::<Sha1>(),
-                        hash::HashAlgo::RsaSha256 => Pkcs1v15Sign::new::<Sha256>(),
-                        hash => {
-                            return Err(DKIMError::UnsupportedHashAlgorithm(format!("{:?}", hash)))
-                        }
-                    },
-                    &header_hash,
-                )
-                .map_err(|err| DKIMError::FailedToSign(err.to_string()))?,
-            DkimPrivateKey::Ed25519(keypair) => {
-                let expanded: ExpandedSecretKey = (&keypair.secret).into();
-                expanded
-                    .sign(&header_hash, &keypair.public)
-                    .to_bytes()
-                    .into()
-            }
-        };
+        let signature = sign_hash(&self.private_key, self.hash_algo, &header_hash)?;
 
         // add the signature into the DKIM header and generate the header
         let dkim_header = dkim_header_builder
@@ -242,6 +222,33 @@ impl Signer {
     }
 }
 
+/// Produce the signature for a computed header hash
+pub(crate) fn sign_hash(
+    private_key: &DkimPrivateKey,
+    hash_algo: hash::HashAlgo,
+    header_hash: &[u8],
+) -> Result<Vec<u8>, DKIMError> {
+    Ok(match private_key {
+        DkimPrivateKey::Rsa(private_key) => private_key
+            .sign(
+                match hash_algo {
+                    hash::HashAlgo::RsaSha1 => Pkcs1v15Sign::new::<Sha1>(),
+                    hash::HashAlgo::RsaSha256 => Pkcs1v15Sign::new::<Sha256>(),
+                    hash => return Err(DKIMError::UnsupportedHashAlgorithm(format!("{:?}", hash))),
+                },
+                header_hash,
+            )
+            .map_err(|err| DKIMError::FailedToSign(err.to_string()))?,
+        DkimPrivateKey::Ed25519(keypair) => {
+            let expanded: ExpandedSecretKey = (&keypair.secret).into();
+            expanded
+                .sign(header_hash, &keypair.public)
+                .to_bytes()
+                .into()
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,19 +1,26 @@
 use kumo_log_types::ResolvedAddress;
+use kumo_sender_auth::{Lookup, LookupError, LookupFuture};
 use lruttl::LruCacheWithTtl;
+use rfc5321::TlsaRecord;
 use serde::Serialize;
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv6Addr};
 use std::sync::{Arc, Mutex as StdMutex};
 use std::time::Instant;
-use trust_dns_resolver::error::{ResolveErrorKind, ResolveResult};
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind, ResolveResult};
+use trust_dns_resolver::lookup::Lookup as DnsLookup;
+use trust_dns_resolver::proto::rr::{RData, RecordType};
 use trust_dns_resolver::{Name, TokioAsyncResolver};
 
 lazy_static::lazy_static! {
     static ref RESOLVER: TokioAsyncResolver = TokioAsyncResolver::tokio_from_system_conf().unwrap();
+    static ref DNSSEC_RESOLVER: TokioAsyncResolver = dnssec_resolver().unwrap();
     static ref MX_CACHE: StdMutex<LruCacheWithTtl<Name, Arc<MailExchanger>>> = StdMutex::new(LruCacheWithTtl::new(64 * 1024));
     static ref IPV4_CACHE: StdMutex<LruCacheWithTtl<Name, Arc<Vec<IpAddr>>>> = StdMutex::new(LruCacheWithTtl::new(1024));
     static ref IPV6_CACHE: StdMutex<LruCacheWithTtl<Name, Arc<Vec<IpAddr>>>> = StdMutex::new(LruCacheWithTtl::new(1024));
     static ref IP_CACHE: StdMutex<LruCacheWithTtl<Name, Arc<Vec<IpAddr>>>> = StdMutex::new(LruCacheWithTtl::new(1024));
+    static ref TXT_CACHE: StdMutex<LruCacheWithTtl<Name, Arc<Vec<String>>>> = StdMutex::new(LruCacheWithTtl::new(1024));
+    static ref TLSA_CACHE: StdMutex<LruCacheWithTtl<Name, Arc<Vec<TlsaRecord>>>> = StdMutex::new(LruCacheWithTtl::new(1024));
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -140,6 +147,77 @@ impl MailExchanger {
         Ok(mx)
     }
 
+    /// Builds a MailExchanger that delivers to an explicitly configured
+    /// list of hosts, rather than to the MX hosts of domain_name.
+    /// Each host is paired with its preference; hosts with lower
+    /// preference values are tried first.
+    /// A host may be a name, an IP address or an IP domain literal
+    /// such as `[10.0.0.1]`.
+    pub fn with_hosts(domain_name: &str, hosts: &[(u16, String)]) -> anyhow::Result<Self> {
+        if hosts.is_empty() {
+            anyhow::bail!("no hosts were specified for {domain_name}");
+        }
+
+        let mut by_pref: Vec<ByPreference> = vec![];
+        for (pref, host) in hosts {
+            let host = match host.strip_prefix('[') {
+                Some(literal) => {
+                    let literal = literal.strip_suffix(']').ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "host `{host}` is a malformed literal \
+                             domain with no trailing `]`"
+                        )
+                    })?;
+                    let lowered = literal.to_ascii_lowercase();
+                    let literal = lowered.strip_prefix("ipv6:").unwrap_or(&lowered);
+                    literal
+                        .parse::<IpAddr>()
+                        .map_err(|err| anyhow::anyhow!("invalid address: `{literal}`: {err:#}"))?
+                        .to_string()
+                }
+                None => host.to_ascii_lowercase(),
+            };
+
+            if let Some(record) = by_pref.iter_mut().find(|r| r.pref == *pref) {
+                record.hosts.push(host);
+            } else {
+                by_pref.push(ByPreference {
+                    hosts: vec![host],
+                    pref: *pref,
+                });
+            }
+        }
+
+        by_pref.sort_unstable_by(|a, b| a.pref.cmp(&b.pref));
+        for pref in &mut by_pref {
+            pref.hosts.sort();
+        }
+
+        let hosts: Vec<String> = by_pref
+            .iter()
+            .flat_map(|pref| pref.hosts.iter().cloned())
+            .collect();
+
+        // Addresses don't factor well, and merging the names of distinct
+        // sets of hosts would cause their ready queues to be shared,
+        // so list the hosts explicitly
+        let site_name = hosts.join(",");
+
+        Ok(Self {
+            hosts,
+            domain_name: domain_name.to_string(),
+            site_name,
+            by_pref: by_pref
+                .into_iter()
+                .map(|pref| (pref.pref, pref.hosts))
+                .collect(),
+            // The hosts were not obtained from the MX records of
+            // domain_name, so DNS based policy, such as MTA-STS,
+            // doesn't apply to them, just as it doesn't for a literal
+            is_domain_literal: true,
+        })
+    }
+
     pub async fn resolve_addresses(&self) -> Vec<ResolvedAddress> {
         let mut result = vec![];
 
@@ -319,6 +397,140 @@ pub async fn ipv6_lookup(key: &str) -> ResolveResult<(Arc<Vec<IpAddr>>, Instant)
     Ok((ips, expires))
 }
 
+/// Returns the TXT records for key. The character-strings that
+/// make up each record are concatenated together.
+pub async fn txt_lookup(key: &str) -> ResolveResult<(Arc<Vec<String>>, Instant)> {
+    let key_fq = fully_qualify(key)?;
+    if let Some(value) = TXT_CACHE.lock().unwrap().get_with_expiry(&key_fq) {
+        return Ok(value);
+    }
+
+    let txt_lookup = RESOLVER.txt_lookup(key_fq.clone()).await?;
+    let records = txt_lookup
+        .iter()
+        .map(|txt| {
+            txt.iter()
+                .map(|data| String::from_utf8_lossy(data))
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>();
+
+    let records = Arc::new(records);
+    let expires = txt_lookup.valid_until();
+    TXT_CACHE
+        .lock()
+        .unwrap()
+        .insert(key_fq, records.clone(), expires);
+    Ok((records, expires))
+}
+
+/// A resolver that only returns answers that pass DNSSEC validation
+fn dnssec_resolver() -> ResolveResult<TokioAsyncResolver> {
+    let (config, mut opts) = trust_dns_resolver::system_conf::read_system_conf()?;
+    opts.validate = true;
+    TokioAsyncResolver::tokio(config, opts)
+}
+
+/// Look up the TLSA records for the SMTP service on host:port.
+/// Only records from a DNSSEC validated answer are usable for DANE,
+/// RFC 7672 section 2.2.  An empty list is returned if there are no
+/// TLSA records.  Any other failure, such as an answer that fails
+/// validation, a SERVFAIL or a timeout, is returned as an error, so
+/// that DANE cannot be disabled by interfering with the query.
+pub async fn tlsa_lookup(port: u16, host: &str) -> ResolveResult<(Arc<Vec<TlsaRecord>>, Instant)> {
+    let key_fq = fully_qualify(&format!("_{port}._tcp.{host}"))?;
+    if let Some(value) = TLSA_CACHE.lock().unwrap().get_with_expiry(&key_fq) {
+        return Ok(value);
+    }
+
+    let answer = DNSSEC_RESOLVER
+        .lookup(key_fq.clone(), RecordType::TLSA)
+        .await;
+    let (records, expires) = validated_tlsa_records(&key_fq, answer)?;
+    if records.is_empty() {
+        return Ok((Arc::new(vec![]), Instant::now()));
+    }
+
+    let records = Arc::new(records);
+    TLSA_CACHE
+        .lock()
+        .unwrap()
+        .insert(key_fq, records.clone(), expires);
+    Ok((records, expires))
+}
+
+/// Extracts the TLSA records from the answer of the validating resolver.
+/// A nonexistent answer yields no records.  Answers that failed
+/// validation are surfaced by the resolver as errors, which are
+/// returned, as are indeterminate answers.
+fn validated_tlsa_records(
+    key: &Name,
+    answer: ResolveResult<DnsLookup>,
+) -> ResolveResult<(Vec<TlsaRecord>, Instant)> {
+    let lookup = match answer {
+        Ok(lookup) => lookup,
+        Err(err) => match err.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => return Ok((vec![], Instant::now())),
+            _ => {
+                tracing::debug!("TLSA lookup for {key} failed: {err:#}");
+                return Err(err);
+            }
+        },
+    };
+    let records = lookup
+        .iter()
+        .filter_map(|rdata| match rdata {
+            RData::TLSA(tlsa) => Some(TlsaRecord {
+                usage: tlsa.cert_usage().into(),
+                selector: tlsa.selector().into(),
+                matching: tlsa.matching().into(),
+                data: tlsa.cert_data().to_vec(),
+            }),
+            _ => None,
+        })
+        .collect();
+    Ok((records, lookup.valid_until()))
+}
+
+/// Makes the cached resolver available for SPF and DMARC evaluation
+pub struct SenderAuthLookup;
+
+fn to_lookup_error(err: ResolveError) -> LookupError {
+    match err.kind() {
+        ResolveErrorKind::NoRecordsFound { .. } => LookupError::NotFound,
+        _ => LookupError::Temporary(format!("{err:#}")),
+    }
+}
+
+impl Lookup for SenderAuthLookup {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> LookupFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            let (records, _expires) = txt_lookup(name).await.map_err(to_lookup_error)?;
+            Ok(records.to_vec())
+        })
+    }
+
+    fn lookup_ip<'a>(&'a self, name: &'a str) -> LookupFuture<'a, Vec<IpAddr>> {
+        Box::pin(async move {
+            let (addrs, _expires) = ip_lookup(name).await.map_err(to_lookup_error)?;
+            Ok(addrs.to_vec())
+        })
+    }
+
+    fn lookup_mx<'a>(&'a self, name: &'a str) -> LookupFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            // Unlike MailExchanger::resolve, this must not fall back
+            // to the implicit MX when there are no MX records
+            let name_fq = fully_qualify(name).map_err(to_lookup_error)?;
+            let mx_lookup = RESOLVER.mx_lookup(name_fq).await.map_err(to_lookup_error)?;
+            Ok(mx_lookup
+                .iter()
+                .map(|mx| mx.exchange().to_lowercase().to_string())
+                .collect())
+        })
+    }
+}
+
 /// Given a list of host names, produce a pseudo-regex style alternation list
 /// of the different elements of the hostnames.
 /// The goal is to produce a more compact representation of the name list
@@ -391,6 +603,57 @@ fn factor_names<S: AsRef<str>>(name_strings: &[S]) -> String {
 #[cfg(test)]
 mod test {
     use super::*;
+    use trust_dns_resolver::proto::error::{ProtoError, ProtoErrorKind};
+    use trust_dns_resolver::proto::op::{Query, ResponseCode};
+    use trust_dns_resolver::proto::rr::rdata::tlsa::{CertUsage, Matching, Selector, TLSA};
+
+    fn tlsa_answer(name: &Name) -> DnsLookup {
+        DnsLookup::from_rdata(
+            Query::query(name.clone(), RecordType::TLSA),
+            RData::TLSA(TLSA::new(
+                CertUsage::DomainIssued,
+                Selector::Spki,
+                Matching::Sha256,
+                vec![0xab; 32],
+            )),
+        )
+    }
+
+    #[test]
+    fn validated_tlsa() {
+        let name = fully_qualify("_25._tcp.mx.example.com").unwrap();
+        let (records, _expires) = validated_tlsa_records(&name, Ok(tlsa_answer(&name))).unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].is_usable());
+    }
+
+    #[test]
+    fn unvalidated_tlsa() {
+        // An answer that fails validation, or that cannot be obtained,
+        // must not silently disable DANE
+        let name = fully_qualify("_25._tcp.mx.example.com").unwrap();
+        let err: ResolveError = ProtoError::from(ProtoErrorKind::RrsigsNotPresent {
+            name: name.clone(),
+            record_type: RecordType::TLSA,
+        })
+        .into();
+        assert!(validated_tlsa_records(&name, Err(err)).is_err());
+
+        let err = ResolveError::from("SERVFAIL");
+        assert!(validated_tlsa_records(&name, Err(err)).is_err());
+
+        // A nonexistent answer means that DANE doesn't apply
+        let err: ResolveError = ResolveErrorKind::NoRecordsFound {
+            query: Box::new(Query::query(name.clone(), RecordType::TLSA)),
+            soa: None,
+            negative_ttl: None,
+            response_code: ResponseCode::NXDomain,
+            trusted: true,
+        }
+        .into();
+        let (records, _expires) = validated_tlsa_records(&name, Err(err)).unwrap();
+        assert!(records.is_empty());
+    }
 
     #[tokio::test]
     async fn literal_resolve() {
@@ -488,6 +751,51 @@ MailExchanger {
         );
     }
 
+    #[tokio::test]
+    async fn explicit_hosts() {
+        let mx = MailExchanger::with_hosts(
+            "example.com",
+            &[
+                (20, "backup.Partner.com".to_string()),
+                (10, "relay2.partner.com".to_string()),
+                (10, "relay1.partner.com".to_string()),
+                (30, "[10.0.0.1]".to_string()),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            mx.hosts,
+            vec![
+                "relay1.partner.com",
+                "relay2.partner.com",
+                "backup.partner.com",
+                "10.0.0.1"
+            ]
+        );
+        assert_eq!(
+            mx.site_name,
+            "relay1.partner.com,relay2.partner.com,backup.partner.com,10.0.0.1"
+        );
+        assert!(mx.is_domain_literal);
+
+        let literal =
+            MailExchanger::with_hosts("example.com", &[(1, "[IPv6:::1]".to_string())]).unwrap();
+        k9::snapshot!(
+            literal.resolve_addresses().await,
+            r#"
+[
+    ResolvedAddress {
+        name: "::1",
+        addr: ::1,
+    },
+]
+"#
+        );
+
+        assert!(MailExchanger::with_hosts("example.com", &[]).is_err());
+        assert!(MailExchanger::with_hosts("example.com", &[(1, "[nope]".to_string())]).is_err());
+    }
+
     #[test]
     fn name_factoring() {
         assert_eq!(
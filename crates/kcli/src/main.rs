@@ -5,6 +5,14 @@ mod bounce;
 mod bounce_cancel;
 mod bounce_list;
 mod logfilter;
+mod queue_summary;
+mod rebind;
+mod reload;
+mod shaping_list;
+mod suspend;
+mod suspend_cancel;
+mod suspend_list;
+mod trace;
 
 /// KumoMTA CLI.
 ///
@@ -26,6 +34,14 @@ enum SubCommand {
     BounceList(bounce_list::BounceListCommand),
     BounceCancel(bounce_cancel::BounceCancelCommand),
     SetLogFilter(logfilter::SetLogFilterCommand),
+    QueueSummary(queue_summary::QueueSummaryCommand),
+    Rebind(rebind::RebindCommand),
+    Reload(reload::ReloadCommand),
+    ShapingList(shaping_list::ShapingListCommand),
+    Suspend(suspend::SuspendCommand),
+    SuspendList(suspend_list::SuspendListCommand),
+    SuspendCancel(suspend_cancel::SuspendCancelCommand),
+    Trace(trace::TraceCommand),
 }
 
 impl SubCommand {
@@ -35,6 +51,14 @@ impl SubCommand {
             Self::BounceCancel(cmd) => cmd.run(endpoint).await,
             Self::BounceList(cmd) => cmd.run(endpoint).await,
             Self::SetLogFilter(cmd) => cmd.run(endpoint).await,
+            Self::QueueSummary(cmd) => cmd.run(endpoint).await,
+            Self::Rebind(cmd) => cmd.run(endpoint).await,
+            Self::Reload(cmd) => cmd.run(endpoint).await,
+            Self::ShapingList(cmd) => cmd.run(endpoint).await,
+            Self::Suspend(cmd) => cmd.run(endpoint).await,
+            Self::SuspendCancel(cmd) => cmd.run(endpoint).await,
+            Self::SuspendList(cmd) => cmd.run(endpoint).await,
+            Self::Trace(cmd) => cmd.run(endpoint).await,
         }
     }
 }
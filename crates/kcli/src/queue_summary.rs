@@ -0,0 +1,24 @@
+use clap::Parser;
+use kumo_api_types::QueueSummaryV1Response;
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Returns a summary of the scheduled and ready queues.
+///
+/// Lists each queue on the target instance together with its
+/// message count, the age of its oldest message and, for ready
+/// queues, the number of active connections.
+pub struct QueueSummaryCommand {}
+
+impl QueueSummaryCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let result: QueueSummaryV1Response = reqwest::get(endpoint.join("/api/admin/queues/v1")?)
+            .await?
+            .json()
+            .await?;
+
+        println!("{}", serde_json::to_string_pretty(&result)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use clap::Parser;
+use kumo_api_types::{RebindV1Request, RebindV1Response};
+use reqwest::Url;
+use std::collections::HashMap;
+
+#[derive(Debug, Parser)]
+/// Rebind messages in matching queues.
+///
+/// The scheduled messages in each matching queue are updated
+/// and placed into the queue that corresponds to their updated
+/// meta values.  Messages that move to a different queue become
+/// due immediately.
+///
+/// Use --always-flush without any --set values to force an
+/// immediate retry of the matching messages.
+pub struct RebindCommand {
+    /// The domain name to match.
+    /// If omitted, any domains will match!
+    #[arg(long)]
+    domain: Option<String>,
+
+    /// The routing domain to match.
+    /// If omitted, any routing domains will match!
+    #[arg(long)]
+    routing_domain: Option<String>,
+
+    /// The campaign name to match.
+    /// If omitted, any campaigns will match!
+    #[arg(long)]
+    campaign: Option<String>,
+
+    /// The tenant name to match.
+    /// If omitted, any tenant will match!
+    #[arg(long)]
+    tenant: Option<String>,
+
+    /// The reason to log in the delivery logs
+    #[arg(long)]
+    reason: String,
+
+    /// Rebind all queues.
+    #[arg(long)]
+    everything: bool,
+
+    /// Meta values to assign to the matching messages,
+    /// specified as KEY=VALUE. May be repeated.
+    #[arg(long, value_parser=parse_key_value)]
+    set: Vec<(String, String)>,
+
+    /// Assign this egress pool to the matching messages,
+    /// overriding the pool from their queue config.
+    #[arg(long)]
+    egress_pool: Option<String>,
+
+    /// Make messages that remain in the same queue
+    /// due for immediate delivery.
+    #[arg(long)]
+    always_flush: bool,
+
+    /// Call the rebind_message event for each message,
+    /// passing the --set values to it, rather than assigning
+    /// them directly to the message meta.
+    #[arg(long)]
+    trigger_rebind_event: bool,
+}
+
+fn parse_key_value(s: &str) -> anyhow::Result<(String, String)> {
+    match s.split_once('=') {
+        Some((key, value)) => Ok((key.to_string(), value.to_string())),
+        None => anyhow::bail!("expected KEY=VALUE, but got {s}"),
+    }
+}
+
+impl RebindCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        if self.domain.is_none()
+            && self.routing_domain.is_none()
+            && self.campaign.is_none()
+            && self.tenant.is_none()
+            && !self.everything
+        {
+            anyhow::bail!(
+                "No domain, routing domain, campaign or tenant was specified. \
+                 Use --everything if you intend to rebind all queues"
+            );
+        }
+
+        let result: RebindV1Response = crate::post(
+            endpoint.join("/api/admin/rebind/v1")?,
+            &RebindV1Request {
+                campaign: self.campaign.clone(),
+                domain: self.domain.clone(),
+                routing_domain: self.routing_domain.clone(),
+                tenant: self.tenant.clone(),
+                reason: self.reason.clone(),
+                data: self.set.iter().cloned().collect::<HashMap<_, _>>(),
+                egress_pool: self.egress_pool.clone(),
+                always_flush: self.always_flush,
+                trigger_rebind_event: self.trigger_rebind_event,
+            },
+        )
+        .await?
+        .json()
+        .await?;
+
+        println!("{}", serde_json::to_string_pretty(&result)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+use clap::Parser;
+use kumo_api_types::ReloadV1Response;
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Reloads the lua policy on the target instance.
+///
+/// The policy file is loaded and validated first; if it fails
+/// to load, the error is reported and the current policy remains
+/// in effect.
+pub struct ReloadCommand {}
+
+impl ReloadCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let response = reqwest::Client::builder()
+            .build()?
+            .post(endpoint.join("/api/admin/reload/v1")?)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("{status}: {}", response.text().await?);
+        }
+
+        let result: ReloadV1Response = response.json().await?;
+        println!("{}", serde_json::to_string_pretty(&result)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,24 @@
+use clap::Parser;
+use kumo_api_types::ShapingV1ListEntry;
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Returns the list of active traffic shaping overrides.
+///
+/// Each entry describes an egress path whose connection limit or
+/// message rate has been temporarily reduced because a response
+/// from the remote host matched one of its shaping rules.
+pub struct ShapingListCommand {}
+
+impl ShapingListCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let result: Vec<ShapingV1ListEntry> = reqwest::get(endpoint.join("/api/admin/shaping/v1")?)
+            .await?
+            .json()
+            .await?;
+
+        println!("{}", serde_json::to_string_pretty(&result)?);
+
+        Ok(())
+    }
+}
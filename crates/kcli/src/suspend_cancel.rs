@@ -0,0 +1,41 @@
+use clap::Parser;
+use kumo_api_types::SuspendV1CancelRequest;
+use reqwest::Url;
+use uuid::Uuid;
+
+#[derive(Debug, Parser)]
+/// Cancels an admin suspend entry.
+///
+/// Cancelling the entry resumes delivery of the messages
+/// that it matched.
+pub struct SuspendCancelCommand {
+    /// The id field of the suspend entry that you wish to cancel
+    #[arg(long, value_parser=Uuid::parse_str)]
+    pub id: Uuid,
+}
+
+impl SuspendCancelCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let response = reqwest::Client::builder()
+            .build()?
+            .delete(endpoint.join("/api/admin/suspend/v1")?)
+            .json(&SuspendV1CancelRequest { id: self.id })
+            .send()
+            .await?;
+        let status = response.status();
+
+        let response = response.text().await?;
+
+        if !status.is_success() {
+            anyhow::bail!("{response}");
+        }
+
+        if !response.is_empty() {
+            println!("{response}");
+        } else {
+            println!("OK");
+        }
+
+        Ok(())
+    }
+}
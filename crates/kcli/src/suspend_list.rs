@@ -0,0 +1,23 @@
+use clap::Parser;
+use kumo_api_types::SuspendV1ListEntry;
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Returns list of current administrative suspend rules.
+///
+/// Returns the list of un-expired admin suspensions that are
+/// currently in effect on the target instance.
+pub struct SuspendListCommand {}
+
+impl SuspendListCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let result: Vec<SuspendV1ListEntry> = reqwest::get(endpoint.join("/api/admin/suspend/v1")?)
+            .await?
+            .json()
+            .await?;
+
+        println!("{}", serde_json::to_string_pretty(&result)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+use clap::Parser;
+use kumo_api_types::TraceV1Response;
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Reports the current state of a message.
+///
+/// Shows whether the message is still in the spool, which queue
+/// holds it, when it is next due, how many delivery attempts have
+/// been made, its metadata and its most recent delivery response.
+pub struct TraceCommand {
+    /// The id of the message
+    id: String,
+}
+
+impl TraceCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let response =
+            reqwest::get(endpoint.join(&format!("/api/admin/trace/v1/{}", self.id))?).await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("{status}: {}", response.text().await?);
+        }
+
+        let result: TraceV1Response = response.json().await?;
+        println!("{}", serde_json::to_string_pretty(&result)?);
+
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::time::Duration;
@@ -64,3 +65,239 @@ pub struct BounceV1ListEntry {
 pub struct BounceV1CancelRequest {
     pub id: Uuid,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QueueSummaryV1Response {
+    /// The scheduled queues, holding messages that are
+    /// waiting for their next delivery attempt
+    pub scheduled: Vec<ScheduledQueueSummaryV1>,
+    /// The ready queues, holding messages that are due for
+    /// delivery via a specific egress source
+    pub ready: Vec<ReadyQueueSummaryV1>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScheduledQueueSummaryV1 {
+    pub name: String,
+    pub egress_pool: String,
+    pub message_count: usize,
+    /// The age of the oldest message in the queue
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub oldest_age: Option<Duration>,
+    /// The soonest time at which a message becomes due
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_due: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadyQueueSummaryV1 {
+    pub name: String,
+    pub egress_source: String,
+    pub egress_pool: String,
+    pub message_count: usize,
+    /// The age of the oldest message in the queue
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub oldest_age: Option<Duration>,
+    pub connection_count: usize,
+    pub connection_limit: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QueueSampleV1Request {
+    /// The maximum number of messages to return
+    #[serde(default = "default_sample_limit")]
+    pub limit: usize,
+}
+
+fn default_sample_limit() -> usize {
+    10
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QueueSampleV1Response {
+    pub name: String,
+    pub message_count: usize,
+    pub messages: Vec<QueueSampleV1Entry>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QueueSampleV1Entry {
+    pub id: String,
+    #[serde(with = "humantime_serde")]
+    pub age: Duration,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub due: Option<DateTime<Utc>>,
+    pub num_attempts: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    /// The message metadata
+    pub meta: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SuspendV1Request {
+    #[serde(default)]
+    pub campaign: Option<String>,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub domain: Option<String>,
+
+    pub reason: String,
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub duration: Option<Duration>,
+}
+
+impl SuspendV1Request {
+    pub fn duration(&self) -> Duration {
+        self.duration.unwrap_or_else(default_duration)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SuspendV1Response {
+    pub id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SuspendV1ListEntry {
+    pub id: Uuid,
+
+    #[serde(default)]
+    pub campaign: Option<String>,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub domain: Option<String>,
+
+    pub reason: String,
+
+    #[serde(with = "humantime_serde")]
+    pub duration: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SuspendV1CancelRequest {
+    pub id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RebindV1Request {
+    #[serde(default)]
+    pub campaign: Option<String>,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// Match messages that are routed via this domain;
+    /// see the `routing_domain` meta value
+    #[serde(default)]
+    pub routing_domain: Option<String>,
+
+    /// The reason for the rebind
+    pub reason: String,
+
+    /// Meta values to assign to each matching message; setting
+    /// `queue`, `tenant` or `campaign` moves it to a different queue
+    #[serde(default)]
+    pub data: HashMap<String, String>,
+
+    /// Assign this egress pool to each matching message,
+    /// overriding the pool from its queue config
+    #[serde(default)]
+    pub egress_pool: Option<String>,
+
+    /// Make messages that remain in their current queue
+    /// immediately due, rather than keeping their schedule
+    #[serde(default)]
+    pub always_flush: bool,
+
+    /// Call the `rebind_message` event for each matching message
+    /// instead of applying `data` to its meta
+    #[serde(default)]
+    pub trigger_rebind_event: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RebindV1Response {
+    pub rebound: HashMap<String, usize>,
+    pub total_rebound: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReloadV1Response {
+    /// The policy generation that is now in effect.
+    /// This is incremented by each successful reload.
+    pub generation: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShapingV1ListEntry {
+    /// The name of the ready queue whose egress path is being shaped
+    pub site: String,
+    /// The regex of the shaping rule that matched
+    pub rule: String,
+    /// The response that matched the rule
+    pub response: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection_limit: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_message_rate: Option<String>,
+    /// How long until the override expires
+    #[serde(with = "humantime_serde")]
+    pub duration: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TraceV1Response {
+    pub id: String,
+    /// Whether the message metadata is present in the spool
+    pub in_spool: bool,
+    /// The name of the queue that currently holds the message.
+    /// If the message is not queued, this is the scheduled queue
+    /// named by its metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue: Option<String>,
+    /// Either "scheduled" or "ready", depending on the kind of queue
+    /// that holds the message. Absent if the message is not currently
+    /// queued, which is the case while a delivery attempt is in progress.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub due: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_attempts: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    /// The message metadata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<serde_json::Value>,
+    /// The most recently logged disposition for the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_disposition: Option<TraceV1Disposition>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TraceV1Disposition {
+    /// The log record type, such as "TransientFailure"
+    pub kind: String,
+    pub site: String,
+    pub response: String,
+    pub timestamp: DateTime<Utc>,
+}
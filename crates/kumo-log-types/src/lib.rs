@@ -1,7 +1,7 @@
 use crate::rfc5965::ARFReport;
 use bounce_classify::BounceClass;
 use chrono::{DateTime, Utc};
-use rfc5321::Response;
+use rfc5321::{DsnParameters, Response};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
@@ -9,6 +9,7 @@ use std::net::IpAddr;
 
 pub mod rfc3464;
 pub mod rfc5965;
+pub mod rfc8460;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ResolvedAddress {
@@ -29,6 +30,8 @@ pub enum RecordType {
     Expiration,
     /// Administratively failed
     AdminBounce,
+    /// Administratively moved to a different queue or rescheduled
+    AdminRebind,
     /// Contains information about an OOB bounce
     OOB,
     /// Contains a feedback report
@@ -86,4 +89,8 @@ pub struct JsonLogRecord {
 
     /// The protocol used to receive this message
     pub reception_protocol: Option<String>,
+
+    /// The RFC 3461 DSN parameters that were supplied
+    /// when the message was received
+    pub dsn: Option<DsnParameters>,
 }
@@ -1,5 +1,5 @@
 //! This module parses out RFC3464 delivery status reports
-//! from an email message
+//! from an email message, and can also produce them
 use crate::rfc5965::{
     extract_headers, extract_single, extract_single_conv, extract_single_req, DateTimeRfc2822,
 };
@@ -8,6 +8,7 @@ use chrono::{DateTime, Utc};
 use mailparse::{parse_headers, parse_mail, ParsedMail};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult, Write};
 use std::str::FromStr;
 
 #[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
@@ -34,6 +35,19 @@ impl FromStr for ReportAction {
     }
 }
 
+impl Display for ReportAction {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let action = match self {
+            Self::Failed => "failed",
+            Self::Delayed => "delayed",
+            Self::Delivered => "delivered",
+            Self::Relayed => "relayed",
+            Self::Expanded => "expanded",
+        };
+        write!(fmt, "{action}")
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct ReportStatus {
     pub class: u8,
@@ -80,6 +94,16 @@ impl FromStr for ReportStatus {
     }
 }
 
+impl Display for ReportStatus {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{}.{}.{}", self.class, self.subject, self.detail)?;
+        if let Some(comment) = &self.comment {
+            write!(fmt, " {comment}")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct RemoteMta {
     pub mta_type: String,
@@ -100,6 +124,12 @@ impl FromStr for RemoteMta {
     }
 }
 
+impl Display for RemoteMta {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{}; {}", self.mta_type, self.name)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct Recipient {
     pub recipient_type: String,
@@ -118,6 +148,12 @@ impl FromStr for Recipient {
     }
 }
 
+impl Display for Recipient {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{}; {}", self.recipient_type, self.recipient)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct DiagnosticCode {
     pub diagnostic_type: String,
@@ -136,6 +172,24 @@ impl FromStr for DiagnosticCode {
     }
 }
 
+impl Display for DiagnosticCode {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{}; {}", self.diagnostic_type, self.diagnostic)
+    }
+}
+
+/// Emits the extension fields of a report entry in a stable order
+fn write_extensions(fmt: &mut Formatter, extensions: &HashMap<String, Vec<String>>) -> FmtResult {
+    let mut names: Vec<&String> = extensions.keys().collect();
+    names.sort();
+    for name in names {
+        for value in &extensions[name] {
+            write!(fmt, "{name}: {value}\r\n")?;
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct PerRecipientReportEntry {
     pub final_recipient: Recipient,
@@ -187,6 +241,41 @@ impl PerRecipientReportEntry {
     }
 }
 
+impl Display for PerRecipientReportEntry {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        if let Some(original_recipient) = &self.original_recipient {
+            write!(fmt, "Original-Recipient: {original_recipient}\r\n")?;
+        }
+        write!(fmt, "Final-Recipient: {}\r\n", self.final_recipient)?;
+        write!(fmt, "Action: {}\r\n", self.action)?;
+        write!(fmt, "Status: {}\r\n", self.status)?;
+        if let Some(remote_mta) = &self.remote_mta {
+            write!(fmt, "Remote-MTA: {remote_mta}\r\n")?;
+        }
+        if let Some(diagnostic_code) = &self.diagnostic_code {
+            write!(fmt, "Diagnostic-Code: {diagnostic_code}\r\n")?;
+        }
+        if let Some(last_attempt_date) = &self.last_attempt_date {
+            write!(
+                fmt,
+                "Last-Attempt-Date: {}\r\n",
+                last_attempt_date.to_rfc2822()
+            )?;
+        }
+        if let Some(final_log_id) = &self.final_log_id {
+            write!(fmt, "Final-Log-ID: {final_log_id}\r\n")?;
+        }
+        if let Some(will_retry_until) = &self.will_retry_until {
+            write!(
+                fmt,
+                "Will-Retry-Until: {}\r\n",
+                will_retry_until.to_rfc2822()
+            )?;
+        }
+        write_extensions(fmt, &self.extensions)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct PerMessageReportEntry {
     pub original_envelope_id: Option<String>,
@@ -206,7 +295,8 @@ impl PerMessageReportEntry {
         let dsn_gateway = extract_single("dsn-gateway", &mut extensions)?;
         let received_from_mta = extract_single("received-from-mta", &mut extensions)?;
 
-        let arrival_date = extract_single("arrival-date", &mut extensions)?;
+        let arrival_date =
+            extract_single_conv::<DateTimeRfc2822, DateTime<Utc>>("arrival-date", &mut extensions)?;
 
         Ok(Self {
             original_envelope_id,
@@ -219,6 +309,41 @@ impl PerMessageReportEntry {
     }
 }
 
+impl Display for PerMessageReportEntry {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        if let Some(original_envelope_id) = &self.original_envelope_id {
+            write!(fmt, "Original-Envelope-Id: {original_envelope_id}\r\n")?;
+        }
+        write!(fmt, "Reporting-MTA: {}\r\n", self.reporting_mta)?;
+        if let Some(dsn_gateway) = &self.dsn_gateway {
+            write!(fmt, "DSN-Gateway: {dsn_gateway}\r\n")?;
+        }
+        if let Some(received_from_mta) = &self.received_from_mta {
+            write!(fmt, "Received-From-MTA: {received_from_mta}\r\n")?;
+        }
+        if let Some(arrival_date) = &self.arrival_date {
+            write!(fmt, "Arrival-Date: {}\r\n", arrival_date.to_rfc2822())?;
+        }
+        write_extensions(fmt, &self.extensions)
+    }
+}
+
+/// The envelope and human readable portions of a multipart/report
+/// message produced by `Report::to_message`
+pub struct ReportMessageParams<'a> {
+    pub from: &'a str,
+    pub to: &'a str,
+    pub subject: &'a str,
+    pub message_id: &'a str,
+    pub date: DateTime<Utc>,
+    pub boundary: &'a str,
+    /// The human readable explanation of the report
+    pub text: &'a str,
+    /// When true, `Report::original_message` holds only the
+    /// headers of the original message
+    pub headers_only: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct Report {
     pub per_message: PerMessageReportEntry,
@@ -278,12 +403,141 @@ impl Report {
             original_message,
         })
     }
+
+    /// Returns the content of the message/delivery-status part
+    pub fn delivery_status(&self) -> String {
+        let mut status = self.per_message.to_string();
+        for recip in &self.per_recipient {
+            status.push_str("\r\n");
+            status.push_str(&recip.to_string());
+        }
+        status
+    }
+
+    /// Compose a complete multipart/report message that
+    /// conveys this report
+    pub fn to_message(&self, params: &ReportMessageParams) -> String {
+        let ReportMessageParams {
+            from,
+            to,
+            subject,
+            message_id,
+            date,
+            boundary,
+            text,
+            headers_only,
+        } = params;
+        let mut message = String::new();
+
+        // Writing to a String cannot fail
+        let _ = write!(
+            message,
+            "From: {from}\r\n\
+             To: {to}\r\n\
+             Subject: {subject}\r\n\
+             Message-ID: {message_id}\r\n\
+             Date: {date}\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: multipart/report; report-type=delivery-status;\r\n\
+             \tboundary=\"{boundary}\"\r\n\
+             \r\n\
+             --{boundary}\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             \r\n\
+             {text}\r\n\
+             \r\n\
+             --{boundary}\r\n\
+             Content-Type: message/delivery-status\r\n\
+             \r\n\
+             {status}\r\n",
+            date = date.to_rfc2822(),
+            text = normalize_crlf(text),
+            status = self.delivery_status(),
+        );
+
+        if let Some(original) = &self.original_message {
+            let content_type = if *headers_only {
+                "text/rfc822-headers"
+            } else {
+                "message/rfc822"
+            };
+            let _ = write!(
+                message,
+                "--{boundary}\r\n\
+                 Content-Type: {content_type}\r\n\
+                 \r\n\
+                 {original}\r\n",
+                original = normalize_crlf(original.trim_end()),
+            );
+        }
+
+        let _ = write!(message, "--{boundary}--\r\n");
+        message
+    }
+}
+
+fn normalize_crlf(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\n', "\r\n")
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn rfc3464_round_trip() {
+        let date: DateTime<Utc> = DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:52:37 +0200")
+            .unwrap()
+            .into();
+        let mut report = Report {
+            per_message: PerMessageReportEntry {
+                original_envelope_id: Some("QQ314159".to_string()),
+                reporting_mta: "dns; mta.example.com".parse().unwrap(),
+                dsn_gateway: None,
+                received_from_mta: None,
+                arrival_date: Some(date),
+                extensions: HashMap::new(),
+            },
+            per_recipient: vec![PerRecipientReportEntry {
+                final_recipient: "rfc822; user@example.com".parse().unwrap(),
+                action: ReportAction::Failed,
+                status: "5.1.1".parse().unwrap(),
+                original_recipient: Some("rfc822; User@example.com".parse().unwrap()),
+                remote_mta: Some("dns; mx.example.com".parse().unwrap()),
+                diagnostic_code: Some("smtp; 550 5.1.1 no such user".parse().unwrap()),
+                last_attempt_date: Some(date),
+                final_log_id: None,
+                will_retry_until: None,
+                extensions: HashMap::new(),
+            }],
+            original_message: Some("Subject: hello\n".to_string()),
+        };
+        // Each recipient has its own group of fields
+        let mut other = report.per_recipient[0].clone();
+        other.final_recipient = "rfc822; other@example.com".parse().unwrap();
+        other.original_recipient = None;
+        report.per_recipient.push(other);
+
+        let message = report.to_message(&ReportMessageParams {
+            from: "<MAILER-DAEMON@mta.example.com>",
+            to: "<sender@example.com>",
+            subject: "Undelivered Mail",
+            message_id: "<report@mta.example.com>",
+            date,
+            boundary: "report-boundary",
+            text: "Your message could not be delivered.",
+            headers_only: true,
+        });
+
+        let parsed = Report::parse(message.as_bytes()).unwrap().unwrap();
+        assert_eq!(parsed.per_message, report.per_message);
+        assert_eq!(parsed.per_recipient, report.per_recipient);
+        assert!(parsed
+            .original_message
+            .unwrap()
+            .starts_with("Subject: hello"));
+    }
+
     #[test]
     fn rfc3464_1() {
         let result = Report::parse(include_bytes!("../data/rfc3464/1.eml")).unwrap();
@@ -130,6 +130,8 @@ impl ARFReport {
         let original_rcpto_to = extract_multiple("original-rcpt-to", &mut extensions)?;
         let reported_domain = extract_multiple("reported-domain", &mut extensions)?;
         let reported_uri = extract_multiple("reported-uri", &mut extensions)?;
+        let supplemental_trace =
+            supplemental_trace.map(|trace| resolve_batch_recipient(trace, &original_rcpto_to));
 
         Ok(Self {
             feedback_type,
@@ -152,6 +154,40 @@ impl ARFReport {
     }
 }
 
+/// A message that was received as part of a batch lists all of its
+/// recipients in its supplemental trace. Fill in the "recipient" that
+/// unbatched messages carry, using the recipient that the report is about.
+fn resolve_batch_recipient(
+    mut trace: serde_json::Value,
+    original_rcpt_to: &[String],
+) -> serde_json::Value {
+    let obj = match trace.as_object_mut() {
+        Some(obj) if !obj.contains_key("recipient") => obj,
+        _ => return trace,
+    };
+    let recipient = obj
+        .get("recipients")
+        .and_then(|recipients| recipients.as_array())
+        .and_then(|recipients| {
+            recipients
+                .iter()
+                .filter_map(|recipient| recipient.as_str())
+                .find(|recipient| {
+                    original_rcpt_to.iter().any(|rcpt| {
+                        rcpt.trim()
+                            .trim_start_matches('<')
+                            .trim_end_matches('>')
+                            .eq_ignore_ascii_case(recipient)
+                    })
+                })
+        })
+        .map(|recipient| recipient.to_string());
+    if let Some(recipient) = recipient {
+        obj.insert("recipient".to_string(), recipient.into());
+    }
+    trace
+}
+
 pub(crate) fn extract_headers(part: &[u8]) -> anyhow::Result<HashMap<String, Vec<String>>> {
     let (headers, _) = parse_headers(part)?;
 
@@ -378,4 +414,17 @@ Spam Spam Spam
 "#
         );
     }
+
+    #[test]
+    fn rfc5965_batch_recipient() {
+        let result = ARFReport::parse(include_bytes!("../data/rfc5965/3.eml"))
+            .unwrap()
+            .unwrap();
+        let trace = result.supplemental_trace.unwrap();
+        assert_eq!(trace["recipient"], "user@example.com");
+        assert_eq!(
+            trace["recipients"],
+            serde_json::json!(["other@example.com", "user@example.com"])
+        );
+    }
 }
@@ -0,0 +1,332 @@
+//! SMTP TLS Reporting (RFC 8460) aggregate reports
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::net::IpAddr;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum PolicyType {
+    Tlsa,
+    Sts,
+    NoPolicyFound,
+}
+
+/// The result types from RFC 8460 section 4.3
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResultType {
+    StarttlsNotSupported,
+    CertificateHostMismatch,
+    CertificateExpired,
+    CertificateNotTrusted,
+    ValidationFailure,
+    TlsaInvalid,
+    DnssecInvalid,
+    DaneRequired,
+    StsPolicyFetchError,
+    StsPolicyInvalid,
+    StsWebpkiInvalid,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub struct PolicyDetails {
+    pub policy_type: PolicyType,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub policy_string: Vec<String>,
+    pub policy_domain: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mx_host: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Summary {
+    pub total_successful_session_count: u64,
+    pub total_failure_session_count: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct FailureDetails {
+    pub result_type: ResultType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sending_mta_ip: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receiving_mx_hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receiving_mx_helo: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receiving_ip: Option<IpAddr>,
+    pub failed_session_count: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub additional_information: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure_reason_code: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct PolicyReport {
+    pub policy: PolicyDetails,
+    pub summary: Summary,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failure_details: Vec<FailureDetails>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DateRange {
+    pub start_datetime: DateTime<Utc>,
+    pub end_datetime: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Report {
+    pub organization_name: String,
+    pub date_range: DateRange,
+    pub contact_info: String,
+    pub report_id: String,
+    pub policies: Vec<PolicyReport>,
+}
+
+pub struct ReportMessageParams<'a> {
+    pub from: &'a str,
+    pub to: &'a str,
+    pub message_id: &'a str,
+    pub date: DateTime<Utc>,
+    pub boundary: &'a str,
+    /// The domain for which the report was generated
+    pub policy_domain: &'a str,
+    /// The human readable explanation of the report
+    pub text: &'a str,
+}
+
+impl Report {
+    /// The file name for the report; RFC 8460 section 5.1
+    pub fn file_name(&self, policy_domain: &str) -> String {
+        format!(
+            "{}!{policy_domain}!{}!{}!{}.json",
+            self.organization_name,
+            self.date_range.start_datetime.timestamp(),
+            self.date_range.end_datetime.timestamp(),
+            self.report_id
+        )
+    }
+
+    /// Produce a multipart/report message holding the JSON
+    /// report; RFC 8460 section 5.3
+    pub fn to_message(&self, params: &ReportMessageParams) -> String {
+        let ReportMessageParams {
+            from,
+            to,
+            message_id,
+            date,
+            boundary,
+            policy_domain,
+            text,
+        } = params;
+
+        let json = serde_json::to_string(self).expect("report to serialize");
+        let encoded = base64::encode(json);
+
+        let mut message = String::new();
+        // Writing to a String cannot fail
+        let _ = write!(
+            message,
+            "From: {from}\r\n\
+             To: {to}\r\n\
+             Subject: Report Domain: {policy_domain} Submitter: {submitter} \
+             Report-ID: <{report_id}>\r\n\
+             Message-ID: {message_id}\r\n\
+             Date: {date}\r\n\
+             TLS-Report-Domain: {policy_domain}\r\n\
+             TLS-Report-Submitter: {submitter}\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: multipart/report; report-type=\"tlsrpt\";\r\n\
+             \tboundary=\"{boundary}\"\r\n\
+             \r\n\
+             --{boundary}\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             \r\n\
+             {text}\r\n\
+             \r\n\
+             --{boundary}\r\n\
+             Content-Type: application/tlsrpt+json\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             Content-Disposition: attachment;\r\n\
+             \tfilename=\"{file_name}\"\r\n\
+             \r\n",
+            submitter = self.organization_name,
+            report_id = self.report_id,
+            date = date.to_rfc2822(),
+            text = text.replace("\r\n", "\n").replace('\n', "\r\n"),
+            file_name = self.file_name(policy_domain),
+        );
+
+        for line in encoded.as_bytes().chunks(76) {
+            // base64 is ASCII, so each chunk is valid UTF-8
+            let _ = write!(message, "{}\r\n", String::from_utf8_lossy(line));
+        }
+
+        let _ = write!(message, "--{boundary}--\r\n");
+        message
+    }
+}
+
+/// Extracts the aggregate report URIs from the `_smtp._tls` TXT records.
+/// Returns None unless there is exactly one valid TLSRPTv1 record;
+/// RFC 8460 section 3
+pub fn parse_txt_records<S: AsRef<str>>(records: &[S]) -> Option<Vec<String>> {
+    let mut records = records
+        .iter()
+        .map(|r| r.as_ref())
+        .filter(|r| r.starts_with("v=TLSRPTv1"));
+
+    let record = match (records.next(), records.next()) {
+        (Some(record), None) => record,
+        _ => return None,
+    };
+
+    let mut fields = record
+        .split(';')
+        .map(str::trim)
+        .filter(|field| !field.is_empty());
+    if fields.next() != Some("v=TLSRPTv1") {
+        return None;
+    }
+    for field in fields {
+        if let Some(("rua", value)) = field.split_once('=') {
+            let uris: Vec<String> = value
+                .split(',')
+                .map(|uri| uri.trim().to_string())
+                .filter(|uri| !uri.is_empty())
+                .collect();
+            if uris.is_empty() {
+                return None;
+            }
+            return Some(uris);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rfc8460_json() {
+        let report = Report {
+            organization_name: "Company-X".to_string(),
+            date_range: DateRange {
+                start_datetime: DateTime::parse_from_rfc3339("2016-04-01T00:00:00Z")
+                    .unwrap()
+                    .into(),
+                end_datetime: DateTime::parse_from_rfc3339("2016-04-01T23:59:59Z")
+                    .unwrap()
+                    .into(),
+            },
+            contact_info: "sts-reporting@company-x.example".to_string(),
+            report_id: "5065427c-23d3-47ca-b6e0-946ea0e8c4be".to_string(),
+            policies: vec![PolicyReport {
+                policy: PolicyDetails {
+                    policy_type: PolicyType::Sts,
+                    policy_string: vec![
+                        "version: STSv1".to_string(),
+                        "mode: testing".to_string(),
+                        "mx: *.mail.company-y.example".to_string(),
+                        "max_age: 86400".to_string(),
+                    ],
+                    policy_domain: "company-y.example".to_string(),
+                    mx_host: vec!["*.mail.company-y.example".to_string()],
+                },
+                summary: Summary {
+                    total_successful_session_count: 5326,
+                    total_failure_session_count: 303,
+                },
+                failure_details: vec![FailureDetails {
+                    result_type: ResultType::CertificateExpired,
+                    sending_mta_ip: Some("2001:db8:abcd:0012::1".parse().unwrap()),
+                    receiving_mx_hostname: Some("mx1.mail.company-y.example".to_string()),
+                    receiving_mx_helo: None,
+                    receiving_ip: None,
+                    failed_session_count: 100,
+                    additional_information: None,
+                    failure_reason_code: None,
+                }],
+            }],
+        };
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "organization-name": "Company-X",
+                "date-range": {
+                    "start-datetime": "2016-04-01T00:00:00Z",
+                    "end-datetime": "2016-04-01T23:59:59Z"
+                },
+                "contact-info": "sts-reporting@company-x.example",
+                "report-id": "5065427c-23d3-47ca-b6e0-946ea0e8c4be",
+                "policies": [{
+                    "policy": {
+                        "policy-type": "sts",
+                        "policy-string": [
+                            "version: STSv1",
+                            "mode: testing",
+                            "mx: *.mail.company-y.example",
+                            "max_age: 86400"
+                        ],
+                        "policy-domain": "company-y.example",
+                        "mx-host": ["*.mail.company-y.example"]
+                    },
+                    "summary": {
+                        "total-successful-session-count": 5326,
+                        "total-failure-session-count": 303
+                    },
+                    "failure-details": [{
+                        "result-type": "certificate-expired",
+                        "sending-mta-ip": "2001:db8:abcd:12::1",
+                        "receiving-mx-hostname": "mx1.mail.company-y.example",
+                        "failed-session-count": 100
+                    }]
+                }]
+            })
+        );
+        let round_trip: Report = serde_json::from_value(json).unwrap();
+        assert_eq!(round_trip, report);
+
+        assert_eq!(
+            report.file_name("company-y.example"),
+            "Company-X!company-y.example!1459468800!1459555199!\
+             5065427c-23d3-47ca-b6e0-946ea0e8c4be.json"
+        );
+    }
+
+    #[test]
+    fn txt_records() {
+        assert_eq!(
+            parse_txt_records(&["v=TLSRPTv1; rua=mailto:reports@example.com"]),
+            Some(vec!["mailto:reports@example.com".to_string()])
+        );
+        assert_eq!(
+            parse_txt_records(&[
+                "v=spf1 -all",
+                "v=TLSRPTv1;rua=mailto:a@example.com, https://example.com/tlsrpt"
+            ]),
+            Some(vec![
+                "mailto:a@example.com".to_string(),
+                "https://example.com/tlsrpt".to_string()
+            ])
+        );
+        assert_eq!(
+            parse_txt_records(&["v=TLSRPTv1; rua=mailto:a@b", "v=TLSRPTv1; rua=mailto:c@d"]),
+            None
+        );
+        assert_eq!(parse_txt_records(&["v=TLSRPTv1;"]), None);
+        assert_eq!(parse_txt_records::<&str>(&[]), None);
+    }
+}
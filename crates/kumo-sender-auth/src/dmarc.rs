@@ -0,0 +1,392 @@
+//! DMARC policy discovery and identifier alignment; RFC 7489
+use crate::{AuthResult, DkimResult, Lookup, LookupError, SpfResult};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "quarantine" => Ok(Self::Quarantine),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!("invalid policy {s}")),
+        }
+    }
+}
+
+impl std::fmt::Display for Policy {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::None => fmt.write_str("none"),
+            Self::Quarantine => fmt.write_str("quarantine"),
+            Self::Reject => fmt.write_str("reject"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    Relaxed,
+    Strict,
+}
+
+impl FromStr for Alignment {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "r" | "R" => Ok(Self::Relaxed),
+            "s" | "S" => Ok(Self::Strict),
+            _ => Err(format!("invalid alignment mode {s}")),
+        }
+    }
+}
+
+/// The parts of a DMARC policy record that are used
+/// to evaluate a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub policy: Policy,
+    pub subdomain_policy: Option<Policy>,
+    pub dkim_alignment: Alignment,
+    pub spf_alignment: Alignment,
+}
+
+impl FromStr for Record {
+    type Err = String;
+    fn from_str(text: &str) -> Result<Self, String> {
+        let mut tags = text
+            .split(';')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| match tag.split_once('=') {
+                Some((name, value)) => Ok((name.trim(), value.trim())),
+                None => Err(format!("invalid tag {tag}")),
+            });
+
+        match tags.next() {
+            Some(Ok(("v", "DMARC1"))) => {}
+            _ => return Err("not a DMARC1 record".to_string()),
+        }
+
+        let mut policy = None;
+        let mut subdomain_policy = None;
+        let mut dkim_alignment = Alignment::Relaxed;
+        let mut spf_alignment = Alignment::Relaxed;
+
+        for tag in tags {
+            let (name, value) = tag?;
+            match name {
+                "p" => policy = Some(value.parse()?),
+                "sp" => subdomain_policy = Some(value.parse()?),
+                "adkim" => dkim_alignment = value.parse()?,
+                "aspf" => spf_alignment = value.parse()?,
+                // Reporting and sampling tags don't affect the result
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            policy: policy.ok_or_else(|| "missing p tag".to_string())?,
+            subdomain_policy,
+            dkim_alignment,
+            spf_alignment,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DmarcResult {
+    pub result: AuthResult,
+    /// The domain from the RFC5322.From header
+    pub from_domain: String,
+    /// The policy that the domain owner requested for messages
+    /// that fail; None if no policy was published
+    pub policy: Option<Policy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Returns the organizational domain of domain, which is the domain
+/// that is one label below its public suffix; RFC 7489 section 3.2.
+/// domain is expected to be lowercase.
+pub fn organizational_domain(domain: &str) -> &str {
+    let domain = domain.trim_end_matches('.');
+    psl::domain_str(domain).unwrap_or(domain)
+}
+
+/// Evaluates the DMARC policy for from_domain against the
+/// previously computed SPF and DKIM results.
+pub async fn evaluate(
+    lookup: &dyn Lookup,
+    from_domain: &str,
+    spf: Option<&SpfResult>,
+    dkim: &[DkimResult],
+) -> DmarcResult {
+    let from_domain = from_domain.trim_end_matches('.').to_ascii_lowercase();
+
+    let (policy_domain, record) = match discover(lookup, &from_domain).await {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            return DmarcResult {
+                result: AuthResult::None,
+                from_domain,
+                policy: None,
+                reason: None,
+            }
+        }
+        Err(err) => {
+            return DmarcResult {
+                result: AuthResult::TempError,
+                from_domain,
+                policy: None,
+                reason: Some(err),
+            }
+        }
+    };
+
+    let policy = if policy_domain == from_domain {
+        record.policy
+    } else {
+        record.subdomain_policy.unwrap_or(record.policy)
+    };
+
+    let from_org_domain = organizational_domain(&from_domain);
+    let is_aligned = |domain: &str, mode: Alignment| {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        match mode {
+            Alignment::Strict => domain == from_domain,
+            Alignment::Relaxed => organizational_domain(&domain) == from_org_domain,
+        }
+    };
+
+    let dkim_aligned = dkim.iter().any(|r| {
+        r.result == AuthResult::Pass
+            && r.domain
+                .as_deref()
+                .map(|domain| is_aligned(domain, record.dkim_alignment))
+                .unwrap_or(false)
+    });
+    let spf_aligned = spf
+        .map(|r| r.result == AuthResult::Pass && is_aligned(&r.domain, record.spf_alignment))
+        .unwrap_or(false);
+
+    let (result, reason) = if dkim_aligned || spf_aligned {
+        (AuthResult::Pass, None)
+    } else {
+        (
+            AuthResult::Fail,
+            Some("no aligned DKIM or SPF pass".to_string()),
+        )
+    };
+
+    DmarcResult {
+        result,
+        from_domain,
+        policy: Some(policy),
+        reason,
+    }
+}
+
+/// Locates the policy record for domain, returning the name
+/// at which it was found along with the parsed record.
+/// The policy of the organizational domain applies when domain
+/// doesn't publish its own; RFC 7489 section 6.6.3
+async fn discover(lookup: &dyn Lookup, domain: &str) -> Result<Option<(String, Record)>, String> {
+    if let Some(record) = lookup_record(lookup, domain).await? {
+        return Ok(Some((domain.to_string(), record)));
+    }
+    let org_domain = organizational_domain(domain);
+    if org_domain != domain {
+        if let Some(record) = lookup_record(lookup, org_domain).await? {
+            return Ok(Some((org_domain.to_string(), record)));
+        }
+    }
+    Ok(None)
+}
+
+async fn lookup_record(lookup: &dyn Lookup, domain: &str) -> Result<Option<Record>, String> {
+    let name = format!("_dmarc.{domain}");
+    let records = match lookup.lookup_txt(&name).await {
+        Ok(records) => records,
+        Err(LookupError::NotFound) => return Ok(None),
+        Err(LookupError::Temporary(err)) => return Err(format!("looking up {name}: {err}")),
+    };
+
+    // Records that don't parse are ignored, and if more than
+    // one remains then none of them apply; RFC 7489 section 6.6.3
+    let mut records = records.iter().filter_map(|r| r.parse::<Record>().ok());
+    match (records.next(), records.next()) {
+        (Some(record), None) => Ok(Some(record)),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_lookup::TestLookup;
+
+    fn spf(result: AuthResult, domain: &str) -> SpfResult {
+        SpfResult {
+            result,
+            domain: domain.to_string(),
+            mail_from: Some(format!("user@{domain}")),
+            helo: "mail.example.net".to_string(),
+            reason: None,
+        }
+    }
+
+    fn dkim(result: AuthResult, domain: &str) -> DkimResult {
+        DkimResult {
+            result,
+            domain: Some(domain.to_string()),
+            selector: Some("s1".to_string()),
+            reason: None,
+        }
+    }
+
+    #[test]
+    fn parse_record() {
+        assert_eq!(
+            "v=DMARC1; p=reject; sp=none; adkim=s; rua=mailto:d@example.com"
+                .parse::<Record>()
+                .unwrap(),
+            Record {
+                policy: Policy::Reject,
+                subdomain_policy: Some(Policy::None),
+                dkim_alignment: Alignment::Strict,
+                spf_alignment: Alignment::Relaxed,
+            }
+        );
+        assert!("v=DMARC1; rua=mailto:d@example.com"
+            .parse::<Record>()
+            .is_err());
+        assert!("p=reject; v=DMARC1".parse::<Record>().is_err());
+        assert!("v=DMARC1; p=bogus".parse::<Record>().is_err());
+    }
+
+    #[tokio::test]
+    async fn alignment() {
+        let lookup = TestLookup::default()
+            .txt("_dmarc.example.com", "v=DMARC1; p=reject; sp=quarantine")
+            .txt(
+                "_dmarc.strict.example.org",
+                "v=DMARC1; p=none; adkim=s; aspf=s",
+            );
+
+        let result = evaluate(
+            &lookup,
+            "example.com",
+            Some(&spf(AuthResult::Pass, "bounces.example.com")),
+            &[],
+        )
+        .await;
+        assert_eq!(result.result, AuthResult::Pass);
+        assert_eq!(result.policy, Some(Policy::Reject));
+
+        // Policy discovered at the parent, and siblings align
+        let result = evaluate(
+            &lookup,
+            "news.example.com",
+            None,
+            &[dkim(AuthResult::Pass, "mail.example.com")],
+        )
+        .await;
+        assert_eq!(result.result, AuthResult::Pass);
+        assert_eq!(result.policy, Some(Policy::Quarantine));
+
+        // A pass for an unrelated domain does not align
+        let result = evaluate(
+            &lookup,
+            "example.com",
+            Some(&spf(AuthResult::Pass, "example.net")),
+            &[
+                dkim(AuthResult::Pass, "esp.example.net"),
+                dkim(AuthResult::Fail, "example.com"),
+            ],
+        )
+        .await;
+        assert_eq!(result.result, AuthResult::Fail);
+
+        let result = evaluate(
+            &lookup,
+            "strict.example.org",
+            Some(&spf(AuthResult::Pass, "bounces.strict.example.org")),
+            &[dkim(AuthResult::Pass, "strict.example.org")],
+        )
+        .await;
+        assert_eq!(result.result, AuthResult::Pass);
+
+        let result = evaluate(
+            &lookup,
+            "strict.example.org",
+            Some(&spf(AuthResult::Pass, "bounces.strict.example.org")),
+            &[],
+        )
+        .await;
+        assert_eq!(result.result, AuthResult::Fail);
+    }
+
+    #[test]
+    fn org_domain() {
+        assert_eq!(organizational_domain("example.com"), "example.com");
+        assert_eq!(organizational_domain("a.b.example.com."), "example.com");
+        assert_eq!(organizational_domain("mail.example.co.uk"), "example.co.uk");
+        assert_eq!(organizational_domain("co.uk"), "co.uk");
+    }
+
+    #[tokio::test]
+    async fn public_suffix_policy() {
+        // A policy published for a public suffix must
+        // not apply to the domains registered under it
+        let lookup = TestLookup::default()
+            .txt("_dmarc.co.uk", "v=DMARC1; p=reject")
+            .txt("_dmarc.example.co.uk", "v=DMARC1; p=quarantine");
+
+        let result = evaluate(&lookup, "mail.victim.co.uk", None, &[]).await;
+        assert_eq!(result.result, AuthResult::None);
+
+        let result = evaluate(
+            &lookup,
+            "news.example.co.uk",
+            None,
+            &[dkim(AuthResult::Pass, "bounces.example.co.uk")],
+        )
+        .await;
+        assert_eq!(result.result, AuthResult::Pass);
+        assert_eq!(result.policy, Some(Policy::Quarantine));
+
+        // Domains that share only a public suffix are not aligned
+        let result = evaluate(
+            &lookup,
+            "example.co.uk",
+            Some(&spf(AuthResult::Pass, "attacker.co.uk")),
+            &[dkim(AuthResult::Pass, "co.uk")],
+        )
+        .await;
+        assert_eq!(result.result, AuthResult::Fail);
+    }
+
+    #[tokio::test]
+    async fn no_policy() {
+        let lookup = TestLookup::default()
+            .txt("_dmarc.example.com", "v=DMARC1; p=reject")
+            .txt("_dmarc.example.com", "v=DMARC1; p=none");
+
+        let result = evaluate(&lookup, "example.com", None, &[]).await;
+        assert_eq!(result.result, AuthResult::None);
+        let result = evaluate(&lookup, "example.net", None, &[]).await;
+        assert_eq!(result.result, AuthResult::None);
+        let result = evaluate(&lookup, "tempfail.example.net", None, &[]).await;
+        assert_eq!(result.result, AuthResult::TempError);
+    }
+}
@@ -0,0 +1,140 @@
+//! Sender authentication for inbound mail: SPF (RFC 7208) and
+//! DMARC (RFC 7489) evaluation, and composition of the RFC 8601
+//! Authentication-Results header, which can also carry DKIM and
+//! ARC (RFC 8617) results computed elsewhere.
+//!
+//! DNS access is abstracted via the `Lookup` trait so that the
+//! caller can supply its own (caching) resolver.
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+
+pub mod dmarc;
+pub mod results;
+pub mod spf;
+
+pub use dmarc::DmarcResult;
+pub use results::{ArcResult, AuthenticationResults, DkimResult};
+pub use spf::SpfResult;
+
+pub type LookupFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, LookupError>> + Send + 'a>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LookupError {
+    /// The name does not exist, or has no records of the requested type
+    NotFound,
+    /// Any other failure; retrying later may produce a different outcome
+    Temporary(String),
+}
+
+/// The DNS operations required to evaluate SPF and DMARC
+pub trait Lookup: Send + Sync {
+    /// Returns the TXT records for name, with the character-strings
+    /// of each record concatenated together
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> LookupFuture<'a, Vec<String>>;
+    /// Returns the A and AAAA records for name
+    fn lookup_ip<'a>(&'a self, name: &'a str) -> LookupFuture<'a, Vec<IpAddr>>;
+    /// Returns the exchange hosts of the MX records for name
+    fn lookup_mx<'a>(&'a self, name: &'a str) -> LookupFuture<'a, Vec<String>>;
+}
+
+/// The result values from the RFC 8601 registry that are
+/// used by the dkim, spf and dmarc methods
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthResult {
+    None,
+    Neutral,
+    Pass,
+    Fail,
+    SoftFail,
+    Policy,
+    TempError,
+    PermError,
+}
+
+impl AuthResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Neutral => "neutral",
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+            Self::SoftFail => "softfail",
+            Self::Policy => "policy",
+            Self::TempError => "temperror",
+            Self::PermError => "permerror",
+        }
+    }
+}
+
+impl std::fmt::Display for AuthResult {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_lookup {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// A Lookup implementation backed by a fixed set of records
+    #[derive(Default)]
+    pub struct TestLookup {
+        pub txt: HashMap<String, Vec<String>>,
+        pub ip: HashMap<String, Vec<IpAddr>>,
+        pub mx: HashMap<String, Vec<String>>,
+    }
+
+    impl TestLookup {
+        pub fn txt(mut self, name: &str, record: &str) -> Self {
+            self.txt
+                .entry(name.to_string())
+                .or_default()
+                .push(record.to_string());
+            self
+        }
+
+        pub fn ip(mut self, name: &str, addr: &str) -> Self {
+            self.ip
+                .entry(name.to_string())
+                .or_default()
+                .push(addr.parse().unwrap());
+            self
+        }
+
+        pub fn mx(mut self, name: &str, host: &str) -> Self {
+            self.mx
+                .entry(name.to_string())
+                .or_default()
+                .push(host.to_string());
+            self
+        }
+    }
+
+    fn get<'a, T: Clone + Send + 'a>(
+        map: &HashMap<String, Vec<T>>,
+        name: &str,
+    ) -> LookupFuture<'a, Vec<T>> {
+        let result = match map.get(&name.to_ascii_lowercase()) {
+            Some(records) => Ok(records.clone()),
+            None if name.contains("tempfail") => Err(LookupError::Temporary("timeout".into())),
+            None => Err(LookupError::NotFound),
+        };
+        Box::pin(async move { result })
+    }
+
+    impl Lookup for TestLookup {
+        fn lookup_txt<'a>(&'a self, name: &'a str) -> LookupFuture<'a, Vec<String>> {
+            get(&self.txt, name)
+        }
+        fn lookup_ip<'a>(&'a self, name: &'a str) -> LookupFuture<'a, Vec<IpAddr>> {
+            get(&self.ip, name)
+        }
+        fn lookup_mx<'a>(&'a self, name: &'a str) -> LookupFuture<'a, Vec<String>> {
+            get(&self.mx, name)
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Composes the RFC 8601 Authentication-Results header
+use crate::{AuthResult, DmarcResult, SpfResult};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DkimResult {
+    pub result: AuthResult,
+    /// The signing domain (d=) of the signature
+    pub domain: Option<String>,
+    /// The selector (s=) of the signature
+    pub selector: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// The outcome of validating the ARC chain; RFC 8617
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArcResult {
+    pub result: AuthResult,
+    /// The number of ARC sets present in the message
+    pub instance: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthenticationResults {
+    /// Identifies the host that performed the checks
+    pub serv_id: String,
+    #[serde(default)]
+    pub dkim: Vec<DkimResult>,
+    #[serde(default)]
+    pub spf: Option<SpfResult>,
+    #[serde(default)]
+    pub dmarc: Option<DmarcResult>,
+    #[serde(default)]
+    pub arc: Option<ArcResult>,
+}
+
+impl AuthenticationResults {
+    /// Returns the value for an Authentication-Results header
+    /// describing these results
+    pub fn header_value(&self) -> String {
+        let mut results = vec![];
+
+        for dkim in &self.dkim {
+            let mut result = format!("dkim={}", dkim.result);
+            push_reason(&mut result, &dkim.reason);
+            if let Some(domain) = &dkim.domain {
+                result.push_str(&format!(" header.d={domain}"));
+            }
+            if let Some(selector) = &dkim.selector {
+                result.push_str(&format!(" header.s={selector}"));
+            }
+            results.push(result);
+        }
+
+        if let Some(spf) = &self.spf {
+            let mut result = format!("spf={}", spf.result);
+            push_reason(&mut result, &spf.reason);
+            match &spf.mail_from {
+                Some(mail_from) => result.push_str(&format!(" smtp.mailfrom={mail_from}")),
+                None => result.push_str(&format!(" smtp.helo={}", spf.helo)),
+            }
+            results.push(result);
+        }
+
+        if let Some(dmarc) = &self.dmarc {
+            let mut result = format!("dmarc={}", dmarc.result);
+            push_reason(&mut result, &dmarc.reason);
+            if let Some(policy) = &dmarc.policy {
+                result.push_str(&format!(" (p={policy})"));
+            }
+            result.push_str(&format!(" header.from={}", dmarc.from_domain));
+            results.push(result);
+        }
+
+        if let Some(arc) = &self.arc {
+            let mut result = format!("arc={}", arc.result);
+            push_reason(&mut result, &arc.reason);
+            results.push(result);
+        }
+
+        if results.is_empty() {
+            format!("{}; none", self.serv_id)
+        } else {
+            format!("{};\r\n\t{}", self.serv_id, results.join(";\r\n\t"))
+        }
+    }
+}
+
+fn push_reason(result: &mut String, reason: &Option<String>) {
+    if let Some(reason) = reason {
+        let reason: String = reason
+            .chars()
+            .filter(|c| !c.is_control())
+            .flat_map(|c| match c {
+                '"' | '\\' => vec!['\\', c],
+                c => vec![c],
+            })
+            .collect();
+        result.push_str(&format!(" reason=\"{reason}\""));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dmarc::Policy;
+
+    #[test]
+    fn header() {
+        let mut results = AuthenticationResults {
+            serv_id: "mx.example.org".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(results.header_value(), "mx.example.org; none");
+
+        results.dkim.push(DkimResult {
+            result: AuthResult::Pass,
+            domain: Some("example.com".to_string()),
+            selector: Some("s1".to_string()),
+            reason: None,
+        });
+        results.dkim.push(DkimResult {
+            result: AuthResult::Fail,
+            domain: Some("example.net".to_string()),
+            selector: None,
+            reason: Some("body hash did not \"verify\"".to_string()),
+        });
+        results.spf.replace(SpfResult {
+            result: AuthResult::SoftFail,
+            domain: "mail.example.com".to_string(),
+            mail_from: None,
+            helo: "mail.example.com".to_string(),
+            reason: None,
+        });
+        results.dmarc.replace(DmarcResult {
+            result: AuthResult::Pass,
+            from_domain: "example.com".to_string(),
+            policy: Some(Policy::Reject),
+            reason: None,
+        });
+
+        assert_eq!(
+            results.header_value(),
+            "mx.example.org;\r\n\t\
+             dkim=pass header.d=example.com header.s=s1;\r\n\t\
+             dkim=fail reason=\"body hash did not \\\"verify\\\"\" header.d=example.net;\r\n\t\
+             spf=softfail smtp.helo=mail.example.com;\r\n\t\
+             dmarc=pass (p=reject) header.from=example.com"
+        );
+
+        results.arc.replace(ArcResult {
+            result: AuthResult::Pass,
+            instance: 1,
+            reason: None,
+        });
+        assert!(results.header_value().ends_with(";\r\n\tarc=pass"));
+
+        let json = serde_json::to_value(&results).unwrap();
+        assert_eq!(json["spf"]["result"], "softfail");
+        assert_eq!(json["dmarc"]["policy"], "reject");
+        let round_trip: AuthenticationResults = serde_json::from_value(json).unwrap();
+        assert_eq!(round_trip, results);
+    }
+}
@@ -0,0 +1,850 @@
+//! An implementation of the check_host() function from RFC 7208
+use crate::{AuthResult, Lookup, LookupError};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+
+/// The number of terms that may cause DNS queries during a
+/// single evaluation; RFC 7208 section 4.6.4
+const MAX_DNS_TERMS: usize = 10;
+/// The number of DNS queries made by terms that may return no
+/// answers before the evaluation fails; RFC 7208 section 4.6.4
+const MAX_VOID_LOOKUPS: usize = 2;
+/// The number of MX hosts that an mx mechanism may consider
+const MAX_MX_HOSTS: usize = 10;
+/// The special characters that are permitted as macro delimiters
+const MACRO_DELIMITERS: &str = ".-+,/_=";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpfResult {
+    pub result: AuthResult,
+    /// The domain whose policy was evaluated
+    pub domain: String,
+    /// The envelope sender. None for the null sender, in which case
+    /// the HELO identity was checked instead
+    pub mail_from: Option<String>,
+    pub helo: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Evaluates the SPF policy that applies to a client connecting from
+/// `ip` that introduced itself as `helo` and used `sender` as the
+/// envelope sender. `sender` is empty for the null sender.
+pub async fn check_host(lookup: &dyn Lookup, ip: IpAddr, helo: &str, sender: &str) -> SpfResult {
+    let (mail_from, local, domain) = match sender.rsplit_once('@') {
+        Some((local, domain)) => (
+            Some(sender.to_string()),
+            local.to_string(),
+            domain.to_string(),
+        ),
+        None => (None, String::new(), helo.to_string()),
+    };
+    let local = if local.is_empty() {
+        "postmaster".to_string()
+    } else {
+        local
+    };
+
+    // An IPv4 client that was accepted on an IPv6 socket
+    // is evaluated as IPv4
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    };
+
+    let mut evaluator = Evaluator {
+        lookup,
+        ip,
+        local,
+        sender_domain: domain.clone(),
+        helo: helo.to_string(),
+        dns_terms: 0,
+        void_lookups: 0,
+    };
+
+    let (result, reason) = match evaluator.check(domain.clone()).await {
+        Ok(result) => (result, None),
+        Err(Error::Temp(reason)) => (AuthResult::TempError, Some(reason)),
+        Err(Error::Perm(reason)) => (AuthResult::PermError, Some(reason)),
+    };
+
+    SpfResult {
+        result,
+        domain,
+        mail_from,
+        helo: helo.to_string(),
+        reason,
+    }
+}
+
+#[derive(Debug)]
+enum Error {
+    Temp(String),
+    Perm(String),
+}
+
+type CheckFuture<'a> = Pin<Box<dyn Future<Output = Result<AuthResult, Error>> + Send + 'a>>;
+
+struct Evaluator<'a> {
+    lookup: &'a dyn Lookup,
+    ip: IpAddr,
+    local: String,
+    sender_domain: String,
+    helo: String,
+    dns_terms: usize,
+    void_lookups: usize,
+}
+
+impl<'a> Evaluator<'a> {
+    fn check<'b>(&'b mut self, domain: String) -> CheckFuture<'b> {
+        Box::pin(async move {
+            if !is_valid_domain(&domain) {
+                return Ok(AuthResult::None);
+            }
+
+            let records = match self.lookup.lookup_txt(&domain).await {
+                Ok(records) => records,
+                Err(LookupError::NotFound) => return Ok(AuthResult::None),
+                Err(LookupError::Temporary(err)) => {
+                    return Err(Error::Temp(format!("looking up {domain}: {err}")))
+                }
+            };
+
+            let mut records = records.iter().filter(|r| is_spf_record(r));
+            let record = match (records.next(), records.next()) {
+                (Some(record), None) => record,
+                (None, _) => return Ok(AuthResult::None),
+                _ => {
+                    return Err(Error::Perm(format!(
+                        "{domain} publishes more than one SPF record"
+                    )))
+                }
+            };
+            let record =
+                Record::parse(record).map_err(|err| Error::Perm(format!("{domain}: {err}")))?;
+
+            for directive in &record.directives {
+                if self.matches(&directive.mechanism, &domain).await? {
+                    return Ok(directive.qualifier);
+                }
+            }
+
+            match &record.redirect {
+                Some(spec) => {
+                    self.count_dns_term()?;
+                    let target = self.expand(spec, &domain)?;
+                    match self.check(target.clone()).await? {
+                        AuthResult::None => Err(Error::Perm(format!(
+                            "redirect target {target} has no SPF record"
+                        ))),
+                        result => Ok(result),
+                    }
+                }
+                None => Ok(AuthResult::Neutral),
+            }
+        })
+    }
+
+    async fn matches(&mut self, mechanism: &Mechanism, domain: &str) -> Result<bool, Error> {
+        match mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Ip { addr, prefix } => Ok(in_network(self.ip, *addr, *prefix)),
+            Mechanism::Include(spec) => {
+                self.count_dns_term()?;
+                let target = self.expand(spec, domain)?;
+                match self.check(target.clone()).await? {
+                    AuthResult::Pass => Ok(true),
+                    AuthResult::None => Err(Error::Perm(format!(
+                        "included domain {target} has no SPF record"
+                    ))),
+                    _ => Ok(false),
+                }
+            }
+            Mechanism::A {
+                domain: spec,
+                cidr4,
+                cidr6,
+            } => {
+                self.count_dns_term()?;
+                let target = self.target(spec, domain)?;
+                let addrs = self.lookup_ip(&target).await?;
+                if addrs.is_empty() {
+                    self.count_void_lookup(&target)?;
+                }
+                Ok(self.any_in_network(&addrs, *cidr4, *cidr6))
+            }
+            Mechanism::Mx {
+                domain: spec,
+                cidr4,
+                cidr6,
+            } => {
+                self.count_dns_term()?;
+                let target = self.target(spec, domain)?;
+                let hosts = match self.lookup.lookup_mx(&target).await {
+                    Ok(hosts) => hosts,
+                    Err(LookupError::NotFound) => vec![],
+                    Err(LookupError::Temporary(err)) => {
+                        return Err(Error::Temp(format!("looking up MX for {target}: {err}")))
+                    }
+                };
+                if hosts.is_empty() {
+                    self.count_void_lookup(&target)?;
+                }
+                if hosts.len() > MAX_MX_HOSTS {
+                    return Err(Error::Perm(format!(
+                        "{target} has more than {MAX_MX_HOSTS} MX records"
+                    )));
+                }
+                for host in hosts {
+                    let addrs = self.lookup_ip(&host).await?;
+                    if self.any_in_network(&addrs, *cidr4, *cidr6) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Ptr => {
+                // ptr is deprecated by RFC 7208 and is not evaluated;
+                // it never matches, but still counts against the limit
+                self.count_dns_term()?;
+                Ok(false)
+            }
+            Mechanism::Exists(spec) => {
+                self.count_dns_term()?;
+                let target = self.expand(spec, domain)?;
+                let addrs = self.lookup_ip(&target).await?;
+                if addrs.is_empty() {
+                    self.count_void_lookup(&target)?;
+                }
+                Ok(addrs.iter().any(|addr| addr.is_ipv4()))
+            }
+        }
+    }
+
+    fn count_dns_term(&mut self) -> Result<(), Error> {
+        self.dns_terms += 1;
+        if self.dns_terms > MAX_DNS_TERMS {
+            return Err(Error::Perm(format!(
+                "more than {MAX_DNS_TERMS} DNS querying terms"
+            )));
+        }
+        Ok(())
+    }
+
+    fn count_void_lookup(&mut self, name: &str) -> Result<(), Error> {
+        self.void_lookups += 1;
+        if self.void_lookups > MAX_VOID_LOOKUPS {
+            return Err(Error::Perm(format!(
+                "more than {MAX_VOID_LOOKUPS} void lookups, the last for {name}"
+            )));
+        }
+        Ok(())
+    }
+
+    async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>, Error> {
+        match self.lookup.lookup_ip(name).await {
+            Ok(addrs) => Ok(addrs),
+            Err(LookupError::NotFound) => Ok(vec![]),
+            Err(LookupError::Temporary(err)) => {
+                Err(Error::Temp(format!("looking up {name}: {err}")))
+            }
+        }
+    }
+
+    fn any_in_network(&self, addrs: &[IpAddr], cidr4: u8, cidr6: u8) -> bool {
+        addrs.iter().any(|addr| match addr {
+            IpAddr::V4(_) => in_network(self.ip, *addr, cidr4),
+            IpAddr::V6(_) => in_network(self.ip, *addr, cidr6),
+        })
+    }
+
+    fn target(&self, spec: &Option<String>, domain: &str) -> Result<String, Error> {
+        match spec {
+            Some(spec) => self.expand(spec, domain),
+            None => Ok(domain.to_string()),
+        }
+    }
+
+    /// Expands the macros in a domain-spec; RFC 7208 section 7
+    fn expand(&self, spec: &str, domain: &str) -> Result<String, Error> {
+        let mut result = String::new();
+        let mut chars = spec.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                result.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => result.push('%'),
+                Some('_') => result.push(' '),
+                Some('-') => result.push_str("%20"),
+                Some('{') => {
+                    let mut body = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => body.push(c),
+                            None => {
+                                return Err(Error::Perm(format!("unterminated macro in {spec}")))
+                            }
+                        }
+                    }
+                    result.push_str(&self.expand_macro(&body, domain)?);
+                }
+                _ => return Err(Error::Perm(format!("invalid macro in {spec}"))),
+            }
+        }
+
+        // Overly long names are shortened by removing labels
+        // from the left; RFC 7208 section 7.3
+        while result.len() > 253 {
+            match result.find('.') {
+                Some(idx) => result = result[idx + 1..].to_string(),
+                None => break,
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn expand_macro(&self, body: &str, domain: &str) -> Result<String, Error> {
+        let invalid = || Error::Perm(format!("invalid macro %{{{body}}}"));
+
+        let mut chars = body.chars();
+        let letter = chars.next().ok_or_else(invalid)?;
+        let transformers = chars.as_str();
+
+        let value = match letter.to_ascii_lowercase() {
+            's' => format!("{}@{}", self.local, self.sender_domain),
+            'l' => self.local.clone(),
+            'o' => self.sender_domain.clone(),
+            'd' => domain.to_string(),
+            'i' => match self.ip {
+                IpAddr::V4(v4) => v4.to_string(),
+                IpAddr::V6(v6) => v6
+                    .octets()
+                    .iter()
+                    .map(|b| format!("{:x}.{:x}", b >> 4, b & 0xf))
+                    .collect::<Vec<_>>()
+                    .join("."),
+            },
+            'p' => "unknown".to_string(),
+            'v' => match self.ip {
+                IpAddr::V4(_) => "in-addr".to_string(),
+                IpAddr::V6(_) => "ip6".to_string(),
+            },
+            'h' => self.helo.clone(),
+            _ => return Err(invalid()),
+        };
+
+        let num_digits = transformers
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+        let keep = if num_digits > 0 {
+            match transformers[..num_digits].parse::<usize>() {
+                Ok(n) if n > 0 => Some(n),
+                _ => return Err(invalid()),
+            }
+        } else {
+            None
+        };
+        let transformers = &transformers[num_digits..];
+        let (reverse, delimiters) = match transformers.strip_prefix(['r', 'R']) {
+            Some(delimiters) => (true, delimiters),
+            None => (false, transformers),
+        };
+        if !delimiters.chars().all(|c| MACRO_DELIMITERS.contains(c)) {
+            return Err(invalid());
+        }
+
+        let mut parts: Vec<&str> = if delimiters.is_empty() {
+            value.split('.').collect()
+        } else {
+            value.split(|c| delimiters.contains(c)).collect()
+        };
+        if reverse {
+            parts.reverse();
+        }
+        if let Some(keep) = keep {
+            if parts.len() > keep {
+                parts.drain(..parts.len() - keep);
+            }
+        }
+        let value = parts.join(".");
+
+        if letter.is_ascii_uppercase() {
+            Ok(url_encode(&value))
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+struct Record {
+    directives: Vec<Directive>,
+    redirect: Option<String>,
+}
+
+struct Directive {
+    qualifier: AuthResult,
+    mechanism: Mechanism,
+}
+
+#[derive(Debug, PartialEq)]
+enum Mechanism {
+    All,
+    Include(String),
+    A {
+        domain: Option<String>,
+        cidr4: u8,
+        cidr6: u8,
+    },
+    Mx {
+        domain: Option<String>,
+        cidr4: u8,
+        cidr6: u8,
+    },
+    Ptr,
+    Ip {
+        addr: IpAddr,
+        prefix: u8,
+    },
+    Exists(String),
+}
+
+impl Record {
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut directives = vec![];
+        let mut redirect = None;
+
+        // Skip over the version
+        for term in text.split_ascii_whitespace().skip(1) {
+            if let Some((name, value)) = term.split_once('=') {
+                if is_modifier_name(name) {
+                    if name.eq_ignore_ascii_case("redirect")
+                        && redirect.replace(value.to_string()).is_some()
+                    {
+                        return Err("more than one redirect modifier".to_string());
+                    }
+                    // exp and unknown modifiers are ignored
+                    continue;
+                }
+            }
+            directives.push(Directive::parse(term)?);
+        }
+
+        Ok(Self {
+            directives,
+            redirect,
+        })
+    }
+}
+
+impl Directive {
+    fn parse(term: &str) -> Result<Self, String> {
+        let (qualifier, rest) = match term.chars().next() {
+            Some('+') => (AuthResult::Pass, &term[1..]),
+            Some('-') => (AuthResult::Fail, &term[1..]),
+            Some('~') => (AuthResult::SoftFail, &term[1..]),
+            Some('?') => (AuthResult::Neutral, &term[1..]),
+            _ => (AuthResult::Pass, term),
+        };
+
+        let (name, arg) = match rest.find([':', '/']) {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, ""),
+        };
+
+        let required_domain = || match arg.strip_prefix(':') {
+            Some(spec) if !spec.is_empty() => Ok(spec.to_string()),
+            _ => Err(format!("{term}: missing domain")),
+        };
+
+        let mechanism = match name.to_ascii_lowercase().as_str() {
+            "all" if arg.is_empty() => Mechanism::All,
+            "include" => Mechanism::Include(required_domain()?),
+            "exists" => Mechanism::Exists(required_domain()?),
+            "ptr" => Mechanism::Ptr,
+            "a" => {
+                let (domain, cidr4, cidr6) = parse_domain_and_cidr(arg)
+                    .ok_or_else(|| format!("{term}: invalid domain or cidr length"))?;
+                Mechanism::A {
+                    domain,
+                    cidr4,
+                    cidr6,
+                }
+            }
+            "mx" => {
+                let (domain, cidr4, cidr6) = parse_domain_and_cidr(arg)
+                    .ok_or_else(|| format!("{term}: invalid domain or cidr length"))?;
+                Mechanism::Mx {
+                    domain,
+                    cidr4,
+                    cidr6,
+                }
+            }
+            "ip4" | "ip6" => {
+                let spec = arg
+                    .strip_prefix(':')
+                    .ok_or_else(|| format!("{term}: missing address"))?;
+                let (addr, prefix) = match spec.split_once('/') {
+                    Some((addr, prefix)) => (addr, Some(prefix)),
+                    None => (spec, None),
+                };
+                let addr: IpAddr = addr
+                    .parse()
+                    .map_err(|_| format!("{term}: invalid address"))?;
+                let max = match (name.eq_ignore_ascii_case("ip4"), addr) {
+                    (true, IpAddr::V4(_)) => 32,
+                    (false, IpAddr::V6(_)) => 128,
+                    _ => return Err(format!("{term}: wrong address family")),
+                };
+                let prefix = match prefix {
+                    Some(prefix) => parse_prefix(prefix, max)
+                        .ok_or_else(|| format!("{term}: invalid cidr length"))?,
+                    None => max,
+                };
+                Mechanism::Ip { addr, prefix }
+            }
+            _ => return Err(format!("unknown mechanism {term}")),
+        };
+
+        Ok(Self {
+            qualifier,
+            mechanism,
+        })
+    }
+}
+
+/// Parses the `[:domain-spec][/cidr4][//cidr6]` portion of an a or mx
+/// mechanism
+fn parse_domain_and_cidr(arg: &str) -> Option<(Option<String>, u8, u8)> {
+    let (domain, cidr) = match arg.strip_prefix(':') {
+        Some(spec) => {
+            // A macro may use '/' as a delimiter, so only look for
+            // the cidr after the last macro in the spec
+            let search_from = spec.rfind('}').map(|idx| idx + 1).unwrap_or(0);
+            match spec[search_from..].find('/') {
+                Some(idx) => (Some(&spec[..search_from + idx]), &spec[search_from + idx..]),
+                None => (Some(spec), ""),
+            }
+        }
+        None => (None, arg),
+    };
+    if domain == Some("") {
+        return None;
+    }
+
+    let (cidr4, cidr6) = match cidr.find("//") {
+        Some(idx) => (&cidr[..idx], Some(&cidr[idx + 2..])),
+        None => (cidr, None),
+    };
+    let cidr4 = if cidr4.is_empty() {
+        32
+    } else {
+        parse_prefix(cidr4.strip_prefix('/')?, 32)?
+    };
+    let cidr6 = match cidr6 {
+        Some(cidr6) => parse_prefix(cidr6, 128)?,
+        None => 128,
+    };
+
+    Some((domain.map(|d| d.to_string()), cidr4, cidr6))
+}
+
+fn parse_prefix(text: &str, max: u8) -> Option<u8> {
+    if text.is_empty() || !text.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    text.parse::<u8>().ok().filter(|&len| len <= max)
+}
+
+fn is_modifier_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn is_spf_record(text: &str) -> bool {
+    let text = text.to_ascii_lowercase();
+    text == "v=spf1" || text.starts_with("v=spf1 ")
+}
+
+/// A domain must have multiple labels of valid lengths
+/// to be eligible for evaluation; RFC 7208 section 4.3
+fn is_valid_domain(domain: &str) -> bool {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    domain.len() <= 253
+        && domain.contains('.')
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+fn in_network(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+fn url_encode(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for b in text.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            result.push(b as char);
+        } else {
+            result.push_str(&format!("%{b:02X}"));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_lookup::TestLookup;
+
+    async fn check(lookup: &TestLookup, ip: &str, sender: &str) -> SpfResult {
+        check_host(lookup, ip.parse().unwrap(), "mail.example.com", sender).await
+    }
+
+    #[tokio::test]
+    async fn basic_policies() {
+        let lookup = TestLookup::default()
+            .txt(
+                "example.com",
+                "v=spf1 ip4:192.0.2.0/24 ip6:2001:db8::/32 a mx -all",
+            )
+            .txt("example.com", "some other record")
+            .ip("example.com", "198.51.100.1")
+            .mx("example.com", "mx.example.com")
+            .ip("mx.example.com", "198.51.100.25");
+
+        for ip in [
+            "192.0.2.10",
+            "2001:db8::1",
+            "::ffff:192.0.2.10",
+            "198.51.100.1",
+            "198.51.100.25",
+        ] {
+            assert_eq!(
+                check(&lookup, ip, "user@example.com").await.result,
+                AuthResult::Pass,
+                "{ip}"
+            );
+        }
+        assert_eq!(
+            check(&lookup, "203.0.113.1", "user@example.com")
+                .await
+                .result,
+            AuthResult::Fail
+        );
+        assert_eq!(
+            check(&lookup, "203.0.113.1", "user@other.example.net")
+                .await
+                .result,
+            AuthResult::None
+        );
+    }
+
+    #[tokio::test]
+    async fn null_sender_uses_helo() {
+        let lookup = TestLookup::default().txt("mail.example.com", "v=spf1 ip4:192.0.2.1 ~all");
+        let result = check(&lookup, "192.0.2.1", "").await;
+        assert_eq!(result.result, AuthResult::Pass);
+        assert_eq!(result.domain, "mail.example.com");
+        assert_eq!(result.mail_from, None);
+        assert_eq!(
+            check(&lookup, "192.0.2.2", "").await.result,
+            AuthResult::SoftFail
+        );
+    }
+
+    #[tokio::test]
+    async fn include_and_redirect() {
+        let lookup = TestLookup::default()
+            .txt("example.com", "v=spf1 include:_spf.example.net ?all")
+            .txt("_spf.example.net", "v=spf1 ip4:192.0.2.0/24 -all")
+            .txt("example.org", "v=spf1 redirect=example.com")
+            .txt(
+                "missing.example.org",
+                "v=spf1 include:nothing.example.org -all",
+            )
+            .txt(
+                "tempfail.example.org",
+                "v=spf1 include:tempfail.example.com -all",
+            );
+
+        assert_eq!(
+            check(&lookup, "192.0.2.1", "user@example.org").await.result,
+            AuthResult::Pass
+        );
+        // A fail from an include is just a non-match
+        assert_eq!(
+            check(&lookup, "198.51.100.1", "user@example.org")
+                .await
+                .result,
+            AuthResult::Neutral
+        );
+        assert_eq!(
+            check(&lookup, "192.0.2.1", "user@missing.example.org")
+                .await
+                .result,
+            AuthResult::PermError
+        );
+        assert_eq!(
+            check(&lookup, "192.0.2.1", "user@tempfail.example.org")
+                .await
+                .result,
+            AuthResult::TempError
+        );
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        let lookup = TestLookup::default()
+            .txt("multiple.example.com", "v=spf1 -all")
+            .txt("multiple.example.com", "v=spf1 +all")
+            .txt("syntax.example.com", "v=spf1 ip4:bogus -all")
+            .txt("loop.example.com", "v=spf1 include:loop.example.com -all");
+
+        for domain in [
+            "multiple.example.com",
+            "syntax.example.com",
+            "loop.example.com",
+        ] {
+            assert_eq!(
+                check(&lookup, "192.0.2.1", &format!("user@{domain}"))
+                    .await
+                    .result,
+                AuthResult::PermError,
+                "{domain}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn void_lookups() {
+        let lookup = TestLookup::default()
+            .txt(
+                "two.example.com",
+                "v=spf1 a:void1.example.com mx:void2.example.com ip4:192.0.2.1 -all",
+            )
+            .txt(
+                "three.example.com",
+                "v=spf1 a:void1.example.com mx:void2.example.com \
+                 exists:void3.example.com ip4:192.0.2.1 -all",
+            );
+
+        assert_eq!(
+            check(&lookup, "192.0.2.1", "user@two.example.com")
+                .await
+                .result,
+            AuthResult::Pass
+        );
+        let result = check(&lookup, "192.0.2.1", "user@three.example.com").await;
+        assert_eq!(result.result, AuthResult::PermError);
+        assert_eq!(
+            result.reason.as_deref(),
+            Some("more than 2 void lookups, the last for void3.example.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn macros() {
+        let lookup = TestLookup::default()
+            .txt("example.com", "v=spf1 exists:%{ir}.%{l1r+-}._spf.%{d} -all")
+            .ip("1.2.0.192.strong._spf.example.com", "127.0.0.2");
+
+        assert_eq!(
+            check(&lookup, "192.0.2.1", "strong-bad+someuser@example.com")
+                .await
+                .result,
+            AuthResult::Pass
+        );
+        assert_eq!(
+            check(&lookup, "192.0.2.2", "strong-bad+someuser@example.com")
+                .await
+                .result,
+            AuthResult::Fail
+        );
+    }
+
+    #[test]
+    fn macro_expansion() {
+        let lookup = TestLookup::default();
+        let eval = Evaluator {
+            lookup: &lookup,
+            ip: "2001:db8::cb01".parse().unwrap(),
+            local: "strong-bad".to_string(),
+            sender_domain: "email.example.com".to_string(),
+            helo: "mail.example.com".to_string(),
+            dns_terms: 0,
+            void_lookups: 0,
+        };
+        let expand = |spec| eval.expand(spec, "email.example.com").unwrap();
+
+        assert_eq!(expand("%{s}"), "strong-bad@email.example.com");
+        assert_eq!(expand("%{o}"), "email.example.com");
+        assert_eq!(expand("%{d4}"), "email.example.com");
+        assert_eq!(expand("%{d2}"), "example.com");
+        assert_eq!(expand("%{dr}"), "com.example.email");
+        assert_eq!(expand("%{d2r}"), "example.email");
+        assert_eq!(expand("%{l-}"), "strong.bad");
+        assert_eq!(expand("%{lr-}"), "bad.strong");
+        assert_eq!(expand("%{l1r-}"), "strong");
+        assert_eq!(expand("%{v}.%{h}"), "ip6.mail.example.com");
+        assert_eq!(expand("%%%_%-"), "% %20");
+        assert_eq!(expand("%{S}"), "strong-bad%40email.example.com");
+        assert_eq!(
+            expand("%{ir}.%{v}._spf.%{d2}"),
+            "1.0.b.c.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6._spf.example.com"
+        );
+        assert!(eval.expand("%{x}", "example.com").is_err());
+        assert!(eval.expand("%{d", "example.com").is_err());
+    }
+
+    #[test]
+    fn parse_mechanisms() {
+        let parse = |term| Directive::parse(term).map(|d| d.mechanism);
+
+        assert_eq!(
+            parse("a:example.com/24//64").unwrap(),
+            Mechanism::A {
+                domain: Some("example.com".to_string()),
+                cidr4: 24,
+                cidr6: 64
+            }
+        );
+        assert_eq!(
+            parse("mx//64").unwrap(),
+            Mechanism::Mx {
+                domain: None,
+                cidr4: 32,
+                cidr6: 64
+            }
+        );
+        assert_eq!(
+            parse("-ip6:2001:db8::/32").unwrap(),
+            Mechanism::Ip {
+                addr: "2001:db8::".parse().unwrap(),
+                prefix: 32
+            }
+        );
+        assert!(parse("ip4:192.0.2.1/33").is_err());
+        assert!(parse("ip4:2001:db8::").is_err());
+        assert!(parse("include").is_err());
+        assert!(parse("bogus").is_err());
+    }
+}
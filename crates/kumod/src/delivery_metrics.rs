@@ -17,6 +17,9 @@ pub struct DeliveryMetrics {
 
     pub msgs_fail: IntCounter,
     pub global_msgs_fail: IntCounter,
+
+    pub tls_policy_failures: IntCounter,
+    pub global_tls_policy_failures: IntCounter,
 }
 
 impl DeliveryMetrics {
@@ -32,6 +35,11 @@ impl DeliveryMetrics {
         }
     }
 
+    pub fn inc_tls_policy_failures(&self) {
+        self.tls_policy_failures.inc();
+        self.global_tls_policy_failures.inc();
+    }
+
     pub fn new(service: &str, service_type: &str) -> Self {
         DeliveryMetrics {
             connection_gauge: crate::metrics_helper::connection_gauge_for_service(&service),
@@ -53,6 +61,11 @@ impl DeliveryMetrics {
             ),
             msgs_fail: crate::metrics_helper::total_msgs_fail_for_service(&service),
             global_msgs_fail: crate::metrics_helper::total_msgs_fail_for_service(service_type),
+            tls_policy_failures: crate::metrics_helper::total_tls_policy_failures_for_service(
+                &service,
+            ),
+            global_tls_policy_failures:
+                crate::metrics_helper::total_tls_policy_failures_for_service(service_type),
         }
     }
 }
@@ -1,9 +1,11 @@
+use crate::shaping::ShapingRule;
 use cidr_map::{AnyIpCidr, CidrSet};
 use data_loader::KeySource;
 use mlua::prelude::*;
 use rfc5321::SmtpClientTimeouts;
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
+use std::time::Duration;
 use throttle::ThrottleSpec;
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Copy)]
@@ -24,6 +26,16 @@ pub enum Tls {
     RequiredInsecure,
     /// Do not try to use TLS
     Disabled,
+    /// Apply the MTA-STS policy published by the destination domain.
+    /// When the policy is in enforce mode, only the MX hosts that it lists
+    /// are used, and TLS with valid certs is required. Otherwise, this
+    /// behaves like Opportunistic.
+    MtaSts,
+    /// Authenticate the server using its DANE TLSA records. When usable
+    /// TLSA records are published for an MX host, TLS is required and its
+    /// certificate must match them. Otherwise, this behaves like Opportunistic.
+    /// Requires a DNSSEC validating resolver.
+    Dane,
 }
 
 impl Tls {
@@ -41,11 +53,51 @@ impl Default for Tls {
     }
 }
 
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct ConnectionRampUp {
+    /// The number of connections that may be opened initially
+    #[serde(default = "ConnectionRampUp::default_initial")]
+    pub initial: usize,
+
+    /// The number of permitted connections doubles after each interval
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+impl ConnectionRampUp {
+    fn default_initial() -> usize {
+        1
+    }
+
+    /// Returns the number of connections that are permitted
+    /// once elapsed time has passed since the ramp up started,
+    /// along with the time at which that number next increases
+    pub fn limit(&self, elapsed: Duration) -> (usize, Duration) {
+        let interval = self.interval.max(Duration::from_millis(1));
+        let steps = (elapsed.as_millis() / interval.as_millis()).min(usize::BITS as u128) as u32;
+        let limit = 2usize
+            .checked_pow(steps)
+            .and_then(|factor| self.initial.max(1).checked_mul(factor))
+            .unwrap_or(usize::MAX);
+        (limit, interval * (steps + 1))
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct EgressPathConfig {
     #[serde(default = "EgressPathConfig::default_connection_limit")]
     pub connection_limit: usize,
 
+    /// The maximum number of concurrent connections to the site
+    /// across all of the nodes that share the redis throttle server
+    #[serde(default)]
+    pub cluster_connection_limit: Option<usize>,
+
+    /// The key under which cluster_connection_limit is tracked.
+    /// Defaults to the site name.
+    #[serde(default)]
+    pub cluster_connection_limit_key: Option<String>,
+
     #[serde(default)]
     pub enable_tls: Tls,
 
@@ -79,6 +131,26 @@ pub struct EgressPathConfig {
     #[serde(default)]
     pub max_deliveries_per_connection: Option<usize>,
 
+    /// When set, a ready queue with no connections opens them
+    /// gradually rather than all at once
+    #[serde(default)]
+    pub connection_ramp_up: Option<ConnectionRampUp>,
+
+    /// The delay before opening a new connection after the first
+    /// consecutive connection failure; doubles with each further failure
+    #[serde(
+        default = "EgressPathConfig::default_connection_backoff",
+        with = "humantime_serde"
+    )]
+    pub connection_backoff: Duration,
+
+    /// The upper bound for connection_backoff
+    #[serde(
+        default = "EgressPathConfig::default_max_connection_backoff",
+        with = "humantime_serde"
+    )]
+    pub max_connection_backoff: Duration,
+
     #[serde(default = "EgressPathConfig::default_prohibited_hosts")]
     pub prohibited_hosts: CidrSet,
 
@@ -87,6 +159,24 @@ pub struct EgressPathConfig {
 
     #[serde(default)]
     pub ehlo_domain: Option<String>,
+
+    /// When the peer doesn't advertise 8BITMIME, re-encode 8-bit
+    /// MIME parts as quoted-printable rather than failing the message
+    #[serde(default)]
+    pub downgrade_8bit_mime: bool,
+
+    /// Rules that temporarily reduce the limits of this path
+    /// when the remote host responds in a particular way
+    #[serde(default)]
+    pub shaping_rules: Vec<ShapingRule>,
+
+    /// How long the source is excluded from its pool after the
+    /// bounce classifier classifies a response as a SpamBlock
+    #[serde(
+        default = "EgressPathConfig::default_blocked_source_duration",
+        with = "humantime_serde"
+    )]
+    pub blocked_source_duration: Duration,
 }
 
 impl LuaUserData for EgressPathConfig {}
@@ -95,6 +185,8 @@ impl Default for EgressPathConfig {
     fn default() -> Self {
         Self {
             connection_limit: Self::default_connection_limit(),
+            cluster_connection_limit: None,
+            cluster_connection_limit_key: None,
             enable_tls: Tls::default(),
             max_ready: Self::default_max_ready(),
             consecutive_connection_failures_before_delay:
@@ -103,18 +195,37 @@ impl Default for EgressPathConfig {
             max_message_rate: None,
             max_connection_rate: None,
             max_deliveries_per_connection: None,
+            connection_ramp_up: None,
+            connection_backoff: Self::default_connection_backoff(),
+            max_connection_backoff: Self::default_max_connection_backoff(),
             client_timeouts: SmtpClientTimeouts::default(),
             prohibited_hosts: Self::default_prohibited_hosts(),
             skip_hosts: CidrSet::default(),
             ehlo_domain: None,
+            downgrade_8bit_mime: false,
             allow_smtp_auth_plain_without_tls: false,
             smtp_auth_plain_username: None,
             smtp_auth_plain_password: None,
+            shaping_rules: vec![],
+            blocked_source_duration: Self::default_blocked_source_duration(),
         }
     }
 }
 
 impl EgressPathConfig {
+    /// Returns how long to wait before opening a new connection
+    /// after the specified number of consecutive connection failures
+    pub fn connection_backoff_after(&self, failures: usize) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        let exponent = (failures - 1).min(31) as u32;
+        self.connection_backoff
+            .checked_mul(1 << exponent)
+            .unwrap_or(Duration::MAX)
+            .min(self.max_connection_backoff)
+    }
+
     fn default_connection_limit() -> usize {
         32
     }
@@ -127,6 +238,18 @@ impl EgressPathConfig {
         100
     }
 
+    fn default_connection_backoff() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn default_max_connection_backoff() -> Duration {
+        Duration::from_secs(300)
+    }
+
+    fn default_blocked_source_duration() -> Duration {
+        Duration::from_secs(1800)
+    }
+
     fn default_smtp_port() -> u16 {
         25
     }
@@ -1,4 +1,6 @@
+use crate::source_health::{effective_weights, publish_effective_weight};
 use anyhow::Context;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
 use config::LuaConfig;
 use gcd::Gcd;
 use lruttl::LruCacheWithTtl;
@@ -7,9 +9,12 @@ use serde::{Deserialize, Serialize};
 use socksv5::v5::{
     SocksV5AuthMethod, SocksV5Command, SocksV5Host, SocksV5RequestStatus, SocksV5Response,
 };
+use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
+use throttle::counter::{get_count, increment_count};
+use throttle::ThrottleSpec;
 use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpSocket, TcpStream};
 
@@ -45,13 +50,145 @@ pub struct EgressSource {
     /// a connection
     pub socks5_proxy_source_address: Option<IpAddr>,
 
+    /// Limits the daily volume of a newly introduced source
+    #[serde(default)]
+    pub warmup: Option<WarmupPlan>,
+
     #[serde(default = "default_ttl", with = "humantime_serde")]
     pub ttl: Duration,
 }
 
 impl LuaUserData for EgressSource {}
 
+/// Caps the number of messages that a source sends to each site per
+/// day, for the first few days of its use
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct WarmupPlan {
+    /// The first day of the plan, in UTC
+    pub start: NaiveDate,
+
+    /// The cap for each day of the plan, starting with the first.
+    /// The plan ends after the last of these days.
+    pub daily_limits: Vec<u64>,
+
+    /// Per-site caps which take precedence over daily_limits
+    #[serde(default)]
+    pub sites: HashMap<String, Vec<u64>>,
+
+    /// Spread each day's volume across the day, rather than
+    /// permitting it to be sent in a burst
+    #[serde(default)]
+    pub smooth: bool,
+}
+
+impl WarmupPlan {
+    /// Returns the cap for site on the specified day, or None
+    /// if the plan doesn't limit it. Days prior to the start
+    /// of the plan use the cap of its first day.
+    pub fn limit_for(&self, site: &str, today: NaiveDate) -> Option<u64> {
+        let limits = self.sites.get(site).unwrap_or(&self.daily_limits);
+        let day = (today - self.start).num_days().max(0) as usize;
+        limits.get(day).copied()
+    }
+
+    /// If the cap for site has been reached today, returns how long
+    /// source must wait before it may send to site again: until the
+    /// next day of the plan, or, when smoothing, until the throttle
+    /// permits it if that is sooner.  This doesn't count against the cap.
+    async fn capped_for(&self, source: &str, site: &str) -> Option<Duration> {
+        let now = Utc::now();
+        let today = now.date_naive();
+        let limit = match self.limit_for(site, today) {
+            Some(limit) => limit,
+            None => return None,
+        };
+        let until_next_day = until_next_day(now);
+        if limit == 0 {
+            return Some(until_next_day);
+        }
+
+        match get_count(Self::counter_key(source, site, today)).await {
+            Ok(count) if count >= limit => return Some(until_next_day),
+            Ok(_) => {}
+            Err(err) => {
+                tracing::error!("checking warmup plan of {source} for {site}: {err:#}");
+            }
+        }
+
+        if !self.smooth {
+            return None;
+        }
+        match Self::throttle_spec(limit)
+            .peek(format!("warmup:{source}->{site}"))
+            .await
+        {
+            Ok(result) => result
+                .retry_after
+                .map(|retry_after| retry_after.min(until_next_day)),
+            Err(err) => {
+                tracing::error!("checking warmup plan of {source} for {site}: {err:#}");
+                None
+            }
+        }
+    }
+
+    /// Counts a message that source is sending to site against
+    /// today's cap
+    async fn count_use(&self, source: &str, site: &str) {
+        let now = Utc::now();
+        let today = now.date_naive();
+        let limit = match self.limit_for(site, today) {
+            Some(limit @ 1..) => limit,
+            _ => return,
+        };
+        // The count for today is no longer needed once today has passed
+        if let Err(err) =
+            increment_count(Self::counter_key(source, site, today), until_next_day(now)).await
+        {
+            tracing::error!("updating warmup plan of {source} for {site}: {err:#}");
+        }
+        if self.smooth {
+            if let Err(err) = Self::throttle_spec(limit)
+                .throttle(format!("warmup:{source}->{site}"))
+                .await
+            {
+                tracing::error!("updating warmup plan of {source} for {site}: {err:#}");
+            }
+        }
+    }
+
+    fn counter_key(source: &str, site: &str, day: NaiveDate) -> String {
+        format!("warmup:{source}->{site}:{day}")
+    }
+
+    fn throttle_spec(limit: u64) -> ThrottleSpec {
+        // Spread the volume across the day rather than
+        // permitting it all in a burst
+        ThrottleSpec {
+            limit,
+            period: 86400,
+            max_burst: Some(0),
+        }
+    }
+}
+
+/// Returns the time remaining until midnight UTC
+fn until_next_day(now: DateTime<Utc>) -> Duration {
+    let next_day = Utc.from_utc_datetime(
+        &(now.date_naive() + chrono::Duration::days(1))
+            .and_hms_opt(0, 0, 0)
+            .expect("midnight is valid"),
+    );
+    (next_day - now).to_std().unwrap_or_default()
+}
+
 impl EgressSource {
+    /// Discards the cached sources, so that they will be
+    /// resolved again via the policy
+    pub fn clear_cache() {
+        SOURCES.lock().unwrap().clear();
+    }
+
     pub async fn resolve(name: &str, config: &mut LuaConfig) -> anyhow::Result<Self> {
         if let Some(source) = SOURCES.lock().unwrap().get(name) {
             return Ok(source.clone());
@@ -67,6 +204,7 @@ impl EgressSource {
                 socks5_proxy_server: None,
                 socks5_proxy_source_address: None,
                 source_address: None,
+                warmup: None,
             }
         } else {
             config
@@ -217,6 +355,12 @@ pub struct EgressPool {
 impl LuaUserData for EgressPool {}
 
 impl EgressPool {
+    /// Discards the cached pools, so that they will be
+    /// resolved again via the policy
+    pub fn clear_cache() {
+        POOLS.lock().unwrap().clear();
+    }
+
     pub async fn resolve(name: Option<&str>, config: &mut LuaConfig) -> anyhow::Result<Self> {
         let name = name.unwrap_or("unspecified");
 
@@ -255,13 +399,25 @@ impl EgressPool {
     }
 }
 
+/// The source selected by EgressPoolRoundRobin::next_for_site
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceSelection {
+    Source(String),
+    /// Every source in the pool is capped by its warmup plan for
+    /// the site; the first of them may send again after retry_after
+    Capped {
+        retry_after: Duration,
+    },
+}
+
 /// Maintains the state to manage Weighted Round Robin
 /// <http://kb.linuxvirtualserver.org/wiki/Weighted_Round-Robin_Scheduling>
 pub struct EgressPoolRoundRobin {
     pub name: String,
     entries: Vec<EgressPoolEntry>,
-    max_weight: u32,
-    gcd: u32,
+    warmup: HashMap<String, WarmupPlan>,
+    /// The effective weights most recently exported for each site
+    published_weights: HashMap<String, Vec<u32>>,
 
     current_index: usize,
     current_weight: u32,
@@ -269,24 +425,18 @@ pub struct EgressPoolRoundRobin {
 
 impl EgressPoolRoundRobin {
     pub fn new(pool: &EgressPool) -> Self {
-        let mut entries = vec![];
-        let mut max_weight = 0;
-        let mut gcd = 0;
-
-        for entry in &pool.entries {
-            if entry.weight == 0 {
-                continue;
-            }
-            max_weight = max_weight.max(entry.weight);
-            gcd = gcd.gcd(entry.weight);
-            entries.push(entry.clone());
-        }
+        let entries = pool
+            .entries
+            .iter()
+            .filter(|entry| entry.weight > 0)
+            .cloned()
+            .collect();
 
         Self {
             name: pool.name.to_string(),
             entries,
-            max_weight,
-            gcd,
+            warmup: HashMap::new(),
+            published_weights: HashMap::new(),
             current_index: 0,
             current_weight: 0,
         }
@@ -299,26 +449,128 @@ impl EgressPoolRoundRobin {
             .collect()
     }
 
+    /// Applies the warmup plan of the named source to the selection
+    /// made by next_for_site
+    pub fn set_warmup(&mut self, source: &str, plan: WarmupPlan) {
+        self.warmup.insert(source.to_string(), plan);
+    }
+
+    /// Like next, but adjusts the weight of each source according to its
+    /// health when sending to site, and skips sources that have reached the
+    /// cap of their warmup plan for site, so that their share of the traffic
+    /// goes to the other sources in the pool.
+    /// Checking the cap doesn't count against it; call count_warmup_use
+    /// once the message has been assigned to the selected source.
+    pub async fn next_for_site(&mut self, site: &str) -> anyhow::Result<SourceSelection> {
+        let names: Vec<String> = self
+            .entries
+            .iter()
+            .map(|entry| format!("{}->{site}", entry.name))
+            .collect();
+        let configured: Vec<u32> = self.entries.iter().map(|entry| entry.weight).collect();
+        let weights = effective_weights(&names, &configured);
+        self.publish_weights(site, &names, &configured, &weights);
+
+        let num_sources = self
+            .entries
+            .iter()
+            .zip(&weights)
+            .filter(|(_, &weight)| weight > 0)
+            .map(|(entry, _)| entry.name.as_str())
+            .collect::<HashSet<_>>()
+            .len();
+        if num_sources == 0 {
+            anyhow::bail!("no sources in pool");
+        }
+
+        let mut tried = HashSet::new();
+        let mut retry_after: Option<Duration> = None;
+        while tried.len() < num_sources {
+            let index = self
+                .next_index(&weights)
+                .ok_or_else(|| anyhow::anyhow!("no sources in pool"))?;
+            let name = self.entries[index].name.to_string();
+            if !tried.insert(name.clone()) {
+                continue;
+            }
+            let capped = match self.warmup.get(&name) {
+                Some(plan) => plan.capped_for(&name, site).await,
+                None => None,
+            };
+            match capped {
+                Some(delay) => {
+                    tracing::trace!("{name} is capped by its warmup plan for {site}");
+                    retry_after = Some(retry_after.map_or(delay, |d| d.min(delay)));
+                }
+                None => return Ok(SourceSelection::Source(name)),
+            }
+        }
+        Ok(SourceSelection::Capped {
+            retry_after: retry_after.unwrap_or_default(),
+        })
+    }
+
+    /// Counts a message that source is sending to site against
+    /// the cap of its warmup plan, if any
+    pub async fn count_warmup_use(&self, source: &str, site: &str) {
+        if let Some(plan) = self.warmup.get(source) {
+            plan.count_use(source, site).await;
+        }
+    }
+
+    fn publish_weights(
+        &mut self,
+        site: &str,
+        names: &[String],
+        configured: &[u32],
+        weights: &[u32],
+    ) {
+        if self.published_weights.get(site).map(|w| w.as_slice()) == Some(weights) {
+            return;
+        }
+        for ((name, &configured), &weight) in names.iter().zip(configured).zip(weights) {
+            publish_effective_weight(&self.name, name, configured, weight);
+        }
+        self.published_weights
+            .insert(site.to_string(), weights.to_vec());
+    }
+
     pub fn next(&mut self) -> Option<String> {
-        if self.entries.is_empty() || self.max_weight == 0 {
+        let weights: Vec<u32> = self.entries.iter().map(|entry| entry.weight).collect();
+        let index = self.next_index(&weights)?;
+        Some(self.entries[index].name.to_string())
+    }
+
+    /// Returns the index of the next entry, using weights in
+    /// place of the configured weights of the entries
+    fn next_index(&mut self, weights: &[u32]) -> Option<usize> {
+        let mut max_weight = 0;
+        let mut gcd = 0;
+        for &weight in weights {
+            max_weight = max_weight.max(weight);
+            gcd = gcd.gcd(weight);
+        }
+
+        if self.entries.is_empty() || max_weight == 0 {
             return None;
         }
         if self.entries.len() == 1 {
-            return self.entries.get(0).map(|entry| entry.name.to_string());
+            return Some(0);
         }
+        // The weights may have been reduced since the previous call
+        self.current_weight = self.current_weight.min(max_weight);
         loop {
             self.current_index = (self.current_index + 1) % self.entries.len();
             if self.current_index == 0 {
-                self.current_weight = self.current_weight.saturating_sub(self.gcd);
+                self.current_weight = self.current_weight.saturating_sub(gcd);
                 if self.current_weight == 0 {
-                    self.current_weight = self.max_weight;
+                    self.current_weight = max_weight;
                 }
             }
 
-            if let Some(entry) = self.entries.get(self.current_index) {
-                if entry.weight >= self.current_weight {
-                    return Some(entry.name.to_string());
-                }
+            let weight = weights[self.current_index];
+            if weight > 0 && weight >= self.current_weight {
+                return Some(self.current_index);
             }
         }
     }
@@ -364,6 +616,177 @@ mod test {
         assert_eq!(counts["two"], 20, "two");
         assert_eq!(counts["three"], 30, "three");
     }
+
+    fn selected(selection: anyhow::Result<SourceSelection>) -> String {
+        match selection.unwrap() {
+            SourceSelection::Source(name) => name,
+            selection => panic!("expected a source, got {selection:?}"),
+        }
+    }
+
+    #[test]
+    fn warmup_limits() {
+        let day = |d| NaiveDate::from_ymd_opt(2023, 5, d).unwrap();
+        let plan = WarmupPlan {
+            start: day(10),
+            daily_limits: vec![100, 200],
+            sites: [("example.com".to_string(), vec![10, 20, 30])]
+                .into_iter()
+                .collect(),
+            smooth: false,
+        };
+
+        assert_eq!(plan.limit_for("other.com", day(1)), Some(100));
+        assert_eq!(plan.limit_for("other.com", day(10)), Some(100));
+        assert_eq!(plan.limit_for("other.com", day(11)), Some(200));
+        assert_eq!(plan.limit_for("other.com", day(12)), None);
+        assert_eq!(plan.limit_for("example.com", day(12)), Some(30));
+        assert_eq!(plan.limit_for("example.com", day(13)), None);
+    }
+
+    #[tokio::test]
+    async fn health_adjusts_weights() {
+        let pool = EgressPool {
+            name: "pool".to_string(),
+            entries: vec![
+                EgressPoolEntry {
+                    name: "failing".to_string(),
+                    weight: 4,
+                },
+                EgressPoolEntry {
+                    name: "healthy".to_string(),
+                    weight: 4,
+                },
+            ],
+            ttl: default_ttl(),
+        };
+        let site = "health.example.com";
+        crate::source_health::record_connection_failure(&format!("failing->{site}"));
+        crate::source_health::record_connection_failure(&format!("failing->{site}"));
+
+        let mut rr = EgressPoolRoundRobin::new(&pool);
+        let mut counts = HashMap::new();
+        for _ in 0..50 {
+            let name = selected(rr.next_for_site(site).await);
+            *counts.entry(name).or_insert(0) += 1;
+        }
+        // Two failures reduce the weight to a quarter
+        assert_eq!(counts["failing"], 10);
+        assert_eq!(counts["healthy"], 40);
+
+        crate::source_health::record_connection_success(&format!("failing->{site}"));
+        let mut counts = HashMap::new();
+        for _ in 0..50 {
+            let name = selected(rr.next_for_site(site).await);
+            *counts.entry(name).or_insert(0) += 1;
+        }
+        assert_eq!(counts["failing"], 25);
+        assert_eq!(counts["healthy"], 25);
+    }
+
+    #[tokio::test]
+    async fn warmup_spills_to_other_sources() {
+        let pool = EgressPool {
+            name: "pool".to_string(),
+            entries: vec![
+                EgressPoolEntry {
+                    name: "established".to_string(),
+                    weight: 1,
+                },
+                EgressPoolEntry {
+                    name: "new".to_string(),
+                    weight: 1,
+                },
+            ],
+            ttl: default_ttl(),
+        };
+
+        let plan = WarmupPlan {
+            start: Utc::now().date_naive(),
+            daily_limits: vec![1],
+            sites: [
+                ("capped.example.com".to_string(), vec![0]),
+                ("busy.example.com".to_string(), vec![3]),
+                ("smooth.example.com".to_string(), vec![100]),
+            ]
+            .into_iter()
+            .collect(),
+            smooth: false,
+        };
+
+        let mut rr = EgressPoolRoundRobin::new(&pool);
+        rr.set_warmup("new", plan.clone());
+
+        // Selecting a source doesn't count against its cap
+        let mut counts = HashMap::new();
+        for _ in 0..4 {
+            let name = selected(rr.next_for_site("unused.example.com").await);
+            *counts.entry(name).or_insert(0) += 1;
+        }
+        assert_eq!(counts["new"], 2);
+        assert_eq!(counts["established"], 2);
+
+        let mut counts = HashMap::new();
+        for _ in 0..10 {
+            let name = selected(rr.next_for_site("other.example.com").await);
+            rr.count_warmup_use(&name, "other.example.com").await;
+            *counts.entry(name).or_insert(0) += 1;
+        }
+        // Once today's cap has been used, the new source is skipped
+        assert_eq!(counts["new"], 1);
+        assert_eq!(counts["established"], 9);
+
+        let mut counts = HashMap::new();
+        for _ in 0..10 {
+            let name = selected(rr.next_for_site("busy.example.com").await);
+            rr.count_warmup_use(&name, "busy.example.com").await;
+            *counts.entry(name).or_insert(0) += 1;
+        }
+        assert_eq!(counts["new"], 3);
+        assert_eq!(counts["established"], 7);
+
+        // When smoothing, the cap is spread across the day, so
+        // only the first message is permitted to use the new source
+        rr.set_warmup(
+            "new",
+            WarmupPlan {
+                smooth: true,
+                ..plan.clone()
+            },
+        );
+        let mut counts = HashMap::new();
+        for _ in 0..10 {
+            let name = selected(rr.next_for_site("smooth.example.com").await);
+            rr.count_warmup_use(&name, "smooth.example.com").await;
+            *counts.entry(name).or_insert(0) += 1;
+        }
+        assert_eq!(counts["new"], 1);
+        assert_eq!(counts["established"], 9);
+
+        for _ in 0..10 {
+            assert_eq!(
+                rr.next_for_site("capped.example.com").await.unwrap(),
+                SourceSelection::Source("established".to_string())
+            );
+        }
+
+        // When every source is capped, there is nothing to select,
+        // and the messages should wait no longer than the next day
+        let mut rr = EgressPoolRoundRobin::new(&EgressPool {
+            name: "pool".to_string(),
+            entries: vec![pool.entries[1].clone()],
+            ttl: default_ttl(),
+        });
+        rr.set_warmup("new", plan);
+        for site in ["capped.example.com", "other.example.com"] {
+            match rr.next_for_site(site).await.unwrap() {
+                SourceSelection::Capped { retry_after } => {
+                    assert!(retry_after <= Duration::from_secs(86400), "{retry_after:?}")
+                }
+                selection => panic!("expected {site} to be capped, got {selection:?}"),
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -0,0 +1,386 @@
+use crate::logging::{log_disposition, LogDisposition};
+use crate::ready_queue::{Dispatcher, QueueDispatcher};
+use crate::runtime::{rt_spawn, spawn};
+use crate::spool::SpoolManager;
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use kumo_log_types::{RecordType, ResolvedAddress};
+use message::Message;
+use reqwest::header::{HeaderMap, CONTENT_TYPE, RETRY_AFTER};
+use reqwest::StatusCode;
+use rfc5321::{EnhancedStatusCode, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+/// Only this much of the response body is included in the logs
+const MAX_LOGGED_BODY: usize = 1024;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HttpPayloadFormat {
+    /// POST the RFC 5322 message content as message/rfc822
+    #[default]
+    Raw,
+    /// POST a JSON object holding the envelope, metadata and content
+    Json,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HttpDeliveryProtocol {
+    /// The URL to which messages are POSTed
+    pub url: String,
+
+    #[serde(default)]
+    pub format: HttpPayloadFormat,
+
+    /// Additional headers to send with each request,
+    /// such as Authorization
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+
+    #[serde(
+        default = "HttpDeliveryProtocol::default_timeout",
+        with = "humantime_serde"
+    )]
+    pub timeout: Duration,
+}
+
+impl HttpDeliveryProtocol {
+    fn default_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+}
+
+/// The body of the request when using HttpPayloadFormat::Json
+#[derive(Serialize)]
+struct JsonPayload {
+    id: String,
+    sender: String,
+    recipients: Vec<String>,
+    meta: serde_json::Value,
+    /// The message content, base64 encoded so that 8-bit and
+    /// binary content survives the trip through JSON
+    data: String,
+}
+
+/// How the response to a POST is to be treated
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Delivered,
+    Transient { retry_after: Option<Duration> },
+    Permanent,
+}
+
+fn classify_status(status: StatusCode, headers: &HeaderMap) -> Outcome {
+    if status.is_success() {
+        Outcome::Delivered
+    } else if status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status.is_server_error()
+    {
+        let retry_after = headers
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, Utc::now()));
+        Outcome::Transient { retry_after }
+    } else {
+        Outcome::Permanent
+    }
+}
+
+/// Expresses the outcome as an SMTP response, so that the logs,
+/// bounce classification and non-delivery reports treat it the same
+/// way as any other delivery. The HTTP status is kept in the content.
+fn smtp_response(status: StatusCode, outcome: &Outcome, body: &str) -> Response {
+    let (code, class, subject) = match outcome {
+        Outcome::Delivered => (250, 2, 0),
+        Outcome::Transient { .. } => (451, 4, 3),
+        Outcome::Permanent => (554, 5, 3),
+    };
+    Response {
+        code,
+        enhanced_code: Some(EnhancedStatusCode {
+            class,
+            subject,
+            detail: 0,
+        }),
+        content: format!("HTTP {status} {}", body.trim())
+            .trim_end()
+            .to_string(),
+        command: None,
+    }
+}
+
+/// The shortest delay that we will honor from a Retry-After header,
+/// so that a peer can't have us retry in a tight loop
+const MIN_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Parses a Retry-After header, which is either a number of
+/// seconds or an HTTP date; RFC 9110 section 10.2.3
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let when = DateTime::parse_from_rfc2822(value).ok()?;
+            when.with_timezone(&Utc)
+                .signed_duration_since(now)
+                .to_std()
+                .unwrap_or_default()
+        }
+    };
+    Some(delay.max(MIN_RETRY_AFTER))
+}
+
+#[derive(Debug)]
+pub struct HttpQueueDispatcher {
+    proto_config: HttpDeliveryProtocol,
+    client: Option<reqwest::Client>,
+    peer_address: ResolvedAddress,
+}
+
+impl HttpQueueDispatcher {
+    pub fn new(proto_config: HttpDeliveryProtocol) -> Self {
+        let peer_address = ResolvedAddress {
+            name: proto_config.url.to_string(),
+            addr: Ipv4Addr::UNSPECIFIED.into(),
+        };
+
+        Self {
+            proto_config,
+            client: None,
+            peer_address,
+        }
+    }
+
+    fn build_body(&self, msg: &Message) -> anyhow::Result<(&'static str, Vec<u8>)> {
+        let data = msg.get_data();
+        match self.proto_config.format {
+            HttpPayloadFormat::Raw => Ok(("message/rfc822", data.to_vec())),
+            HttpPayloadFormat::Json => {
+                let payload = JsonPayload {
+                    id: msg.id().to_string(),
+                    sender: msg.sender()?.to_string(),
+                    recipients: msg
+                        .recipients()?
+                        .iter()
+                        .map(|recip| recip.to_string())
+                        .collect(),
+                    meta: msg.get_meta_obj()?,
+                    data: base64::encode(&*data),
+                };
+                Ok(("application/json", serde_json::to_vec(&payload)?))
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl QueueDispatcher for HttpQueueDispatcher {
+    async fn close_connection(&mut self, _dispatcher: &mut Dispatcher) -> anyhow::Result<bool> {
+        Ok(self.client.take().is_some())
+    }
+
+    async fn attempt_connection(&mut self, _dispatcher: &mut Dispatcher) -> anyhow::Result<()> {
+        if self.client.is_none() {
+            let client = reqwest::Client::builder()
+                .timeout(self.proto_config.timeout)
+                .build()
+                .context("building http client")?;
+            self.client.replace(client);
+        }
+        Ok(())
+    }
+
+    async fn have_more_connection_candidates(&mut self, _dispatcher: &mut Dispatcher) -> bool {
+        false
+    }
+
+    async fn deliver_message(
+        &mut self,
+        msg: Message,
+        dispatcher: &mut Dispatcher,
+    ) -> anyhow::Result<()> {
+        msg.load_meta_if_needed().await?;
+        msg.load_data_if_needed().await?;
+
+        let (content_type, body) = self.build_body(&msg)?;
+        let client = self.client.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("client is not set in HttpQueueDispatcher::deliver_message!?")
+        })?;
+
+        let mut request = client
+            .post(&self.proto_config.url)
+            .header(CONTENT_TYPE, content_type)
+            .body(body);
+        for (name, value) in &self.proto_config.headers {
+            request = request.header(name, value);
+        }
+
+        let result = request.send().await;
+        let http_response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                // Treat this the same as a connection failure
+                tracing::debug!("failed to send message to {}: {err:#}", dispatcher.name);
+                return Err(err.into());
+            }
+        };
+
+        let status = http_response.status();
+        let outcome = classify_status(status, http_response.headers());
+        let content: String = http_response
+            .text()
+            .await
+            .unwrap_or_default()
+            .chars()
+            .take(MAX_LOGGED_BODY)
+            .collect();
+        let response = smtp_response(status, &outcome, &content);
+        tracing::debug!("POST to {} resulted in {response:?}", dispatcher.name);
+
+        let kind = match &outcome {
+            Outcome::Delivered => {
+                dispatcher.metrics.msgs_delivered.inc();
+                dispatcher.metrics.global_msgs_delivered.inc();
+                RecordType::Delivery
+            }
+            Outcome::Transient { .. } => {
+                dispatcher.metrics.msgs_transfail.inc();
+                dispatcher.metrics.global_msgs_transfail.inc();
+                RecordType::TransientFailure
+            }
+            Outcome::Permanent => {
+                dispatcher.metrics.msgs_fail.inc();
+                dispatcher.metrics.global_msgs_fail.inc();
+                RecordType::Bounce
+            }
+        };
+
+        if let Some(msg) = dispatcher.msg.take() {
+            log_disposition(LogDisposition {
+                kind,
+                msg: msg.clone(),
+                recipient: None,
+                site: &dispatcher.name,
+                peer_address: Some(&self.peer_address),
+                response,
+                egress_pool: Some(&dispatcher.egress_pool),
+                egress_source: Some(&dispatcher.egress_source.name),
+                relay_disposition: None,
+                delivery_protocol: Some(&dispatcher.delivery_protocol),
+            })
+            .await;
+
+            match outcome {
+                Outcome::Transient { retry_after } => {
+                    let delay =
+                        retry_after.and_then(|delay| chrono::Duration::from_std(delay).ok());
+                    rt_spawn("requeue message".to_string(), move || {
+                        Ok(async move { Dispatcher::requeue_message(msg, true, delay).await })
+                    })
+                    .await?;
+                }
+                Outcome::Delivered | Outcome::Permanent => {
+                    spawn("remove from spool", async move {
+                        SpoolManager::remove_from_spool(*msg.id()).await
+                    })?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn retry_after() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:30:00 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        // Delays that are too short, or in the past, are raised to the minimum
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(MIN_RETRY_AFTER)
+        );
+        assert_eq!(parse_retry_after("0", now), Some(MIN_RETRY_AFTER));
+        assert_eq!(parse_retry_after("30", now), Some(MIN_RETRY_AFTER));
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn classify() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            classify_status(StatusCode::OK, &headers),
+            Outcome::Delivered
+        );
+        assert_eq!(
+            classify_status(StatusCode::ACCEPTED, &headers),
+            Outcome::Delivered
+        );
+        assert_eq!(
+            classify_status(StatusCode::BAD_REQUEST, &headers),
+            Outcome::Permanent
+        );
+        assert_eq!(
+            classify_status(StatusCode::SERVICE_UNAVAILABLE, &headers),
+            Outcome::Transient { retry_after: None }
+        );
+        assert_eq!(
+            classify_status(StatusCode::REQUEST_TIMEOUT, &headers),
+            Outcome::Transient { retry_after: None }
+        );
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("90"));
+        assert_eq!(
+            classify_status(StatusCode::TOO_MANY_REQUESTS, &headers),
+            Outcome::Transient {
+                retry_after: Some(Duration::from_secs(90))
+            }
+        );
+    }
+
+    #[test]
+    fn status_as_smtp_response() {
+        let response = smtp_response(StatusCode::NOT_FOUND, &Outcome::Permanent, "no such app\n");
+        assert_eq!(response.code, 554);
+        assert_eq!(
+            response.enhanced_code,
+            Some(EnhancedStatusCode {
+                class: 5,
+                subject: 3,
+                detail: 0
+            })
+        );
+        assert_eq!(response.content, "HTTP 404 Not Found no such app");
+
+        let response = smtp_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            &Outcome::Transient { retry_after: None },
+            "",
+        );
+        assert_eq!(response.code, 451);
+        assert_eq!(response.enhanced_code.unwrap().class, 4);
+        assert_eq!(response.content, "HTTP 429 Too Many Requests");
+
+        let response = smtp_response(StatusCode::NO_CONTENT, &Outcome::Delivered, "");
+        assert_eq!(response.code, 250);
+        assert_eq!(response.enhanced_code.unwrap().class, 2);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::http_server::admin_rules::save_admin_rules;
 use crate::http_server::auth::TrustedIpRequired;
 use crate::http_server::AppError;
 use crate::logging::{log_disposition, LogDisposition, RecordType};
@@ -28,7 +29,7 @@ pub struct AdminBounceEntry {
     pub bounced: Arc<Mutex<HashMap<String, usize>>>,
 }
 
-fn match_criteria(current_thing: Option<&str>, wanted_thing: Option<&str>) -> bool {
+pub fn match_criteria(current_thing: Option<&str>, wanted_thing: Option<&str>) -> bool {
     match (current_thing, wanted_thing) {
         (Some(a), Some(b)) => a == b,
         (None, Some(_)) => {
@@ -51,26 +52,35 @@ impl AdminBounceEntry {
     }
 
     pub fn remove_by_id(id: &Uuid) -> bool {
-        let mut entries = ENTRIES.lock().unwrap();
-        let len_before = entries.len();
-        entries.retain(|e| e.id != *id);
-        len_before != entries.len()
+        let removed = {
+            let mut entries = ENTRIES.lock().unwrap();
+            let len_before = entries.len();
+            entries.retain(|e| e.id != *id);
+            len_before != entries.len()
+        };
+        if removed {
+            save_admin_rules();
+        }
+        removed
     }
 
     pub fn add(entry: Self) {
-        let mut entries = ENTRIES.lock().unwrap();
-        let now = Instant::now();
-        // Age out expired entries, and replace any entries with the
-        // same criteria; this allows updating the reason with a newer
-        // version of the bounce info.
-        entries.retain(|ent| {
-            ent.expires > now
-                && !(ent.campaign == entry.campaign
-                    && ent.tenant == entry.tenant
-                    && ent.domain == entry.domain)
-        });
-
-        entries.push(entry);
+        {
+            let mut entries = ENTRIES.lock().unwrap();
+            let now = Instant::now();
+            // Age out expired entries, and replace any entries with the
+            // same criteria; this allows updating the reason with a newer
+            // version of the bounce info.
+            entries.retain(|ent| {
+                ent.expires > now
+                    && !(ent.campaign == entry.campaign
+                        && ent.tenant == entry.tenant
+                        && ent.domain == entry.domain)
+            });
+
+            entries.push(entry);
+        }
+        save_admin_rules();
     }
 
     pub fn matches(
@@ -137,6 +147,7 @@ impl AdminBounceEntry {
         log_disposition(LogDisposition {
             kind: RecordType::AdminBounce,
             msg,
+            recipient: None,
             site: "localhost",
             peer_address: None,
             response: rfc5321::Response {
@@ -0,0 +1,89 @@
+use crate::http_server::auth::TrustedIpRequired;
+use crate::http_server::AppError;
+use crate::queue::QueueManager;
+use crate::ready_queue::ReadyQueueManager;
+use axum::extract::{Json, Path, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use kumo_api_types::{
+    QueueSampleV1Entry, QueueSampleV1Request, QueueSampleV1Response, QueueSummaryV1Response,
+};
+use message::Message;
+use spool::get_meta_spool;
+
+pub async fn queues_v1_list(
+    _: TrustedIpRequired,
+) -> Result<Json<QueueSummaryV1Response>, AppError> {
+    let mut scheduled = vec![];
+    for name in QueueManager::all_queue_names().await {
+        if let Some(queue) = QueueManager::get_opt(&name).await {
+            scheduled.push(queue.lock().await.summary());
+        }
+    }
+    scheduled.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut ready = vec![];
+    for queue in ReadyQueueManager::all_queues().await {
+        ready.push(queue.lock().await.summary());
+    }
+    ready.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(Json(QueueSummaryV1Response { scheduled, ready }))
+}
+
+pub async fn queues_v1_sample(
+    _: TrustedIpRequired,
+    Path(name): Path<String>,
+    Query(request): Query<QueueSampleV1Request>,
+) -> Result<Response, AppError> {
+    let (message_count, msgs) = if let Some(queue) = QueueManager::get_opt(&name).await {
+        let queue = queue.lock().await;
+        (queue.delayed_count(), queue.sample_messages(request.limit))
+    } else if let Some(queue) = ReadyQueueManager::get_by_name(&name).await {
+        let queue = queue.lock().await;
+        (queue.ready_count(), queue.sample_messages(request.limit))
+    } else {
+        return Ok((StatusCode::NOT_FOUND, format!("queue {name} not found")).into_response());
+    };
+
+    let mut messages = vec![];
+    for msg in msgs {
+        messages.push(sample_entry(&msg).await?);
+    }
+
+    Ok(Json(QueueSampleV1Response {
+        name,
+        message_count,
+        messages,
+    })
+    .into_response())
+}
+
+async fn sample_entry(msg: &Message) -> anyhow::Result<QueueSampleV1Entry> {
+    // Messages in the scheduled queue are usually shrunk. Rather than
+    // loading the metadata into the queued message, which we no longer
+    // own once the queue lock is released, read a copy from the spool
+    let loaded;
+    let meta_msg = if msg.is_meta_loaded() {
+        msg
+    } else {
+        let id = *msg.id();
+        loaded = Message::new_from_spool(id, get_meta_spool().load(id).await?)?;
+        &loaded
+    };
+
+    Ok(QueueSampleV1Entry {
+        id: msg.id().to_string(),
+        age: msg.age(Utc::now()).to_std().unwrap_or_default(),
+        due: msg.get_due(),
+        num_attempts: msg.get_num_attempts(),
+        sender: meta_msg.sender().ok().map(|sender| sender.to_string()),
+        recipients: meta_msg
+            .recipients()?
+            .iter()
+            .map(|recip| recip.to_string())
+            .collect(),
+        meta: meta_msg.get_meta_obj()?,
+    })
+}
@@ -0,0 +1,203 @@
+use crate::egress_source::EgressPool;
+use crate::http_server::admin_bounce_v1::match_criteria;
+use crate::http_server::auth::TrustedIpRequired;
+use crate::http_server::AppError;
+use crate::logging::{log_disposition, LogDisposition, RecordType};
+use crate::queue::QueueManager;
+use crate::runtime::rt_spawn;
+use axum::extract::Json;
+use config::{load_config, LuaConfig};
+use kumo_api_types::{RebindV1Request, RebindV1Response};
+use message::message::QueueNameComponents;
+use message::Message;
+use std::collections::HashMap;
+
+fn queue_matches(queue_name: &str, request: &RebindV1Request) -> bool {
+    let components = QueueNameComponents::parse(queue_name);
+    match_criteria(components.campaign, request.campaign.as_deref())
+        && match_criteria(components.tenant, request.tenant.as_deref())
+        && match_criteria(Some(components.domain), request.domain.as_deref())
+        && match_criteria(
+            Some(components.routing_domain()),
+            request.routing_domain.as_deref(),
+        )
+}
+
+/// Applies data and egress_pool from the request to the meta of msg
+fn apply_meta(msg: &Message, request: &RebindV1Request, apply_data: bool) -> anyhow::Result<()> {
+    if apply_data {
+        for (key, value) in &request.data {
+            msg.set_meta(key, value.as_str())?;
+        }
+    }
+    if let Some(pool) = &request.egress_pool {
+        msg.set_meta("egress_pool", pool.as_str())?;
+    }
+    Ok(())
+}
+
+/// Applies the rebind to msg and inserts it into its (possibly new) queue
+async fn rebind_message(
+    msg: &Message,
+    queue_name: &str,
+    request: &RebindV1Request,
+    config: &mut LuaConfig,
+) -> anyhow::Result<()> {
+    msg.load_meta_if_needed().await?;
+
+    if request.trigger_rebind_event {
+        config
+            .async_call_callback("rebind_message", (msg.clone(), request.data.clone()))
+            .await?;
+    }
+    apply_meta(msg, request, !request.trigger_rebind_event)?;
+
+    let new_queue_name = msg.get_queue_name()?;
+    if new_queue_name != queue_name || request.always_flush {
+        msg.set_due(None).await?;
+    }
+    if msg.needs_save() {
+        msg.save().await?;
+    }
+
+    log_disposition(LogDisposition {
+        kind: RecordType::AdminRebind,
+        msg: msg.clone(),
+        recipient: None,
+        site: "",
+        peer_address: None,
+        response: rfc5321::Response {
+            code: 250,
+            enhanced_code: None,
+            content: format!(
+                "Administrator rebound from {queue_name} to {new_queue_name} \
+                 with reason: {}",
+                request.reason
+            ),
+            command: None,
+        },
+        egress_pool: None,
+        egress_source: None,
+        relay_disposition: None,
+        delivery_protocol: None,
+    })
+    .await;
+
+    QueueManager::insert(&new_queue_name, msg.clone()).await
+}
+
+async fn rebind_v1_impl(request: RebindV1Request) -> Result<Json<RebindV1Response>, AppError> {
+    let mut config = load_config().await?;
+    let mut rebound = HashMap::new();
+
+    // Fail the request up front, rather than each message
+    if let Some(pool) = &request.egress_pool {
+        EgressPool::resolve(Some(pool), &mut config).await?;
+    }
+
+    for queue_name in QueueManager::all_queue_names().await {
+        if !queue_matches(&queue_name, &request) {
+            continue;
+        }
+        let msgs = match QueueManager::get_opt(&queue_name).await {
+            Some(queue) => queue.lock().await.drain_delayed(),
+            None => continue,
+        };
+        if msgs.is_empty() {
+            continue;
+        }
+
+        rebound.insert(queue_name.clone(), msgs.len());
+        for msg in msgs {
+            if let Err(err) = rebind_message(&msg, &queue_name, &request, &mut config).await {
+                tracing::error!("failed to rebind {}: {err:#}", msg.id());
+                // Put it back where it came from
+                if let Err(err) = QueueManager::insert(&queue_name, msg.clone()).await {
+                    tracing::error!("failed to requeue {}: {err:#}", msg.id());
+                }
+            }
+        }
+    }
+
+    let total_rebound = rebound.values().sum();
+    Ok(Json(RebindV1Response {
+        rebound,
+        total_rebound,
+    }))
+}
+
+pub async fn rebind_v1(
+    _: TrustedIpRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<RebindV1Request>,
+) -> Result<Json<RebindV1Response>, AppError> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+
+    // Bounce to the thread pool where we can run async lua
+    rt_spawn("http rebind_v1".to_string(), move || {
+        Ok(async move { tx.send(rebind_v1_impl(request).await) })
+    })
+    .await?;
+    rx.await?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::EnvelopeAddress;
+    use spool::SpoolId;
+    use std::sync::Arc;
+
+    fn request(json: serde_json::Value) -> RebindV1Request {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn routing_domain_criteria() {
+        let req = request(serde_json::json!({
+            "routing_domain": "mx.example.net",
+            "reason": "test",
+        }));
+        assert!(queue_matches("tenant@example.com!mx.example.net", &req));
+        assert!(queue_matches("mx.example.net", &req));
+        assert!(!queue_matches("tenant@example.com", &req));
+        assert!(!queue_matches("example.com!other.example.net", &req));
+
+        let req = request(serde_json::json!({
+            "domain": "example.com",
+            "reason": "test",
+        }));
+        assert!(queue_matches("tenant@example.com!mx.example.net", &req));
+    }
+
+    #[test]
+    fn egress_pool_override() {
+        let msg = Message::new_dirty(
+            SpoolId::new(),
+            EnvelopeAddress::parse("sender@example.com").unwrap(),
+            EnvelopeAddress::parse("recip@example.com").unwrap(),
+            serde_json::json!({}),
+            Arc::new(
+                b"Subject: hello\r\n\r\nHello!\r\n"
+                    .to_vec()
+                    .into_boxed_slice(),
+            ),
+        )
+        .unwrap();
+
+        let req = request(serde_json::json!({
+            "reason": "test",
+            "egress_pool": "warm",
+            "data": {"tenant": "other"},
+        }));
+        apply_meta(&msg, &req, false).unwrap();
+        assert_eq!(
+            msg.get_meta_string("egress_pool").unwrap().as_deref(),
+            Some("warm")
+        );
+        assert_eq!(msg.get_meta_string("tenant").unwrap(), None);
+
+        apply_meta(&msg, &req, true).unwrap();
+        assert_eq!(msg.get_queue_name().unwrap(), "other@example.com");
+    }
+}
@@ -0,0 +1,19 @@
+use crate::http_server::auth::TrustedIpRequired;
+use crate::http_server::AppError;
+use crate::policy::reload_policy;
+use crate::runtime::rt_spawn;
+use axum::extract::Json;
+use kumo_api_types::ReloadV1Response;
+
+pub async fn reload_v1(_: TrustedIpRequired) -> Result<Json<ReloadV1Response>, AppError> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+
+    // Bounce to the thread pool where we can run async lua
+    rt_spawn("http reload_v1".to_string(), move || {
+        Ok(async move { tx.send(reload_policy().await) })
+    })
+    .await?;
+    let generation = rx.await??;
+
+    Ok(Json(ReloadV1Response { generation }))
+}
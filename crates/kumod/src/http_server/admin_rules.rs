@@ -0,0 +1,220 @@
+//! Persists the administrative bounce and suspend rules so that they
+//! survive a restart of kumod
+use crate::http_server::admin_bounce_v1::AdminBounceEntry;
+use crate::http_server::admin_suspend_v1::AdminSuspendEntry;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use uuid::Uuid;
+
+/// The name of the file, in the meta spool directory,
+/// in which the rules are stored
+pub const RULES_FILE_NAME: &str = "admin-rules.json";
+
+lazy_static::lazy_static! {
+    /// Where the rules are saved. This is None until the rules
+    /// have been loaded, so that the rules cannot be saved before
+    /// the persisted rules have been restored.
+    static ref RULES_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct PersistedRules {
+    #[serde(default)]
+    bounces: Vec<PersistedRule>,
+    #[serde(default)]
+    suspensions: Vec<PersistedRule>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct PersistedRule {
+    id: Uuid,
+    #[serde(default)]
+    campaign: Option<String>,
+    #[serde(default)]
+    tenant: Option<String>,
+    #[serde(default)]
+    domain: Option<String>,
+    reason: String,
+    expires: DateTime<Utc>,
+}
+
+impl PersistedRule {
+    fn new(
+        id: Uuid,
+        campaign: &Option<String>,
+        tenant: &Option<String>,
+        domain: &Option<String>,
+        reason: &str,
+        expires: Instant,
+        now: (Instant, DateTime<Utc>),
+    ) -> Self {
+        let remaining = expires.saturating_duration_since(now.0);
+        Self {
+            id,
+            campaign: campaign.clone(),
+            tenant: tenant.clone(),
+            domain: domain.clone(),
+            reason: reason.to_string(),
+            expires: now.1
+                + chrono::Duration::from_std(remaining)
+                    .unwrap_or_else(|_| chrono::Duration::zero()),
+        }
+    }
+
+    /// Returns the Instant at which the rule expires,
+    /// or None if it has already expired
+    fn expires(&self, now: (Instant, DateTime<Utc>)) -> Option<Instant> {
+        let remaining = (self.expires - now.1).to_std().ok()?;
+        Some(now.0 + remaining)
+    }
+}
+
+fn now() -> (Instant, DateTime<Utc>) {
+    (Instant::now(), Utc::now())
+}
+
+fn collect_rules() -> PersistedRules {
+    let now = now();
+    PersistedRules {
+        bounces: AdminBounceEntry::get_all()
+            .iter()
+            .map(|ent| {
+                PersistedRule::new(
+                    ent.id,
+                    &ent.campaign,
+                    &ent.tenant,
+                    &ent.domain,
+                    &ent.reason,
+                    ent.expires,
+                    now,
+                )
+            })
+            .collect(),
+        suspensions: AdminSuspendEntry::get_all()
+            .iter()
+            .map(|ent| {
+                PersistedRule::new(
+                    ent.id,
+                    &ent.campaign,
+                    &ent.tenant,
+                    &ent.domain,
+                    &ent.reason,
+                    ent.expires,
+                    now,
+                )
+            })
+            .collect(),
+    }
+}
+
+fn write_rules(path: &Path, rules: &PersistedRules) -> anyhow::Result<()> {
+    let json = serde_json::to_vec_pretty(rules)?;
+    // Write to a temporary file and rename it into place, so that
+    // a crash cannot leave a partially written file behind
+    let temp_path = path.with_extension("tmp");
+    std::fs::write(&temp_path, json).with_context(|| format!("writing {}", temp_path.display()))?;
+    std::fs::rename(&temp_path, path)
+        .with_context(|| format!("renaming {} to {}", temp_path.display(), path.display()))?;
+    Ok(())
+}
+
+/// Saves the current set of rules. Called whenever a rule is
+/// added or removed.
+pub fn save_admin_rules() {
+    let path = RULES_PATH.lock().unwrap();
+    if let Some(path) = path.as_ref() {
+        if let Err(err) = write_rules(path, &collect_rules()) {
+            tracing::error!("failed to save admin rules: {err:#}");
+        }
+    }
+}
+
+/// Restores the rules that were saved in spool_dir, and arranges
+/// for subsequent changes to be saved there.
+/// Rules that expired while kumod was not running are discarded.
+pub fn load_admin_rules(spool_dir: &Path) -> anyhow::Result<()> {
+    let path = spool_dir.join(RULES_FILE_NAME);
+
+    let rules: PersistedRules = match std::fs::read(&path) {
+        Ok(data) => {
+            serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))?
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => PersistedRules::default(),
+        Err(err) => return Err(err).with_context(|| format!("reading {}", path.display())),
+    };
+
+    let now = now();
+    for rule in rules.bounces {
+        if let Some(expires) = rule.expires(now) {
+            tracing::info!("restoring admin bounce {}", rule.id);
+            AdminBounceEntry::add(AdminBounceEntry {
+                id: rule.id,
+                campaign: rule.campaign,
+                tenant: rule.tenant,
+                domain: rule.domain,
+                reason: rule.reason,
+                expires,
+                bounced: Arc::new(Mutex::new(HashMap::new())),
+            });
+        }
+    }
+    for rule in rules.suspensions {
+        if let Some(expires) = rule.expires(now) {
+            tracing::info!("restoring admin suspension {}", rule.id);
+            AdminSuspendEntry::add(AdminSuspendEntry {
+                id: rule.id,
+                campaign: rule.campaign,
+                tenant: rule.tenant,
+                domain: rule.domain,
+                reason: rule.reason,
+                expires,
+            });
+        }
+    }
+
+    RULES_PATH.lock().unwrap().replace(path);
+    // Rewrite the file, dropping any expired rules
+    save_admin_rules();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn expiry_round_trip() {
+        let now = now();
+        let rule = PersistedRule::new(
+            Uuid::new_v4(),
+            &None,
+            &None,
+            &Some("example.com".to_string()),
+            "testing",
+            now.0 + Duration::from_secs(300),
+            now,
+        );
+        assert_eq!(rule.expires, now.1 + chrono::Duration::seconds(300));
+        assert_eq!(rule.expires(now), Some(now.0 + Duration::from_secs(300)));
+
+        let later = (
+            now.0 + Duration::from_secs(600),
+            now.1 + chrono::Duration::seconds(600),
+        );
+        assert_eq!(rule.expires(later), None);
+
+        let rules = PersistedRules {
+            bounces: vec![rule],
+            suspensions: vec![],
+        };
+        let json = serde_json::to_string(&rules).unwrap();
+        let round_trip: PersistedRules = serde_json::from_str(&json).unwrap();
+        assert_eq!(round_trip, rules);
+    }
+}
@@ -0,0 +1,11 @@
+use crate::http_server::auth::TrustedIpRequired;
+use crate::http_server::AppError;
+use crate::shaping::list_overrides;
+use axum::extract::Json;
+use kumo_api_types::ShapingV1ListEntry;
+
+pub async fn shaping_v1_list(
+    _: TrustedIpRequired,
+) -> Result<Json<Vec<ShapingV1ListEntry>>, AppError> {
+    Ok(Json(list_overrides()))
+}
@@ -0,0 +1,203 @@
+use crate::http_server::admin_bounce_v1::match_criteria;
+use crate::http_server::admin_rules::save_admin_rules;
+use crate::http_server::auth::TrustedIpRequired;
+use crate::http_server::AppError;
+use crate::queue::QueueManager;
+use crate::ready_queue::ReadyQueueManager;
+use crate::runtime::rt_spawn;
+use axum::extract::Json;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use kumo_api_types::{
+    SuspendV1CancelRequest, SuspendV1ListEntry, SuspendV1Request, SuspendV1Response,
+};
+use message::message::QueueNameComponents;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+lazy_static::lazy_static! {
+    static ref ENTRIES: Mutex<Vec<AdminSuspendEntry>> = Mutex::new(vec![]);
+}
+
+#[derive(Clone, Debug)]
+pub struct AdminSuspendEntry {
+    pub id: Uuid,
+    pub campaign: Option<String>,
+    pub tenant: Option<String>,
+    pub domain: Option<String>,
+    pub reason: String,
+    pub expires: Instant,
+}
+
+impl AdminSuspendEntry {
+    pub fn get_all() -> Vec<Self> {
+        let mut entries = ENTRIES.lock().unwrap();
+        let now = Instant::now();
+        entries.retain(|ent| ent.expires > now);
+        entries.clone()
+    }
+
+    pub fn remove_by_id(id: &Uuid) -> Option<Self> {
+        let removed = {
+            let mut entries = ENTRIES.lock().unwrap();
+            let idx = entries.iter().position(|e| e.id == *id)?;
+            entries.remove(idx)
+        };
+        save_admin_rules();
+        Some(removed)
+    }
+
+    pub fn add(entry: Self) {
+        {
+            let mut entries = ENTRIES.lock().unwrap();
+            let now = Instant::now();
+            // Age out expired entries, and replace any entries with the
+            // same criteria; this allows updating the reason and duration
+            // of an existing suspension.
+            entries.retain(|ent| {
+                ent.expires > now
+                    && !(ent.campaign == entry.campaign
+                        && ent.tenant == entry.tenant
+                        && ent.domain == entry.domain)
+            });
+
+            entries.push(entry);
+        }
+        save_admin_rules();
+    }
+
+    pub fn matches(
+        &self,
+        campaign: Option<&str>,
+        tenant: Option<&str>,
+        domain: Option<&str>,
+    ) -> bool {
+        if !match_criteria(campaign, self.campaign.as_deref()) {
+            return false;
+        }
+        if !match_criteria(tenant, self.tenant.as_deref()) {
+            return false;
+        }
+        if !match_criteria(domain, self.domain.as_deref()) {
+            return false;
+        }
+        true
+    }
+
+    pub fn matches_queue_name(&self, queue_name: &str) -> bool {
+        let components = QueueNameComponents::parse(queue_name);
+        self.matches(
+            components.campaign,
+            components.tenant,
+            Some(components.domain),
+        )
+    }
+
+    /// Returns the suspension that applies to queue_name.
+    /// When several apply, the one that expires last is returned.
+    pub fn get_for_queue_name(queue_name: &str) -> Option<Self> {
+        Self::get_all()
+            .into_iter()
+            .filter(|ent| ent.matches_queue_name(queue_name))
+            .max_by_key(|ent| ent.expires)
+    }
+
+    /// How long until this suspension expires
+    pub fn get_duration(&self) -> Duration {
+        self.expires.saturating_duration_since(Instant::now())
+    }
+
+    pub async fn list_matching_queues(&self) -> Vec<String> {
+        let mut names = QueueManager::all_queue_names().await;
+        names.retain(|queue_name| self.matches_queue_name(queue_name));
+        names
+    }
+}
+
+pub async fn suspend_v1(
+    _: TrustedIpRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<SuspendV1Request>,
+) -> Result<Json<SuspendV1Response>, AppError> {
+    let duration = request.duration();
+    let entry = AdminSuspendEntry {
+        id: Uuid::new_v4(),
+        campaign: request.campaign,
+        tenant: request.tenant,
+        domain: request.domain,
+        reason: request.reason,
+        expires: Instant::now() + duration,
+    };
+
+    AdminSuspendEntry::add(entry.clone());
+
+    // Pull any matching messages that are already in the
+    // ready queues back into their scheduled queues
+    for queue in ReadyQueueManager::all_queues().await {
+        queue.lock().await.requeue_suspended().await;
+    }
+
+    Ok(Json(SuspendV1Response { id: entry.id }))
+}
+
+pub async fn suspend_v1_list(
+    _: TrustedIpRequired,
+) -> Result<Json<Vec<SuspendV1ListEntry>>, AppError> {
+    let now = Instant::now();
+    Ok(Json(
+        AdminSuspendEntry::get_all()
+            .into_iter()
+            .filter_map(|entry| {
+                entry
+                    .expires
+                    .checked_duration_since(now)
+                    .map(|duration| SuspendV1ListEntry {
+                        id: entry.id,
+                        campaign: entry.campaign,
+                        tenant: entry.tenant,
+                        domain: entry.domain,
+                        reason: entry.reason,
+                        duration,
+                    })
+            })
+            .collect(),
+    ))
+}
+
+pub async fn suspend_v1_delete(
+    _: TrustedIpRequired,
+    Json(request): Json<SuspendV1CancelRequest>,
+) -> Response {
+    match AdminSuspendEntry::remove_by_id(&request.id) {
+        Some(entry) => {
+            // Make the messages that were held by this entry
+            // eligible for delivery again.
+            // Bounce to the thread pool where we can run async lua,
+            // as re-inserting them may need to resolve their ready queue
+            if let Err(err) = rt_spawn("http suspend_v1_delete".to_string(), move || {
+                Ok(async move {
+                    for name in entry.list_matching_queues().await {
+                        if let Some(q) = QueueManager::get_opt(&name).await {
+                            q.lock().await.resume_suspended(&entry).await;
+                        }
+                    }
+                })
+            })
+            .await
+            {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("failed to resume {}: {err:#}", request.id),
+                )
+                    .into_response();
+            }
+            (StatusCode::OK, format!("removed {}", request.id))
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            format!("suspend entry {} not found", request.id),
+        ),
+    }
+    .into_response()
+}
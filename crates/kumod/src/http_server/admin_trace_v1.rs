@@ -0,0 +1,111 @@
+use crate::http_server::auth::TrustedIpRequired;
+use crate::http_server::AppError;
+use crate::logging::RecentDisposition;
+use crate::queue::QueueManager;
+use crate::ready_queue::ReadyQueueManager;
+use axum::extract::{Json, Path};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use kumo_api_types::{TraceV1Disposition, TraceV1Response};
+use message::Message;
+use spool::{get_meta_spool, SpoolId};
+
+/// Finds the in-memory copy of the message, returning it along
+/// with the name of the queue that holds it and its location
+async fn find_queued_message(id: &SpoolId) -> Option<(String, &'static str, Message)> {
+    for name in QueueManager::all_queue_names().await {
+        if let Some(queue) = QueueManager::get_opt(&name).await {
+            if let Some(msg) = queue.lock().await.get_message(id) {
+                return Some((name, "scheduled", msg));
+            }
+        }
+    }
+
+    for queue in ReadyQueueManager::all_queues().await {
+        let queue = queue.lock().await;
+        if let Some(msg) = queue.get_message(id) {
+            return Some((queue.name().to_string(), "ready", msg));
+        }
+    }
+
+    None
+}
+
+pub async fn trace_v1(_: TrustedIpRequired, Path(id): Path<String>) -> Result<Response, AppError> {
+    let id = match SpoolId::from_str(&id) {
+        Some(id) => id,
+        None => {
+            return Ok(
+                (StatusCode::BAD_REQUEST, format!("invalid message id {id}")).into_response(),
+            )
+        }
+    };
+
+    let last_disposition = RecentDisposition::get(&id).map(|disp| TraceV1Disposition {
+        kind: format!("{:?}", disp.kind),
+        site: disp.site,
+        response: disp.response.to_single_line(),
+        timestamp: disp.timestamp,
+    });
+
+    let mut response = TraceV1Response {
+        id: id.to_string(),
+        in_spool: false,
+        queue: None,
+        location: None,
+        due: None,
+        num_attempts: None,
+        sender: None,
+        recipients: vec![],
+        meta: None,
+        last_disposition,
+    };
+
+    // Only the metadata is loaded; the body is left in the spool
+    let msg = match find_queued_message(&id).await {
+        Some((queue, location, msg)) => {
+            response.queue.replace(queue);
+            response.location.replace(location.to_string());
+            response.due = msg.get_due();
+            response.num_attempts.replace(msg.get_num_attempts());
+
+            if msg.is_meta_loaded() {
+                fill_from_meta(&mut response, &msg);
+            } else if let Ok(data) = get_meta_spool().load(id).await {
+                // The queued message is shrunk; read a copy of its metadata
+                // from the spool rather than loading it into the queued message
+                fill_from_meta(&mut response, &Message::new_from_spool(id, data)?);
+            }
+            return Ok(Json(response).into_response());
+        }
+        None => match get_meta_spool().load(id).await {
+            // The message is in the spool but not in any queue;
+            // it is most likely in the middle of a delivery attempt
+            Ok(data) => Message::new_from_spool(id, data)?,
+            Err(_) if response.last_disposition.is_some() => {
+                return Ok(Json(response).into_response())
+            }
+            Err(_) => {
+                return Ok(
+                    (StatusCode::NOT_FOUND, format!("message {id} not found")).into_response()
+                )
+            }
+        },
+    };
+
+    response.queue = msg.get_queue_name().ok();
+    fill_from_meta(&mut response, &msg);
+    Ok(Json(response).into_response())
+}
+
+fn fill_from_meta(response: &mut TraceV1Response, msg: &Message) {
+    response.in_spool = true;
+    response.sender = msg.sender().ok().map(|sender| sender.to_string());
+    response.recipients = msg
+        .recipients()
+        .unwrap_or_default()
+        .iter()
+        .map(|recip| recip.to_string())
+        .collect();
+    response.meta = msg.get_meta_obj().ok();
+}
@@ -424,6 +424,7 @@ async fn process_recipient<'a>(
         log_disposition(LogDisposition {
             kind: RecordType::Reception,
             msg: message.clone(),
+            recipient: None,
             site: "",
             peer_address: Some(&ResolvedAddress {
                 name: "".to_string(),
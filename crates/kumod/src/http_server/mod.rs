@@ -16,6 +16,13 @@ use std::sync::Arc;
 pub mod auth;
 
 pub mod admin_bounce_v1;
+pub mod admin_queues_v1;
+pub mod admin_rebind_v1;
+pub mod admin_reload_v1;
+pub mod admin_rules;
+pub mod admin_shaping_v1;
+pub mod admin_suspend_v1;
+pub mod admin_trace_v1;
 pub mod inject_v1;
 
 use auth::*;
@@ -92,6 +99,27 @@ impl HttpListenerParams {
                 "/api/admin/bounce/v1",
                 delete(admin_bounce_v1::bounce_v1_delete),
             )
+            .route("/api/admin/queues/v1", get(admin_queues_v1::queues_v1_list))
+            .route(
+                "/api/admin/queues/v1/:name",
+                get(admin_queues_v1::queues_v1_sample),
+            )
+            .route("/api/admin/rebind/v1", post(admin_rebind_v1::rebind_v1))
+            .route("/api/admin/reload/v1", post(admin_reload_v1::reload_v1))
+            .route(
+                "/api/admin/shaping/v1",
+                get(admin_shaping_v1::shaping_v1_list),
+            )
+            .route("/api/admin/suspend/v1", post(admin_suspend_v1::suspend_v1))
+            .route(
+                "/api/admin/suspend/v1",
+                get(admin_suspend_v1::suspend_v1_list),
+            )
+            .route(
+                "/api/admin/suspend/v1",
+                delete(admin_suspend_v1::suspend_v1_delete),
+            )
+            .route("/api/admin/trace/v1/:id", get(admin_trace_v1::trace_v1))
             .route(
                 "/api/admin/set_diagnostic_log_filter/v1",
                 post(set_diagnostic_log_filter_v1),
@@ -2,6 +2,8 @@
 //! and to shut things down gracefully.
 //!
 //! See <https://tokio.rs/tokio/topics/shutdown> for more information.
+use crate::policy::reload_policy;
+use crate::runtime::rt_spawn;
 use once_cell::sync::OnceCell;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
@@ -47,6 +49,14 @@ impl Activity {
     pub fn is_shutting_down(&self) -> bool {
         SHUTTING_DOWN.load(Ordering::Relaxed)
     }
+
+    /// Obtain an Activity instance for a test that doesn't
+    /// initialize the process LifeCycle
+    #[cfg(test)]
+    pub fn for_testing() -> Self {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        Self { _tx: tx }
+    }
 }
 
 struct ShutdownState {
@@ -147,12 +157,28 @@ impl LifeCycle {
         let mut sig_hup =
             tokio::signal::unix::signal(SignalKind::hangup()).expect("listen for SIGUP");
 
-        tokio::select! {
-            _ = sig_term.recv() => {}
-            _ = sig_hup.recv() => {}
-            _ = tokio::signal::ctrl_c() => {}
-            _ = self.request_shutdown_rx.recv() => {}
-        };
+        loop {
+            tokio::select! {
+                _ = sig_term.recv() => {}
+                _ = sig_hup.recv() => {
+                    tracing::info!("SIGHUP received, reloading policy");
+                    // Errors are logged by reload_policy
+                    if let Err(err) = rt_spawn("reload policy".to_string(), || {
+                        Ok(async move {
+                            reload_policy().await.ok();
+                        })
+                    })
+                    .await
+                    {
+                        tracing::error!("failed to spawn policy reload: {err:#}");
+                    }
+                    continue;
+                }
+                _ = tokio::signal::ctrl_c() => {}
+                _ = self.request_shutdown_rx.recv() => {}
+            };
+            break;
+        }
         tracing::debug!("wait_for_shutdown: shutdown requested!");
         tracing::info!("Shutdown requested, please wait while work is saved");
         // Signal that we are stopping
@@ -0,0 +1,219 @@
+use crate::delivery_metrics::MetricsWrappedConnection;
+use crate::logging::{log_disposition, LogDisposition, RecordType};
+use crate::ready_queue::{Dispatcher, QueueDispatcher};
+use crate::runtime::{rt_spawn, spawn};
+use crate::spool::SpoolManager;
+use anyhow::Context;
+use async_trait::async_trait;
+use kumo_log_types::ResolvedAddress;
+use message::Message;
+use rfc5321::{ClientError, ForwardPath, ReversePath, SmtpClient};
+use std::net::Ipv4Addr;
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time::timeout;
+
+/// Delivers messages to a local store, such as Dovecot or Cyrus,
+/// using LMTP (RFC 2033). Unlike SMTP, the server returns a response
+/// to the message content for each of the accepted recipients.
+#[derive(Debug)]
+pub struct LmtpDispatcher {
+    /// Either host:port, or the path to a unix domain socket
+    address: String,
+    client: Option<MetricsWrappedConnection<SmtpClient>>,
+    lhlo_name: String,
+    peer_address: ResolvedAddress,
+}
+
+impl LmtpDispatcher {
+    pub fn new(address: &str, dispatcher: &Dispatcher) -> Self {
+        let lhlo_name = match &dispatcher.path_config.ehlo_domain {
+            Some(n) => n.to_string(),
+            None => gethostname::gethostname()
+                .to_str()
+                .unwrap_or("[127.0.0.1]")
+                .to_string(),
+        };
+        let peer_address = ResolvedAddress {
+            name: address.to_string(),
+            addr: Ipv4Addr::UNSPECIFIED.into(),
+        };
+
+        Self {
+            address: address.to_string(),
+            client: None,
+            lhlo_name,
+            peer_address,
+        }
+    }
+
+    /// Returns the path to the socket if the address refers
+    /// to a unix domain socket
+    fn unix_socket_path(&self) -> Option<&str> {
+        if let Some(path) = self.address.strip_prefix("unix:") {
+            Some(path)
+        } else if self.address.starts_with('/') {
+            Some(&self.address)
+        } else {
+            None
+        }
+    }
+
+    async fn connect(&mut self, dispatcher: &Dispatcher) -> anyhow::Result<SmtpClient> {
+        let timeouts = dispatcher.path_config.client_timeouts.clone();
+        let mut client = match self.unix_socket_path() {
+            Some(path) => {
+                let stream = UnixStream::connect(path).await?;
+                SmtpClient::with_stream(stream, "localhost", timeouts)
+            }
+            None => {
+                let stream = TcpStream::connect(&self.address).await?;
+                if let Ok(peer) = stream.peer_addr() {
+                    self.peer_address.addr = peer.ip();
+                }
+                SmtpClient::with_stream(stream, &self.address, timeouts)
+            }
+        };
+
+        let banner = client.read_response(None).await.context("reading banner")?;
+        if banner.code != 220 {
+            return Err(ClientError::Rejected(banner).into());
+        }
+
+        client.lhlo(&self.lhlo_name).await.context("LHLO")?;
+        Ok(client)
+    }
+}
+
+#[async_trait(?Send)]
+impl QueueDispatcher for LmtpDispatcher {
+    async fn close_connection(&mut self, _dispatcher: &mut Dispatcher) -> anyhow::Result<bool> {
+        if let Some(mut client) = self.client.take() {
+            client.send_command(&rfc5321::Command::Quit).await.ok();
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    async fn attempt_connection(&mut self, dispatcher: &mut Dispatcher) -> anyhow::Result<()> {
+        if self.client.is_some() {
+            return Ok(());
+        }
+
+        let connection_wrapper = dispatcher.metrics.wrap_connection(());
+        let connect_context = format!("connect to LMTP server {}", self.address);
+        let client = timeout(
+            dispatcher.path_config.client_timeouts.connect_timeout,
+            self.connect(dispatcher),
+        )
+        .await
+        .with_context(|| connect_context.clone())?
+        .with_context(|| connect_context.clone())?;
+
+        self.client
+            .replace(connection_wrapper.map_connection(client));
+        dispatcher.delivered_this_connection = 0;
+        Ok(())
+    }
+
+    async fn have_more_connection_candidates(&mut self, _dispatcher: &mut Dispatcher) -> bool {
+        false
+    }
+
+    async fn deliver_message(
+        &mut self,
+        msg: Message,
+        dispatcher: &mut Dispatcher,
+    ) -> anyhow::Result<()> {
+        msg.load_meta_if_needed().await?;
+        msg.load_data_if_needed().await?;
+
+        let data = msg.get_data();
+        let sender: ReversePath = msg
+            .sender()?
+            .try_into()
+            .map_err(|err| anyhow::anyhow!("{err}"))?;
+        let recipients = msg.recipients()?;
+        let mut forward_paths: Vec<ForwardPath> = vec![];
+        for recipient in &recipients {
+            forward_paths.push(
+                recipient
+                    .clone()
+                    .try_into()
+                    .map_err(|err| anyhow::anyhow!("{err}"))?,
+            );
+        }
+        let dsn = msg.get_dsn_parameters()?.unwrap_or_default();
+
+        let client = self.client.as_mut().unwrap();
+        dispatcher.delivered_this_connection += 1;
+        let result = client
+            .send_mail_multi_recip(sender, forward_paths, &data[..], &dsn)
+            .await;
+
+        match result {
+            Err(ClientError::Rejected(response)) => {
+                // The transaction failed as a whole
+                tracing::debug!(
+                    "failed to send message to {}: {response:?}",
+                    dispatcher.name
+                );
+                let transient = response.is_transient();
+                if transient {
+                    dispatcher.metrics.msgs_transfail.inc();
+                    dispatcher.metrics.global_msgs_transfail.inc();
+                } else {
+                    dispatcher.metrics.msgs_fail.inc();
+                    dispatcher.metrics.global_msgs_fail.inc();
+                }
+                if let Some(msg) = dispatcher.msg.take() {
+                    log_disposition(LogDisposition {
+                        kind: if transient {
+                            RecordType::TransientFailure
+                        } else {
+                            RecordType::Bounce
+                        },
+                        msg: msg.clone(),
+                        recipient: None,
+                        site: &dispatcher.name,
+                        peer_address: Some(&self.peer_address),
+                        response,
+                        egress_pool: Some(&dispatcher.egress_pool),
+                        egress_source: Some(&dispatcher.egress_source.name),
+                        relay_disposition: None,
+                        delivery_protocol: Some(&dispatcher.delivery_protocol),
+                    })
+                    .await;
+                    if transient {
+                        rt_spawn("requeue message".to_string(), move || {
+                            Ok(async move { Dispatcher::requeue_message(msg, true, None).await })
+                        })
+                        .await?;
+                    } else {
+                        spawn("remove from spool", async move {
+                            SpoolManager::remove_from_spool(*msg.id()).await
+                        })?;
+                    }
+                }
+            }
+            Err(err) => {
+                tracing::debug!("failed to send message to {}: {err:#}", dispatcher.name);
+                return Err(err.into());
+            }
+            Ok(result) => {
+                tracing::debug!("Delivery result {result:?}");
+                // Each recipient has its own response to the content
+                let responses = recipients
+                    .into_iter()
+                    .enumerate()
+                    .map(|(idx, recipient)| (recipient, result.recipient_response(idx).clone()))
+                    .collect();
+                dispatcher
+                    .log_recipient_responses(responses, Some(&self.peer_address))
+                    .await?;
+            }
+        };
+
+        Ok(())
+    }
+}
@@ -1,13 +1,15 @@
+use crate::non_delivery_report::generate_non_delivery_report;
 use crate::queue::QueueManager;
 use crate::runtime::rt_spawn_non_blocking;
 use crate::smtp_server::RelayDisposition;
 use anyhow::{anyhow, Context};
 use async_channel::{Receiver, Sender};
 use bounce_classify::{BounceClass, BounceClassifier, BounceClassifierBuilder};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use config::load_config;
 use kumo_log_types::rfc3464::ReportAction;
 pub use kumo_log_types::*;
+use lruttl::LruCacheWithTtl;
 use message::{EnvelopeAddress, Message};
 use minijinja::{Environment, Source, Template};
 use once_cell::sync::{Lazy, OnceCell};
@@ -28,6 +30,40 @@ use zstd::stream::write::Encoder;
 
 static LOGGER: Lazy<Mutex<Vec<Arc<Logger>>>> = Lazy::new(|| Mutex::new(vec![]));
 static CLASSIFY: OnceCell<BounceClassifier> = OnceCell::new();
+static RECENT_DISPOSITIONS: Lazy<LruCacheWithTtl<SpoolId, RecentDisposition>> =
+    Lazy::new(|| LruCacheWithTtl::new(RECENT_DISPOSITION_CAPACITY));
+
+/// How many messages to remember the most recent disposition of.
+/// On a busy server, entries are evicted to make room for newer
+/// messages long before RECENT_DISPOSITION_TTL has elapsed.
+const RECENT_DISPOSITION_CAPACITY: usize = 64 * 1024;
+
+/// The longest time to remember the most recent disposition of a message
+const RECENT_DISPOSITION_TTL: Duration = Duration::from_secs(7 * 86400);
+
+/// The most recent disposition that was logged for a message,
+/// as reported by the message trace API
+#[derive(Clone, Debug)]
+pub struct RecentDisposition {
+    pub kind: RecordType,
+    pub site: String,
+    pub response: Response,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl RecentDisposition {
+    pub fn get(id: &SpoolId) -> Option<Self> {
+        RECENT_DISPOSITIONS.get(id)
+    }
+}
+
+/// Classifies response using the configured bounce classifier,
+/// returning None if no classifier has been configured
+pub fn classify_response(response: &Response) -> Option<BounceClass> {
+    CLASSIFY
+        .get()
+        .map(|classifier| classifier.classify_response(response))
+}
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct ClassifierParams {
@@ -363,6 +399,9 @@ impl Logger {
 pub struct LogDisposition<'a> {
     pub kind: RecordType,
     pub msg: Message,
+    /// The recipient to which this disposition applies.
+    /// If None, a record is logged for each recipient of msg.
+    pub recipient: Option<&'a EnvelopeAddress>,
     pub site: &'a str,
     pub peer_address: Option<&'a ResolvedAddress>,
     pub response: Response,
@@ -376,6 +415,7 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
     let LogDisposition {
         mut kind,
         msg,
+        recipient,
         site,
         peer_address,
         response,
@@ -385,16 +425,47 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
         delivery_protocol,
     } = args;
 
+    msg.load_meta_if_needed().await.ok();
+
+    RECENT_DISPOSITIONS.insert(
+        *msg.id(),
+        RecentDisposition {
+            kind,
+            site: site.to_string(),
+            response: response.clone(),
+            timestamp: Utc::now(),
+        },
+        Instant::now() + RECENT_DISPOSITION_TTL,
+    );
+
+    let recipients = match recipient {
+        Some(recipient) => Ok(vec![recipient.clone()]),
+        None => msg.recipients(),
+    };
+
+    if let Ok(recipients) = &recipients {
+        // The recipients share this disposition, so they share a report
+        if let Err(err) =
+            generate_non_delivery_report(kind, &msg, recipients, &response, peer_address).await
+        {
+            tracing::error!("failed to generate non-delivery report: {err:#}");
+        }
+    }
+
     let loggers = Logger::get_loggers();
     if loggers.is_empty() {
         return;
     }
 
-    let mut feedback_report = None;
+    let recipients: Vec<String> = match recipients {
+        Ok(recipients) => recipients.iter().map(|addr| addr.to_string()).collect(),
+        Err(err) => vec![format!("{err:#}")],
+    };
 
-    msg.load_meta_if_needed().await.ok();
+    let mut feedback_report = None;
 
     let reception_protocol = msg.get_meta_string("reception_protocol").unwrap_or(None);
+    let dsn = msg.get_dsn_parameters().unwrap_or(None);
 
     if kind == RecordType::Reception {
         if let Some(RelayDisposition { log_arf: true, .. }) = relay_disposition {
@@ -414,38 +485,38 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
 
         let (headers, meta) = logger.extract_fields(&msg).await;
 
-        let record = JsonLogRecord {
-            kind,
-            id: msg.id().to_string(),
-            size: msg.get_data().len() as u64,
-            sender: msg
-                .sender()
-                .map(|addr| addr.to_string())
-                .unwrap_or_else(|err| format!("{err:#}")),
-            recipient: msg
-                .recipient()
-                .map(|addr| addr.to_string())
-                .unwrap_or_else(|err| format!("{err:#}")),
-            queue: msg
-                .get_queue_name()
-                .unwrap_or_else(|err| format!("{err:#}")),
-            site: site.to_string(),
-            peer_address: peer_address.cloned(),
-            response: response.clone(),
-            timestamp: now,
-            created: msg.id().created(),
-            num_attempts: msg.get_num_attempts(),
-            egress_pool: egress_pool.map(|s| s.to_string()),
-            egress_source: egress_source.map(|s| s.to_string()),
-            bounce_classification: BounceClass::Uncategorized,
-            feedback_report: feedback_report.clone(),
-            headers,
-            meta,
-            delivery_protocol: delivery_protocol.map(|s| s.to_string()),
-            reception_protocol: reception_protocol.clone(),
-        };
-        if let Err(err) = logger.log(record).await {
-            tracing::error!("failed to log: {err:#}");
+        for recipient in &recipients {
+            let record = JsonLogRecord {
+                kind,
+                id: msg.id().to_string(),
+                size: msg.get_data().len() as u64,
+                sender: msg
+                    .sender()
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_else(|err| format!("{err:#}")),
+                recipient: recipient.to_string(),
+                queue: msg
+                    .get_queue_name()
+                    .unwrap_or_else(|err| format!("{err:#}")),
+                site: site.to_string(),
+                peer_address: peer_address.cloned(),
+                response: response.clone(),
+                timestamp: now,
+                created: msg.id().created(),
+                num_attempts: msg.get_num_attempts(),
+                egress_pool: egress_pool.map(|s| s.to_string()),
+                egress_source: egress_source.map(|s| s.to_string()),
+                bounce_classification: BounceClass::Uncategorized,
+                feedback_report: feedback_report.clone(),
+                headers: headers.clone(),
+                meta: meta.clone(),
+                delivery_protocol: delivery_protocol.map(|s| s.to_string()),
+                reception_protocol: reception_protocol.clone(),
+                dsn: dsn.clone(),
+            };
+            if let Err(err) = logger.log(record).await {
+                tracing::error!("failed to log: {err:#}");
+            }
         }
 
         if kind == RecordType::Reception {
@@ -523,6 +594,7 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
                             meta: HashMap::new(),
                             delivery_protocol: None,
                             reception_protocol: reception_protocol.clone(),
+                            dsn: None,
                         };
 
                         if let Err(err) = logger.log(record).await {
@@ -118,6 +118,7 @@ impl QueueDispatcher for LuaQueueDispatcher {
                             log_disposition(LogDisposition {
                                 kind: RecordType::TransientFailure,
                                 msg: msg.clone(),
+                                recipient: None,
                                 site: &dispatcher.name,
                                 peer_address: Some(&self.peer_address),
                                 response,
@@ -145,6 +146,7 @@ impl QueueDispatcher for LuaQueueDispatcher {
                             log_disposition(LogDisposition {
                                 kind: RecordType::Bounce,
                                 msg: msg.clone(),
+                                recipient: None,
                                 site: &dispatcher.name,
                                 peer_address: Some(&self.peer_address),
                                 response,
@@ -178,6 +180,7 @@ impl QueueDispatcher for LuaQueueDispatcher {
                     log_disposition(LogDisposition {
                         kind: RecordType::Delivery,
                         msg: msg.clone(),
+                        recipient: None,
                         site: &dispatcher.name,
                         peer_address: Some(&self.peer_address),
                         response,
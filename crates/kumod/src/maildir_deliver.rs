@@ -0,0 +1,394 @@
+//! Delivery of messages into local maildirs
+use crate::logging::{log_disposition, LogDisposition, RecordType};
+use crate::runtime::{spawn, spawn_blocking};
+use crate::spool::SpoolManager;
+use anyhow::Context;
+use message::{EnvelopeAddress, Message};
+use minijinja::Environment;
+use rfc5321::{EnhancedStatusCode, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MaildirConfig {
+    /// A minijinja template that produces the path to the maildir.
+    /// It can reference `user`, `domain` and `meta`.
+    pub maildir_path: String,
+
+    /// The permissions to use when creating directories
+    #[serde(default)]
+    pub dir_mode: Option<u32>,
+
+    /// The permissions to apply to the stored messages
+    #[serde(default)]
+    pub file_mode: Option<u32>,
+
+    /// Deliver user+folder@domain into the Maildir++ folder
+    /// `.folder` of the maildir for user@domain
+    #[serde(default)]
+    pub subaddress_folders: bool,
+
+    /// The maximum size of a mailbox, in bytes
+    #[serde(default)]
+    pub max_mailbox_size: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct PathContext<'a> {
+    user: &'a str,
+    domain: &'a str,
+    meta: &'a serde_json::Value,
+}
+
+/// Where a message is to be stored for a recipient
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct MaildirTarget {
+    /// The maildir of the recipient
+    mailbox: PathBuf,
+    /// The Maildir++ folder within mailbox, if any
+    folder: Option<String>,
+}
+
+impl MaildirTarget {
+    fn path(&self) -> PathBuf {
+        match &self.folder {
+            Some(folder) => self.mailbox.join(format!(".{folder}")),
+            None => self.mailbox.clone(),
+        }
+    }
+}
+
+/// Why a message could not be stored for a recipient
+#[derive(Debug, Clone)]
+enum StoreFailure {
+    Permanent(Response),
+    Transient(Response),
+}
+
+fn response(code: u16, subject: u16, detail: u16, content: String) -> Response {
+    Response {
+        code,
+        enhanced_code: Some(EnhancedStatusCode {
+            class: (code / 100) as u8,
+            subject,
+            detail,
+        }),
+        content,
+        command: None,
+    }
+}
+
+/// Splits user+folder into user and folder
+fn split_subaddress(user: &str) -> (&str, Option<&str>) {
+    match user.split_once('+') {
+        Some((user, folder)) if !folder.is_empty() => (user, Some(folder)),
+        Some((user, _)) => (user, None),
+        None => (user, None),
+    }
+}
+
+/// Returns true if name can safely be used as a single component of a path
+fn is_safe_component(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\0'])
+}
+
+impl MaildirConfig {
+    /// Returns the maildir (and Maildir++ folder) into
+    /// which the message should be stored for recipient
+    fn resolve_target(
+        &self,
+        env: &Environment,
+        recipient: &EnvelopeAddress,
+        meta: &serde_json::Value,
+    ) -> Result<MaildirTarget, StoreFailure> {
+        let (user, folder) = if self.subaddress_folders {
+            split_subaddress(recipient.user())
+        } else {
+            (recipient.user(), None)
+        };
+        let domain = recipient.domain();
+
+        for component in [Some(user), Some(domain), folder].into_iter().flatten() {
+            if !is_safe_component(component) {
+                return Err(StoreFailure::Permanent(response(
+                    550,
+                    1,
+                    3,
+                    format!("{} cannot be mapped to a maildir", recipient.to_string()),
+                )));
+            }
+        }
+
+        let path = env
+            .get_template("maildir_path")
+            .and_then(|template| template.render(PathContext { user, domain, meta }))
+            .map_err(|err| {
+                StoreFailure::Transient(response(
+                    451,
+                    3,
+                    0,
+                    format!("failed to expand maildir_path template: {err:#}"),
+                ))
+            })?;
+
+        Ok(MaildirTarget {
+            mailbox: PathBuf::from(path),
+            folder: folder.map(|folder| folder.to_string()),
+        })
+    }
+
+    fn create_dirs(&self, target: &MaildirTarget) -> anyhow::Result<()> {
+        let path = target.path();
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        if let Some(mode) = self.dir_mode {
+            builder.mode(mode);
+        }
+        for sub in ["cur", "new", "tmp"] {
+            let dir = path.join(sub);
+            builder
+                .create(&dir)
+                .with_context(|| format!("creating {}", dir.display()))?;
+        }
+
+        // Maildir++ folders are marked with a maildirfolder file
+        if target.folder.is_some() {
+            let marker = path.join("maildirfolder");
+            if !marker.exists() {
+                std::fs::write(&marker, b"")
+                    .with_context(|| format!("creating {}", marker.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_quota(&self, target: &MaildirTarget, size: u64) -> Result<(), StoreFailure> {
+        let max_size = match self.max_mailbox_size {
+            Some(max_size) => max_size,
+            None => return Ok(()),
+        };
+        if size > max_size {
+            return Err(StoreFailure::Permanent(response(
+                552,
+                2,
+                2,
+                format!("message size {size} exceeds the mailbox quota of {max_size}"),
+            )));
+        }
+
+        // The quota applies to the whole mailbox, including its folders
+        let used = mailbox_size(&target.mailbox);
+        if used + size > max_size {
+            return Err(StoreFailure::Transient(response(
+                452,
+                2,
+                2,
+                format!("mailbox is full: {used} of {max_size} bytes used"),
+            )));
+        }
+        Ok(())
+    }
+
+    fn store(&self, target: &MaildirTarget, data: &[u8]) -> Result<String, StoreFailure> {
+        self.check_quota(target, data.len() as u64)?;
+
+        let transient = |err: anyhow::Error| {
+            StoreFailure::Transient(response(
+                400,
+                0,
+                0,
+                format!("failed to write to maildir: {err:#}"),
+            ))
+        };
+
+        self.create_dirs(target).map_err(transient)?;
+        let path = target.path();
+        let md = maildir::Maildir::from(path.clone());
+        let id = md
+            .store_new(data)
+            .map_err(|err| transient(anyhow::anyhow!("{err:#}")))?;
+
+        if let Some(mode) = self.file_mode {
+            let file = path.join("new").join(&id);
+            std::fs::set_permissions(&file, std::fs::Permissions::from_mode(mode))
+                .with_context(|| format!("setting permissions of {}", file.display()))
+                .map_err(transient)?;
+        }
+        Ok(id)
+    }
+}
+
+/// Returns the total size of the messages in the maildir at path,
+/// and in its Maildir++ folders
+fn mailbox_size(path: &Path) -> u64 {
+    fn dir_size(path: &Path) -> u64 {
+        std::fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok()?.metadata().ok())
+                    .filter(|meta| meta.is_file())
+                    .map(|meta| meta.len())
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+    fn maildir_size(path: &Path) -> u64 {
+        dir_size(&path.join("cur")) + dir_size(&path.join("new"))
+    }
+
+    let mut size = maildir_size(path);
+    if let Ok(entries) = std::fs::read_dir(path) {
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let is_folder = name
+                .to_str()
+                .map(|name| name.starts_with('.') && name != "." && name != "..")
+                .unwrap_or(false);
+            if is_folder && entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                size += maildir_size(&entry.path());
+            }
+        }
+    }
+    size
+}
+
+/// Stores msg into the maildir of each of its recipients, logging the
+/// outcome for each of them. If any recipients failed transiently,
+/// the message is updated to hold just those recipients and an error
+/// is returned so that the message will be retried.
+pub async fn deliver_to_maildir(
+    queue_name: &str,
+    msg: &Message,
+    config: &MaildirConfig,
+) -> anyhow::Result<()> {
+    msg.load_meta_if_needed().await?;
+    msg.load_data_if_needed().await?;
+
+    let recipients = msg.recipients()?;
+    let meta = msg.get_meta_obj()?;
+
+    let results: Vec<(EnvelopeAddress, Result<String, StoreFailure>)> =
+        spawn_blocking("write to maildir", {
+            let msg = msg.clone();
+            let config = config.clone();
+            let name = queue_name.to_string();
+            move || {
+                let mut env = Environment::new();
+                if let Err(err) = env.add_template("maildir_path", &config.maildir_path) {
+                    tracing::error!("invalid maildir_path template in queue {name}: {err:#}");
+                }
+
+                // Recipients that map to the same maildir share a single copy
+                let mut by_target: BTreeMap<MaildirTarget, Vec<EnvelopeAddress>> = BTreeMap::new();
+                let mut results = vec![];
+                for recipient in recipients {
+                    match config.resolve_target(&env, &recipient, &meta) {
+                        Ok(target) => by_target.entry(target).or_default().push(recipient),
+                        Err(failure) => results.push((recipient, Err(failure))),
+                    }
+                }
+
+                let data = msg.get_data();
+                for (target, recipients) in by_target {
+                    let path = target.path();
+                    tracing::trace!("Deliver msg {} to maildir at {}", msg.id(), path.display());
+                    let result = config
+                        .store(&target, &data[..])
+                        .map(|id| format!("wrote to maildir {} with id={id}", path.display()));
+                    for recipient in recipients {
+                        results.push((recipient, result.clone()));
+                    }
+                }
+                results
+            }
+        })?
+        .await?;
+
+    let mut retry = vec![];
+    for (recipient, result) in results {
+        let (kind, response) = match result {
+            Ok(content) => (
+                RecordType::Delivery,
+                Response {
+                    code: 200,
+                    enhanced_code: None,
+                    content,
+                    command: None,
+                },
+            ),
+            Err(StoreFailure::Permanent(response)) => (RecordType::Bounce, response),
+            Err(StoreFailure::Transient(response)) => (RecordType::TransientFailure, response),
+        };
+        log_disposition(LogDisposition {
+            kind,
+            msg: msg.clone(),
+            recipient: Some(&recipient),
+            site: "",
+            peer_address: None,
+            response,
+            egress_pool: None,
+            egress_source: None,
+            relay_disposition: None,
+            delivery_protocol: Some("Maildir"),
+        })
+        .await;
+        if kind == RecordType::TransientFailure {
+            retry.push(recipient);
+        }
+    }
+
+    if retry.is_empty() {
+        let id = *msg.id();
+        spawn("remove from spool", async move {
+            SpoolManager::remove_from_spool(id).await
+        })?;
+        Ok(())
+    } else {
+        let count = retry.len();
+        msg.set_recipients(retry)?;
+        anyhow::bail!("failed maildir store for {count} recipient(s) in queue {queue_name}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subaddress() {
+        assert_eq!(split_subaddress("user"), ("user", None));
+        assert_eq!(split_subaddress("user+lists"), ("user", Some("lists")));
+        assert_eq!(split_subaddress("user+"), ("user", None));
+    }
+
+    #[test]
+    fn resolve_target() {
+        let config = MaildirConfig {
+            maildir_path: "/var/mail/{{ domain }}/{{ user }}".to_string(),
+            dir_mode: None,
+            file_mode: None,
+            subaddress_folders: true,
+            max_mailbox_size: None,
+        };
+        let mut env = Environment::new();
+        env.add_template("maildir_path", &config.maildir_path)
+            .unwrap();
+        let meta = serde_json::json!({});
+
+        let recip = EnvelopeAddress::parse("fred+lists@example.com").unwrap();
+        let target = config.resolve_target(&env, &recip, &meta).unwrap();
+        assert_eq!(target.mailbox, PathBuf::from("/var/mail/example.com/fred"));
+        assert_eq!(
+            target.path(),
+            PathBuf::from("/var/mail/example.com/fred/.lists")
+        );
+
+        let recip = EnvelopeAddress::parse("fred+a/../b@example.com").unwrap();
+        assert!(matches!(
+            config.resolve_target(&env, &recip, &meta),
+            Err(StoreFailure::Permanent(_))
+        ));
+    }
+}
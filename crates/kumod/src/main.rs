@@ -39,20 +39,30 @@ static GLOBAL: Jemalloc = Jemalloc;
 mod delivery_metrics;
 mod egress_path;
 mod egress_source;
+mod http_deliver;
 mod http_server;
 mod lifecycle;
+mod lmtp_dispatcher;
 mod logging;
 mod lua_deliver;
+mod maildir_deliver;
 mod memory;
 mod metrics_helper;
 mod mod_kumo;
+mod mta_sts;
+mod non_delivery_report;
+mod policy;
 mod queue;
 mod ready_queue;
 mod runtime;
+mod shaping;
 mod smtp_dispatcher;
 mod smtp_server;
+mod source_health;
 mod spool;
 mod tls_helpers;
+mod tls_report;
+mod validate;
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
 #[clap(rename_all = "kebab_case")]
@@ -97,6 +107,19 @@ struct Opt {
     /// start as root and set `--user root` to make it explicit.
     #[arg(long)]
     user: Option<String>,
+
+    /// Check the policy and then exit, rather than starting up.
+    ///
+    /// The init event is run without starting any listeners or spools,
+    /// and the configuration events are called with sample arguments.
+    /// The exit status is non-zero if any problems are found.
+    #[arg(long)]
+    validate: bool,
+
+    /// The domain to use when calling the configuration events
+    /// in --validate mode. May be specified multiple times.
+    #[arg(long, default_value = "example.com")]
+    validate_domain: Vec<String>,
 }
 
 impl Opt {
@@ -154,6 +177,9 @@ fn main() -> anyhow::Result<()> {
     // This MUST happen before we spawn any threads,
     // which is why we manually set up the tokio
     // runtime after we've called it.
+    // This also applies to validation, so that the policy
+    // is loaded with the same privileges as it would be
+    // when serving.
     opts.drop_privs()?;
 
     let (_no_file_soft, no_file_hard) = getrlimit(Resource::RLIMIT_NOFILE)?;
@@ -248,6 +274,17 @@ async fn run(opts: Opt) -> anyhow::Result<()> {
         config::register(func);
     }
 
+    if opts.validate {
+        let validate_handle =
+            rt_spawn("validate".to_string(), move || {
+                Ok(async move {
+                    crate::validate::validate_policy(opts.policy, opts.validate_domain).await
+                })
+            })
+            .await?;
+        return validate_handle.await?;
+    }
+
     config::set_policy_path(opts.policy.clone()).await?;
 
     let mut life_cycle = LifeCycle::new();
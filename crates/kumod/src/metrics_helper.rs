@@ -31,6 +31,12 @@ lazy_static::lazy_static! {
             "total number of message delivery attempts that permanently failed",
             &["service"]).unwrap()
     };
+    pub static ref TOTAL_TLS_POLICY_FAILURES: IntCounterVec = {
+        prometheus::register_int_counter_vec!(
+            "total_tls_policy_failures",
+            "total number of delivery attempts that failed to satisfy the MTA-STS or DANE policy",
+            &["service"]).unwrap()
+    };
     pub static ref READY_COUNT_GAUGE: IntGaugeVec = {
         prometheus::register_int_gauge_vec!(
             "ready_count",
@@ -71,6 +77,12 @@ pub fn total_msgs_fail_for_service(service: &str) -> IntCounter {
         .unwrap()
 }
 
+pub fn total_tls_policy_failures_for_service(service: &str) -> IntCounter {
+    TOTAL_TLS_POLICY_FAILURES
+        .get_metric_with_label_values(&[service])
+        .unwrap()
+}
+
 /// Remove metrics that are parameterized by a service name of
 /// some kind.
 ///
@@ -91,4 +103,7 @@ pub fn remove_metrics_for_service(service: &str) {
     TOTAL_MSGS_DELIVERED.remove_label_values(&[service]).ok();
     TOTAL_MSGS_TRANSFAIL.remove_label_values(&[service]).ok();
     TOTAL_MSGS_FAIL.remove_label_values(&[service]).ok();
+    TOTAL_TLS_POLICY_FAILURES
+        .remove_label_values(&[service])
+        .ok();
 }
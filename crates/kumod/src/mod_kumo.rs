@@ -3,9 +3,13 @@ use crate::egress_source::{EgressPool, EgressSource};
 use crate::http_server::HttpListenerParams;
 use crate::lifecycle::LifeCycle;
 use crate::logging::{ClassifierParams, LogFileParams, LogHookParams};
+use crate::mta_sts::MtaStsParams;
+use crate::non_delivery_report::NonDeliveryReportParams;
 use crate::queue::QueueConfig;
 use crate::runtime::spawn;
 use crate::smtp_server::{EsmtpDomain, EsmtpListenerParams, RejectError};
+use crate::tls_report::TlsReportingParams;
+use crate::validate::{is_validating, record_action};
 use anyhow::Context;
 use config::{any_err, from_lua_value, get_or_create_module};
 use mlua::{Function, Lua, LuaSerdeExt, Value};
@@ -43,7 +47,7 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
             }
 
             lua.set_named_registry_value(&decorated_name, func)?;
-            Ok(())
+            config::record_event_handler(lua, &name)
         })?,
     )?;
 
@@ -66,6 +70,10 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         "configure_local_logs",
         lua.create_function(move |lua, params: Value| {
             let params: LogFileParams = from_lua_value(lua, params)?;
+            if is_validating() {
+                record_action("configure_local_logs", params.log_dir.display().to_string());
+                return Ok(());
+            }
             crate::logging::Logger::init(params).map_err(any_err)
         })?,
     )?;
@@ -74,14 +82,54 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         "configure_log_hook",
         lua.create_function(move |lua, params: Value| {
             let params: LogHookParams = from_lua_value(lua, params)?;
+            if is_validating() {
+                record_action("configure_log_hook", String::new());
+                return Ok(());
+            }
             crate::logging::Logger::init_hook(params).map_err(any_err)
         })?,
     )?;
 
+    kumo_mod.set(
+        "configure_non_delivery_reports",
+        lua.create_function(move |lua, params: Value| {
+            let params: NonDeliveryReportParams = from_lua_value(lua, params)?;
+            params.register().map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_mta_sts",
+        lua.create_function(move |lua, params: Value| {
+            let params: MtaStsParams = from_lua_value(lua, params)?;
+            if is_validating() {
+                record_action("configure_mta_sts", String::new());
+                return Ok(());
+            }
+            params.register().map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_tls_reporting",
+        lua.create_function(move |lua, params: Value| {
+            let params: TlsReportingParams = from_lua_value(lua, params)?;
+            if is_validating() {
+                record_action("configure_tls_reporting", String::new());
+                return Ok(());
+            }
+            params.register().map_err(any_err)
+        })?,
+    )?;
+
     kumo_mod.set(
         "start_http_listener",
         lua.create_async_function(|lua, params: Value| async move {
             let params: HttpListenerParams = from_lua_value(lua, params)?;
+            if is_validating() {
+                record_action("start_http_listener", params.listen);
+                return Ok(());
+            }
             params.start().await.map_err(any_err)?;
             Ok(())
         })?,
@@ -91,6 +139,10 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         "start_esmtp_listener",
         lua.create_async_function(|lua, params: Value| async move {
             let params: EsmtpListenerParams = from_lua_value(lua, params)?;
+            if is_validating() {
+                record_action("start_esmtp_listener", params.listen);
+                return Ok(());
+            }
             spawn("start_esmtp_listener", async move {
                 if let Err(err) = params.run().await {
                     tracing::error!("Error in SmtpServer: {err:#}");
@@ -104,7 +156,14 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
     kumo_mod.set(
         "define_spool",
         lua.create_async_function(|lua, params: Value| async move {
-            let params = from_lua_value(lua, params)?;
+            let params: DefineSpoolParams = from_lua_value(lua, params)?;
+            if is_validating() {
+                record_action(
+                    "define_spool",
+                    format!("{} {}", params.name, params.path.display()),
+                );
+                return Ok(());
+            }
             spawn("define_spool", async move {
                 if let Err(err) = define_spool(params).await {
                     tracing::error!("Error in spool: {err:#}");
@@ -121,6 +180,10 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         "configure_redis_throttles",
         lua.create_async_function(|lua, params: Value| async move {
             let key: RedisConnKey = from_lua_value(lua, params)?;
+            if is_validating() {
+                record_action("configure_redis_throttles", String::new());
+                return Ok(());
+            }
             let conn = key.open().await.map_err(any_err)?;
             throttle::use_redis(conn).map_err(any_err)
         })?,
@@ -0,0 +1,202 @@
+//! Discovery of MTA-STS policies for outbound delivery.
+//! By default this uses the cached DNS resolver and an HTTPS client,
+//! which can be adjusted via `kumo.configure_mta_sts`.
+use anyhow::Context;
+use dns_resolver::SenderAuthLookup;
+use kumo_sender_auth::{Lookup, LookupError};
+use mta_sts::{FetchError, FetchFuture, Fetcher, Policy, PolicyCache};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Policies larger than this are rejected; RFC 8461 section 3.3
+const MAX_POLICY_SIZE: usize = 64 * 1024;
+
+lazy_static::lazy_static! {
+    static ref POLICY_CACHE: PolicyCache = PolicyCache::new(64 * 1024);
+    static ref FETCHER: RwLock<Arc<dyn Fetcher>> = RwLock::new(Arc::new(
+        HttpsFetcher::new(&MtaStsParams::default())
+            .expect("failed to build MTA-STS http client")
+    ));
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct MtaStsParams {
+    /// How long to wait for a policy to be fetched
+    #[serde(
+        default = "MtaStsParams::default_fetch_timeout",
+        with = "humantime_serde"
+    )]
+    pub fetch_timeout: Duration,
+
+    /// Connect to these addresses rather than resolving
+    /// the named policy hosts via DNS
+    #[serde(default)]
+    pub resolve: BTreeMap<String, SocketAddr>,
+
+    /// A PEM file holding additional CA certificates to
+    /// trust when verifying the certificates of policy hosts
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+}
+
+impl Default for MtaStsParams {
+    fn default() -> Self {
+        Self {
+            fetch_timeout: Self::default_fetch_timeout(),
+            resolve: BTreeMap::new(),
+            ca_file: None,
+        }
+    }
+}
+
+impl MtaStsParams {
+    fn default_fetch_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    /// Replaces the fetcher used to discover MTA-STS policies
+    pub fn register(self) -> anyhow::Result<()> {
+        set_fetcher(Arc::new(HttpsFetcher::new(&self)?));
+        Ok(())
+    }
+}
+
+struct HttpsFetcher {
+    client: reqwest::Client,
+}
+
+impl HttpsFetcher {
+    fn new(params: &MtaStsParams) -> anyhow::Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(params.fetch_timeout);
+        for (host, addr) in &params.resolve {
+            builder = builder.resolve(host, *addr);
+        }
+        if let Some(ca_file) = &params.ca_file {
+            let pem =
+                std::fs::read(ca_file).with_context(|| format!("reading {}", ca_file.display()))?;
+            // Each of the certificates in the file is trusted
+            let certs = reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("parsing {}", ca_file.display()))?;
+            builder = builder.add_root_certificate(certs);
+        }
+        let client = builder
+            .build()
+            .context("failed to build MTA-STS http client")?;
+        Ok(Self { client })
+    }
+}
+
+impl Fetcher for HttpsFetcher {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> FetchFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            SenderAuthLookup
+                .lookup_txt(name)
+                .await
+                .map_err(|err| match err {
+                    LookupError::NotFound => FetchError::NotFound,
+                    LookupError::Temporary(err) => FetchError::Failed(err),
+                })
+        })
+    }
+
+    fn http_get<'a>(&'a self, url: &'a str) -> FetchFuture<'a, String> {
+        Box::pin(async move {
+            let failed = |err: reqwest::Error| FetchError::Failed(format!("{err:#}"));
+            let mut response = self.client.get(url).send().await.map_err(failed)?;
+            if response.status() != reqwest::StatusCode::OK {
+                return Err(FetchError::Failed(format!(
+                    "HTTP status {}",
+                    response.status()
+                )));
+            }
+
+            let mut body = vec![];
+            while let Some(chunk) = response.chunk().await.map_err(failed)? {
+                if body.len() + chunk.len() > MAX_POLICY_SIZE {
+                    return Err(FetchError::Failed(format!(
+                        "policy is larger than {MAX_POLICY_SIZE} bytes"
+                    )));
+                }
+                body.extend_from_slice(&chunk);
+            }
+
+            String::from_utf8(body)
+                .map_err(|err| FetchError::Failed(format!("policy is not UTF-8: {err}")))
+        })
+    }
+}
+
+/// Replaces the fetcher used to discover MTA-STS policies.
+/// Policies that have already been cached remain in use
+/// until they expire.
+pub fn set_fetcher(fetcher: Arc<dyn Fetcher>) {
+    *FETCHER.write().unwrap() = fetcher;
+}
+
+/// Returns the MTA-STS policy for domain, if it publishes one
+pub async fn get_policy(domain: &str) -> Result<Option<Arc<Policy>>, String> {
+    let fetcher = FETCHER.read().unwrap().clone();
+    POLICY_CACHE.get_policy(&*fetcher, domain).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mta_sts::Mode;
+
+    struct StaticFetcher;
+
+    impl Fetcher for StaticFetcher {
+        fn lookup_txt<'a>(&'a self, name: &'a str) -> FetchFuture<'a, Vec<String>> {
+            let result = match name {
+                "_mta-sts.injected.example.com" => Ok(vec!["v=STSv1; id=1".to_string()]),
+                _ => Err(FetchError::NotFound),
+            };
+            Box::pin(async move { result })
+        }
+
+        fn http_get<'a>(&'a self, url: &'a str) -> FetchFuture<'a, String> {
+            let result = match url {
+                "https://mta-sts.injected.example.com/.well-known/mta-sts.txt" => Ok(
+                    "version: STSv1\nmode: testing\nmx: mx.example.com\nmax_age: 3600\n"
+                        .to_string(),
+                ),
+                _ => Err(FetchError::Failed("404".to_string())),
+            };
+            Box::pin(async move { result })
+        }
+    }
+
+    #[tokio::test]
+    async fn injected_fetcher() {
+        let prior = FETCHER.read().unwrap().clone();
+        set_fetcher(Arc::new(StaticFetcher));
+        let policy = get_policy("injected.example.com").await;
+        let other = get_policy("other.example.com").await;
+        set_fetcher(prior);
+
+        assert_eq!(policy.unwrap().unwrap().mode, Mode::Testing);
+        assert_eq!(other, Ok(None));
+    }
+
+    #[test]
+    fn params() {
+        let params: MtaStsParams = serde_json::from_value(serde_json::json!({
+            "fetch_timeout": "10s",
+            "resolve": {"mta-sts.example.com": "127.0.0.1:8443"},
+        }))
+        .unwrap();
+        assert_eq!(params.fetch_timeout, Duration::from_secs(10));
+        assert_eq!(
+            params.resolve["mta-sts.example.com"],
+            "127.0.0.1:8443".parse().unwrap()
+        );
+        HttpsFetcher::new(&params).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+//! Generates RFC 3464 non-delivery reports for messages that
+//! permanently fail or expire, addressed to the envelope sender
+use crate::logging::{RecordType, ResolvedAddress};
+use crate::queue::QueueManager;
+use crate::runtime::rt_spawn_non_blocking;
+use chrono::Utc;
+use config::load_config;
+use kumo_log_types::rfc3464::{
+    DiagnosticCode, PerMessageReportEntry, PerRecipientReportEntry, Recipient, RemoteMta, Report,
+    ReportAction, ReportMessageParams, ReportStatus,
+};
+use message::{EnvelopeAddress, Message};
+use once_cell::sync::OnceCell;
+use rfc5321::{DsnNotify, DsnRet, Response};
+use serde::Deserialize;
+use serde_json::json;
+use spool::SpoolId;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+static GENERATOR: OnceCell<NonDeliveryReportParams> = OnceCell::new();
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct NonDeliveryReportParams {
+    /// The name used in the Reporting-MTA field and in the default
+    /// From header
+    #[serde(default = "NonDeliveryReportParams::default_reporting_mta")]
+    pub reporting_mta: String,
+
+    /// The From header of the report.
+    /// Defaults to `MAILER-DAEMON@` the reporting_mta
+    #[serde(default)]
+    pub from: Option<String>,
+
+    #[serde(default = "NonDeliveryReportParams::default_subject")]
+    pub subject: String,
+
+    /// Which queue to place reports into. If unset, the queue
+    /// is derived from the envelope recipient as usual.
+    #[serde(default)]
+    pub queue: Option<String>,
+
+    /// Whether to include the full original message when the
+    /// sender did not specify RET. Otherwise only the headers
+    /// are included.
+    #[serde(default)]
+    pub return_full_message: bool,
+
+    #[serde(default)]
+    pub deferred_spool: bool,
+}
+
+impl NonDeliveryReportParams {
+    fn default_reporting_mta() -> String {
+        gethostname::gethostname()
+            .to_str()
+            .unwrap_or("localhost")
+            .to_string()
+    }
+
+    fn default_subject() -> String {
+        "Undelivered Mail Returned to Sender".to_string()
+    }
+
+    pub fn register(self) -> anyhow::Result<()> {
+        GENERATOR
+            .set(self)
+            .map_err(|_| anyhow::anyhow!("non-delivery reports already configured"))
+    }
+}
+
+/// Called when a Bounce or Expiration is logged for recipients of msg.
+/// If configured, and if the DSN parameters of the message permit it,
+/// compose a single report covering all of the recipients and submit it
+/// to the should_enqueue_non_delivery_report event to decide whether it
+/// should be queued.
+pub async fn generate_non_delivery_report(
+    kind: RecordType,
+    msg: &Message,
+    recipients: &[EnvelopeAddress],
+    response: &Response,
+    peer_address: Option<&ResolvedAddress>,
+) -> anyhow::Result<()> {
+    let params = match GENERATOR.get() {
+        Some(params) => params,
+        None => return Ok(()),
+    };
+    if !matches!(kind, RecordType::Bounce | RecordType::Expiration) || recipients.is_empty() {
+        return Ok(());
+    }
+
+    msg.load_meta_if_needed().await?;
+    let sender = msg.sender()?;
+    if sender.domain().is_empty() {
+        // Never generate a report for a null sender, as that
+        // is what we use for the reports themselves
+        return Ok(());
+    }
+
+    let dsn = msg.get_dsn_parameters()?.unwrap_or_default();
+    if !dsn.should_notify(DsnNotify::Failure) {
+        return Ok(());
+    }
+
+    // Load the data now, as the message is about to be removed
+    // from the spool
+    msg.load_data_if_needed().await?;
+
+    let now = Utc::now();
+    let headers_only = match dsn.ret {
+        Some(DsnRet::Full) => false,
+        Some(DsnRet::Hdrs) => true,
+        None => !params.return_full_message,
+    };
+    let data = msg.get_data();
+    let original = if headers_only {
+        match data.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => &data[..end + 2],
+            None => &data[..],
+        }
+    } else {
+        &data[..]
+    };
+
+    let status = match &response.enhanced_code {
+        Some(code) => ReportStatus {
+            class: code.class,
+            subject: code.subject,
+            detail: code.detail,
+            comment: None,
+        },
+        None => ReportStatus {
+            class: if response.is_transient() { 4 } else { 5 },
+            subject: 0,
+            detail: 0,
+            comment: None,
+        },
+    };
+
+    // Only a Bounce has a response that came from (or on behalf of)
+    // the destination; an Expiration is our own decision
+    let diagnostic_code = if kind == RecordType::Bounce {
+        Some(DiagnosticCode {
+            diagnostic_type: "smtp".to_string(),
+            diagnostic: format!(
+                "{} {}",
+                response.code,
+                response.content.replace(['\r', '\n'], " ")
+            ),
+        })
+    } else {
+        None
+    };
+
+    let report = Report {
+        per_message: PerMessageReportEntry {
+            original_envelope_id: dsn.envid.clone(),
+            reporting_mta: RemoteMta {
+                mta_type: "dns".to_string(),
+                name: params.reporting_mta.to_string(),
+            },
+            dsn_gateway: None,
+            received_from_mta: None,
+            arrival_date: Some(msg.id().created()),
+            extensions: HashMap::new(),
+        },
+        // Each recipient has its own group of fields
+        per_recipient: recipients
+            .iter()
+            .map(|recipient| PerRecipientReportEntry {
+                final_recipient: Recipient {
+                    recipient_type: "rfc822".to_string(),
+                    recipient: recipient.to_string(),
+                },
+                action: ReportAction::Failed,
+                status: status.clone(),
+                original_recipient: dsn.orcpt.as_deref().and_then(|orcpt| orcpt.parse().ok()),
+                remote_mta: peer_address.map(|addr| RemoteMta {
+                    mta_type: "dns".to_string(),
+                    name: addr.name.to_string(),
+                }),
+                diagnostic_code: diagnostic_code.clone(),
+                last_attempt_date: Some(now),
+                final_log_id: Some(msg.id().to_string()),
+                will_retry_until: None,
+                extensions: HashMap::new(),
+            })
+            .collect(),
+        original_message: Some(String::from_utf8_lossy(original).to_string()),
+    };
+
+    let reason = if kind == RecordType::Expiration {
+        "Your message could not be delivered before it expired from the queue."
+    } else {
+        "Your message could not be delivered to one or more recipients."
+    };
+    let failures: Vec<String> = recipients
+        .iter()
+        .map(|recipient| {
+            format!(
+                "<{recipient}>: {code} {content}",
+                recipient = recipient.to_string(),
+                code = response.code,
+                content = response.content,
+            )
+        })
+        .collect();
+    let text = format!(
+        "This is the mail system at host {reporting_mta}.\n\n\
+         {reason}\n\n\
+         {failures}",
+        reporting_mta = params.reporting_mta,
+        failures = failures.join("\n"),
+    );
+
+    let id = SpoolId::new();
+    let from = params
+        .from
+        .clone()
+        .unwrap_or_else(|| format!("MAILER-DAEMON@{}", params.reporting_mta));
+    let message_id = format!("<{id}@{}>", params.reporting_mta);
+    let boundary = format!("{id}/{}", params.reporting_mta);
+    let report_text = report.to_message(&ReportMessageParams {
+        from: &from,
+        to: &format!("<{}>", sender.to_string()),
+        subject: &params.subject,
+        message_id: &message_id,
+        date: now,
+        boundary: &boundary,
+        text: &text,
+        headers_only,
+    });
+
+    let mut meta = json!({
+        "reception_protocol": "NonDeliveryReport",
+    });
+    if let Some(queue) = &params.queue {
+        meta["queue"] = queue.to_string().into();
+    }
+
+    let report_msg = Message::new_dirty(
+        id,
+        EnvelopeAddress::null_sender(),
+        sender,
+        meta,
+        Arc::new(report_text.into_bytes().into_boxed_slice()),
+    )?;
+
+    let original = msg.clone();
+    let deferred_spool = params.deferred_spool;
+
+    rt_spawn_non_blocking(
+        "should_enqueue_non_delivery_report".to_string(),
+        move || {
+            Ok(async move {
+                let mut lua_config = load_config().await?;
+                let enqueue: Option<bool> = lua_config
+                    .async_call_callback_non_default_opt(
+                        "should_enqueue_non_delivery_report",
+                        (report_msg.clone(), original),
+                    )
+                    .await?;
+
+                if enqueue.unwrap_or(true) {
+                    let queue_name = report_msg.get_queue_name()?;
+                    if !deferred_spool {
+                        report_msg.save().await?;
+                    }
+                    QueueManager::insert(&queue_name, report_msg).await?;
+                }
+
+                anyhow::Result::<()>::Ok(())
+            })
+        },
+    )?;
+
+    Ok(())
+}
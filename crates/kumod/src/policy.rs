@@ -0,0 +1,22 @@
+//! Reloading of the lua policy while the process is running
+use crate::egress_source::{EgressPool, EgressSource};
+
+/// Loads and validates the policy file, and if it is valid,
+/// arranges for it to be used in place of the current policy.
+/// The cached egress sources and pools are discarded, and each
+/// scheduled queue and ready queue will resolve its configuration
+/// again the next time that it is maintained.
+/// If the policy fails to load, the current policy remains in
+/// effect and the error is returned.
+pub async fn reload_policy() -> anyhow::Result<usize> {
+    let generation = config::reload_config().await.map_err(|err| {
+        tracing::error!("policy reload failed, keeping the current policy: {err:#}");
+        err
+    })?;
+
+    EgressSource::clear_cache();
+    EgressPool::clear_cache();
+
+    tracing::info!("policy reloaded; now at generation {generation}");
+    Ok(generation)
+}
@@ -1,26 +1,32 @@
-use crate::egress_source::{EgressPool, EgressPoolRoundRobin};
+use crate::egress_source::{EgressPool, EgressPoolRoundRobin, EgressSource, SourceSelection};
+use crate::http_deliver::HttpDeliveryProtocol;
 use crate::http_server::admin_bounce_v1::AdminBounceEntry;
+use crate::http_server::admin_suspend_v1::AdminSuspendEntry;
 use crate::lifecycle::{Activity, ShutdownSubcription};
 use crate::logging::{log_disposition, LogDisposition, RecordType};
 use crate::lua_deliver::LuaDeliveryProtocol;
+use crate::maildir_deliver::{deliver_to_maildir, MaildirConfig};
 use crate::ready_queue::ReadyQueueManager;
-use crate::runtime::{rt_spawn, spawn, spawn_blocking};
+use crate::runtime::rt_spawn;
 use crate::spool::SpoolManager;
-use anyhow::{anyhow, Context};
+use anyhow::anyhow;
 use chrono::Utc;
-use config::load_config;
+use config::{load_config, LuaConfig};
+use kumo_api_types::ScheduledQueueSummaryV1;
 use message::message::QueueNameComponents;
 use message::Message;
 use mlua::prelude::*;
 use prometheus::{IntGauge, IntGaugeVec};
 use rfc5321::{EnhancedStatusCode, Response};
 use serde::{Deserialize, Serialize};
+use spool::SpoolId;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use timeq::{PopResult, TimeQ, TimerError};
 use tokio::sync::{Mutex, MutexGuard};
 use tracing::instrument;
+use uuid::Uuid;
 
 lazy_static::lazy_static! {
     static ref MANAGER: Mutex<QueueManager> = Mutex::new(QueueManager::new());
@@ -33,8 +39,10 @@ lazy_static::lazy_static! {
 #[serde(untagged)]
 pub enum DeliveryProto {
     Smtp,
-    Maildir { maildir_path: std::path::PathBuf },
+    Maildir(MaildirConfig),
     Lua { custom_lua: LuaDeliveryProtocol },
+    Lmtp { address: String },
+    Http { http: HttpDeliveryProtocol },
 }
 
 impl Default for DeliveryProto {
@@ -69,10 +77,31 @@ pub struct QueueConfig {
 
     #[serde(default)]
     pub protocol: DeliveryProto,
+
+    /// When delivering via smtp, deliver to these hosts rather
+    /// than resolving the MX records of the destination domain
+    #[serde(default)]
+    pub routing_hosts: Option<Vec<RoutingHost>>,
 }
 
 impl LuaUserData for QueueConfig {}
 
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RoutingHost {
+    /// A host name, IP address or IP domain literal
+    pub host: String,
+
+    /// Hosts with lower preference values are tried first
+    #[serde(default = "RoutingHost::default_preference")]
+    pub preference: u16,
+}
+
+impl RoutingHost {
+    fn default_preference() -> u16 {
+        1
+    }
+}
+
 impl Default for QueueConfig {
     fn default() -> Self {
         Self {
@@ -81,6 +110,7 @@ impl Default for QueueConfig {
             max_age: Self::default_max_age(),
             egress_pool: None,
             protocol: DeliveryProto::default(),
+            routing_hosts: None,
         }
     }
 }
@@ -153,6 +183,8 @@ impl QueueConfig {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::egress_source::{EgressPoolEntry, WarmupPlan};
+    use message::EnvelopeAddress;
 
     /// Returns the list of delays up until the max_age would be reached
     fn compute_schedule(config: &QueueConfig) -> Vec<i64> {
@@ -326,6 +358,102 @@ mod test {
             ]
         );
     }
+
+    /// A spool that discards everything that is stored in it
+    struct NullSpool;
+
+    #[async_trait::async_trait]
+    impl spool::Spool for NullSpool {
+        async fn load(&self, id: SpoolId) -> anyhow::Result<Vec<u8>> {
+            Err(anyhow!("{id} is not in the null spool"))
+        }
+        async fn remove(&self, _id: SpoolId) -> anyhow::Result<()> {
+            Ok(())
+        }
+        async fn store(&self, _id: SpoolId, _data: &[u8], _force_sync: bool) -> anyhow::Result<()> {
+            Ok(())
+        }
+        fn enumerate(
+            &self,
+            _sender: tokio::sync::mpsc::Sender<spool::SpoolEntry>,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+        async fn cleanup(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn insert_due_message_into_capped_pool() {
+        spool::set_meta_spool(Arc::new(NullSpool));
+        spool::set_data_spool(Arc::new(NullSpool));
+
+        let name = "capped.example.com";
+        let mut rr = EgressPoolRoundRobin::new(&EgressPool {
+            name: "pool".to_string(),
+            entries: vec![EgressPoolEntry {
+                name: "new".to_string(),
+                weight: 1,
+            }],
+            ttl: Duration::from_secs(60),
+        });
+        rr.set_warmup(
+            "new",
+            WarmupPlan {
+                start: Utc::now().date_naive(),
+                daily_limits: vec![0],
+                sites: HashMap::new(),
+                smooth: false,
+            },
+        );
+        let mut queue = Queue {
+            name: name.to_string(),
+            queue: TimeQ::new(),
+            last_change: Instant::now(),
+            // Lua delivery doesn't require resolving the MX,
+            // so the site is the domain itself
+            queue_config: QueueConfig {
+                protocol: DeliveryProto::Lua {
+                    custom_lua: LuaDeliveryProtocol {
+                        constructor: "make_client".to_string(),
+                    },
+                },
+                ..Default::default()
+            },
+            config_generation: config::get_generation(),
+            delayed_gauge: DELAY_GAUGE.get_metric_with_label_values(&[name]).unwrap(),
+            activity: Activity::for_testing(),
+            rr,
+            held_by_suspension: HashMap::new(),
+            pool_overrides: HashMap::new(),
+            pool_assignments: HashMap::new(),
+        };
+
+        let msg = Message::new_dirty(
+            SpoolId::new(),
+            EnvelopeAddress::parse("sender@example.com").unwrap(),
+            EnvelopeAddress::parse(&format!("recip@{name}")).unwrap(),
+            serde_json::json!({}),
+            Arc::new(
+                b"Subject: hello\r\n\r\nhello\r\n"
+                    .to_vec()
+                    .into_boxed_slice(),
+            ),
+        )
+        .unwrap();
+        assert!(msg.get_due().is_none());
+
+        // The message is due now, but the only source in the pool
+        // is capped, so it must wait in the delayed queue rather
+        // than being assigned to that source
+        queue.insert(msg.clone()).await.unwrap();
+        assert_eq!(queue.delayed_count(), 1);
+        // The cap for today is zero, so it is held until tomorrow
+        let tomorrow = Utc::now().date_naive() + chrono::Duration::days(1);
+        let due = msg.get_due().expect("message was delayed");
+        assert!(due.date_naive() >= tomorrow, "{due:?}");
+    }
 }
 
 #[derive(Clone)]
@@ -342,16 +470,27 @@ pub struct Queue {
     queue: TimeQ<Message>,
     last_change: Instant,
     queue_config: QueueConfig,
+    config_generation: usize,
     delayed_gauge: IntGauge,
     activity: Activity,
     rr: EgressPoolRoundRobin,
+    /// The messages in the delayed queue that are being held
+    /// by an administrative suspension, and the id of that suspension
+    held_by_suspension: HashMap<SpoolId, Uuid>,
+    /// The resolved egress pools that are assigned to individual
+    /// messages via their `egress_pool` meta value
+    pool_overrides: HashMap<String, EgressPoolRoundRobin>,
+    /// The `egress_pool` meta value of the messages in this queue
+    /// that have one, as noted by note_pool_override
+    pool_assignments: HashMap<SpoolId, String>,
 }
 
 impl Queue {
-    pub async fn new(name: String) -> anyhow::Result<QueueHandle> {
+    /// Resolves the configuration and egress pool for the named queue
+    async fn resolve_config(name: &str) -> anyhow::Result<(QueueConfig, EgressPoolRoundRobin)> {
         let mut config = load_config().await?;
 
-        let components = QueueNameComponents::parse(&name);
+        let components = QueueNameComponents::parse(name);
         let queue_config: QueueConfig = config
             .async_call_callback(
                 "get_queue_config",
@@ -359,8 +498,92 @@ impl Queue {
             )
             .await?;
 
-        let pool = EgressPool::resolve(queue_config.egress_pool.as_deref(), &mut config).await?;
-        let rr = EgressPoolRoundRobin::new(&pool);
+        let rr = Self::resolve_pool(queue_config.egress_pool.as_deref(), &mut config).await?;
+
+        Ok((queue_config, rr))
+    }
+
+    /// Resolves the named egress pool, along with the
+    /// warmup plans of its sources
+    pub async fn resolve_pool(
+        name: Option<&str>,
+        config: &mut LuaConfig,
+    ) -> anyhow::Result<EgressPoolRoundRobin> {
+        let pool = EgressPool::resolve(name, config).await?;
+        let mut rr = EgressPoolRoundRobin::new(&pool);
+        for name in rr.all_sources() {
+            let source = EgressSource::resolve(&name, config).await?;
+            if let Some(plan) = source.warmup {
+                rr.set_warmup(&name, plan);
+            }
+        }
+        Ok(rr)
+    }
+
+    /// Notes the `egress_pool` meta value of msg, so that
+    /// rr_for_message doesn't need to load the meta of every message
+    /// from the spool.  The meta is only consulted when it is already
+    /// loaded, as it is for messages that have just been received,
+    /// rebound or loaded from the spool.
+    fn note_pool_override(&mut self, msg: &Message) {
+        if !msg.is_meta_loaded() {
+            return;
+        }
+        match msg.get_meta_string("egress_pool") {
+            Ok(Some(pool)) if pool != self.rr.name => {
+                self.pool_assignments.insert(*msg.id(), pool);
+            }
+            Ok(_) => {
+                self.pool_assignments.remove(msg.id());
+            }
+            Err(err) => {
+                tracing::error!("{}: egress_pool of {}: {err:#}", self.name, msg.id());
+            }
+        }
+    }
+
+    /// Returns the egress pool to use for msg; this is the pool from
+    /// the queue config unless msg was noted as having an `egress_pool`
+    /// meta value.  If that pool cannot be resolved, the pool from the
+    /// queue config is used instead.
+    async fn rr_for_message(&mut self, msg: &Message) -> &mut EgressPoolRoundRobin {
+        let pool = match self.pool_assignments.get(msg.id()) {
+            Some(pool) => pool.clone(),
+            None => return &mut self.rr,
+        };
+        if !self.pool_overrides.contains_key(&pool) {
+            let resolved = match load_config().await {
+                Ok(mut config) => Self::resolve_pool(Some(&pool), &mut config).await,
+                Err(err) => Err(err),
+            };
+            match resolved {
+                Ok(rr) => {
+                    self.pool_overrides.insert(pool.clone(), rr);
+                }
+                Err(err) => {
+                    tracing::error!(
+                        "{}: resolving egress_pool {pool} of {}: {err:#}",
+                        self.name,
+                        msg.id()
+                    );
+                    return &mut self.rr;
+                }
+            }
+        }
+        self.pool_overrides
+            .get_mut(&pool)
+            .expect("pool override was just inserted")
+    }
+
+    /// Returns the named egress pool, as previously returned
+    /// by rr_for_message
+    fn rr_named(&self, pool: &str) -> &EgressPoolRoundRobin {
+        self.pool_overrides.get(pool).unwrap_or(&self.rr)
+    }
+
+    pub async fn new(name: String) -> anyhow::Result<QueueHandle> {
+        let config_generation = config::get_generation();
+        let (queue_config, rr) = Self::resolve_config(&name).await?;
 
         let delayed_gauge = DELAY_GAUGE.get_metric_with_label_values(&[&name])?;
 
@@ -371,9 +594,13 @@ impl Queue {
             queue: TimeQ::new(),
             last_change: Instant::now(),
             queue_config,
+            config_generation,
             delayed_gauge,
             activity,
             rr,
+            held_by_suspension: HashMap::new(),
+            pool_overrides: HashMap::new(),
+            pool_assignments: HashMap::new(),
         })));
 
         let queue_clone = handle.clone();
@@ -392,11 +619,29 @@ impl Queue {
         Ok(handle)
     }
 
+    /// If the policy has been reloaded since the configuration
+    /// for this queue was resolved, resolve it again
+    async fn refresh_config_if_needed(&mut self) -> anyhow::Result<()> {
+        let generation = config::get_generation();
+        if generation == self.config_generation {
+            return Ok(());
+        }
+
+        let (queue_config, rr) = Self::resolve_config(&self.name).await?;
+        self.queue_config = queue_config;
+        self.rr = rr;
+        self.pool_overrides.clear();
+        self.config_generation = generation;
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     pub async fn bounce_all(&mut self, bounce: &AdminBounceEntry) {
         let msgs = self.queue.drain();
         let count = msgs.len();
         self.delayed_gauge.sub(count as i64);
+        self.held_by_suspension.clear();
+        self.pool_assignments.clear();
         for msg in msgs {
             let msg = (*msg).clone();
             let id = *msg.id();
@@ -414,6 +659,15 @@ impl Queue {
         }
     }
 
+    /// Removes all of the messages from the delayed queue
+    pub fn drain_delayed(&mut self) -> Vec<Message> {
+        let msgs = self.queue.drain();
+        self.delayed_gauge.sub(msgs.len() as i64);
+        self.held_by_suspension.clear();
+        self.pool_assignments.clear();
+        msgs.into_iter().map(|msg| (*msg).clone()).collect()
+    }
+
     #[instrument(skip(self, msg))]
     pub async fn requeue_message(
         &mut self,
@@ -424,9 +678,16 @@ impl Queue {
         let id = *msg.id();
         if increment_attempts {
             msg.increment_num_attempts();
-            let delay = self.queue_config.delay_for_attempt(msg.get_num_attempts());
-            let jitter = (rand::random::<f32>() * 60.) - 30.0;
-            let delay = chrono::Duration::seconds(delay.num_seconds() + jitter as i64);
+            // An explicit delay, such as one requested by the peer,
+            // takes precedence over the retry schedule
+            let delay = match delay {
+                Some(delay) => delay,
+                None => {
+                    let delay = self.queue_config.delay_for_attempt(msg.get_num_attempts());
+                    let jitter = (rand::random::<f32>() * 60.) - 30.0;
+                    chrono::Duration::seconds(delay.num_seconds() + jitter as i64)
+                }
+            };
 
             let now = Utc::now();
             let max_age = self.queue_config.get_max_age();
@@ -437,6 +698,7 @@ impl Queue {
                 log_disposition(LogDisposition {
                     kind: RecordType::Expiration,
                     msg,
+                    recipient: None,
                     site: "localhost",
                     peer_address: None,
                     response: Response {
@@ -470,6 +732,52 @@ impl Queue {
         Ok(())
     }
 
+    /// Holds msg in the delayed queue until the suspension expires
+    #[instrument(skip(self, msg, suspend))]
+    async fn insert_suspended(
+        &mut self,
+        msg: Message,
+        suspend: &AdminSuspendEntry,
+    ) -> anyhow::Result<()> {
+        tracing::trace!("insert_suspended {}", msg.id());
+        // Spread out the messages so that they don't all become
+        // due at the same instant when the suspension expires
+        let jitter = chrono::Duration::seconds((rand::random::<f32>() * 60.) as i64);
+        let remaining = chrono::Duration::from_std(suspend.get_duration())
+            .unwrap_or_else(|_| chrono::Duration::zero());
+        msg.delay_by(remaining + jitter).await?;
+        self.held_by_suspension.insert(*msg.id(), suspend.id);
+        match self.insert_delayed(msg).await? {
+            InsertResult::Delayed => Ok(()),
+            InsertResult::Ready(msg) => self.force_into_delayed(msg).await,
+        }
+    }
+
+    /// Called when suspend has been cancelled; reschedules the messages
+    /// that it was holding so that they are retried shortly.
+    /// Messages that are delayed for other reasons keep their schedule.
+    #[instrument(skip(self, suspend))]
+    pub async fn resume_suspended(&mut self, suspend: &AdminSuspendEntry) {
+        let msgs = self.queue.drain();
+        self.delayed_gauge.sub(msgs.len() as i64);
+
+        for msg in msgs {
+            let msg = (*msg).clone();
+            let held = self.held_by_suspension.get(msg.id()) == Some(&suspend.id);
+            let result = async {
+                if held {
+                    self.held_by_suspension.remove(msg.id());
+                    msg.delay_with_jitter(60).await?;
+                }
+                self.insert(msg.clone()).await
+            }
+            .await;
+            if let Err(err) = result {
+                tracing::error!("error resuming {}: {err:#}", msg.id());
+            }
+        }
+    }
+
     #[instrument(skip(self, msg))]
     async fn insert_delayed(&mut self, msg: Message) -> anyhow::Result<InsertResult> {
         tracing::trace!("insert_delayed {}", msg.id());
@@ -500,6 +808,23 @@ impl Queue {
         }
     }
 
+    /// Delays msg until one of the sources in its pool is no
+    /// longer capped by its warmup plan
+    #[instrument(skip(self, msg))]
+    async fn delay_until_uncapped(
+        &mut self,
+        msg: Message,
+        retry_after: Duration,
+    ) -> anyhow::Result<()> {
+        tracing::trace!("delay_until_uncapped {} {retry_after:?}", msg.id());
+        let delay = chrono::Duration::from_std(retry_after)?.max(chrono::Duration::seconds(1));
+        msg.delay_by(delay).await?;
+        match self.insert_delayed(msg).await? {
+            InsertResult::Delayed => Ok(()),
+            InsertResult::Ready(msg) => self.force_into_delayed(msg).await,
+        }
+    }
+
     #[instrument(skip(msg))]
     pub async fn save_if_needed(msg: &Message) -> anyhow::Result<()> {
         tracing::trace!("save_if_needed {}", msg.id());
@@ -521,33 +846,81 @@ impl Queue {
         Self::save_if_needed(&msg).await
     }
 
+    /// Selects the egress source for msg from its egress pool, taking
+    /// into account the health of each source when sending to the site
+    /// that msg will be delivered to, and the warmup plan of each source.
+    /// Checking the warmup plan doesn't count against it; the caller
+    /// must call count_warmup_use if it assigns msg to the source.
+    async fn select_source(&mut self, msg: &Message) -> anyhow::Result<Selection> {
+        // If this fails, resolve_by_queue_name will report the problem
+        let site_name = ReadyQueueManager::resolve_site_name(&self.name, &self.queue_config)
+            .await
+            .ok();
+        let rr = self.rr_for_message(msg).await;
+        let selection = match &site_name {
+            Some(site_name) => rr.next_for_site(site_name).await?,
+            None => {
+                SourceSelection::Source(rr.next().ok_or_else(|| anyhow!("no sources in pool"))?)
+            }
+        };
+        Ok(match selection {
+            SourceSelection::Source(egress_source) => Selection::Source {
+                egress_source,
+                egress_pool: rr.name.clone(),
+                site_name,
+            },
+            SourceSelection::Capped { retry_after } => Selection::Capped { retry_after },
+        })
+    }
+
     #[instrument(skip(self, msg))]
     async fn insert_ready(&mut self, msg: Message) -> anyhow::Result<()> {
         tracing::trace!("insert_ready {}", msg.id());
         match &self.queue_config.protocol {
-            DeliveryProto::Smtp | DeliveryProto::Lua { .. } => {
-                let egress_source = self
-                    .rr
-                    .next()
-                    .ok_or_else(|| anyhow!("no sources in pool"))?;
+            DeliveryProto::Smtp
+            | DeliveryProto::Lua { .. }
+            | DeliveryProto::Lmtp { .. }
+            | DeliveryProto::Http { .. } => {
+                let (egress_source, egress_pool, site_name) = match self.select_source(&msg).await?
+                {
+                    Selection::Source {
+                        egress_source,
+                        egress_pool,
+                        site_name,
+                    } => (egress_source, egress_pool, site_name),
+                    Selection::Capped { retry_after } => {
+                        // Every source in the pool has reached
+                        // the cap of its warmup plan for the site
+                        return self.delay_until_uncapped(msg, retry_after).await;
+                    }
+                };
                 match ReadyQueueManager::resolve_by_queue_name(
                     &self.name,
                     &self.queue_config,
                     &egress_source,
-                    &self.rr.name,
+                    &egress_pool,
                 )
                 .await
                 {
                     Ok(site) => {
                         let mut site = site.lock().await;
+                        let id = *msg.id();
                         site.insert(msg)
                             .await
-                            .map_err(|_| anyhow!("no room in ready queue"))
+                            .map_err(|_| anyhow!("no room in ready queue"))?;
+                        self.pool_assignments.remove(&id);
+                        if let Some(site_name) = &site_name {
+                            self.rr_named(&egress_pool)
+                                .count_warmup_use(&egress_source, site_name)
+                                .await;
+                        }
+                        Ok(())
                     }
                     Err(err) => {
                         log_disposition(LogDisposition {
                             kind: RecordType::TransientFailure,
                             msg: msg.clone(),
+                            recipient: None,
                             site: "",
                             peer_address: None,
                             response: Response {
@@ -570,76 +943,15 @@ impl Queue {
                     }
                 }
             }
-            DeliveryProto::Maildir { maildir_path } => {
-                tracing::trace!(
-                    "Deliver msg {} to maildir at {}",
-                    msg.id(),
-                    maildir_path.display()
-                );
-                let maildir_path = maildir_path.to_path_buf();
-
-                msg.load_data_if_needed().await?;
-                let name = self.name.to_string();
-                let result: anyhow::Result<String> = spawn_blocking("write to maildir", {
-                    let msg = msg.clone();
-                    move || {
-                        let md = maildir::Maildir::from(maildir_path.clone());
-                        md.create_dirs().with_context(|| {
-                            format!(
-                                "creating dirs for maildir {maildir_path:?} in queue {}",
-                                name
-                            )
-                        })?;
-                        Ok(md.store_new(&msg.get_data())?)
-                    }
-                })?
-                .await?;
-
-                match result {
-                    Ok(id) => {
-                        log_disposition(LogDisposition {
-                            kind: RecordType::Delivery,
-                            msg: msg.clone(),
-                            site: "",
-                            peer_address: None,
-                            response: Response {
-                                code: 200,
-                                enhanced_code: None,
-                                content: format!("wrote to maildir with id={id}"),
-                                command: None,
-                            },
-                            egress_pool: None,
-                            egress_source: None,
-                            relay_disposition: None,
-                            delivery_protocol: Some("Maildir"),
-                        })
-                        .await;
-                        spawn("remove from spool", async move {
-                            SpoolManager::remove_from_spool(*msg.id()).await
-                        })?;
-                        Ok(())
-                    }
-                    Err(err) => {
-                        log_disposition(LogDisposition {
-                            kind: RecordType::TransientFailure,
-                            msg: msg.clone(),
-                            site: "",
-                            peer_address: None,
-                            response: Response {
-                                code: 400,
-                                enhanced_code: None,
-                                content: format!("failed to write to maildir: {err:#}"),
-                                command: None,
-                            },
-                            egress_pool: None,
-                            egress_source: None,
-                            relay_disposition: None,
-                            delivery_protocol: Some("Maildir"),
-                        })
-                        .await;
-                        anyhow::bail!("failed maildir store: {err:#}");
-                    }
+            DeliveryProto::Maildir(maildir) => {
+                let result = deliver_to_maildir(&self.name, &msg, maildir).await;
+                if let Err(err) = result {
+                    tracing::debug!("{err:#}");
+                    // Retry the recipients that failed transiently
+                    // according to the retry schedule of the queue
+                    Box::pin(self.requeue_message(msg, true, None)).await?;
                 }
+                Ok(())
             }
         }
     }
@@ -662,6 +974,12 @@ impl Queue {
             return Ok(());
         }
 
+        self.note_pool_override(&msg);
+
+        if let Some(suspend) = AdminSuspendEntry::get_for_queue_name(&self.name) {
+            return self.insert_suspended(msg, &suspend).await;
+        }
+
         match self.insert_delayed(msg.clone()).await? {
             InsertResult::Delayed => Ok(()),
             InsertResult::Ready(msg) => {
@@ -677,6 +995,41 @@ impl Queue {
     pub fn get_config(&self) -> &QueueConfig {
         &self.queue_config
     }
+
+    pub fn delayed_count(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Produces the summary reported by the queue inspection API
+    pub fn summary(&self) -> ScheduledQueueSummaryV1 {
+        let now = Utc::now();
+        ScheduledQueueSummaryV1 {
+            name: self.name.clone(),
+            egress_pool: self.rr.name.clone(),
+            message_count: self.queue.len(),
+            oldest_age: self
+                .queue
+                .iter()
+                .map(|msg| msg.age(now))
+                .max()
+                .and_then(|age| age.to_std().ok()),
+            next_due: self.queue.iter().filter_map(|msg| msg.get_due()).min(),
+        }
+    }
+
+    pub fn get_message(&self, id: &SpoolId) -> Option<Message> {
+        self.queue.get_by_id(id).map(|msg| (**msg).clone())
+    }
+
+    /// Returns up to `limit` of the queued messages, in no
+    /// particular order
+    pub fn sample_messages(&self, limit: usize) -> Vec<Message> {
+        self.queue
+            .iter()
+            .take(limit)
+            .map(|msg| (**msg).clone())
+            .collect()
+    }
 }
 
 #[must_use]
@@ -685,6 +1038,17 @@ enum InsertResult {
     Ready(Message),
 }
 
+/// The egress source selected by Queue::select_source
+enum Selection {
+    Source {
+        egress_source: String,
+        egress_pool: String,
+        site_name: Option<String>,
+    },
+    /// Every source in the pool is capped by its warmup plan
+    Capped { retry_after: Duration },
+}
+
 pub struct QueueManager {
     named: HashMap<String, QueueHandle>,
 }
@@ -754,6 +1118,10 @@ async fn maintain_named_queue(queue: &QueueHandle) -> anyhow::Result<()> {
                 q.queue.len()
             );
 
+            if let Err(err) = q.refresh_config_if_needed().await {
+                tracing::error!("refreshing config for queue {}: {err:#}", q.name);
+            }
+
             if let Some(b) = AdminBounceEntry::get_for_queue_name(&q.name) {
                 q.bounce_all(&b).await;
             }
@@ -784,71 +1152,29 @@ async fn maintain_named_queue(queue: &QueueHandle) -> anyhow::Result<()> {
                     q.delayed_gauge.sub(messages.len() as i64);
                     let max_age = q.queue_config.get_max_age();
                     tracing::trace!("{} msgs are now ready", messages.len());
+                    let suspend = AdminSuspendEntry::get_for_queue_name(&q.name);
 
                     for msg in messages {
-                        let egress_source =
-                            q.rr.next().ok_or_else(|| anyhow!("no sources in pool"))?;
-
-                        match ReadyQueueManager::resolve_by_queue_name(
-                            &q.name,
-                            &q.queue_config,
-                            &egress_source,
-                            &q.rr.name,
-                        )
-                        .await
-                        {
-                            Ok(site) => {
-                                let mut site = site.lock().await;
-
-                                let msg = (*msg).clone();
-                                let id = *msg.id();
-
-                                let age = msg.age(now);
-                                if age >= max_age {
-                                    // TODO: log failure due to expiration
-                                    tracing::debug!("expiring {id} {age} > {max_age}");
-                                    SpoolManager::remove_from_spool(id).await?;
-                                    continue;
-                                }
-
-                                match site.insert(msg.clone()).await {
-                                    Ok(_) => {}
-                                    Err(_) => loop {
-                                        msg.delay_with_jitter(60).await?;
-                                        if matches!(
-                                            q.insert_delayed(msg.clone()).await?,
-                                            InsertResult::Delayed
-                                        ) {
-                                            break;
-                                        }
-                                    },
-                                }
-                            }
-                            Err(err) => {
-                                tracing::error!("Failed to resolve {}: {err:#}", q.name);
-                                log_disposition(LogDisposition {
-                                    kind: RecordType::TransientFailure,
-                                    msg: (*msg).clone(),
-                                    site: "",
-                                    peer_address: None,
-                                    response: Response {
-                                        code: 451,
-                                        enhanced_code: Some(EnhancedStatusCode {
-                                            class: 4,
-                                            subject: 4,
-                                            detail: 4,
-                                        }),
-                                        content: format!("failed to resolve {}: {err:#}", q.name),
-                                        command: None,
-                                    },
-                                    egress_pool: None,
-                                    egress_source: None,
-                                    relay_disposition: None,
-                                    delivery_protocol: None,
-                                })
-                                .await;
-                                q.force_into_delayed((*msg).clone()).await?;
-                            }
+                        q.held_by_suspension.remove(msg.id());
+                        if let Some(suspend) = &suspend {
+                            q.insert_suspended((*msg).clone(), suspend).await?;
+                            continue;
+                        }
+
+                        let msg = (*msg).clone();
+                        let id = *msg.id();
+                        let age = msg.age(now);
+                        if age >= max_age {
+                            // TODO: log failure due to expiration
+                            tracing::debug!("expiring {id} {age} > {max_age}");
+                            q.pool_assignments.remove(&id);
+                            SpoolManager::remove_from_spool(id).await?;
+                            continue;
+                        }
+
+                        if let Err(err) = q.insert_ready(msg.clone()).await {
+                            tracing::debug!("insert_ready: {err:#}");
+                            q.force_into_delayed(msg).await?;
                         }
                     }
                 }
@@ -1,26 +1,36 @@
 use crate::delivery_metrics::DeliveryMetrics;
 use crate::egress_path::EgressPathConfig;
 use crate::egress_source::EgressSource;
+use crate::http_deliver::HttpQueueDispatcher;
 use crate::http_server::admin_bounce_v1::AdminBounceEntry;
+use crate::http_server::admin_suspend_v1::AdminSuspendEntry;
 use crate::lifecycle::{Activity, ShutdownSubcription};
+use crate::lmtp_dispatcher::LmtpDispatcher;
 use crate::logging::{log_disposition, LogDisposition, RecordType};
 use crate::lua_deliver::LuaQueueDispatcher;
 use crate::queue::{DeliveryProto, Queue, QueueConfig, QueueManager};
 use crate::runtime::{rt_spawn, rt_spawn_non_blocking, spawn};
-use crate::smtp_dispatcher::SmtpDispatcher;
+use crate::shaping::{apply_shaping_rules, effective_connection_limit, effective_max_message_rate};
+use crate::smtp_dispatcher::{tls_policy_response, SmtpDispatcher, TlsPolicyFailure};
+use crate::source_health;
 use crate::spool::SpoolManager;
 use anyhow::Context;
 use async_trait::async_trait;
 use config::load_config;
 use dns_resolver::MailExchanger;
+use kumo_api_types::ReadyQueueSummaryV1;
+use kumo_log_types::ResolvedAddress;
 use message::message::QueueNameComponents;
-use message::Message;
-use rfc5321::{EnhancedStatusCode, Response};
-use std::collections::{HashMap, VecDeque};
+use message::{EnvelopeAddress, Message};
+use rfc5321::{ClientError, EnhancedStatusCode, Response};
+use spool::SpoolId;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex as StdMutex};
 use std::time::{Duration, Instant};
+use throttle::limit::LimitSpec;
+use throttle::Error as ThrottleError;
 use tokio::sync::{Mutex, MutexGuard, Notify};
 use tokio::task::JoinHandle;
 use tracing::instrument; // TODO move to here
@@ -47,25 +57,66 @@ impl ReadyQueueManager {
         MANAGER.lock().await
     }
 
+    pub async fn get_by_name(name: &str) -> Option<ReadyQueueHandle> {
+        let manager = Self::get().await;
+        manager.queues.get(name).cloned()
+    }
+
+    pub async fn all_queues() -> Vec<ReadyQueueHandle> {
+        let manager = Self::get().await;
+        manager.queues.values().cloned().collect()
+    }
+
+    /// Determines the hosts to which smtp deliveries for domain
+    /// should be made, returning None for other protocols
+    async fn resolve_mx(
+        domain: &str,
+        queue_config: &QueueConfig,
+    ) -> anyhow::Result<Option<Arc<MailExchanger>>> {
+        if !matches!(&queue_config.protocol, DeliveryProto::Smtp) {
+            return Ok(None);
+        }
+
+        match &queue_config.routing_hosts {
+            Some(routing_hosts) => {
+                let hosts: Vec<(u16, String)> = routing_hosts
+                    .iter()
+                    .map(|entry| (entry.preference, entry.host.to_string()))
+                    .collect();
+                Ok(Some(Arc::new(MailExchanger::with_hosts(domain, &hosts)?)))
+            }
+            None => Ok(Some(MailExchanger::resolve(domain).await?)),
+        }
+    }
+
+    /// Returns the name of the site to which the messages
+    /// in queue_name will be delivered
+    pub async fn resolve_site_name(
+        queue_name: &str,
+        queue_config: &QueueConfig,
+    ) -> anyhow::Result<String> {
+        let components = QueueNameComponents::parse(queue_name);
+        let mx = Self::resolve_mx(components.routing_domain(), queue_config).await?;
+        Ok(mx
+            .as_ref()
+            .map(|mx| mx.site_name.to_string())
+            .unwrap_or_else(|| components.routing_domain().to_string()))
+    }
+
     pub async fn get_opt(
         queue_name: &str,
         queue_config: &QueueConfig,
         egress_source: &str,
     ) -> Option<ReadyQueueHandle> {
         let components = QueueNameComponents::parse(queue_name);
-
-        let needs_mx = matches!(&queue_config.protocol, DeliveryProto::Smtp);
-
-        let mx = if needs_mx {
-            Some(MailExchanger::resolve(components.domain).await.ok()?)
-        } else {
-            None
-        };
+        let mx = Self::resolve_mx(components.routing_domain(), queue_config)
+            .await
+            .ok()?;
 
         let site_name = mx
             .as_ref()
             .map(|mx| mx.site_name.to_string())
-            .unwrap_or_else(|| components.domain.to_string());
+            .unwrap_or_else(|| components.routing_domain().to_string());
         let name = format!("{egress_source}->{site_name}");
 
         let manager = Self::get().await;
@@ -79,35 +130,17 @@ impl ReadyQueueManager {
         egress_pool: &str,
     ) -> anyhow::Result<ReadyQueueHandle> {
         let components = QueueNameComponents::parse(queue_name);
-
-        let needs_mx = matches!(&queue_config.protocol, DeliveryProto::Smtp);
-
-        let mx = if needs_mx {
-            Some(MailExchanger::resolve(components.domain).await?)
-        } else {
-            None
-        };
+        let mx = Self::resolve_mx(components.routing_domain(), queue_config).await?;
 
         let site_name = mx
             .as_ref()
             .map(|mx| mx.site_name.to_string())
-            .unwrap_or_else(|| components.domain.to_string());
+            .unwrap_or_else(|| components.routing_domain().to_string());
         let name = format!("{egress_source}->{site_name}");
 
-        let mut config = load_config().await?;
-
-        let egress_source = EgressSource::resolve(egress_source, &mut config).await?;
-
-        let path_config: EgressPathConfig = config
-            .async_call_callback(
-                "get_egress_path_config",
-                (components.domain, egress_source.name.to_string(), site_name),
-            )
-            .await
-            .map_err(|err| {
-                tracing::error!("Error while calling get_egress_path_config: {err:#}");
-                err
-            })?;
+        let config_generation = config::get_generation();
+        let (egress_source, path_config) =
+            Self::resolve_path_config(components.domain, egress_source, &site_name).await?;
 
         let mut manager = Self::get().await;
         let activity = Activity::get()?;
@@ -124,22 +157,65 @@ impl ReadyQueueManager {
             let notify = Arc::new(Notify::new());
             ReadyQueueHandle(Arc::new(Mutex::new(ReadyQueue {
                 name: name.clone(),
+                domain: components.domain.to_string(),
+                site_name,
                 queue_name: queue_name.to_string(),
+                queue_names: [queue_name.to_string()].into_iter().collect(),
                 ready,
                 mx,
                 notify,
                 connections: vec![],
                 last_change: Instant::now(),
                 path_config,
+                config_generation,
                 queue_config: queue_config.clone(),
                 egress_source,
                 metrics,
                 activity,
                 consecutive_connection_failures: Arc::new(AtomicUsize::new(0)),
+                observed_connection_failures: None,
+                ramp_up_started: None,
+                maintain_scheduled: Arc::new(AtomicBool::new(false)),
                 egress_pool: egress_pool.to_string(),
             })))
         });
-        Ok(handle.clone())
+        let handle = handle.clone();
+        drop(manager);
+
+        {
+            let mut queue = handle.lock().await;
+            if !queue.queue_names.contains(queue_name) {
+                queue.queue_names.insert(queue_name.to_string());
+            }
+        }
+        Ok(handle)
+    }
+
+    async fn resolve_path_config(
+        domain: &str,
+        egress_source: &str,
+        site_name: &str,
+    ) -> anyhow::Result<(EgressSource, EgressPathConfig)> {
+        let mut config = load_config().await?;
+
+        let egress_source = EgressSource::resolve(egress_source, &mut config).await?;
+
+        let path_config: EgressPathConfig = config
+            .async_call_callback(
+                "get_egress_path_config",
+                (
+                    domain.to_string(),
+                    egress_source.name.to_string(),
+                    site_name.to_string(),
+                ),
+            )
+            .await
+            .map_err(|err| {
+                tracing::error!("Error while calling get_egress_path_config: {err:#}");
+                err
+            })?;
+
+        Ok((egress_source, path_config))
     }
 
     async fn maintainer_task(name: String) -> anyhow::Result<()> {
@@ -160,6 +236,10 @@ impl ReadyQueueManager {
                 None => break,
                 Some(queue) => {
                     let mut queue = queue.lock().await;
+                    if let Err(err) = queue.refresh_config_if_needed().await {
+                        tracing::error!("refreshing config for ready queue {name}: {err:#}");
+                    }
+                    queue.requeue_suspended().await;
                     if queue.reapable().await {
                         tracing::debug!("reaping site {name}");
                         mgr.queues.remove(&name);
@@ -177,6 +257,20 @@ impl ReadyQueueManager {
     }
 }
 
+/// Returns true if msg is held by any of suspensions.
+/// A message whose metadata cannot be loaded is treated as
+/// suspended, so that it doesn't bypass the suspension.
+async fn is_suspended(msg: &Message, suspensions: &[AdminSuspendEntry]) -> bool {
+    if let Err(err) = msg.load_meta_if_needed().await {
+        tracing::error!("loading meta for {}: {err:#}", msg.id());
+        return true;
+    }
+    match msg.get_queue_name() {
+        Ok(name) => suspensions.iter().any(|s| s.matches_queue_name(&name)),
+        Err(_) => true,
+    }
+}
+
 #[derive(Clone)]
 pub struct ReadyQueueHandle(Arc<Mutex<ReadyQueue>>);
 
@@ -188,7 +282,12 @@ impl ReadyQueueHandle {
 
 pub struct ReadyQueue {
     name: String,
+    domain: String,
+    site_name: String,
     queue_name: String,
+    /// The names of the scheduled queues that have
+    /// inserted messages into this ready queue
+    queue_names: HashSet<String>,
     ready: Arc<StdMutex<VecDeque<Message>>>,
     mx: Option<Arc<MailExchanger>>,
     notify: Arc<Notify>,
@@ -197,18 +296,46 @@ pub struct ReadyQueue {
     metrics: DeliveryMetrics,
     activity: Activity,
     consecutive_connection_failures: Arc<AtomicUsize>,
+    /// The consecutive_connection_failures count when it was
+    /// last observed to change, and when that happened
+    observed_connection_failures: Option<(usize, Instant)>,
+    /// When the queue last started opening connections from cold
+    ramp_up_started: Option<Instant>,
+    maintain_scheduled: Arc<AtomicBool>,
     path_config: EgressPathConfig,
+    config_generation: usize,
     queue_config: QueueConfig,
     egress_pool: String,
     egress_source: EgressSource,
 }
 
 impl ReadyQueue {
-    #[allow(unused)]
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    /// If the policy has been reloaded since the egress source and
+    /// path configuration for this queue were resolved, resolve them
+    /// again.  Connections that are already open keep using the
+    /// configuration that was in effect when they were opened.
+    async fn refresh_config_if_needed(&mut self) -> anyhow::Result<()> {
+        let generation = config::get_generation();
+        if generation == self.config_generation {
+            return Ok(());
+        }
+
+        let (egress_source, path_config) = ReadyQueueManager::resolve_path_config(
+            &self.domain,
+            &self.egress_source.name,
+            &self.site_name,
+        )
+        .await?;
+        self.egress_source = egress_source;
+        self.path_config = path_config;
+        self.config_generation = generation;
+        Ok(())
+    }
+
     pub async fn bounce_all(&mut self, bounce: &AdminBounceEntry) {
         let msgs: Vec<Message> = self.ready.lock().unwrap().drain(..).collect();
         self.metrics.ready_count.set(0);
@@ -219,6 +346,83 @@ impl ReadyQueue {
         }
     }
 
+    /// Moves messages that match an administrative suspension
+    /// back to their scheduled queues, where they will be held
+    /// until the suspension is lifted
+    pub async fn requeue_suspended(&mut self) {
+        // Only the suspensions that apply to one of the scheduled
+        // queues that feed this ready queue can match its messages
+        let suspensions: Vec<AdminSuspendEntry> = AdminSuspendEntry::get_all()
+            .into_iter()
+            .filter(|suspend| {
+                self.queue_names
+                    .iter()
+                    .any(|name| suspend.matches_queue_name(name))
+            })
+            .collect();
+        if suspensions.is_empty() {
+            return;
+        }
+
+        // Check a snapshot of the ready queue, so that dispatchers
+        // can continue to take messages from it meanwhile.
+        // The metadata of shrunk messages must be loaded in order
+        // to match them.
+        let candidates: Vec<Message> = self.ready.lock().unwrap().iter().cloned().collect();
+        let mut suspended = HashSet::new();
+        let mut loaded = HashSet::new();
+        for msg in candidates {
+            let was_shrunk = !msg.is_meta_loaded() && !msg.is_data_loaded();
+            if is_suspended(&msg, &suspensions).await {
+                suspended.insert(*msg.id());
+            } else if was_shrunk {
+                loaded.insert(*msg.id());
+            }
+        }
+
+        // Take the suspended messages out of the ready queue, unless a
+        // dispatcher has already taken them, and shrink the messages
+        // that remain in it again.  Messages are only shrunk while they
+        // are in the ready queue, so that none of them is being delivered.
+        let mut msgs = vec![];
+        {
+            let mut ready = self.ready.lock().unwrap();
+            ready.retain(|msg| {
+                if suspended.contains(msg.id()) {
+                    msgs.push(msg.clone());
+                    return false;
+                }
+                if loaded.contains(msg.id()) {
+                    msg.shrink().ok();
+                }
+                true
+            });
+        }
+        if msgs.is_empty() {
+            return;
+        }
+
+        tracing::debug!(
+            "suspended: moving {} messages from ready queue {}",
+            msgs.len(),
+            self.name
+        );
+        self.metrics.ready_count.sub(msgs.len() as i64);
+        let activity = self.activity.clone();
+        rt_spawn("requeue for suspend".to_string(), move || {
+            Ok(async move {
+                for msg in msgs {
+                    if let Err(err) = Dispatcher::requeue_message(msg, false, None).await {
+                        tracing::error!("error requeuing message: {err:#}");
+                    }
+                }
+                drop(activity);
+            })
+        })
+        .await
+        .expect("failed to spawn requeue");
+    }
+
     pub async fn insert(&mut self, msg: Message) -> Result<(), Message> {
         if crate::memory::low_memory() {
             msg.shrink().ok();
@@ -236,11 +440,60 @@ impl ReadyQueue {
         self.ready.lock().unwrap().len()
     }
 
+    /// Produces the summary reported by the queue inspection API
+    pub fn summary(&self) -> ReadyQueueSummaryV1 {
+        let now = chrono::Utc::now();
+        let ready = self.ready.lock().unwrap();
+        ReadyQueueSummaryV1 {
+            name: self.name.clone(),
+            egress_source: self.egress_source.name.clone(),
+            egress_pool: self.egress_pool.clone(),
+            message_count: ready.len(),
+            oldest_age: ready
+                .iter()
+                .map(|msg| msg.age(now))
+                .max()
+                .and_then(|age| age.to_std().ok()),
+            connection_count: self
+                .connections
+                .iter()
+                .filter(|handle| !handle.is_finished())
+                .count(),
+            connection_limit: effective_connection_limit(
+                &self.name,
+                self.path_config.connection_limit,
+            ),
+        }
+    }
+
+    pub fn get_message(&self, id: &SpoolId) -> Option<Message> {
+        self.ready
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|msg| msg.id() == id)
+            .cloned()
+    }
+
+    /// Returns up to `limit` of the ready messages, in the order
+    /// in which they will be dispatched
+    pub fn sample_messages(&self, limit: usize) -> Vec<Message> {
+        self.ready
+            .lock()
+            .unwrap()
+            .iter()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
     pub fn ideal_connection_count(&self) -> usize {
         if self.activity.is_shutting_down() {
             0
         } else {
-            let n = ideal_connection_count(self.ready_count(), self.path_config.connection_limit);
+            let connection_limit =
+                effective_connection_limit(&self.name, self.path_config.connection_limit);
+            let n = ideal_connection_count(self.ready_count(), connection_limit);
             if n > 0 && crate::memory::get_headroom() == 0 {
                 n.min(2)
             } else {
@@ -297,10 +550,110 @@ impl ReadyQueue {
             return;
         }
 
-        // TODO: throttle rate at which connections are opened
         let ideal = self.ideal_connection_count();
+        let mut budget = ideal.saturating_sub(self.connections.len());
+        if budget == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut retry_after: Option<Duration> = None;
+        let mut defer = |delay: Duration| {
+            retry_after = Some(retry_after.map_or(delay, |prior| prior.min(delay)));
+        };
+
+        // Open connections gradually when starting from cold
+        if self.connections.is_empty() {
+            self.ramp_up_started.take();
+        }
+        if let Some(ramp_up) = &self.path_config.connection_ramp_up {
+            let started = *self.ramp_up_started.get_or_insert(now);
+            let elapsed = now.duration_since(started);
+            let (limit, next_step) = ramp_up.limit(elapsed);
+            if limit < ideal {
+                budget = budget.min(limit.saturating_sub(self.connections.len()));
+                defer(next_step.saturating_sub(elapsed));
+            }
+        }
+
+        // Back off exponentially while connections are failing,
+        // allowing a single new connection per back off period
+        let failures = self.consecutive_connection_failures.load(Ordering::SeqCst);
+        if failures == 0 {
+            self.observed_connection_failures.take();
+        } else {
+            let since = match self.observed_connection_failures {
+                Some((observed, since)) if observed == failures => since,
+                _ => now,
+            };
+            let backoff = self.path_config.connection_backoff_after(failures);
+            let elapsed = now.duration_since(since);
+            if elapsed < backoff {
+                budget = 0;
+                defer(backoff - elapsed);
+            } else {
+                budget = budget.min(1);
+            }
+            self.observed_connection_failures.replace((failures, since));
+        }
+
+        for _ in 0..budget {
+            // The limit applies to the site as a whole, rather
+            // than to each of the sources that send to it
+            let lease_key = self
+                .path_config
+                .cluster_connection_limit_key
+                .as_deref()
+                .unwrap_or(&self.site_name);
+            let lease = match self.path_config.cluster_connection_limit {
+                Some(limit) => match LimitSpec::new(limit as u64)
+                    .acquire_lease(format!("{lease_key}-connection-limit"))
+                    .await
+                {
+                    Ok(lease) => Some(lease),
+                    Err(ThrottleError::TooManyLeases(delay)) => {
+                        tracing::trace!(
+                            "{} reached cluster_connection_limit, defer for {delay:?}",
+                            self.name
+                        );
+                        // Leases are released as soon as connections close,
+                        // which is likely to be sooner than the oldest lease expires
+                        defer(delay.min(Duration::from_secs(5)));
+                        break;
+                    }
+                    Err(err) => {
+                        tracing::error!("checking connection limit for {}: {err:#}", self.name);
+                        None
+                    }
+                },
+                None => None,
+            };
+
+            // The dispatcher skips the connection rate throttle for
+            // its first connection when it was accounted for here
+            let mut connection_rate_reserved = false;
+            if let Some(throttle) = &self.path_config.max_connection_rate {
+                match throttle
+                    .throttle(format!("{}-connection-rate", self.name))
+                    .await
+                {
+                    Ok(result) => match result.retry_after {
+                        Some(delay) => {
+                            tracing::trace!(
+                                "{} throttled connection rate, defer for {delay:?}",
+                                self.name
+                            );
+                            defer(delay);
+                            break;
+                        }
+                        None => connection_rate_reserved = true,
+                    },
+                    Err(err) => {
+                        tracing::error!("checking connection rate for {}: {err:#}", self.name);
+                    }
+                }
+            }
 
-        for _ in self.connections.len()..ideal {
             // Open a new connection
             let name = self.name.clone();
             let queue_name = self.queue_name.clone();
@@ -313,11 +666,15 @@ impl ReadyQueue {
             let egress_source = self.egress_source.clone();
             let egress_pool = self.egress_pool.clone();
             let consecutive_connection_failures = self.consecutive_connection_failures.clone();
+            let lease_lost = lease
+                .as_ref()
+                .map(|lease| lease.lost_flag())
+                .unwrap_or_default();
 
             tracing::trace!("spawning client for {name}");
             if let Ok(handle) = rt_spawn(format!("smtp client {name}"), move || {
                 Ok(async move {
-                    if let Err(err) = Dispatcher::run(
+                    let run = Dispatcher::run(
                         &name,
                         queue_name,
                         mx,
@@ -329,9 +686,15 @@ impl ReadyQueue {
                         consecutive_connection_failures.clone(),
                         egress_source,
                         egress_pool,
-                    )
-                    .await
-                    {
+                        connection_rate_reserved,
+                        lease_lost,
+                    );
+                    // The lease is held for as long as the dispatcher runs
+                    let result = match lease {
+                        Some(lease) => lease.hold_while(run).await,
+                        None => run.await,
+                    };
+                    if let Err(err) = result {
                         tracing::debug!(
                             "Error in Dispatcher::run for {name}: {err:#} \
                          (consecutive_connection_failures={consecutive_connection_failures:?})"
@@ -342,7 +705,35 @@ impl ReadyQueue {
             .await
             {
                 self.connections.push(handle);
+                if let Some((_, since)) = &mut self.observed_connection_failures {
+                    *since = now;
+                }
+            }
+        }
+
+        if let Some(delay) = retry_after {
+            self.schedule_maintain(delay);
+        }
+    }
+
+    /// Arranges for maintain to be called again after delay, so that
+    /// connections that were deferred by maintain are opened without
+    /// waiting for the next message to be inserted
+    fn schedule_maintain(&self, delay: Duration) {
+        if self.maintain_scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let name = self.name.clone();
+        let scheduled = self.maintain_scheduled.clone();
+        if let Err(err) = spawn(format!("deferred maintain {name}"), async move {
+            tokio::time::sleep(delay).await;
+            scheduled.store(false, Ordering::SeqCst);
+            if let Some(queue) = ReadyQueueManager::get_by_name(&name).await {
+                queue.lock().await.maintain().await;
             }
+        }) {
+            self.maintain_scheduled.store(false, Ordering::SeqCst);
+            tracing::error!("failed to schedule maintain for {}: {err:#}", self.name);
         }
     }
 
@@ -385,6 +776,12 @@ pub struct Dispatcher {
     pub delivered_this_connection: usize,
     pub msg: Option<Message>,
     pub delivery_protocol: String,
+    /// Set when the connection rate throttle has already been
+    /// applied to the first connection of this dispatcher
+    pub connection_rate_reserved: bool,
+    /// Set when the cluster_connection_limit lease held
+    /// for this connection could not be extended
+    pub lease_lost: Arc<AtomicBool>,
 }
 
 impl Drop for Dispatcher {
@@ -420,13 +817,17 @@ impl Dispatcher {
         consecutive_connection_failures: Arc<AtomicUsize>,
         egress_source: EgressSource,
         egress_pool: String,
+        connection_rate_reserved: bool,
+        lease_lost: Arc<AtomicBool>,
     ) -> anyhow::Result<()> {
         let activity = Activity::get()?;
 
         let delivery_protocol = match &queue_config.protocol {
             DeliveryProto::Smtp => "ESMTP".to_string(),
             DeliveryProto::Lua { .. } => "Lua".to_string(),
-            DeliveryProto::Maildir { .. } => "Maildir".to_string(),
+            DeliveryProto::Maildir(_) => "Maildir".to_string(),
+            DeliveryProto::Lmtp { .. } => "LMTP".to_string(),
+            DeliveryProto::Http { .. } => "HTTP".to_string(),
         };
 
         let mut dispatcher = Self {
@@ -444,6 +845,8 @@ impl Dispatcher {
             egress_pool,
             delivered_this_connection: 0,
             delivery_protocol,
+            connection_rate_reserved,
+            lease_lost,
         };
 
         let mut queue_dispatcher: Box<dyn QueueDispatcher> = match &queue_config.protocol {
@@ -457,7 +860,9 @@ impl Dispatcher {
                 let lua_config = load_config().await?;
                 Box::new(LuaQueueDispatcher::new(lua_config, proto_config.clone()))
             }
-            DeliveryProto::Maildir { .. } => {
+            DeliveryProto::Lmtp { address } => Box::new(LmtpDispatcher::new(address, &dispatcher)),
+            DeliveryProto::Http { http } => Box::new(HttpQueueDispatcher::new(http.clone())),
+            DeliveryProto::Maildir(_) => {
                 anyhow::bail!("Should not reach Dispatcher::run with DeliveryProto::Maildir")
             }
         };
@@ -470,6 +875,7 @@ impl Dispatcher {
         }
 
         let mut connection_failures = vec![];
+        let mut tls_policy_failed = false;
 
         loop {
             if !dispatcher.wait_for_message(&mut *queue_dispatcher).await? {
@@ -480,6 +886,12 @@ impl Dispatcher {
             }
             if let Err(err) = queue_dispatcher.attempt_connection(&mut dispatcher).await {
                 connection_failures.push(format!("{err:#}"));
+                if let Some(ClientError::Rejected(response)) = err.downcast_ref::<ClientError>() {
+                    dispatcher.check_response(response);
+                }
+                if err.downcast_ref::<TlsPolicyFailure>().is_some() {
+                    tls_policy_failed = true;
+                }
                 dispatcher.metrics.connection_gauge.dec();
                 dispatcher.metrics.global_connection_gauge.dec();
                 if !queue_dispatcher
@@ -487,21 +899,27 @@ impl Dispatcher {
                     .await
                 {
                     if let Some(msg) = dispatcher.msg.take() {
+                        let content = format!(
+                            "KumoMTA internal: \
+                             failed to connect to any candidate \
+                             hosts: {}",
+                            connection_failures.join(", ")
+                        );
                         log_disposition(LogDisposition {
                             kind: RecordType::TransientFailure,
                             msg: msg.clone(),
+                            recipient: None,
                             site: &dispatcher.name,
                             peer_address: None,
-                            response: Response {
-                                code: 400,
-                                enhanced_code: None,
-                                content: format!(
-                                    "KumoMTA internal: \
-                                     failed to connect to any candidate \
-                                     hosts: {}",
-                                    connection_failures.join(", ")
-                                ),
-                                command: None,
+                            response: if tls_policy_failed {
+                                tls_policy_response(content)
+                            } else {
+                                Response {
+                                    code: 400,
+                                    enhanced_code: None,
+                                    content,
+                                    command: None,
+                                }
                             },
                             egress_pool: Some(&dispatcher.egress_pool),
                             egress_source: Some(&dispatcher.egress_source.name),
@@ -511,6 +929,7 @@ impl Dispatcher {
                         .await;
                     }
 
+                    source_health::record_connection_failure(&dispatcher.name);
                     if consecutive_connection_failures.fetch_add(1, Ordering::SeqCst)
                         > dispatcher
                             .path_config
@@ -526,7 +945,9 @@ impl Dispatcher {
             }
 
             connection_failures.clear();
+            tls_policy_failed = false;
             consecutive_connection_failures.store(0, Ordering::SeqCst);
+            source_health::record_connection_success(&dispatcher.name);
             dispatcher
                 .deliver_message(&mut *queue_dispatcher)
                 .await
@@ -542,6 +963,15 @@ impl Dispatcher {
         let msg = self.msg.as_ref().unwrap();
 
         msg.load_meta_if_needed().await?;
+
+        // A suspension may have been added while this connection was open
+        let suspensions = AdminSuspendEntry::get_all();
+        if !suspensions.is_empty() && is_suspended(msg, &suspensions).await {
+            let msg = self.msg.take().unwrap();
+            tracing::debug!("{} is suspended, requeuing {}", self.name, msg.id());
+            return Self::requeue_message(msg, false, None).await;
+        }
+
         msg.load_data_if_needed().await?;
 
         let activity = match Activity::get_opt() {
@@ -551,7 +981,9 @@ impl Dispatcher {
             }
         };
 
-        if let Some(throttle) = &self.path_config.max_message_rate {
+        if let Some(throttle) =
+            &effective_max_message_rate(&self.name, self.path_config.max_message_rate)
+        {
             loop {
                 let result = throttle
                     .throttle(format!("{}-message-rate", self.name))
@@ -592,6 +1024,80 @@ impl Dispatcher {
         Ok(())
     }
 
+    /// Applies the shaping rules of the egress path to a response
+    /// from the remote host, and updates the health of the source
+    pub fn check_response(&self, response: &Response) {
+        apply_shaping_rules(&self.name, &self.path_config.shaping_rules, response);
+        source_health::record_response(
+            &self.name,
+            response,
+            self.path_config.blocked_source_duration,
+        );
+    }
+
+    /// Logs the response to each recipient of the current message,
+    /// all of which were sent in the same transaction.  The message is
+    /// then removed from the spool or, if any of the recipients were
+    /// deferred, requeued with just those recipients.
+    pub async fn log_recipient_responses(
+        &mut self,
+        responses: Vec<(EnvelopeAddress, Response)>,
+        peer_address: Option<&ResolvedAddress>,
+    ) -> anyhow::Result<()> {
+        let msg = match self.msg.take() {
+            Some(msg) => msg,
+            None => return Ok(()),
+        };
+
+        let mut retry = vec![];
+        for (recipient, response) in responses {
+            let kind = if response.is_success() {
+                self.metrics.msgs_delivered.inc();
+                self.metrics.global_msgs_delivered.inc();
+                RecordType::Delivery
+            } else if response.is_transient() {
+                self.metrics.msgs_transfail.inc();
+                self.metrics.global_msgs_transfail.inc();
+                RecordType::TransientFailure
+            } else {
+                self.metrics.msgs_fail.inc();
+                self.metrics.global_msgs_fail.inc();
+                RecordType::Bounce
+            };
+            log_disposition(LogDisposition {
+                kind,
+                msg: msg.clone(),
+                recipient: Some(&recipient),
+                site: &self.name,
+                peer_address,
+                response,
+                egress_pool: Some(&self.egress_pool),
+                egress_source: Some(&self.egress_source.name),
+                relay_disposition: None,
+                delivery_protocol: Some(&self.delivery_protocol),
+            })
+            .await;
+            if kind == RecordType::TransientFailure {
+                retry.push(recipient);
+            }
+        }
+
+        if retry.is_empty() {
+            spawn("remove from spool", async move {
+                SpoolManager::remove_from_spool(*msg.id()).await
+            })?;
+        } else {
+            // Only the recipients that were deferred remain
+            // associated with the message
+            msg.set_recipients(retry)?;
+            rt_spawn("requeue message".to_string(), move || {
+                Ok(async move { Dispatcher::requeue_message(msg, true, None).await })
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
     #[instrument(skip(msg))]
     pub async fn requeue_message(
         msg: Message,
@@ -666,6 +1172,7 @@ impl Dispatcher {
                                     RecordType::Bounce
                                 },
                                 msg: msg.clone(),
+                                recipient: None,
                                 site: &name,
                                 peer_address: None,
                                 response: response.clone(),
@@ -758,6 +1265,33 @@ impl Dispatcher {
             }
         }
 
+        // Shed connections when a shaping override has reduced
+        // the connection limit below the number that are open
+        let connection_limit =
+            effective_connection_limit(&self.name, self.path_config.connection_limit);
+        if self.metrics.connection_gauge.get() > connection_limit as i64 {
+            tracing::trace!(
+                "{} has more connections than the limit of {connection_limit}, closing",
+                self.name
+            );
+            let closed = queue_dispatcher.close_connection(self).await?;
+            if closed {
+                return Ok(false);
+            }
+        }
+
+        // The connection no longer counts against the
+        // cluster_connection_limit, so it must not be used
+        // for any further messages
+        if self.lease_lost.load(Ordering::SeqCst) {
+            tracing::debug!(
+                "{} lost its cluster_connection_limit lease, closing",
+                self.name
+            );
+            queue_dispatcher.close_connection(self).await?;
+            return Ok(false);
+        }
+
         if self.obtain_message() {
             return Ok(true);
         }
@@ -787,6 +1321,7 @@ pub fn ideal_connection_count(queue_size: usize, connection_limit: usize) -> usi
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::egress_path::ConnectionRampUp;
 
     #[test]
     fn connection_limit() {
@@ -828,4 +1363,31 @@ mod test {
             targets
         );
     }
+    #[test]
+    fn connection_ramp_up() {
+        let ramp_up = ConnectionRampUp {
+            initial: 2,
+            interval: Duration::from_secs(10),
+        };
+        assert_eq!(ramp_up.limit(Duration::ZERO), (2, Duration::from_secs(10)));
+        assert_eq!(
+            ramp_up.limit(Duration::from_secs(15)),
+            (4, Duration::from_secs(20))
+        );
+        assert_eq!(
+            ramp_up.limit(Duration::from_secs(30)),
+            (16, Duration::from_secs(40))
+        );
+        assert_eq!(ramp_up.limit(Duration::from_secs(86400)).0, usize::MAX);
+    }
+
+    #[test]
+    fn connection_backoff() {
+        let config = EgressPathConfig::default();
+        let backoffs: Vec<u64> = [0, 1, 2, 3, 8, 9, 10, 1000]
+            .iter()
+            .map(|&failures| config.connection_backoff_after(failures).as_secs())
+            .collect();
+        assert_eq!(backoffs, vec![0, 1, 2, 4, 128, 256, 300, 300]);
+    }
 }
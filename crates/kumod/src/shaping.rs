@@ -0,0 +1,253 @@
+//! Adaptive traffic shaping. Shaping rules are configured per egress
+//! path; when a response from the remote host matches a rule, the
+//! connection_limit and/or max_message_rate of that path are reduced
+//! until the rule's duration has elapsed.
+use kumo_api_types::ShapingV1ListEntry;
+use prometheus::{IntCounterVec, IntGaugeVec};
+use regex::Regex;
+use rfc5321::Response;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use throttle::ThrottleSpec;
+
+lazy_static::lazy_static! {
+    static ref OVERRIDES: Mutex<HashMap<String, ShapingOverride>> = Mutex::new(HashMap::new());
+    static ref ACTIVE_GAUGE: IntGaugeVec = {
+        prometheus::register_int_gauge_vec!(
+            "shaping_override_active",
+            "whether a traffic shaping override is in effect",
+            &["service"]).unwrap()
+    };
+    static ref TOTAL_MATCHES: IntCounterVec = {
+        prometheus::register_int_counter_vec!(
+            "total_shaping_rule_matches",
+            "total number of responses that matched a traffic shaping rule",
+            &["service"]).unwrap()
+    };
+}
+
+/// A regex that is compiled when the configuration is loaded
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "String")]
+pub struct ShapingRegex(Regex);
+
+impl TryFrom<String> for ShapingRegex {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, String> {
+        Regex::new(&s)
+            .map(Self)
+            .map_err(|err| format!("compiling shaping rule regex {s}: {err:#}"))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ShapingRule {
+    /// Matched against the response, in the same single line
+    /// form that is used by the bounce classifier
+    pub regex: ShapingRegex,
+
+    /// The connection_limit to use while the rule is in effect
+    #[serde(default)]
+    pub connection_limit: Option<usize>,
+
+    /// The max_message_rate to use while the rule is in effect
+    #[serde(default)]
+    pub max_message_rate: Option<ThrottleSpec>,
+
+    /// How long the rule remains in effect after the last
+    /// matching response
+    #[serde(with = "humantime_serde")]
+    pub duration: Duration,
+}
+
+impl ShapingRule {
+    pub fn matches(&self, response: &Response) -> bool {
+        self.regex.0.is_match(&response.to_single_line())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ShapingOverride {
+    rule: String,
+    response: String,
+    connection_limit: Option<usize>,
+    max_message_rate: Option<ThrottleSpec>,
+    expires: Instant,
+}
+
+fn service_name(site: &str) -> String {
+    format!("smtp_client:{site}")
+}
+
+/// Returns the unexpired overrides, pruning the expired ones
+fn active_overrides() -> std::sync::MutexGuard<'static, HashMap<String, ShapingOverride>> {
+    let mut overrides = OVERRIDES.lock().unwrap();
+    let now = Instant::now();
+    overrides.retain(|site, entry| {
+        let active = entry.expires > now;
+        if !active {
+            tracing::info!("shaping override for {site} has expired");
+            ACTIVE_GAUGE
+                .with_label_values(&[service_name(site).as_str()])
+                .set(0);
+        }
+        active
+    });
+    overrides
+}
+
+/// Checks response against rules, and when one of them matches,
+/// applies its limits to site for the duration of the rule.
+/// The first matching rule wins.
+pub fn apply_shaping_rules(site: &str, rules: &[ShapingRule], response: &Response) {
+    let rule = match rules.iter().find(|rule| rule.matches(response)) {
+        Some(rule) => rule,
+        None => return,
+    };
+
+    let service = service_name(site);
+    TOTAL_MATCHES.with_label_values(&[service.as_str()]).inc();
+    ACTIVE_GAUGE.with_label_values(&[service.as_str()]).set(1);
+
+    let mut overrides = active_overrides();
+    if !overrides.contains_key(site) {
+        tracing::info!(
+            "shaping {site} for {:?} because {:?} matched rule {}",
+            rule.duration,
+            response.to_single_line(),
+            rule.regex.0.as_str()
+        );
+    }
+    // A subsequent match extends the duration
+    overrides.insert(
+        site.to_string(),
+        ShapingOverride {
+            rule: rule.regex.0.as_str().to_string(),
+            response: response.to_single_line(),
+            connection_limit: rule.connection_limit,
+            max_message_rate: rule.max_message_rate,
+            expires: Instant::now() + rule.duration,
+        },
+    );
+}
+
+/// Returns the connection limit for site, taking into
+/// account any shaping override that is in effect
+pub fn effective_connection_limit(site: &str, connection_limit: usize) -> usize {
+    active_overrides()
+        .get(site)
+        .and_then(|entry| entry.connection_limit)
+        .map(|limit| limit.min(connection_limit))
+        .unwrap_or(connection_limit)
+}
+
+/// Returns the message rate for site, taking into
+/// account any shaping override that is in effect
+pub fn effective_max_message_rate(
+    site: &str,
+    max_message_rate: Option<ThrottleSpec>,
+) -> Option<ThrottleSpec> {
+    let override_rate = active_overrides()
+        .get(site)
+        .and_then(|entry| entry.max_message_rate);
+    match (override_rate, max_message_rate) {
+        (Some(a), Some(b)) => Some(stricter_rate(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Returns whichever of a and b permits fewer messages per second,
+/// preferring b if they permit the same rate
+fn stricter_rate(a: ThrottleSpec, b: ThrottleSpec) -> ThrottleSpec {
+    // Compare a.limit / a.period with b.limit / b.period
+    let a_rate = a.limit as u128 * b.period as u128;
+    let b_rate = b.limit as u128 * a.period as u128;
+    if a_rate < b_rate {
+        a
+    } else {
+        b
+    }
+}
+
+fn describe_rate(spec: &ThrottleSpec) -> String {
+    match spec.period {
+        1 => format!("{}/s", spec.limit),
+        60 => format!("{}/m", spec.limit),
+        3600 => format!("{}/h", spec.limit),
+        86400 => format!("{}/d", spec.limit),
+        period => format!("{} per {period}s", spec.limit),
+    }
+}
+
+/// Returns the overrides that are currently in effect
+pub fn list_overrides() -> Vec<ShapingV1ListEntry> {
+    let now = Instant::now();
+    let mut entries: Vec<ShapingV1ListEntry> = active_overrides()
+        .iter()
+        .map(|(site, entry)| ShapingV1ListEntry {
+            site: site.to_string(),
+            rule: entry.rule.to_string(),
+            response: entry.response.to_string(),
+            connection_limit: entry.connection_limit,
+            max_message_rate: entry.max_message_rate.as_ref().map(describe_rate),
+            duration: entry.expires.saturating_duration_since(now),
+        })
+        .collect();
+    entries.sort_by(|a, b| a.site.cmp(&b.site));
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn response(code: u16, content: &str) -> Response {
+        Response {
+            code,
+            enhanced_code: None,
+            content: content.to_string(),
+            command: None,
+        }
+    }
+
+    #[test]
+    fn shaping() {
+        let rules = vec![ShapingRule {
+            regex: ShapingRegex::try_from("^421 .*too many connections".to_string()).unwrap(),
+            connection_limit: Some(2),
+            max_message_rate: Some(ThrottleSpec::try_from("10/m").unwrap()),
+            duration: Duration::from_secs(60),
+        }];
+        let site = "test-shaping->example.com";
+
+        apply_shaping_rules(site, &rules, &response(250, "ok"));
+        assert_eq!(effective_connection_limit(site, 32), 32);
+        assert_eq!(effective_max_message_rate(site, None), None);
+
+        apply_shaping_rules(site, &rules, &response(421, "too many connections"));
+        assert_eq!(effective_connection_limit(site, 32), 2);
+        // An override never raises the configured limit
+        assert_eq!(effective_connection_limit(site, 1), 1);
+        assert_eq!(
+            effective_max_message_rate(site, None),
+            Some(ThrottleSpec::try_from("10/m").unwrap())
+        );
+
+        // An override never raises the configured rate either
+        assert_eq!(
+            effective_max_message_rate(site, Some(ThrottleSpec::try_from("100/h").unwrap())),
+            Some(ThrottleSpec::try_from("100/h").unwrap())
+        );
+        assert_eq!(
+            effective_max_message_rate(site, Some(ThrottleSpec::try_from("1000/h").unwrap())),
+            Some(ThrottleSpec::try_from("10/m").unwrap())
+        );
+
+        let listed = list_overrides();
+        let entry = listed.iter().find(|entry| entry.site == site).unwrap();
+        assert_eq!(entry.max_message_rate.as_deref(), Some("10/m"));
+        assert_eq!(entry.connection_limit, Some(2));
+    }
+}
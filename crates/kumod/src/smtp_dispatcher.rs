@@ -5,20 +5,95 @@ use crate::logging::{log_disposition, LogDisposition, RecordType};
 use crate::ready_queue::{Dispatcher, QueueDispatcher};
 use crate::runtime::{rt_spawn, spawn};
 use crate::spool::SpoolManager;
+use crate::tls_report::{classify_handshake_error, failure_details, record_session};
 use anyhow::Context;
 use async_trait::async_trait;
+use kumo_log_types::rfc8460::{PolicyDetails, PolicyType, ResultType};
 use kumo_log_types::ResolvedAddress;
 use message::Message;
-use rfc5321::{ClientError, EnhancedStatusCode, ForwardPath, Response, ReversePath, SmtpClient};
+use mta_sts::{Mode, Policy};
+use rfc5321::{
+    ClientError, EnhancedStatusCode, ForwardPath, Response, ReversePath, SmtpClient, TlsaRecord,
+};
 use std::net::SocketAddr;
+use std::sync::Arc;
+use thiserror::Error;
 use tokio::time::timeout;
 
+/// Returned by attempt_connection when a host doesn't satisfy the
+/// MTA-STS or DANE policy for the destination
+#[derive(Error, Debug)]
+#[error("{policy} policy: {reason}")]
+pub struct TlsPolicyFailure {
+    pub policy: &'static str,
+    pub reason: String,
+}
+
+impl TlsPolicyFailure {
+    fn new(dispatcher: &Dispatcher, reason: String) -> anyhow::Error {
+        dispatcher.metrics.inc_tls_policy_failures();
+        let policy = match dispatcher.path_config.enable_tls {
+            Tls::Dane => "DANE",
+            _ => "MTA-STS",
+        };
+        Self { policy, reason }.into()
+    }
+}
+
+/// The transient response used when delivery is prevented by the
+/// MTA-STS or DANE policy
+pub fn tls_policy_response(content: String) -> Response {
+    Response {
+        code: 451,
+        enhanced_code: Some(EnhancedStatusCode {
+            class: 4,
+            subject: 7,
+            detail: 5,
+        }),
+        content,
+        command: None,
+    }
+}
+
+/// The TLS reporting policy details for an MTA-STS policy
+fn sts_report_policy(domain: &str, policy: Option<&Policy>) -> PolicyDetails {
+    match policy {
+        Some(policy) => PolicyDetails {
+            policy_type: PolicyType::Sts,
+            policy_string: policy.to_string().lines().map(str::to_string).collect(),
+            policy_domain: domain.to_string(),
+            mx_host: policy.mx.clone(),
+        },
+        None => PolicyDetails {
+            policy_type: PolicyType::NoPolicyFound,
+            policy_string: vec![],
+            policy_domain: domain.to_string(),
+            mx_host: vec![],
+        },
+    }
+}
+
+/// The TLS reporting policy details for the TLSA records of an MX host
+fn tlsa_report_policy(domain: &str, mx_host: &str, tlsa: &[TlsaRecord]) -> PolicyDetails {
+    if tlsa.is_empty() {
+        return sts_report_policy(domain, None);
+    }
+    PolicyDetails {
+        policy_type: PolicyType::Tlsa,
+        policy_string: tlsa.iter().map(|record| record.to_string()).collect(),
+        policy_domain: domain.to_string(),
+        mx_host: vec![mx_host.to_string()],
+    }
+}
+
 #[derive(Debug)]
 pub struct SmtpDispatcher {
     addresses: Vec<ResolvedAddress>,
     client: Option<MetricsWrappedConnection<SmtpClient>>,
     client_address: Option<ResolvedAddress>,
     ehlo_name: String,
+    mta_sts_policy: Option<Arc<Policy>>,
+    tls_enabled: bool,
 }
 
 impl SmtpDispatcher {
@@ -94,13 +169,112 @@ impl SmtpDispatcher {
             return Ok(None);
         }
 
+        let mut mta_sts_policy = None;
+        if dispatcher.path_config.enable_tls == Tls::MtaSts {
+            let mx = dispatcher.mx.clone().expect("to have mx when doing smtp");
+            let domain = mx.domain_name.trim_end_matches('.');
+            if !mx.is_domain_literal {
+                mta_sts_policy = match crate::mta_sts::get_policy(domain).await {
+                    Ok(policy) => policy,
+                    Err(err) => {
+                        // Without a cached policy, this is treated
+                        // the same as there being no policy;
+                        // RFC 8461 section 5
+                        tracing::debug!("MTA-STS policy discovery for {domain} failed: {err}");
+                        record_session(
+                            sts_report_policy(domain, None),
+                            Some(failure_details(
+                                ResultType::StsPolicyFetchError,
+                                None,
+                                None,
+                                err,
+                            )),
+                        );
+                        None
+                    }
+                };
+            }
+
+            if let Some(policy) = &mta_sts_policy {
+                if policy.mode == Mode::Enforce {
+                    addresses.retain(|addr| policy.mx_name_matches(&addr.name));
+                    if addresses.is_empty() {
+                        record_session(
+                            sts_report_policy(domain, Some(policy)),
+                            Some(failure_details(
+                                ResultType::ValidationFailure,
+                                None,
+                                None,
+                                "none of the MX hosts are permitted by the policy".to_string(),
+                            )),
+                        );
+                        dispatcher.metrics.inc_tls_policy_failures();
+                        dispatcher
+                            .bulk_ready_queue_operation(tls_policy_response(format!(
+                                "MTA-STS policy for {} does not permit any of its MX hosts",
+                                mx.domain_name
+                            )))
+                            .await;
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+
         Ok(Some(Self {
             addresses,
             client: None,
             client_address: None,
             ehlo_name,
+            mta_sts_policy,
+            tls_enabled: false,
         }))
     }
+
+    fn mta_sts_enforced(&self) -> bool {
+        self.mta_sts_policy
+            .as_ref()
+            .map(|policy| policy.mode == Mode::Enforce)
+            .unwrap_or(false)
+    }
+
+    /// A ready queue may hold messages for several domains that share
+    /// the same MX hosts. Check that the established connection satisfies
+    /// the MTA-STS policy of the recipient domain, which may be different
+    /// from the policy that was applied when connecting.
+    async fn check_mta_sts(
+        &self,
+        recipient_domain: &str,
+        dispatcher: &Dispatcher,
+    ) -> Option<Response> {
+        if dispatcher.path_config.enable_tls != Tls::MtaSts {
+            return None;
+        }
+        let mx = dispatcher.mx.as_ref()?;
+        if mx.is_domain_literal
+            || recipient_domain.eq_ignore_ascii_case(mx.domain_name.trim_end_matches('.'))
+        {
+            return None;
+        }
+
+        let policy = match crate::mta_sts::get_policy(recipient_domain).await {
+            Ok(Some(policy)) if policy.mode == Mode::Enforce => policy,
+            _ => return None,
+        };
+        let host = self
+            .client_address
+            .as_ref()
+            .map(|addr| addr.name.as_str())
+            .unwrap_or_default();
+        if self.tls_enabled && policy.mx_name_matches(host) {
+            return None;
+        }
+
+        dispatcher.metrics.inc_tls_policy_failures();
+        Some(tls_policy_response(format!(
+            "connection to {host} does not satisfy the MTA-STS policy for {recipient_domain}"
+        )))
+    }
 }
 
 #[async_trait(?Send)]
@@ -121,7 +295,13 @@ impl QueueDispatcher for SmtpDispatcher {
             return Ok(());
         }
 
-        if let Some(throttle) = &dispatcher.path_config.max_connection_rate {
+        let connection_rate_reserved = std::mem::take(&mut dispatcher.connection_rate_reserved);
+        if let Some(throttle) = dispatcher
+            .path_config
+            .max_connection_rate
+            .as_ref()
+            .filter(|_| !connection_rate_reserved)
+        {
             loop {
                 let result = throttle
                     .throttle(format!("{}-connection-rate", dispatcher.name))
@@ -163,9 +343,59 @@ impl QueueDispatcher for SmtpDispatcher {
             .egress_source
             .remote_port
             .unwrap_or(dispatcher.path_config.smtp_port);
+
+        // The policy domain for TLS reporting, if enabled
+        let policy_domain = match dispatcher.mx.as_ref() {
+            Some(mx) if !mx.is_domain_literal && crate::tls_report::is_enabled() => {
+                Some(mx.domain_name.trim_end_matches('.').to_string())
+            }
+            _ => None,
+        };
+
+        let mut tlsa: Vec<TlsaRecord> = vec![];
+        if enable_tls == Tls::Dane {
+            match dns_resolver::tlsa_lookup(port, &address.name).await {
+                Ok((records, _expires)) => {
+                    tlsa = records.iter().filter(|r| r.is_usable()).cloned().collect();
+                }
+                Err(err) => {
+                    let reason = format!("TLSA lookup for {} failed: {err:#}", address.name);
+                    if let Some(domain) = &policy_domain {
+                        record_session(
+                            tlsa_report_policy(domain, &address.name, &[]),
+                            Some(failure_details(
+                                ResultType::TlsaInvalid,
+                                None,
+                                Some(&address),
+                                reason.clone(),
+                            )),
+                        );
+                    }
+                    return Err(TlsPolicyFailure::new(dispatcher, reason));
+                }
+            }
+        }
+        let tls_policy_enforced = match enable_tls {
+            Tls::MtaSts => self.mta_sts_enforced(),
+            Tls::Dane => !tlsa.is_empty(),
+            _ => false,
+        };
+        let report_policy = match (&policy_domain, enable_tls) {
+            (Some(domain), Tls::MtaSts) => {
+                Some(sts_report_policy(domain, self.mta_sts_policy.as_deref()))
+            }
+            (Some(domain), Tls::Dane) => Some(tlsa_report_policy(domain, &address.name, &tlsa)),
+            _ => None,
+        };
+        // In testing mode, hosts that aren't permitted by the MTA-STS
+        // policy are still used, but are reported as failures
+        let mx_not_permitted = match &self.mta_sts_policy {
+            Some(policy) if policy.mode == Mode::Testing => !policy.mx_name_matches(&address.name),
+            _ => false,
+        };
         let connect_context = format!("connect to {address:?} port {port} and read initial banner");
 
-        let mut client = timeout(dispatcher.path_config.client_timeouts.connect_timeout, {
+        let connected = timeout(dispatcher.path_config.client_timeouts.connect_timeout, {
             let address = address.clone();
             let timeouts = dispatcher.path_config.client_timeouts.clone();
             let egress_source = dispatcher.egress_source.clone();
@@ -183,15 +413,33 @@ impl QueueDispatcher for SmtpDispatcher {
                 // Read banner
                 let banner = client.read_response(None).await.context("reading banner")?;
                 if banner.code != 220 {
-                    return anyhow::Result::<SmtpClient>::Err(ClientError::Rejected(banner).into());
+                    return anyhow::Result::<(SmtpClient, SocketAddr)>::Err(
+                        ClientError::Rejected(banner).into(),
+                    );
                 }
 
-                Ok(client)
+                Ok((client, source_address))
             }
         })
         .await
         .with_context(|| connect_context.clone())?
         .with_context(|| connect_context.clone())?;
+        let (mut client, source_address) = connected;
+        let source_ip = Some(source_address.ip());
+
+        let report_failure = |result_type: ResultType, reason: &str| {
+            if let Some(policy) = &report_policy {
+                record_session(
+                    policy.clone(),
+                    Some(failure_details(
+                        result_type,
+                        source_ip,
+                        Some(&address),
+                        reason.to_string(),
+                    )),
+                );
+            }
+        };
 
         // Say EHLO
         let caps = client.ehlo(&ehlo_name).await.context("EHLO")?;
@@ -202,25 +450,68 @@ impl QueueDispatcher for SmtpDispatcher {
             (Tls::Required | Tls::RequiredInsecure, false) => {
                 anyhow::bail!("tls policy is {enable_tls:?} but STARTTLS is not advertised",);
             }
+            (Tls::MtaSts | Tls::Dane, false) if tls_policy_enforced => {
+                let reason = format!("STARTTLS is not advertised by {}", address.name);
+                report_failure(ResultType::StarttlsNotSupported, &reason);
+                return Err(TlsPolicyFailure::new(dispatcher, reason));
+            }
+            (Tls::MtaSts | Tls::Dane, false) => {
+                report_failure(
+                    ResultType::StarttlsNotSupported,
+                    "STARTTLS is not advertised",
+                );
+                false
+            }
             (Tls::Disabled, _) | (Tls::Opportunistic | Tls::OpportunisticInsecure, false) => {
                 // Do not use TLS
                 false
             }
+            (Tls::Dane, true) if tls_policy_enforced => {
+                if let Some(handshake_error) = client.starttls_dane(&tlsa).await? {
+                    client.send_command(&rfc5321::Command::Quit).await.ok();
+                    report_failure(ResultType::ValidationFailure, &handshake_error);
+                    return Err(TlsPolicyFailure::new(
+                        dispatcher,
+                        format!("TLS handshake failed: {handshake_error}"),
+                    ));
+                }
+                true
+            }
             (
                 Tls::Opportunistic
                 | Tls::OpportunisticInsecure
                 | Tls::Required
-                | Tls::RequiredInsecure,
+                | Tls::RequiredInsecure
+                | Tls::MtaSts
+                | Tls::Dane,
                 true,
             ) => {
                 if let Some(handshake_error) = client.starttls(enable_tls.allow_insecure()).await? {
                     client.send_command(&rfc5321::Command::Quit).await.ok();
+                    report_failure(classify_handshake_error(&handshake_error), &handshake_error);
+                    if tls_policy_enforced {
+                        return Err(TlsPolicyFailure::new(
+                            dispatcher,
+                            format!("TLS handshake failed: {handshake_error}"),
+                        ));
+                    }
                     anyhow::bail!("TLS handshake failed: {handshake_error}");
                 }
                 true
             }
         };
 
+        if let Some(policy) = report_policy.clone() {
+            if mx_not_permitted {
+                report_failure(
+                    ResultType::ValidationFailure,
+                    "MX host is not permitted by the policy",
+                );
+            } else if tls_enabled {
+                record_session(policy, None);
+            }
+        }
+
         if let Some(username) = &dispatcher.path_config.smtp_auth_plain_username {
             if !tls_enabled && !dispatcher.path_config.allow_smtp_auth_plain_without_tls {
                 anyhow::bail!("TLS is not enabled and AUTH PLAIN is required. Skipping this host");
@@ -248,6 +539,7 @@ impl QueueDispatcher for SmtpDispatcher {
         self.client
             .replace(connection_wrapper.map_connection(client));
         self.client_address.replace(address);
+        self.tls_enabled = tls_enabled;
         dispatcher.delivered_this_connection = 0;
         Ok(())
     }
@@ -269,21 +561,61 @@ impl QueueDispatcher for SmtpDispatcher {
             .sender()?
             .try_into()
             .map_err(|err| anyhow::anyhow!("{err}"))?;
-        let recipient: ForwardPath = msg
-            .recipient()?
-            .try_into()
-            .map_err(|err| anyhow::anyhow!("{err}"))?;
+        let recipients = msg.recipients()?;
+        let mut forward_paths: Vec<ForwardPath> = vec![];
+        for recipient in &recipients {
+            forward_paths.push(
+                recipient
+                    .clone()
+                    .try_into()
+                    .map_err(|err| anyhow::anyhow!("{err}"))?,
+            );
+        }
 
-        dispatcher.delivered_this_connection += 1;
-        match self
-            .client
-            .as_mut()
-            .unwrap()
-            .send_mail(sender, recipient, &*data)
-            .await
+        let client = self.client.as_mut().unwrap();
+
+        let mut downgraded = None;
+        if dispatcher.path_config.downgrade_8bit_mime
+            && !client.capabilities().contains_key("8BITMIME")
+            && rfc5321::has_8bit(&data)
         {
+            match rfc5321::downgrade_to_7bit(&data) {
+                Ok(data) => {
+                    downgraded.replace(data);
+                }
+                Err(err) => {
+                    // send_mail will fail the message with a permanent error
+                    tracing::debug!("unable to downgrade {} to 7bit: {err}", msg.id());
+                }
+            }
+        }
+        let data: &[u8] = match &downgraded {
+            Some(downgraded) => downgraded.as_slice(),
+            None => &data[..],
+        };
+
+        let dsn = msg.get_dsn_parameters()?.unwrap_or_default();
+
+        let recipient_domain = recipients
+            .first()
+            .map(|recip| recip.domain())
+            .unwrap_or_default();
+        let policy_rejection = self.check_mta_sts(recipient_domain, dispatcher).await;
+        let client = self.client.as_mut().unwrap();
+
+        dispatcher.delivered_this_connection += 1;
+        let result = match policy_rejection {
+            Some(response) => Err(ClientError::Rejected(response)),
+            None => {
+                client
+                    .send_mail_multi_recip(sender, forward_paths, data, &dsn)
+                    .await
+            }
+        };
+        match result {
             Err(ClientError::Rejected(response)) if response.code >= 400 && response.code < 500 => {
                 // Transient failure
+                dispatcher.check_response(&response);
                 tracing::debug!(
                     "failed to send message to {} {:?}: {response:?}",
                     dispatcher.name,
@@ -293,6 +625,7 @@ impl QueueDispatcher for SmtpDispatcher {
                     log_disposition(LogDisposition {
                         kind: RecordType::TransientFailure,
                         msg: msg.clone(),
+                        recipient: None,
                         site: &dispatcher.name,
                         peer_address: self.client_address.as_ref(),
                         response,
@@ -311,6 +644,7 @@ impl QueueDispatcher for SmtpDispatcher {
                 dispatcher.metrics.global_msgs_transfail.inc();
             }
             Err(ClientError::Rejected(response)) => {
+                dispatcher.check_response(&response);
                 dispatcher.metrics.msgs_fail.inc();
                 dispatcher.metrics.global_msgs_fail.inc();
                 tracing::debug!(
@@ -322,6 +656,7 @@ impl QueueDispatcher for SmtpDispatcher {
                     log_disposition(LogDisposition {
                         kind: RecordType::Bounce,
                         msg: msg.clone(),
+                        recipient: None,
                         site: &dispatcher.name,
                         peer_address: self.client_address.as_ref(),
                         response,
@@ -345,27 +680,19 @@ impl QueueDispatcher for SmtpDispatcher {
                 );
                 return Err(err.into());
             }
-            Ok(response) => {
-                tracing::debug!("Delivered OK! {response:?}");
-                if let Some(msg) = dispatcher.msg.take() {
-                    log_disposition(LogDisposition {
-                        kind: RecordType::Delivery,
-                        msg: msg.clone(),
-                        site: &dispatcher.name,
-                        peer_address: self.client_address.as_ref(),
-                        response,
-                        egress_pool: Some(&dispatcher.egress_pool),
-                        egress_source: Some(&dispatcher.egress_source.name),
-                        relay_disposition: None,
-                        delivery_protocol: Some(&dispatcher.delivery_protocol),
-                    })
-                    .await;
-                    spawn("remove from spool", async move {
-                        SpoolManager::remove_from_spool(*msg.id()).await
-                    })?;
+            Ok(result) => {
+                tracing::debug!("Delivery result {result:?}");
+                let responses: Vec<_> = recipients
+                    .into_iter()
+                    .enumerate()
+                    .map(|(idx, recipient)| (recipient, result.recipient_response(idx).clone()))
+                    .collect();
+                for (_, response) in &responses {
+                    dispatcher.check_response(response);
                 }
-                dispatcher.metrics.msgs_delivered.inc();
-                dispatcher.metrics.global_msgs_delivered.inc();
+                dispatcher
+                    .log_recipient_responses(responses, self.client_address.as_ref())
+                    .await?;
             }
         };
 
@@ -8,7 +8,9 @@ use chrono::Utc;
 use cidr_map::{AnyIpCidr, CidrSet};
 use config::{load_config, LuaConfig};
 use data_loader::KeySource;
+use dns_resolver::SenderAuthLookup;
 use kumo_log_types::ResolvedAddress;
+use kumo_sender_auth::{dmarc, spf, ArcResult, AuthResult, AuthenticationResults, DkimResult};
 use lruttl::LruCacheWithTtl;
 use memchr::memmem::Finder;
 use message::{EnvelopeAddress, Message};
@@ -16,7 +18,7 @@ use mlua::prelude::LuaUserData;
 use mlua::ToLuaMulti;
 use once_cell::sync::{Lazy, OnceCell};
 use prometheus::IntGauge;
-use rfc5321::{AsyncReadAndWrite, BoxedAsyncReadAndWrite, Command, Response};
+use rfc5321::{AsyncReadAndWrite, BoxedAsyncReadAndWrite, Command, DsnParameters, Response};
 use rustls::ServerConfig;
 use serde::Deserialize;
 use serde_json::json;
@@ -106,6 +108,37 @@ impl TraceHeaders {
     }
 }
 
+#[derive(Deserialize, Clone, Debug)]
+pub struct SenderAuthentication {
+    /// The authserv-id used in the Authentication-Results header.
+    /// Defaults to the hostname of the listener
+    #[serde(default)]
+    pub authserv_id: Option<String>,
+
+    /// Whether to verify the DKIM signatures of the message
+    #[serde(default = "default_true")]
+    pub dkim: bool,
+
+    /// Whether to evaluate the SPF policy of the envelope sender
+    /// (or of the HELO domain, for the null sender)
+    #[serde(default = "default_true")]
+    pub spf: bool,
+
+    /// Whether to evaluate the DMARC policy of the From header domain.
+    /// Uses the DKIM and SPF results, so is only meaningful when
+    /// they are enabled
+    #[serde(default = "default_true")]
+    pub dmarc: bool,
+
+    /// Whether to validate the ARC chain of the message
+    #[serde(default = "default_true")]
+    pub arc: bool,
+
+    /// Whether to prepend an Authentication-Results header
+    #[serde(default = "default_true")]
+    pub prepend_header: bool,
+}
+
 fn default_true() -> bool {
     true
 }
@@ -132,6 +165,9 @@ pub struct EsmtpListenerParams {
     #[serde(default)]
     pub trace_headers: TraceHeaders,
 
+    #[serde(default)]
+    pub sender_authentication: Option<SenderAuthentication>,
+
     #[serde(
         default = "EsmtpListenerParams::default_client_timeout",
         with = "humantime_serde"
@@ -148,6 +184,8 @@ pub struct EsmtpListenerParams {
     max_messages_per_connection: usize,
     #[serde(default = "EsmtpListenerParams::default_max_recipients_per_message")]
     max_recipients_per_message: usize,
+    #[serde(default = "EsmtpListenerParams::default_max_recipients_per_batch")]
+    max_recipients_per_batch: usize,
 
     #[serde(default = "EsmtpListenerParams::default_max_message_size")]
     max_message_size: usize,
@@ -165,6 +203,10 @@ impl EsmtpListenerParams {
         1024
     }
 
+    fn default_max_recipients_per_batch() -> usize {
+        1
+    }
+
     fn default_max_message_size() -> usize {
         20 * 1024 * 1024
     }
@@ -349,8 +391,16 @@ pub struct SmtpServer {
 #[derive(Debug)]
 struct TransactionState {
     sender: EnvelopeAddress,
-    recipients: Vec<EnvelopeAddress>,
+    /// Each recipient together with its DSN parameters
+    recipients: Vec<(EnvelopeAddress, DsnParameters)>,
     meta: serde_json::Value,
+    /// DSN parameters from MAIL FROM
+    dsn: DsnParameters,
+    /// Set when SMTPUTF8 was passed with MAIL FROM
+    smtputf8: bool,
+    /// Accumulated payload from BDAT chunks; None until the
+    /// first BDAT command is received
+    bdat_data: Option<DebugabbleReadBuffer>,
 }
 
 pub struct RelayDisposition {
@@ -586,6 +636,62 @@ impl SmtpServer {
         }
     }
 
+    /// Read exactly chunk_size bytes of BDAT payload.
+    /// When discard is true the bytes are consumed from the client
+    /// but not retained, and ReadData::TooBig is returned.
+    #[instrument(skip(self))]
+    async fn read_chunk(&mut self, chunk_size: usize, discard: bool) -> anyhow::Result<ReadData> {
+        tracing::trace!("reading chunk of {chunk_size} bytes");
+        let mut chunk = if discard {
+            vec![]
+        } else {
+            Vec::with_capacity(chunk_size)
+        };
+        let mut remaining = chunk_size;
+        let mut data = DebugabbleReadBuffer(vec![0u8; self.params.data_buffer_size]);
+
+        loop {
+            let avail = remaining.min(self.read_buffer.len());
+            if avail > 0 {
+                if !discard {
+                    chunk.extend_from_slice(&self.read_buffer[0..avail]);
+                }
+                self.read_buffer.drain(0..avail);
+                remaining -= avail;
+            }
+
+            if remaining == 0 {
+                if discard {
+                    return Ok(ReadData::TooBig);
+                }
+                return Ok(ReadData::Data(chunk));
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(self.params.client_timeout) => {
+                    return Ok(ReadData::TimedOut);
+                }
+                size = self.socket.as_mut().unwrap().read(&mut data) => {
+                    match size {
+                        Err(err) => {
+                            tracing::trace!("error reading: {err:#}");
+                            return Ok(ReadData::Disconnected);
+                        }
+                        Ok(size) if size == 0 => {
+                            return Ok(ReadData::Disconnected);
+                        }
+                        Ok(size) => {
+                            self.read_buffer.extend_from_slice(&data[0..size]);
+                        }
+                    }
+                }
+                _ = self.shutdown.shutting_down() => {
+                    return Ok(ReadData::ShuttingDown);
+                }
+            };
+        }
+    }
+
     #[instrument(skip(self))]
     async fn read_line(&mut self, override_limit: Option<usize>) -> anyhow::Result<ReadLine> {
         let mut too_long = false;
@@ -656,6 +762,289 @@ impl SmtpServer {
         }
     }
 
+    /// Verify DKIM signatures and the ARC chain, and evaluate the SPF
+    /// and DMARC policies that apply to msg, as configured by params
+    async fn authenticate_sender(
+        &self,
+        params: &SenderAuthentication,
+        msg: &Message,
+    ) -> anyhow::Result<AuthenticationResults> {
+        let mut results = AuthenticationResults {
+            serv_id: params
+                .authserv_id
+                .clone()
+                .unwrap_or_else(|| self.params.hostname.clone()),
+            ..Default::default()
+        };
+
+        if params.dkim {
+            results.dkim = match msg.verify_dkim().await {
+                Ok(dkim) => dkim,
+                Err(err) => vec![DkimResult {
+                    result: AuthResult::PermError,
+                    domain: None,
+                    selector: None,
+                    reason: Some(format!("{err:#}")),
+                }],
+            };
+        }
+
+        if params.spf {
+            results.spf.replace(
+                spf::check_host(
+                    &SenderAuthLookup,
+                    self.peer_address.ip(),
+                    self.said_hello.as_deref().unwrap_or(""),
+                    &msg.sender()?.to_string(),
+                )
+                .await,
+            );
+        }
+
+        if params.dmarc {
+            // A missing or multi-valued From header cannot be evaluated
+            let from_domain = msg
+                .get_address_header("From")
+                .ok()
+                .flatten()
+                .and_then(|from| from.domain().ok().map(|domain| domain.to_string()));
+            if let Some(from_domain) = from_domain {
+                results.dmarc.replace(
+                    dmarc::evaluate(
+                        &SenderAuthLookup,
+                        &from_domain,
+                        results.spf.as_ref(),
+                        &results.dkim,
+                    )
+                    .await,
+                );
+            }
+        }
+
+        if params.arc {
+            results.arc.replace(match msg.verify_arc().await {
+                Ok(arc) => arc,
+                Err(err) => ArcResult {
+                    result: AuthResult::PermError,
+                    instance: 0,
+                    reason: Some(format!("{err:#}")),
+                },
+            });
+        }
+
+        Ok(results)
+    }
+
+    /// Turn the received message payload into a Message per recipient,
+    /// run the smtp_server_message_received event, then spool and queue
+    /// the results
+    async fn process_data(&mut self, state: TransactionState, data: Vec<u8>) -> anyhow::Result<()> {
+        tracing::trace!(?state);
+
+        let mut ids = vec![];
+        let mut messages = vec![];
+
+        let datestamp = Utc::now().to_rfc2822();
+
+        let batches = batch_recipients(state.recipients, self.params.max_recipients_per_batch);
+        // Computed for the first message and shared with the rest
+        // of the batches, as they all have the same content
+        let mut auth_results: Option<AuthenticationResults> = None;
+
+        for (recipients, dsn) in batches {
+            let id = SpoolId::new();
+            let protocol = "ESMTP";
+
+            let mut body = if self.params.trace_headers.received_header {
+                let received = {
+                    let from_domain = self.said_hello.as_deref().unwrap_or("unspecified");
+                    let peer_address = self.peer_address.ip();
+                    let my_address = self.my_address.ip();
+                    let hostname = &self.params.hostname;
+                    let protocol = if state.smtputf8 { "UTF8SMTP" } else { "ESMTP" };
+                    // Only name the recipient when there is exactly one,
+                    // so that recipients in a batch are not disclosed
+                    // to each other
+                    let for_recip = match recipients.as_slice() {
+                        [recip] => format!(" for <{}>", recip.to_string()),
+                        _ => String::new(),
+                    };
+                    format!(
+                        "Received: from {from_domain} ({peer_address})\r\n  \
+                           by {hostname} (KumoMTA {my_address}) \r\n  \
+                           with {protocol} id {id}{for_recip};\r\n  \
+                           {datestamp}\r\n"
+                    )
+                };
+
+                let mut body = Vec::with_capacity(data.len() + received.len());
+                body.extend_from_slice(received.as_bytes());
+                body
+            } else {
+                Vec::with_capacity(data.len())
+            };
+
+            body.extend_from_slice(&data);
+
+            let message = Message::new_dirty(
+                id,
+                state.sender.clone(),
+                recipients[0].clone(),
+                state.meta.clone(),
+                Arc::new(body.into_boxed_slice()),
+            )?;
+            if recipients.len() > 1 {
+                message.set_recipients(recipients)?;
+            }
+
+            if let Some(authz) = &self.authorization_id {
+                message.set_meta("authz_id", json!(authz))?;
+            }
+            if let Some(authn) = &self.authentication_id {
+                message.set_meta("authn_id", json!(authn))?;
+            }
+
+            if !dsn.is_empty() {
+                message.set_meta("dsn", serde_json::to_value(&dsn)?)?;
+            }
+            message.set_meta("reception_protocol", protocol)?;
+            message.set_meta("received_via", self.my_address.to_string())?;
+            message.set_meta("received_from", self.peer_address.to_string())?;
+
+            if let Some(auth) = &self.params.sender_authentication {
+                let results = match &auth_results {
+                    Some(results) => results.clone(),
+                    None => {
+                        let results = self.authenticate_sender(auth, &message).await?;
+                        auth_results.replace(results.clone());
+                        results
+                    }
+                };
+
+                // Remove any existing headers that claim to be from us;
+                // RFC 8601 section 5
+                message.retain_headers(|hdr| {
+                    !hdr.get_key_ref()
+                        .eq_ignore_ascii_case("Authentication-Results")
+                        || !hdr
+                            .get_value()
+                            .split(';')
+                            .next()
+                            .and_then(|id| id.split_whitespace().next())
+                            .map(|id| id.eq_ignore_ascii_case(&results.serv_id))
+                            .unwrap_or(false)
+                })?;
+                if auth.prepend_header {
+                    message.prepend_header(Some("Authentication-Results"), &results.header_value());
+                }
+                message.set_meta("authentication_results", serde_json::to_value(&results)?)?;
+            }
+
+            if let Err(rej) = self
+                .call_callback::<(), _, _>("smtp_server_message_received", message.clone())
+                .await?
+            {
+                self.write_response(rej.code, rej.message).await?;
+                continue;
+            }
+
+            if self.params.trace_headers.supplemental_header {
+                let mut object = json!({
+                    // Marker to identify encoded supplemental header
+                    "_@_": "\\_/",
+                });
+                // A batch lists all of its recipients, so that a feedback
+                // report can be matched to the recipient that it is about
+                match message.recipients()?.as_slice() {
+                    [recipient] => {
+                        object
+                            .as_object_mut()
+                            .unwrap()
+                            .insert("recipient".to_string(), json!(recipient));
+                    }
+                    recipients => {
+                        object
+                            .as_object_mut()
+                            .unwrap()
+                            .insert("recipients".to_string(), json!(recipients));
+                    }
+                }
+
+                for name in &self.params.trace_headers.include_meta_names {
+                    if let Ok(value) = message.get_meta(name) {
+                        object
+                            .as_object_mut()
+                            .unwrap()
+                            .insert(name.to_string(), value);
+                    }
+                }
+
+                let value = base64::encode(serde_json::to_string(&object)?);
+                message.prepend_header(Some(&self.params.trace_headers.header_name), &value);
+            }
+
+            ids.push(message.id().to_string());
+
+            let queue_name = message.get_queue_name()?;
+
+            let relay_disposition = self
+                .check_relaying(&message.sender()?, &message.recipient()?)
+                .await?;
+
+            if queue_name != "null" {
+                if relay_disposition.relay && !self.params.deferred_spool {
+                    message.save().await?;
+                }
+            }
+            log_disposition(LogDisposition {
+                kind: RecordType::Reception,
+                msg: message.clone(),
+                recipient: None,
+                site: "",
+                peer_address: Some(&ResolvedAddress {
+                    name: self.said_hello.as_deref().unwrap_or("").to_string(),
+                    addr: self.peer_address.ip(),
+                }),
+                response: Response {
+                    code: 250,
+                    enhanced_code: None,
+                    command: None,
+                    content: "".to_string(),
+                },
+                egress_pool: None,
+                egress_source: None,
+                relay_disposition: None,
+                delivery_protocol: None,
+            })
+            .await;
+            if queue_name != "null" {
+                if relay_disposition.relay {
+                    messages.push((queue_name, message));
+                }
+            }
+        }
+
+        if !messages.is_empty() {
+            spawn_local(
+                format!(
+                    "SmtpServer: insert {} msgs for {:?}",
+                    messages.len(),
+                    self.peer_address
+                ),
+                async move {
+                    for (queue_name, msg) in messages {
+                        QueueManager::insert(&queue_name, msg).await?;
+                    }
+                    Ok::<(), anyhow::Error>(())
+                },
+            )?;
+        }
+
+        let ids = ids.join(" ");
+        self.write_response(250, format!("OK ids={ids}")).await?;
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     async fn process(&mut self) -> anyhow::Result<()> {
         let _activity = match Activity::get_opt() {
@@ -902,11 +1291,19 @@ impl SmtpServer {
                         continue;
                     }
 
-                    let mut extensions = vec!["PIPELINING", "ENHANCEDSTATUSCODES"];
+                    let mut extensions = vec![
+                        "PIPELINING".to_string(),
+                        "ENHANCEDSTATUSCODES".to_string(),
+                        "DSN".to_string(),
+                        format!("SIZE {}", self.params.max_message_size),
+                        "8BITMIME".to_string(),
+                        "SMTPUTF8".to_string(),
+                        "CHUNKING".to_string(),
+                    ];
                     if !self.tls_active {
-                        extensions.push("STARTTLS");
+                        extensions.push("STARTTLS".to_string());
                     } else {
-                        extensions.push("AUTH PLAIN");
+                        extensions.push("AUTH PLAIN".to_string());
                     }
 
                     self.write_response(
@@ -935,7 +1332,7 @@ impl SmtpServer {
                 }
                 Ok(Command::MailFrom {
                     address,
-                    parameters: _,
+                    parameters,
                 }) => {
                     if self.state.is_some() {
                         self.write_response(
@@ -946,7 +1343,78 @@ impl SmtpServer {
                         continue;
                     }
 
-                    let address = EnvelopeAddress::parse(&address.to_string())?;
+                    let mut smtputf8 = false;
+                    let mut meta = serde_json::Map::new();
+                    let mut param_error = None;
+
+                    for param in &parameters {
+                        match (param.name.to_ascii_uppercase().as_str(), &param.value) {
+                            ("SIZE", Some(value)) => match value.parse::<usize>() {
+                                Ok(size) if size > self.params.max_message_size => {
+                                    param_error.replace((
+                                        552,
+                                        "5.3.4 message size exceeds fixed maximum message size"
+                                            .to_string(),
+                                    ));
+                                }
+                                Ok(_) => {}
+                                Err(_) => {
+                                    param_error.replace((
+                                        501,
+                                        format!("5.5.4 invalid SIZE parameter {value}"),
+                                    ));
+                                }
+                            },
+                            ("BODY", Some(value)) => {
+                                let body_type = value.to_ascii_uppercase();
+                                match body_type.as_str() {
+                                    "7BIT" | "8BITMIME" => {
+                                        meta.insert("body_type".to_string(), json!(body_type));
+                                    }
+                                    _ => {
+                                        param_error.replace((
+                                            501,
+                                            format!("5.5.4 unsupported BODY type {value}"),
+                                        ));
+                                    }
+                                }
+                            }
+                            ("SMTPUTF8", None) => {
+                                smtputf8 = true;
+                                meta.insert("smtputf8".to_string(), json!(true));
+                            }
+                            ("SIZE" | "BODY" | "SMTPUTF8", _) => {
+                                param_error.replace((
+                                    501,
+                                    format!("5.5.4 invalid parameter {}", param.to_string()),
+                                ));
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    if let Some((code, message)) = param_error {
+                        self.write_response(code, message).await?;
+                        continue;
+                    }
+
+                    let mut dsn = DsnParameters::default();
+                    if let Err(err) = dsn.parse_mail_parameters(&parameters) {
+                        self.write_response(501, format!("5.5.4 {err}")).await?;
+                        continue;
+                    }
+
+                    let address = address.to_string();
+                    if !smtputf8 && !address.is_ascii() {
+                        self.write_response(
+                            553,
+                            "5.6.7 non-ASCII address requires the SMTPUTF8 parameter",
+                        )
+                        .await?;
+                        continue;
+                    }
+
+                    let address = EnvelopeAddress::parse(&address)?;
                     if let Err(rej) = self
                         .call_callback::<(), _, _>("smtp_server_mail_from", address.clone())
                         .await?
@@ -958,15 +1426,42 @@ impl SmtpServer {
                     self.state.replace(TransactionState {
                         sender: address.clone(),
                         recipients: vec![],
-                        meta: serde_json::json!({}),
+                        meta: serde_json::Value::Object(meta),
+                        dsn,
+                        smtputf8,
+                        bdat_data: None,
                     });
                     self.write_response(250, format!("OK {address:?}")).await?;
                 }
                 Ok(Command::RcptTo {
                     address,
-                    parameters: _,
+                    parameters,
                 }) => {
-                    let address = EnvelopeAddress::parse(&address.to_string())?;
+                    let mut dsn = match &self.state {
+                        Some(state) => state.dsn.clone(),
+                        None => {
+                            self.write_response(503, "5.5.0 MAIL FROM must be issued first")
+                                .await?;
+                            continue;
+                        }
+                    };
+                    if let Err(err) = dsn.parse_rcpt_parameters(&parameters) {
+                        self.write_response(501, format!("5.5.4 {err}")).await?;
+                        continue;
+                    }
+
+                    let address = address.to_string();
+                    if !address.is_ascii()
+                        && !self.state.as_ref().map(|s| s.smtputf8).unwrap_or(false)
+                    {
+                        self.write_response(
+                            553,
+                            "5.6.7 non-ASCII address requires the SMTPUTF8 parameter",
+                        )
+                        .await?;
+                        continue;
+                    }
+                    let address = EnvelopeAddress::parse(&address)?;
 
                     let sender = self.state.as_ref().unwrap().sender.clone();
                     let relay_disposition = self.check_relaying(&sender, &address).await?;
@@ -1022,7 +1517,7 @@ impl SmtpServer {
                         .as_mut()
                         .expect("checked state above")
                         .recipients
-                        .push(address);
+                        .push((address, dsn));
                 }
                 Ok(Command::Data) => {
                     if self.state.is_none() {
@@ -1040,6 +1535,16 @@ impl SmtpServer {
                             .await?;
                         continue;
                     }
+                    if self
+                        .state
+                        .as_ref()
+                        .map(|s| s.bdat_data.is_some())
+                        .unwrap_or(false)
+                    {
+                        self.write_response(503, "5.5.1 DATA cannot be mixed with BDAT")
+                            .await?;
+                        continue;
+                    }
 
                     self.write_response(354, "Send body; end with CRLF.CRLF")
                         .await?;
@@ -1078,154 +1583,84 @@ impl SmtpServer {
                         .take()
                         .ok_or_else(|| anyhow!("transaction state is impossibly not set!?"))?;
 
-                    tracing::trace!(?state);
-
-                    let mut ids = vec![];
-                    let mut messages = vec![];
-
-                    let datestamp = Utc::now().to_rfc2822();
-
-                    for recip in state.recipients {
-                        let id = SpoolId::new();
-                        let protocol = "ESMTP";
-
-                        let mut body = if self.params.trace_headers.received_header {
-                            let received = {
-                                let from_domain =
-                                    self.said_hello.as_deref().unwrap_or("unspecified");
-                                let peer_address = self.peer_address.ip();
-                                let my_address = self.my_address.ip();
-                                let hostname = &self.params.hostname;
-                                let protocol = "ESMTP";
-                                let recip = recip.to_string();
-                                format!(
-                                    "Received: from {from_domain} ({peer_address})\r\n  \
-                                       by {hostname} (KumoMTA {my_address}) \r\n  \
-                                       with {protocol} id {id} for <{recip}>;\r\n  \
-                                       {datestamp}\r\n"
-                                )
-                            };
+                    self.process_data(state, data).await?;
+                }
+                Ok(Command::Bdat { chunk_size, last }) => {
+                    // The chunk payload must always be consumed, even when
+                    // we are going to reject it, in order to stay in sync
+                    // with the client
+                    let accepting = self
+                        .state
+                        .as_ref()
+                        .map(|s| !s.recipients.is_empty())
+                        .unwrap_or(false);
+                    let received_so_far = self
+                        .state
+                        .as_ref()
+                        .and_then(|s| s.bdat_data.as_ref())
+                        .map(|d| d.len())
+                        .unwrap_or(0);
+                    let too_big =
+                        received_so_far.saturating_add(chunk_size) > self.params.max_message_size;
 
-                            let mut body = Vec::with_capacity(data.len() + received.len());
-                            body.extend_from_slice(received.as_bytes());
-                            body
-                        } else {
-                            Vec::with_capacity(data.len())
-                        };
-
-                        body.extend_from_slice(&data);
-
-                        let message = Message::new_dirty(
-                            id,
-                            state.sender.clone(),
-                            recip,
-                            state.meta.clone(),
-                            Arc::new(body.into_boxed_slice()),
-                        )?;
-
-                        if let Some(authz) = &self.authorization_id {
-                            message.set_meta("authz_id", json!(authz))?;
-                        }
-                        if let Some(authn) = &self.authentication_id {
-                            message.set_meta("authn_id", json!(authn))?;
+                    let chunk = match self.read_chunk(chunk_size, !accepting || too_big).await? {
+                        ReadData::Disconnected => return Ok(()),
+                        ReadData::Data(chunk) => chunk,
+                        ReadData::TooBig | ReadData::TooLong => {
+                            if !accepting {
+                                self.write_response(
+                                    503,
+                                    "5.5.1 MAIL FROM and RCPT TO must be issued first",
+                                )
+                                .await?;
+                            } else {
+                                self.state.take();
+                                self.write_response(552, "5.3.4 message too big").await?;
+                            }
+                            continue;
                         }
-
-                        message.set_meta("reception_protocol", protocol)?;
-                        message.set_meta("received_via", self.my_address.to_string())?;
-                        message.set_meta("received_from", self.peer_address.to_string())?;
-
-                        if let Err(rej) = self
-                            .call_callback::<(), _, _>(
-                                "smtp_server_message_received",
-                                message.clone(),
+                        ReadData::TimedOut => {
+                            self.write_response(
+                                421,
+                                format!("4.3.2 {} idle too long", self.params.hostname),
                             )
-                            .await?
-                        {
-                            self.write_response(rej.code, rej.message).await?;
-                            continue;
+                            .await?;
+                            return Ok(());
                         }
-
-                        if self.params.trace_headers.supplemental_header {
-                            let mut object = json!({
-                                // Marker to identify encoded supplemental header
-                                "_@_": "\\_/",
-                                "recipient": message.recipient()?,
-                            });
-
-                            for name in &self.params.trace_headers.include_meta_names {
-                                if let Ok(value) = message.get_meta(name) {
-                                    object
-                                        .as_object_mut()
-                                        .unwrap()
-                                        .insert(name.to_string(), value);
-                                }
-                            }
-
-                            let value = base64::encode(serde_json::to_string(&object)?);
-                            message.prepend_header(
-                                Some(&self.params.trace_headers.header_name),
-                                &value,
-                            );
+                        ReadData::ShuttingDown => {
+                            self.write_response(
+                                421,
+                                format!("4.3.2 {} shutting down", self.params.hostname),
+                            )
+                            .await?;
+                            return Ok(());
                         }
+                    };
 
-                        ids.push(message.id().to_string());
-
-                        let queue_name = message.get_queue_name()?;
+                    let state = self.state.as_mut().expect("checked accepting above");
+                    state
+                        .bdat_data
+                        .get_or_insert_with(|| DebugabbleReadBuffer(vec![]))
+                        .extend_from_slice(&chunk);
 
-                        let relay_disposition = self
-                            .check_relaying(&message.sender()?, &message.recipient()?)
+                    if !last {
+                        self.write_response(250, format!("2.0.0 {chunk_size} octets received"))
                             .await?;
-
-                        if queue_name != "null" {
-                            if relay_disposition.relay && !self.params.deferred_spool {
-                                message.save().await?;
-                            }
-                        }
-                        log_disposition(LogDisposition {
-                            kind: RecordType::Reception,
-                            msg: message.clone(),
-                            site: "",
-                            peer_address: Some(&ResolvedAddress {
-                                name: self.said_hello.as_deref().unwrap_or("").to_string(),
-                                addr: self.peer_address.ip(),
-                            }),
-                            response: Response {
-                                code: 250,
-                                enhanced_code: None,
-                                command: None,
-                                content: "".to_string(),
-                            },
-                            egress_pool: None,
-                            egress_source: None,
-                            relay_disposition: None,
-                            delivery_protocol: None,
-                        })
-                        .await;
-                        if queue_name != "null" {
-                            if relay_disposition.relay {
-                                messages.push((queue_name, message));
-                            }
-                        }
+                        continue;
                     }
 
-                    if !messages.is_empty() {
-                        spawn_local(
-                            format!(
-                                "SmtpServer: insert {} msgs for {:?}",
-                                messages.len(),
-                                self.peer_address
-                            ),
-                            async move {
-                                for (queue_name, msg) in messages {
-                                    QueueManager::insert(&queue_name, msg).await?;
-                                }
-                                Ok::<(), anyhow::Error>(())
-                            },
-                        )?;
+                    let mut state = self
+                        .state
+                        .take()
+                        .ok_or_else(|| anyhow!("transaction state is impossibly not set!?"))?;
+                    let data = state.bdat_data.take().map(|d| d.0).unwrap_or_default();
+
+                    if !check_line_lengths(&data, MAX_LINE_LEN) {
+                        self.write_response(500, "5.2.3 line too long").await?;
+                        continue;
                     }
 
-                    let ids = ids.join(" ");
-                    self.write_response(250, format!("OK ids={ids}")).await?;
+                    self.process_data(state, data).await?;
                 }
                 Ok(Command::Rset) => {
                     self.state.take();
@@ -1234,7 +1669,7 @@ impl SmtpServer {
                 Ok(Command::Noop(_)) => {
                     self.write_response(250, "the goggles do nothing").await?;
                 }
-                Ok(Command::Vrfy(_) | Command::Expn(_) | Command::Help(_)) => {
+                Ok(Command::Vrfy(_) | Command::Expn(_) | Command::Help(_) | Command::Lhlo(_)) => {
                     self.write_response(502, format!("5.5.1 Command unimplemented"))
                         .await?;
                 }
@@ -1295,10 +1730,79 @@ fn check_line_lengths(data: &[u8], limit: usize) -> bool {
     data.len() - last_index <= limit
 }
 
+/// Group recipients that share a domain and DSN parameters, so that
+/// they can be stored and delivered as a single message.
+/// Each batch holds at most max_batch recipients.
+fn batch_recipients(
+    recipients: Vec<(EnvelopeAddress, DsnParameters)>,
+    max_batch: usize,
+) -> Vec<(Vec<EnvelopeAddress>, DsnParameters)> {
+    let mut batches: Vec<(Vec<EnvelopeAddress>, DsnParameters)> = vec![];
+    for (recipient, dsn) in recipients {
+        let batch = batches.iter_mut().find(|(batch, batch_dsn)| {
+            batch.len() < max_batch
+                && *batch_dsn == dsn
+                && batch[0].domain().eq_ignore_ascii_case(recipient.domain())
+        });
+        match batch {
+            Some((batch, _)) => batch.push(recipient),
+            None => batches.push((vec![recipient], dsn)),
+        }
+    }
+    batches
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn recipient_batching() {
+        let recip = |s: &str| EnvelopeAddress::parse(s).unwrap();
+        let never = DsnParameters {
+            notify: Some(vec![rfc5321::DsnNotify::Never]),
+            ..Default::default()
+        };
+        let recipients = vec![
+            (recip("a@one.example.com"), DsnParameters::default()),
+            (recip("b@two.example.com"), DsnParameters::default()),
+            (recip("c@ONE.example.com"), DsnParameters::default()),
+            (recip("d@one.example.com"), never.clone()),
+            (recip("e@one.example.com"), DsnParameters::default()),
+        ];
+
+        assert_eq!(batch_recipients(recipients.clone(), 1).len(), 5);
+
+        assert_eq!(
+            batch_recipients(recipients.clone(), 2),
+            vec![
+                (
+                    vec![recip("a@one.example.com"), recip("c@ONE.example.com")],
+                    DsnParameters::default()
+                ),
+                (vec![recip("b@two.example.com")], DsnParameters::default()),
+                (vec![recip("d@one.example.com")], never.clone()),
+                (vec![recip("e@one.example.com")], DsnParameters::default()),
+            ]
+        );
+
+        assert_eq!(
+            batch_recipients(recipients, 10),
+            vec![
+                (
+                    vec![
+                        recip("a@one.example.com"),
+                        recip("c@ONE.example.com"),
+                        recip("e@one.example.com")
+                    ],
+                    DsnParameters::default()
+                ),
+                (vec![recip("b@two.example.com")], DsnParameters::default()),
+                (vec![recip("d@one.example.com")], never),
+            ]
+        );
+    }
+
     #[test]
     fn unstuffer() {
         let stuffed = b"hello\r\n..dot\r\nthere\r\n..more dot".to_vec();
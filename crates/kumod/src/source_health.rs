@@ -0,0 +1,257 @@
+//! Tracks the health of each egress source when sending to each site,
+//! so that the sources that are failing to connect, or that are being
+//! blocked, are given less of the traffic of their pool.
+use crate::logging::classify_response;
+use bounce_classify::BounceClass;
+use prometheus::IntGaugeVec;
+use rfc5321::Response;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+lazy_static::lazy_static! {
+    static ref HEALTH: Mutex<HashMap<String, SourceHealth>> = Mutex::new(HashMap::new());
+    static ref EFFECTIVE_WEIGHT: IntGaugeVec = {
+        prometheus::register_int_gauge_vec!(
+            "egress_source_effective_weight",
+            "the weight of a degraded egress source in its pool, after adjusting for its health",
+            &["pool", "service"]).unwrap()
+    };
+}
+
+/// Connection failures that are older than this are forgotten
+const FAILURE_MEMORY: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Default)]
+struct SourceHealth {
+    connection_failures: u32,
+    last_failure: Option<Instant>,
+    blocked_until: Option<Instant>,
+    /// The pools for which the effective weight is exported
+    published_pools: HashSet<String>,
+}
+
+impl SourceHealth {
+    fn recent_failures(&self, now: Instant) -> u32 {
+        match self.last_failure {
+            Some(last) if now.duration_since(last) < FAILURE_MEMORY => self.connection_failures,
+            _ => 0,
+        }
+    }
+
+    fn is_blocked(&self, now: Instant) -> bool {
+        self.blocked_until.map(|until| until > now).unwrap_or(false)
+    }
+
+    /// Returns true if the entry no longer has any effect
+    fn is_stale(&self, now: Instant) -> bool {
+        !self.is_blocked(now) && self.recent_failures(now) == 0
+    }
+
+    fn weight(&self, weight: u32, now: Instant) -> u32 {
+        if self.is_blocked(now) {
+            return 0;
+        }
+        match self.recent_failures(now) {
+            0 => weight,
+            // Halve the weight for each consecutive failure, but keep
+            // sending a little traffic so that recovery is noticed
+            failures => (weight >> failures.min(31)).max(1),
+        }
+    }
+}
+
+/// Discards the entries that no longer have any effect,
+/// along with their exported weights
+fn prune(health: &mut HashMap<String, SourceHealth>, now: Instant) {
+    health.retain(|name, entry| {
+        if !entry.is_stale(now) {
+            return true;
+        }
+        for pool in &entry.published_pools {
+            remove_effective_weight(pool, name);
+        }
+        false
+    });
+}
+
+/// Updates the health table, discarding entries that no longer
+/// have any effect
+fn update<F: FnOnce(&mut SourceHealth)>(name: &str, func: F) {
+    let mut health = HEALTH.lock().unwrap();
+    prune(&mut health, Instant::now());
+    func(health.entry(name.to_string()).or_default());
+}
+
+/// Records that no connection could be made for the named ready queue
+pub fn record_connection_failure(name: &str) {
+    update(name, |entry| {
+        entry.connection_failures += 1;
+        entry.last_failure.replace(Instant::now());
+    });
+}
+
+/// Records that a connection was made for the named ready queue
+pub fn record_connection_success(name: &str) {
+    let mut health = HEALTH.lock().unwrap();
+    if let Some(entry) = health.get_mut(name) {
+        entry.connection_failures = 0;
+        entry.last_failure.take();
+    }
+}
+
+/// Checks a response from the remote host; if the bounce classifier
+/// considers it to be a block of the source, the named ready queue is
+/// excluded from its pool for block_duration
+pub fn record_response(name: &str, response: &Response, block_duration: Duration) {
+    if classify_response(response) != Some(BounceClass::SpamBlock) {
+        return;
+    }
+    tracing::info!(
+        "excluding {name} from its pool for {block_duration:?} because {:?} \
+         was classified as a block",
+        response.to_single_line()
+    );
+    update(name, |entry| {
+        entry.blocked_until.replace(Instant::now() + block_duration);
+    });
+}
+
+/// Returns the weights of the named ready queues, adjusted for their health.
+/// If every one of them would be excluded, the configured weights are used.
+pub fn effective_weights(names: &[String], weights: &[u32]) -> Vec<u32> {
+    let mut health = HEALTH.lock().unwrap();
+    let now = Instant::now();
+    prune(&mut health, now);
+    let effective: Vec<u32> = names
+        .iter()
+        .zip(weights)
+        .map(|(name, &weight)| match health.get(name) {
+            Some(entry) => entry.weight(weight, now),
+            None => weight,
+        })
+        .collect();
+    if effective.iter().all(|&weight| weight == 0) {
+        weights.to_vec()
+    } else {
+        effective
+    }
+}
+
+/// Exports the effective weight of the named ready queue within pool
+/// while it differs from the configured weight.  Only degraded sources
+/// are exported, so that there isn't a series for every destination site.
+pub fn publish_effective_weight(pool: &str, name: &str, configured: u32, weight: u32) {
+    let mut health = HEALTH.lock().unwrap();
+    match health.get_mut(name) {
+        Some(entry) if weight != configured => {
+            EFFECTIVE_WEIGHT
+                .with_label_values(&[pool, format!("smtp_client:{name}").as_str()])
+                .set(weight as i64);
+            entry.published_pools.insert(pool.to_string());
+        }
+        Some(entry) if entry.published_pools.contains(pool) => {
+            entry.published_pools.remove(pool);
+            remove_effective_weight(pool, name);
+        }
+        _ => {}
+    }
+}
+
+fn remove_effective_weight(pool: &str, name: &str) {
+    EFFECTIVE_WEIGHT
+        .remove_label_values(&[pool, format!("smtp_client:{name}").as_str()])
+        .ok();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn health_weight() {
+        let now = Instant::now();
+        let mut entry = SourceHealth::default();
+        assert_eq!(entry.weight(8, now), 8);
+
+        entry.connection_failures = 2;
+        entry.last_failure.replace(now);
+        assert_eq!(entry.weight(8, now), 2);
+        assert_eq!(entry.weight(1, now), 1);
+        // Old failures are forgotten
+        assert_eq!(entry.weight(8, now + FAILURE_MEMORY), 8);
+
+        entry.blocked_until.replace(now + Duration::from_secs(60));
+        assert_eq!(entry.weight(8, now), 0);
+        assert_eq!(entry.weight(8, now + Duration::from_secs(60)), 2);
+    }
+
+    #[test]
+    fn all_excluded() {
+        let names = vec![
+            "test-all-excluded-one->example.com".to_string(),
+            "test-all-excluded-two->example.com".to_string(),
+        ];
+        for name in &names {
+            update(name, |entry| {
+                entry
+                    .blocked_until
+                    .replace(Instant::now() + Duration::from_secs(60));
+            });
+        }
+        assert_eq!(effective_weights(&names, &[3, 1]), vec![3, 1]);
+
+        record_connection_failure(&names[0]);
+        update(&names[1], |entry| {
+            entry.blocked_until.take();
+        });
+        assert_eq!(effective_weights(&names, &[3, 1]), vec![0, 1]);
+    }
+
+    #[test]
+    fn published_weight_is_removed() {
+        let name = "test-published-weight->example.com";
+        let exported = || {
+            EFFECTIVE_WEIGHT
+                .get_metric_with_label_values(&["pool", &format!("smtp_client:{name}")])
+                .map(|gauge| gauge.get())
+                .unwrap()
+        };
+        let is_exported = || {
+            prometheus::gather()
+                .iter()
+                .filter(|family| family.get_name() == "egress_source_effective_weight")
+                .flat_map(|family| family.get_metric())
+                .any(|metric| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .any(|label| label.get_value() == format!("smtp_client:{name}"))
+                })
+        };
+
+        // Healthy sources are not exported
+        publish_effective_weight("pool", name, 4, 4);
+        assert!(!is_exported());
+
+        record_connection_failure(name);
+        publish_effective_weight("pool", name, 4, 2);
+        assert!(is_exported());
+        assert_eq!(exported(), 2);
+
+        // Once it recovers, the series is removed
+        record_connection_success(name);
+        publish_effective_weight("pool", name, 4, 4);
+        assert!(!is_exported());
+
+        // and likewise when the entry is pruned
+        record_connection_failure(name);
+        publish_effective_weight("pool", name, 4, 2);
+        assert!(is_exported());
+        {
+            let mut health = HEALTH.lock().unwrap();
+            prune(&mut health, Instant::now() + FAILURE_MEMORY);
+        }
+        assert!(!is_exported());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::http_server::admin_rules::load_admin_rules;
 use crate::lifecycle::Activity;
 use crate::logging::{log_disposition, LogDisposition, RecordType};
 use crate::mod_kumo::{DefineSpoolParams, SpoolKind};
@@ -10,6 +11,7 @@ use spool::local_disk::LocalDiskSpool;
 use spool::rocks::RocksSpool;
 use spool::{Spool as SpoolTrait, SpoolEntry, SpoolId};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex as StdMutex};
 use std::time::Duration;
 use tokio::sync::{Mutex, MutexGuard};
@@ -54,6 +56,7 @@ impl Spool {}
 pub struct SpoolManager {
     named: HashMap<String, SpoolHandle>,
     spooled_in: bool,
+    meta_path: Option<PathBuf>,
 }
 
 impl SpoolManager {
@@ -61,6 +64,7 @@ impl SpoolManager {
         Self {
             named: HashMap::new(),
             spooled_in: false,
+            meta_path: None,
         }
     }
 
@@ -74,6 +78,9 @@ impl SpoolManager {
             params.name,
             params.path.display()
         );
+        if params.name == "meta" {
+            self.meta_path.replace(params.path.clone());
+        }
         self.named.insert(
             params.name.to_string(),
             SpoolHandle(Arc::new(Spool {
@@ -190,6 +197,12 @@ impl SpoolManager {
         // otherwise we'll deadlock ourselves in the loop below
         drop(tx);
 
+        // Restore the admin bounce and suspend rules before any
+        // messages are inserted into the queues
+        if let Some(path) = &self.meta_path {
+            load_admin_rules(path).context("loading admin rules")?;
+        }
+
         let activity = Activity::get()?;
         let egress_source = None;
         let egress_pool = None;
@@ -233,6 +246,7 @@ impl SpoolManager {
                                             log_disposition(LogDisposition {
                                                 kind: RecordType::Expiration,
                                                 msg,
+                                                recipient: None,
                                                 site: "localhost",
                                                 peer_address: None,
                                                 response: Response {
@@ -277,6 +291,7 @@ impl SpoolManager {
                                 log_disposition(LogDisposition {
                                     kind: RecordType::Expiration,
                                     msg,
+                                    recipient: None,
                                     site: "localhost",
                                     peer_address: None,
                                     response: Response {
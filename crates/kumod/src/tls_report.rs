@@ -0,0 +1,442 @@
+//! Aggregates the outcome of outbound TLS negotiations into
+//! RFC 8460 SMTP TLS Reporting aggregate reports
+use crate::queue::QueueManager;
+use crate::runtime::{rt_spawn_non_blocking, spawn};
+use chrono::{DateTime, TimeZone, Utc};
+use config::load_config;
+use kumo_log_types::rfc8460::{
+    DateRange, FailureDetails, PolicyDetails, PolicyReport, Report, ReportMessageParams,
+    ResultType, Summary,
+};
+use kumo_log_types::ResolvedAddress;
+use message::{EnvelopeAddress, Message};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use serde_json::json;
+use spool::SpoolId;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+
+static PARAMS: OnceCell<TlsReportingParams> = OnceCell::new();
+
+lazy_static::lazy_static! {
+    static ref SESSIONS: StdMutex<HashMap<PolicyDetails, SessionCounts>> =
+        StdMutex::new(HashMap::new());
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct TlsReportingParams {
+    /// The host name used in the Message-ID and the default
+    /// From header and contact info
+    #[serde(default = "TlsReportingParams::default_reporting_mta")]
+    pub reporting_mta: String,
+
+    /// The organization-name of the report, which is also used
+    /// as the submitter. Defaults to the reporting_mta.
+    #[serde(default)]
+    pub organization_name: Option<String>,
+
+    /// The contact-info of the report.
+    /// Defaults to `postmaster@` the reporting_mta
+    #[serde(default)]
+    pub contact_info: Option<String>,
+
+    /// The From header of reports that are delivered by email.
+    /// Defaults to `postmaster@` the reporting_mta
+    #[serde(default)]
+    pub from: Option<String>,
+
+    /// The length of each reporting period
+    #[serde(
+        default = "TlsReportingParams::default_interval",
+        with = "humantime_serde"
+    )]
+    pub interval: Duration,
+
+    /// If set, each report is written as a JSON file in this directory
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+
+    /// Whether to send reports to the mailto: addresses
+    /// published by the policy domain
+    #[serde(default)]
+    pub deliver: bool,
+
+    /// Which queue to place delivered reports into. If unset,
+    /// the queue is derived from the envelope recipient as usual.
+    #[serde(default)]
+    pub queue: Option<String>,
+}
+
+impl TlsReportingParams {
+    fn default_reporting_mta() -> String {
+        gethostname::gethostname()
+            .to_str()
+            .unwrap_or("localhost")
+            .to_string()
+    }
+
+    fn default_interval() -> Duration {
+        Duration::from_secs(86400)
+    }
+
+    fn organization_name(&self) -> &str {
+        self.organization_name
+            .as_deref()
+            .unwrap_or(&self.reporting_mta)
+    }
+
+    pub fn register(self) -> anyhow::Result<()> {
+        let interval = self.interval.as_secs().max(1);
+        PARAMS
+            .set(self)
+            .map_err(|_| anyhow::anyhow!("TLS reporting already configured"))?;
+        spawn("tls reporting", async move { report_task(interval).await })?;
+        Ok(())
+    }
+}
+
+#[derive(Default, Debug)]
+struct SessionCounts {
+    successful: u64,
+    failures: Vec<FailureDetails>,
+}
+
+/// Returns true if TLS reporting has been configured
+pub fn is_enabled() -> bool {
+    PARAMS.get().is_some()
+}
+
+/// Record the outcome of a session governed by policy;
+/// failure is None if TLS was successfully negotiated
+pub fn record_session(policy: PolicyDetails, failure: Option<FailureDetails>) {
+    if !is_enabled() {
+        return;
+    }
+    let mut sessions = SESSIONS.lock().unwrap();
+    let counts = sessions.entry(policy).or_default();
+    let failure = match failure {
+        Some(failure) => failure,
+        None => {
+            counts.successful += 1;
+            return;
+        }
+    };
+
+    match counts.failures.iter_mut().find(|existing| {
+        existing.result_type == failure.result_type
+            && existing.sending_mta_ip == failure.sending_mta_ip
+            && existing.receiving_mx_hostname == failure.receiving_mx_hostname
+            && existing.receiving_ip == failure.receiving_ip
+    }) {
+        Some(existing) => {
+            existing.failed_session_count += failure.failed_session_count;
+            existing.additional_information = failure.additional_information;
+        }
+        None => counts.failures.push(failure),
+    }
+}
+
+/// Describe a single failed session
+pub fn failure_details(
+    result_type: ResultType,
+    sending_mta_ip: Option<IpAddr>,
+    receiving: Option<&ResolvedAddress>,
+    additional_information: String,
+) -> FailureDetails {
+    FailureDetails {
+        result_type,
+        sending_mta_ip,
+        receiving_mx_hostname: receiving.map(|addr| addr.name.to_string()),
+        receiving_mx_helo: None,
+        receiving_ip: receiving.map(|addr| addr.addr),
+        failed_session_count: 1,
+        additional_information: Some(additional_information),
+        failure_reason_code: None,
+    }
+}
+
+/// Classify the text of a TLS handshake error
+pub fn classify_handshake_error(error: &str) -> ResultType {
+    if error.contains("CertExpired") || error.contains("CertNotValidYet") {
+        ResultType::CertificateExpired
+    } else if error.contains("CertNotValidForName") {
+        ResultType::CertificateHostMismatch
+    } else if error.contains("UnknownIssuer") {
+        ResultType::CertificateNotTrusted
+    } else {
+        ResultType::ValidationFailure
+    }
+}
+
+/// Group the recorded sessions into a report per policy domain
+fn build_reports(
+    sessions: HashMap<PolicyDetails, SessionCounts>,
+    params: &TlsReportingParams,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Vec<(String, Report)> {
+    let mut by_domain: HashMap<String, Vec<PolicyReport>> = HashMap::new();
+    for (policy, counts) in sessions {
+        let total_failure_session_count = counts
+            .failures
+            .iter()
+            .map(|failure| failure.failed_session_count)
+            .sum();
+        by_domain
+            .entry(policy.policy_domain.to_string())
+            .or_default()
+            .push(PolicyReport {
+                policy,
+                summary: Summary {
+                    total_successful_session_count: counts.successful,
+                    total_failure_session_count,
+                },
+                failure_details: counts.failures,
+            });
+    }
+
+    let mut reports: Vec<(String, Report)> = by_domain
+        .into_iter()
+        .map(|(domain, policies)| {
+            let report = Report {
+                organization_name: params.organization_name().to_string(),
+                date_range: DateRange {
+                    start_datetime: start,
+                    end_datetime: end,
+                },
+                contact_info: params
+                    .contact_info
+                    .clone()
+                    .unwrap_or_else(|| format!("postmaster@{}", params.reporting_mta)),
+                report_id: uuid::Uuid::new_v4().to_string(),
+                policies,
+            };
+            (domain, report)
+        })
+        .collect();
+    reports.sort_by(|a, b| a.0.cmp(&b.0));
+    reports
+}
+
+async fn report_task(interval: u64) {
+    let interval = interval as i64;
+    loop {
+        let now = Utc::now().timestamp();
+        let start = now - now.rem_euclid(interval);
+        let end = start + interval;
+        tokio::time::sleep(Duration::from_secs((end - now) as u64)).await;
+
+        let sessions = std::mem::take(&mut *SESSIONS.lock().unwrap());
+        if sessions.is_empty() {
+            continue;
+        }
+        let (start, end) = match (
+            Utc.timestamp_opt(start, 0).single(),
+            Utc.timestamp_opt(end - 1, 0).single(),
+        ) {
+            (Some(start), Some(end)) => (start, end),
+            _ => continue,
+        };
+
+        if let Err(err) = rt_spawn_non_blocking("emit tls reports".to_string(), move || {
+            Ok(async move {
+                if let Err(err) = emit_reports(sessions, start, end).await {
+                    tracing::error!("error emitting TLS reports: {err:#}");
+                }
+            })
+        }) {
+            tracing::error!("failed to spawn TLS report emitter: {err:#}");
+        }
+    }
+}
+
+async fn emit_reports(
+    sessions: HashMap<PolicyDetails, SessionCounts>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let params = PARAMS.get().expect("TLS reporting to be configured");
+
+    for (domain, report) in build_reports(sessions, params, start, end) {
+        let json = serde_json::to_string(&report)?;
+
+        if let Some(directory) = &params.directory {
+            let path = directory.join(report.file_name(&domain));
+            if let Err(err) = tokio::fs::write(&path, &json).await {
+                tracing::error!("failed to write TLS report {}: {err:#}", path.display());
+            }
+        }
+
+        let rua = match dns_resolver::txt_lookup(&format!("_smtp._tls.{domain}")).await {
+            Ok((records, _expires)) => {
+                kumo_log_types::rfc8460::parse_txt_records(&records).unwrap_or_default()
+            }
+            Err(_) => vec![],
+        };
+
+        let mut lua_config = load_config().await?;
+        lua_config
+            .async_call_callback("tls_report_generated", (domain.clone(), json, rua.clone()))
+            .await?;
+
+        if params.deliver {
+            for uri in &rua {
+                if let Some(recipient) = uri.strip_prefix("mailto:") {
+                    if let Err(err) = deliver_report(params, &domain, &report, recipient).await {
+                        tracing::error!("failed to queue TLS report for {domain}: {err:#}");
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn deliver_report(
+    params: &TlsReportingParams,
+    domain: &str,
+    report: &Report,
+    recipient: &str,
+) -> anyhow::Result<()> {
+    // Any query part, such as ?subject=, is ignored
+    let recipient = recipient.split('?').next().unwrap_or(recipient);
+    let recipient = EnvelopeAddress::parse(recipient)?;
+
+    let id = SpoolId::new();
+    let from = params
+        .from
+        .clone()
+        .unwrap_or_else(|| format!("postmaster@{}", params.reporting_mta));
+    let text = format!(
+        "This is an aggregate TLS report from {submitter} for {domain}.",
+        submitter = params.organization_name(),
+    );
+    let report_text = report.to_message(&ReportMessageParams {
+        from: &from,
+        to: &format!("<{}>", recipient.to_string()),
+        message_id: &format!("<{id}@{}>", params.reporting_mta),
+        date: Utc::now(),
+        boundary: &format!("{id}/{}", params.reporting_mta),
+        policy_domain: domain,
+        text: &text,
+    });
+
+    let mut meta = json!({
+        "reception_protocol": "TlsReport",
+    });
+    if let Some(queue) = &params.queue {
+        meta["queue"] = queue.to_string().into();
+    }
+
+    let report_msg = Message::new_dirty(
+        id,
+        EnvelopeAddress::parse(&from)?,
+        recipient,
+        meta,
+        Arc::new(report_text.into_bytes().into_boxed_slice()),
+    )?;
+    let queue_name = report_msg.get_queue_name()?;
+    report_msg.save().await?;
+    QueueManager::insert(&queue_name, report_msg).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use kumo_log_types::rfc8460::PolicyType;
+
+    #[test]
+    fn aggregation() {
+        let params = TlsReportingParams {
+            reporting_mta: "mta.example.com".to_string(),
+            organization_name: None,
+            contact_info: None,
+            from: None,
+            interval: TlsReportingParams::default_interval(),
+            directory: None,
+            deliver: false,
+            queue: None,
+        };
+        let policy = PolicyDetails {
+            policy_type: PolicyType::Sts,
+            policy_string: vec!["version: STSv1".to_string()],
+            policy_domain: "example.net".to_string(),
+            mx_host: vec!["mx.example.net".to_string()],
+        };
+        let failure = |count| FailureDetails {
+            result_type: ResultType::StarttlsNotSupported,
+            sending_mta_ip: None,
+            receiving_mx_hostname: Some("mx.example.net".to_string()),
+            receiving_mx_helo: None,
+            receiving_ip: None,
+            failed_session_count: count,
+            additional_information: None,
+            failure_reason_code: None,
+        };
+
+        let mut sessions = HashMap::new();
+        sessions.insert(
+            policy.clone(),
+            SessionCounts {
+                successful: 3,
+                failures: vec![failure(2)],
+            },
+        );
+        sessions.insert(
+            PolicyDetails {
+                policy_type: PolicyType::NoPolicyFound,
+                policy_string: vec![],
+                policy_domain: "example.org".to_string(),
+                mx_host: vec![],
+            },
+            SessionCounts {
+                successful: 1,
+                failures: vec![],
+            },
+        );
+
+        let start = Utc.timestamp_opt(0, 0).single().unwrap();
+        let end = Utc.timestamp_opt(86399, 0).single().unwrap();
+        let reports = build_reports(sessions, &params, start, end);
+        assert_eq!(reports.len(), 2);
+
+        let (domain, report) = &reports[0];
+        assert_eq!(domain, "example.net");
+        assert_eq!(report.organization_name, "mta.example.com");
+        assert_eq!(report.contact_info, "postmaster@mta.example.com");
+        assert_eq!(
+            report.policies,
+            vec![PolicyReport {
+                policy,
+                summary: Summary {
+                    total_successful_session_count: 3,
+                    total_failure_session_count: 2,
+                },
+                failure_details: vec![failure(2)],
+            }]
+        );
+        assert_eq!(reports[1].0, "example.org");
+    }
+
+    #[test]
+    fn handshake_errors() {
+        assert_eq!(
+            classify_handshake_error("invalid peer certificate: CertExpired"),
+            ResultType::CertificateExpired
+        );
+        assert_eq!(
+            classify_handshake_error("invalid peer certificate: UnknownIssuer"),
+            ResultType::CertificateNotTrusted
+        );
+        assert_eq!(
+            classify_handshake_error("received corrupt message"),
+            ResultType::ValidationFailure
+        );
+    }
+}
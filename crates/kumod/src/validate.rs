@@ -0,0 +1,216 @@
+//! Implements `kumod --validate`, which checks the policy without
+//! starting any listeners, spools or loggers, so that policy changes
+//! can be tested before they are rolled out.
+use crate::egress_path::EgressPathConfig;
+use crate::egress_source::EgressPool;
+use crate::queue::{DeliveryProto, QueueConfig};
+use crate::smtp_server::EsmtpDomain;
+use config::{load_config, LuaConfig};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Events that are called with sample arguments during validation
+const VALIDATED_EVENTS: &[&str] = &[
+    "init",
+    "get_listener_domain",
+    "get_queue_config",
+    "get_egress_pool",
+    "get_egress_source",
+    "get_egress_path_config",
+];
+
+/// Events that depend upon live traffic or state, and so
+/// are not called during validation
+const RUNTIME_EVENTS: &[&str] = &[
+    "http_message_generated",
+    "http_server_validate_auth_basic",
+    "http_server_validate_auth_bearer",
+    "rebind_message",
+    "should_enqueue_log_record",
+    "should_enqueue_non_delivery_report",
+    "smtp_server_auth_plain",
+    "smtp_server_ehlo",
+    "smtp_server_mail_from",
+    "smtp_server_message_received",
+    "smtp_server_rcpt_to",
+    "spool_message_enumerated",
+    "tls_report_generated",
+];
+
+static VALIDATING: AtomicBool = AtomicBool::new(false);
+static ACTIONS: Mutex<Vec<DryRunAction>> = Mutex::new(Vec::new());
+
+/// Something that the init event would have done,
+/// had we not been validating
+#[derive(Debug, Clone)]
+pub struct DryRunAction {
+    /// The name of the kumo function that was called
+    pub function: &'static str,
+    /// A description of its parameters
+    pub detail: String,
+}
+
+/// Returns true when running in validation mode, in which
+/// case functions with side effects should call record_action
+/// instead of performing those side effects.
+pub fn is_validating() -> bool {
+    VALIDATING.load(Ordering::SeqCst)
+}
+
+/// Records an action that would have been taken
+pub fn record_action(function: &'static str, detail: String) {
+    ACTIONS
+        .lock()
+        .unwrap()
+        .push(DryRunAction { function, detail });
+}
+
+#[derive(Default)]
+struct Report {
+    problems: usize,
+}
+
+impl Report {
+    fn check<T>(&mut self, what: &str, result: anyhow::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                println!("OK: {what}");
+                Some(value)
+            }
+            Err(err) => {
+                println!("ERROR: {what}: {err:#}");
+                self.problems += 1;
+                None
+            }
+        }
+    }
+}
+
+/// Loads the policy, runs the init event in dry-run mode and then
+/// calls the configuration events with sample arguments for each
+/// of the specified domains, reporting any errors.
+/// Each of the registered event handlers is then reported, along
+/// with whether it was exercised; handlers for names that are not
+/// known events are flagged, as they are likely to be misspelled.
+pub async fn validate_policy(policy: PathBuf, domains: Vec<String>) -> anyhow::Result<()> {
+    VALIDATING.store(true, Ordering::SeqCst);
+    let mut report = Report::default();
+
+    let loaded = config::set_policy_path(policy.clone()).await;
+    if report
+        .check(&format!("load policy {}", policy.display()), loaded)
+        .is_none()
+    {
+        anyhow::bail!("policy failed to load");
+    }
+
+    let mut config = load_config().await?;
+    let init: anyhow::Result<()> = config.async_call_callback("init", ()).await;
+    report.check("init event", init);
+
+    let actions = ACTIONS.lock().unwrap().clone();
+    for action in &actions {
+        println!(
+            "INFO: init would call {} {}",
+            action.function, action.detail
+        );
+    }
+
+    let mut listeners: Vec<String> = actions
+        .iter()
+        .filter(|action| action.function == "start_esmtp_listener")
+        .map(|action| action.detail.clone())
+        .collect();
+    if listeners.is_empty() {
+        listeners.push("0.0.0.0:25".to_string());
+    }
+
+    let mut constructors = vec![];
+    for domain in &domains {
+        for listener in &listeners {
+            validate_listener_domain(&mut report, &mut config, domain, listener).await;
+        }
+        if let Some(queue_config) = validate_queue(&mut report, &mut config, domain).await {
+            if let DeliveryProto::Lua { custom_lua } = queue_config.protocol {
+                constructors.push(custom_lua.constructor);
+            }
+        }
+    }
+
+    let events = report
+        .check("list event handlers", config.registered_events())
+        .unwrap_or_default();
+    for event in &events {
+        if VALIDATED_EVENTS.contains(&event.as_str()) {
+            println!("INFO: {event} handler was called with sample arguments");
+        } else if RUNTIME_EVENTS.contains(&event.as_str()) {
+            println!("INFO: {event} handler is only called at runtime");
+        } else if constructors.contains(event) {
+            println!("INFO: {event} handler constructs a custom lua delivery protocol");
+        } else {
+            println!(
+                "WARNING: {event} is not a known event, nor the constructor \
+                 of a custom lua delivery protocol used by a validated domain"
+            );
+        }
+    }
+
+    if report.problems > 0 {
+        anyhow::bail!("{} problem(s) found in the policy", report.problems);
+    }
+    println!("Policy is valid");
+    Ok(())
+}
+
+async fn validate_listener_domain(
+    report: &mut Report,
+    config: &mut LuaConfig,
+    domain: &str,
+    listener: &str,
+) {
+    let result: anyhow::Result<Option<EsmtpDomain>> = config
+        .async_call_callback_non_default_opt(
+            "get_listener_domain",
+            (domain.to_string(), listener.to_string()),
+        )
+        .await;
+    report.check(
+        &format!("get_listener_domain({domain}, {listener})"),
+        result,
+    );
+}
+
+async fn validate_queue(
+    report: &mut Report,
+    config: &mut LuaConfig,
+    domain: &str,
+) -> Option<QueueConfig> {
+    let result: anyhow::Result<QueueConfig> = config
+        .async_call_callback("get_queue_config", (domain, None::<&str>, None::<&str>))
+        .await;
+    let queue_config = report.check(&format!("get_queue_config({domain})"), result)?;
+
+    // This calls get_egress_pool and then get_egress_source
+    // for each of the sources in the pool
+    let pool_name = queue_config.egress_pool.as_deref().unwrap_or("unspecified");
+    let pool = EgressPool::resolve(queue_config.egress_pool.as_deref(), config).await;
+    let pool = report.check(&format!("egress pool {pool_name}"), pool)?;
+
+    for entry in &pool.entries {
+        // We don't resolve the MX here, so use the domain
+        // in place of the site name
+        let result: anyhow::Result<EgressPathConfig> = config
+            .async_call_callback(
+                "get_egress_path_config",
+                (domain, entry.name.to_string(), domain),
+            )
+            .await;
+        report.check(
+            &format!("get_egress_path_config({domain}, {}, {domain})", entry.name),
+            result,
+        );
+    }
+
+    Some(queue_config)
+}
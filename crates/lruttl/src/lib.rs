@@ -72,4 +72,9 @@ impl<K: Hash + Eq, V: Clone> LruCacheWithTtl<K, V> {
         );
         item
     }
+
+    /// Removes all entries from the cache
+    pub fn clear(&self) {
+        self.cache.lock().clear();
+    }
 }
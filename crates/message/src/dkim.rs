@@ -1,7 +1,12 @@
 use anyhow::Context;
-use cfdkim::DkimPrivateKey;
+use cfdkim::{DKIMError, DkimPrivateKey, Status};
 use config::{from_lua_value, get_or_create_sub_module};
 use data_loader::KeySource;
+use dns_resolver::SenderAuthLookup;
+use futures::future::BoxFuture;
+use kumo_sender_auth::{
+    ArcResult, AuthResult, AuthenticationResults, DkimResult, Lookup, LookupError,
+};
 use lruttl::LruCacheWithTtl;
 use mail_auth::common::crypto::{Ed25519Key, HashAlgorithm, RsaKey, Sha256, SigningKey};
 use mail_auth::common::headers::HeaderWriter;
@@ -17,6 +22,7 @@ use std::time::{Duration, Instant};
 
 lazy_static::lazy_static! {
     static ref SIGNER_CACHE: LruCacheWithTtl<SignerConfig, Arc<SignerInner>> = LruCacheWithTtl::new(1024);
+    static ref ARC_SIGNER_CACHE: LruCacheWithTtl<SignerConfig, Arc<ArcSignerInner>> = LruCacheWithTtl::new(1024);
 }
 
 #[derive(Deserialize, Hash, Eq, PartialEq, Copy, Clone)]
@@ -186,6 +192,42 @@ impl SignerInner {
 
 impl LuaUserData for Signer {}
 
+pub struct ArcSignerInner {
+    signer: cfdkim::ArcSigner,
+    domain: String,
+}
+
+#[derive(Clone)]
+pub struct ArcSigner(Arc<ArcSignerInner>);
+
+impl ArcSigner {
+    /// Validates the existing ARC chain of message and returns the
+    /// header fields of a new ARC Set that seals it.
+    /// The validation outcome is added to results, which form the
+    /// ARC-Authentication-Results header. If no results are provided,
+    /// the signing domain is used as the authserv-id.
+    pub async fn seal(
+        &self,
+        message: &[u8],
+        results: Option<AuthenticationResults>,
+    ) -> anyhow::Result<String> {
+        let mail = cfdkim::ParsedEmail::parse_bytes(message)
+            .ok_or_else(|| anyhow::anyhow!("failed to parse message to pass to arc signer"))?;
+
+        let chain = cfdkim::verify_arc_chain(&mail, Arc::new(VerifierLookup)).await;
+
+        let mut results = results.unwrap_or_else(|| AuthenticationResults {
+            serv_id: self.0.domain.clone(),
+            ..Default::default()
+        });
+        results.arc.replace(arc_result(&chain));
+
+        Ok(self.0.signer.seal(&mail, &chain, &results.header_value())?)
+    }
+}
+
+impl LuaUserData for ArcSigner {}
+
 pub fn register<'lua>(lua: &'lua Lua) -> anyhow::Result<()> {
     let dkim_mod = get_or_create_sub_module(lua, "dkim")?;
     dkim_mod.set(
@@ -221,6 +263,43 @@ pub fn register<'lua>(lua: &'lua Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    dkim_mod.set(
+        "arc_signer",
+        lua.create_async_function(|lua, params: Value| async move {
+            let params: SignerConfig = from_lua_value(lua, params)?;
+
+            if let Some(inner) = ARC_SIGNER_CACHE.get(&params) {
+                return Ok(ArcSigner(inner));
+            }
+
+            let data = params
+                .key
+                .get()
+                .await
+                .map_err(|err| mlua::Error::external(format!("{:?}: {err:#}", params.key)))?;
+
+            let data = String::from_utf8_lossy(&data);
+
+            let key = load_dkim_rsa_key(&data)
+                .map_err(|err| mlua::Error::external(format!("{:?}: {err}", params.key)))?;
+
+            let signer = params
+                .configure_cfdkim(key)
+                .and_then(|signer| Ok(cfdkim::ArcSigner::new(signer)?))
+                .map_err(|err| mlua::Error::external(format!("{err:#}")))?;
+
+            let inner = Arc::new(ArcSignerInner {
+                signer,
+                domain: params.domain.clone(),
+            });
+
+            let expiration = Instant::now() + Duration::from_secs(params.ttl);
+            ARC_SIGNER_CACHE.insert(params, Arc::clone(&inner), expiration);
+
+            Ok(ArcSigner(inner))
+        })?,
+    )?;
+
     dkim_mod.set(
         "ed25519_signer",
         lua.create_async_function(|lua, params: Value| async move {
@@ -300,3 +379,77 @@ impl CFSigner {
         Ok(dkim_header)
     }
 }
+
+/// Resolves DKIM public keys via the cached resolver from dns-resolver
+struct VerifierLookup;
+
+impl cfdkim::dns::Lookup for VerifierLookup {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(async move {
+            SenderAuthLookup
+                .lookup_txt(name)
+                .await
+                .map_err(|err| match err {
+                    LookupError::NotFound => DKIMError::NoKeyForSignature,
+                    LookupError::Temporary(err) => {
+                        DKIMError::KeyUnavailable(format!("failed to query DNS: {err}"))
+                    }
+                })
+        })
+    }
+}
+
+/// Verifies each of the DKIM signatures present in message
+pub async fn verify(message: &[u8]) -> anyhow::Result<Vec<DkimResult>> {
+    let mail = cfdkim::ParsedEmail::parse_bytes(message)
+        .ok_or_else(|| anyhow::anyhow!("failed to parse message to pass to dkim verifier"))?;
+
+    let results =
+        cfdkim::verify_email_signatures_with_resolver(&mail, Arc::new(VerifierLookup)).await;
+
+    Ok(results
+        .into_iter()
+        .map(|result| {
+            let error = result.error();
+            DkimResult {
+                result: match (result.summary(), &error) {
+                    ("pass", _) => AuthResult::Pass,
+                    (
+                        _,
+                        Some(DKIMError::SignatureDidNotVerify | DKIMError::BodyHashDidNotVerify),
+                    ) => AuthResult::Fail,
+                    (_, Some(err)) if err.clone().status() == Status::Tempfail => {
+                        AuthResult::TempError
+                    }
+                    (_, Some(_)) => AuthResult::PermError,
+                    (_, None) => AuthResult::Neutral,
+                },
+                domain: Some(result.domain_used().to_string()).filter(|d| !d.is_empty()),
+                selector: result.selector().map(|s| s.to_string()),
+                reason: error.map(|err| err.to_string()),
+            }
+        })
+        .collect())
+}
+
+fn arc_result(chain: &cfdkim::ArcChainResult) -> ArcResult {
+    ArcResult {
+        result: match (chain.status, &chain.error) {
+            (cfdkim::ChainValidationStatus::None, _) => AuthResult::None,
+            (cfdkim::ChainValidationStatus::Pass, _) => AuthResult::Pass,
+            (_, Some(err)) if err.clone().status() == Status::Tempfail => AuthResult::TempError,
+            (cfdkim::ChainValidationStatus::Fail, _) => AuthResult::Fail,
+        },
+        instance: chain.instance,
+        reason: chain.error.as_ref().map(|err| err.to_string()),
+    }
+}
+
+/// Validates the ARC chain of message
+pub async fn verify_arc(message: &[u8]) -> anyhow::Result<ArcResult> {
+    let mail = cfdkim::ParsedEmail::parse_bytes(message)
+        .ok_or_else(|| anyhow::anyhow!("failed to parse message to pass to arc verifier"))?;
+
+    let chain = cfdkim::verify_arc_chain(&mail, Arc::new(VerifierLookup)).await;
+    Ok(arc_result(&chain))
+}
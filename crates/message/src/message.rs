@@ -1,5 +1,5 @@
 use crate::address::HeaderAddressList;
-use crate::dkim::Signer;
+use crate::dkim::{ArcSigner, Signer};
 use crate::scheduling::Scheduling;
 use crate::EnvelopeAddress;
 use anyhow::Context;
@@ -8,10 +8,12 @@ use config::{any_err, from_lua_value};
 use futures::FutureExt;
 use kumo_log_types::rfc3464::Report;
 use kumo_log_types::rfc5965::ARFReport;
+use kumo_sender_auth::{ArcResult, AuthenticationResults, DkimResult};
 use mailparse::{MailHeader, MailHeaderMap};
 use mlua::{LuaSerdeExt, UserData, UserDataMethods};
 use prometheus::IntGauge;
-use serde::{Deserialize, Serialize};
+use rfc5321::DsnParameters;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use spool::{get_data_spool, get_meta_spool, Spool, SpoolId};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
@@ -66,12 +68,48 @@ pub struct Message {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct MetaData {
     sender: EnvelopeAddress,
-    recipient: EnvelopeAddress,
+    /// A single recipient is stored as a plain address, which is
+    /// also the format used before multiple recipients were supported
+    #[serde(
+        serialize_with = "serialize_recipients",
+        deserialize_with = "deserialize_recipients"
+    )]
+    recipient: Vec<EnvelopeAddress>,
     meta: serde_json::Value,
     #[serde(default)]
     schedule: Option<Scheduling>,
 }
 
+fn serialize_recipients<S: Serializer>(
+    recipients: &Vec<EnvelopeAddress>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    if recipients.len() == 1 {
+        recipients[0].serialize(serializer)
+    } else {
+        recipients.serialize(serializer)
+    }
+}
+
+fn deserialize_recipients<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<EnvelopeAddress>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(EnvelopeAddress),
+        Many(Vec<EnvelopeAddress>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(recipient) => Ok(vec![recipient]),
+        OneOrMany::Many(recipients) if recipients.is_empty() => Err(serde::de::Error::custom(
+            "message must have at least one recipient",
+        )),
+        OneOrMany::Many(recipients) => Ok(recipients),
+    }
+}
+
 impl Drop for MessageInner {
     fn drop(&mut self) {
         if self.metadata.is_some() {
@@ -103,7 +141,7 @@ impl Message {
             inner: Arc::new(Mutex::new(MessageInner {
                 metadata: Some(MetaData {
                     sender,
-                    recipient,
+                    recipient: vec![recipient],
                     meta,
                     schedule: None,
                 }),
@@ -351,7 +389,16 @@ impl Message {
         }
     }
 
+    /// Returns the first recipient of the message
     pub fn recipient(&self) -> anyhow::Result<EnvelopeAddress> {
+        let inner = self.inner.lock().unwrap();
+        match &inner.metadata {
+            Some(meta) => Ok(meta.recipient[0].clone()),
+            None => anyhow::bail!("metadata is not loaded"),
+        }
+    }
+
+    pub fn recipients(&self) -> anyhow::Result<Vec<EnvelopeAddress>> {
         let inner = self.inner.lock().unwrap();
         match &inner.metadata {
             Some(meta) => Ok(meta.recipient.clone()),
@@ -359,6 +406,25 @@ impl Message {
         }
     }
 
+    /// Replace the recipient list. All recipients are expected to
+    /// share the same domain, as they are delivered in a single
+    /// transaction.
+    pub fn set_recipients(&self, recipients: Vec<EnvelopeAddress>) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !recipients.is_empty(),
+            "message must have at least one recipient"
+        );
+        let mut inner = self.inner.lock().unwrap();
+        match &mut inner.metadata {
+            Some(meta) => {
+                meta.recipient = recipients;
+                inner.flags.set(MessageFlags::META_DIRTY, true);
+                Ok(())
+            }
+            None => anyhow::bail!("metadata is not loaded"),
+        }
+    }
+
     pub fn is_meta_loaded(&self) -> bool {
         self.inner.lock().unwrap().metadata.is_some()
     }
@@ -495,6 +561,17 @@ impl Message {
         }
     }
 
+    /// Returns the RFC 3461 DSN parameters that were supplied
+    /// when the message was received, if any
+    pub fn get_dsn_parameters(&self) -> anyhow::Result<Option<DsnParameters>> {
+        match self.get_meta("dsn")? {
+            serde_json::Value::Null => Ok(None),
+            value => Ok(Some(
+                serde_json::from_value(value).context("parsing dsn meta")?,
+            )),
+        }
+    }
+
     pub fn age(&self, now: DateTime<Utc>) -> chrono::Duration {
         self.id.age(now)
     }
@@ -507,6 +584,7 @@ impl Message {
                     self.get_meta_string("campaign")?,
                     self.get_meta_string("tenant")?,
                     self.recipient()?.domain().to_string().to_lowercase(),
+                    self.get_meta_string("routing_domain")?,
                 );
                 name.to_string()
             }
@@ -668,6 +746,37 @@ impl Message {
         Ok(())
     }
 
+    /// Verifies each DKIM signature in the message, returning
+    /// a result per signature
+    pub async fn verify_dkim(&self) -> anyhow::Result<Vec<DkimResult>> {
+        let data = self.get_data();
+        crate::dkim::verify(&data).await
+    }
+
+    /// Validates the ARC chain of the message
+    pub async fn verify_arc(&self) -> anyhow::Result<ArcResult> {
+        let data = self.get_data();
+        crate::dkim::verify_arc(&data).await
+    }
+
+    /// Validates the ARC chain of the message and prepends a new
+    /// ARC Set. The authentication_results meta value, as recorded
+    /// by the smtp listener, is used for the ARC-Authentication-Results
+    /// header when it is present.
+    pub async fn arc_seal(&self, signer: &ArcSigner) -> anyhow::Result<()> {
+        let results: Option<AuthenticationResults> =
+            match self.get_meta("authentication_results")? {
+                serde_json::Value::Null => None,
+                value => Some(
+                    serde_json::from_value(value).context("parsing authentication_results meta")?,
+                ),
+            };
+        let data = self.get_data();
+        let headers = signer.seal(&data, results).await?;
+        self.prepend_header(None, &headers);
+        Ok(())
+    }
+
     pub fn import_scheduling_header(&self, header_name: &str, remove: bool) -> anyhow::Result<()> {
         if let Some(value) = self.get_first_named_header_value(header_name)? {
             let sched: Scheduling = serde_json::from_str(&value).with_context(|| {
@@ -749,6 +858,9 @@ impl UserData for Message {
         methods.add_method("recipient", move |_, this, _: ()| {
             Ok(this.recipient().map_err(any_err)?)
         });
+        methods.add_method("recipients", move |_, this, _: ()| {
+            Ok(this.recipients().map_err(any_err)?)
+        });
         methods.add_method("dkim_sign", move |_, this, signer: Signer| {
             Ok(this.dkim_sign(&signer).map_err(any_err)?)
         });
@@ -854,6 +966,20 @@ impl UserData for Message {
             this.save().await.map_err(any_err)
         });
 
+        methods.add_async_method("verify_dkim", |lua, this, ()| async move {
+            let results = this.verify_dkim().await.map_err(any_err)?;
+            lua.to_value(&results)
+        });
+
+        methods.add_async_method("verify_arc", |lua, this, ()| async move {
+            let result = this.verify_arc().await.map_err(any_err)?;
+            lua.to_value(&result)
+        });
+
+        methods.add_async_method("arc_seal", |_, this, signer: ArcSigner| async move {
+            this.arc_seal(&signer).await.map_err(any_err)
+        });
+
         methods.add_method("set_force_sync", move |_, this, force: bool| {
             this.set_force_sync(force);
             Ok(())
@@ -890,48 +1016,73 @@ pub struct QueueNameComponents<'a> {
     pub campaign: Option<&'a str>,
     pub tenant: Option<&'a str>,
     pub domain: &'a str,
+    /// When set, messages are routed using the MX records
+    /// of this domain rather than those of domain
+    pub routing_domain: Option<&'a str>,
 }
 
 impl<'a> QueueNameComponents<'a> {
     pub fn parse(name: &'a str) -> Self {
+        let (name, routing_domain) = match name.split_once('!') {
+            Some((name, routing_domain)) => (name, Some(routing_domain)),
+            None => (name, None),
+        };
         match name.split_once('@') {
             Some((prefix, domain)) => match prefix.split_once(':') {
                 Some((campaign, tenant)) => Self {
                     campaign: Some(campaign),
                     tenant: Some(tenant),
                     domain,
+                    routing_domain,
                 },
                 None => Self {
                     campaign: None,
                     tenant: Some(prefix),
                     domain,
+                    routing_domain,
                 },
             },
             None => Self {
                 campaign: None,
                 tenant: None,
                 domain: name,
+                routing_domain,
             },
         }
     }
 
+    /// The domain whose MX records are used to route the messages
+    pub fn routing_domain(&self) -> &'a str {
+        self.routing_domain.unwrap_or(self.domain)
+    }
+
     pub fn to_string(&self) -> String {
-        Self::format(self.campaign.clone(), self.tenant.clone(), &self.domain)
+        Self::format(
+            self.campaign.clone(),
+            self.tenant.clone(),
+            &self.domain,
+            self.routing_domain.clone(),
+        )
     }
 
-    pub fn format<C: AsRef<str>, T: AsRef<str>, D: AsRef<str>>(
+    pub fn format<C: AsRef<str>, T: AsRef<str>, D: AsRef<str>, R: AsRef<str>>(
         campaign: Option<C>,
         tenant: Option<T>,
         domain: D,
+        routing_domain: Option<R>,
     ) -> String {
         let campaign: Option<&str> = campaign.as_ref().map(|c| c.as_ref());
         let tenant: Option<&str> = tenant.as_ref().map(|c| c.as_ref());
         let domain: &str = domain.as_ref();
-        match (campaign, tenant) {
+        let name = match (campaign, tenant) {
             (Some(c), Some(t)) => format!("{c}:{t}@{domain}"),
             (Some(c), None) => format!("{c}:@{domain}"),
             (None, Some(t)) => format!("{t}@{domain}"),
             (None, None) => domain.to_string(),
+        };
+        match routing_domain.as_ref().map(|r| r.as_ref()) {
+            Some(routing_domain) => format!("{name}!{routing_domain}"),
+            None => name,
         }
     }
 }
@@ -956,9 +1107,72 @@ mod test {
         String::from_utf8(msg.get_data().to_vec()).unwrap()
     }
 
+    #[test]
+    fn recipient_list_serialization() {
+        let sender = EnvelopeAddress::parse("sender@example.com").unwrap();
+        let one = EnvelopeAddress::parse("one@example.com").unwrap();
+        let two = EnvelopeAddress::parse("two@example.com").unwrap();
+
+        let meta = MetaData {
+            sender,
+            recipient: vec![one.clone()],
+            meta: json!({}),
+            schedule: None,
+        };
+        let single = serde_json::to_value(&meta).unwrap();
+        assert_eq!(single["recipient"], json!("one@example.com"));
+        let round_trip: MetaData = serde_json::from_value(single).unwrap();
+        assert_eq!(round_trip.recipient, vec![one.clone()]);
+
+        let meta = MetaData {
+            recipient: vec![one.clone(), two.clone()],
+            ..meta
+        };
+        let multi = serde_json::to_value(&meta).unwrap();
+        assert_eq!(
+            multi["recipient"],
+            json!(["one@example.com", "two@example.com"])
+        );
+        let round_trip: MetaData = serde_json::from_value(multi).unwrap();
+        assert_eq!(round_trip.recipient, vec![one, two]);
+
+        assert!(serde_json::from_value::<MetaData>(json!({
+            "sender": "sender@example.com",
+            "recipient": [],
+            "meta": {},
+        }))
+        .is_err());
+    }
+
     const X_HDR_CONTENT: &str =
         "X-Hello: there\r\nX-Header: value\r\nSubject: Hello\r\nFrom :Someone\r\n\r\nBody";
 
+    #[test]
+    fn queue_name_routing_domain() {
+        let components = QueueNameComponents::parse("camp:tenant@example.com!mx.example.net");
+        assert_eq!(components.campaign, Some("camp"));
+        assert_eq!(components.tenant, Some("tenant"));
+        assert_eq!(components.domain, "example.com");
+        assert_eq!(components.routing_domain(), "mx.example.net");
+        assert_eq!(
+            components.to_string(),
+            "camp:tenant@example.com!mx.example.net"
+        );
+
+        let components = QueueNameComponents::parse("tenant@example.com");
+        assert_eq!(components.routing_domain, None);
+        assert_eq!(components.routing_domain(), "example.com");
+
+        let msg = new_msg_body("Subject: hello\r\n\r\nHello!\r\n");
+        msg.set_meta("tenant", "tenant").unwrap();
+        assert_eq!(msg.get_queue_name().unwrap(), "tenant@example.com");
+        msg.set_meta("routing_domain", "mx.example.net").unwrap();
+        assert_eq!(
+            msg.get_queue_name().unwrap(),
+            "tenant@example.com!mx.example.net"
+        );
+    }
+
     #[test]
     fn import_all_x_headers() {
         let msg = new_msg_body(X_HDR_CONTENT);
@@ -5,7 +5,7 @@ use once_cell::sync::Lazy;
 use r2d2::{ManageConnection, Pool, PooledConnection};
 use redis::cluster::{ClusterClient, ClusterConnection};
 use redis::{Client, Connection, ConnectionLike, RedisWrite, ToRedisArgs};
-pub use redis::{Cmd, FromRedisValue, RedisError, Value as RedisValue};
+pub use redis::{Arg, Cmd, FromRedisValue, RedisError, Value as RedisValue};
 use serde::Deserialize;
 use serde_json::Value as JsonValue;
 use std::collections::HashMap;
@@ -0,0 +1,231 @@
+//! MTA-STS (RFC 8461) policy discovery and caching.
+//!
+//! DNS and HTTPS access are abstracted via the `Fetcher` trait so
+//! that the caller can supply its own resolver and HTTP client.
+use lruttl::LruCacheWithTtl;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub mod policy;
+
+pub use policy::{Mode, Policy};
+
+pub type FetchFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, FetchError>> + Send + 'a>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchError {
+    /// The name does not exist, or has no records of the requested type
+    NotFound,
+    /// Any other failure
+    Failed(String),
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NotFound => fmt.write_str("not found"),
+            Self::Failed(err) => fmt.write_str(err),
+        }
+    }
+}
+
+/// The operations required to discover a policy
+pub trait Fetcher: Send + Sync {
+    /// Returns the TXT records for name, with the character-strings
+    /// of each record concatenated together
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> FetchFuture<'a, Vec<String>>;
+    /// Returns the body of the resource at url. Implementations must
+    /// not follow redirects, and must fail for responses other than 200;
+    /// RFC 8461 section 3.3
+    fn http_get<'a>(&'a self, url: &'a str) -> FetchFuture<'a, String>;
+}
+
+struct CachedPolicy {
+    id: String,
+    policy: Arc<Policy>,
+}
+
+/// Caches policies for up to their max_age, refreshing them
+/// when the id published in DNS changes
+pub struct PolicyCache {
+    cache: LruCacheWithTtl<String, Arc<CachedPolicy>>,
+}
+
+impl PolicyCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            cache: LruCacheWithTtl::new(capacity),
+        }
+    }
+
+    /// Returns the policy for domain, or None if it doesn't publish one.
+    /// A previously cached policy continues to be used while it is
+    /// unexpired, even if the policy can no longer be discovered;
+    /// RFC 8461 section 5.1.
+    /// An error is returned if discovery failed and there is no
+    /// cached policy.
+    pub async fn get_policy(
+        &self,
+        fetcher: &dyn Fetcher,
+        domain: &str,
+    ) -> Result<Option<Arc<Policy>>, String> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let cached = self.cache.get(&domain);
+
+        let txt_name = format!("_mta-sts.{domain}");
+        let id = match fetcher.lookup_txt(&txt_name).await {
+            Ok(records) => policy::parse_txt_records(&records),
+            Err(FetchError::NotFound) => None,
+            Err(FetchError::Failed(err)) => {
+                return match cached {
+                    Some(cached) => Ok(Some(Arc::clone(&cached.policy))),
+                    None => Err(format!("looking up {txt_name}: {err}")),
+                };
+            }
+        };
+
+        let id = match id {
+            Some(id) => id,
+            None => return Ok(cached.map(|cached| Arc::clone(&cached.policy))),
+        };
+
+        if let Some(cached) = &cached {
+            if cached.id == id {
+                return Ok(Some(Arc::clone(&cached.policy)));
+            }
+        }
+
+        let url = format!("https://mta-sts.{domain}/.well-known/mta-sts.txt");
+        let policy = match fetcher.http_get(&url).await {
+            Ok(body) => body
+                .parse::<Policy>()
+                .map_err(|err| format!("parsing {url}: {err}")),
+            Err(err) => Err(format!("fetching {url}: {err}")),
+        };
+
+        match policy {
+            Ok(policy) => {
+                let policy = Arc::new(policy);
+                self.cache.insert(
+                    domain,
+                    Arc::new(CachedPolicy {
+                        id,
+                        policy: Arc::clone(&policy),
+                    }),
+                    Instant::now() + Duration::from_secs(policy.max_age),
+                );
+                Ok(Some(policy))
+            }
+            Err(err) => match cached {
+                Some(cached) => Ok(Some(Arc::clone(&cached.policy))),
+                None => Err(err),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// A Fetcher backed by a fixed set of records and documents
+    #[derive(Default)]
+    struct TestFetcher {
+        txt: Mutex<HashMap<String, Vec<String>>>,
+        http: Mutex<HashMap<String, String>>,
+        fetches: Mutex<usize>,
+    }
+
+    impl TestFetcher {
+        fn set_txt(&self, name: &str, record: &str) {
+            self.txt
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), vec![record.to_string()]);
+        }
+
+        fn set_policy(&self, domain: &str, policy: &str) {
+            self.http.lock().unwrap().insert(
+                format!("https://mta-sts.{domain}/.well-known/mta-sts.txt"),
+                policy.to_string(),
+            );
+        }
+    }
+
+    impl Fetcher for TestFetcher {
+        fn lookup_txt<'a>(&'a self, name: &'a str) -> FetchFuture<'a, Vec<String>> {
+            let result = match self.txt.lock().unwrap().get(name) {
+                Some(records) => Ok(records.clone()),
+                None if name.contains("tempfail") => Err(FetchError::Failed("timeout".into())),
+                None => Err(FetchError::NotFound),
+            };
+            Box::pin(async move { result })
+        }
+
+        fn http_get<'a>(&'a self, url: &'a str) -> FetchFuture<'a, String> {
+            *self.fetches.lock().unwrap() += 1;
+            let result = match self.http.lock().unwrap().get(url) {
+                Some(body) => Ok(body.clone()),
+                None => Err(FetchError::Failed("404".into())),
+            };
+            Box::pin(async move { result })
+        }
+    }
+
+    const POLICY: &str = "version: STSv1\nmode: enforce\nmx: mx.example.com\nmax_age: 3600\n";
+
+    #[tokio::test]
+    async fn discovery_and_caching() {
+        let fetcher = TestFetcher::default();
+        let cache = PolicyCache::new(16);
+
+        assert_eq!(cache.get_policy(&fetcher, "example.com").await, Ok(None));
+        assert!(cache
+            .get_policy(&fetcher, "tempfail.example.com")
+            .await
+            .is_err());
+
+        fetcher.set_txt("_mta-sts.example.com", "v=STSv1; id=1");
+        fetcher.set_policy("example.com", POLICY);
+        let policy = cache
+            .get_policy(&fetcher, "Example.com.")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(policy.mode, Mode::Enforce);
+        assert_eq!(*fetcher.fetches.lock().unwrap(), 1);
+
+        // Unchanged id uses the cache
+        cache.get_policy(&fetcher, "example.com").await.unwrap();
+        assert_eq!(*fetcher.fetches.lock().unwrap(), 1);
+
+        // A new id triggers a refresh
+        fetcher.set_txt("_mta-sts.example.com", "v=STSv1; id=2");
+        fetcher.set_policy("example.com", &POLICY.replace("enforce", "testing"));
+        let policy = cache
+            .get_policy(&fetcher, "example.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(policy.mode, Mode::Testing);
+        assert_eq!(*fetcher.fetches.lock().unwrap(), 2);
+
+        // A cached policy survives the removal of the record and
+        // failure to fetch the policy
+        fetcher.set_txt("_mta-sts.example.com", "v=STSv1; id=3");
+        fetcher.http.lock().unwrap().clear();
+        let policy = cache.get_policy(&fetcher, "example.com").await.unwrap();
+        assert_eq!(policy.unwrap().mode, Mode::Testing);
+        fetcher.txt.lock().unwrap().clear();
+        let policy = cache.get_policy(&fetcher, "example.com").await.unwrap();
+        assert_eq!(policy.unwrap().mode, Mode::Testing);
+
+        // Without a cached policy, a failed fetch is an error
+        fetcher.set_txt("_mta-sts.example.net", "v=STSv1; id=1");
+        assert!(cache.get_policy(&fetcher, "example.net").await.is_err());
+    }
+}
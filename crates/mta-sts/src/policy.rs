@@ -0,0 +1,242 @@
+//! Parsing of the MTA-STS policy file and DNS record; RFC 8461
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// The largest permitted max_age; RFC 8461 section 3.2
+const MAX_MAX_AGE: u64 = 31557600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Delivery must not proceed unless the MX and TLS
+    /// checks pass
+    Enforce,
+    /// Failures are reported, but do not prevent delivery
+    Testing,
+    /// The domain has withdrawn its policy
+    None,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "enforce" => Ok(Self::Enforce),
+            "testing" => Ok(Self::Testing),
+            "none" => Ok(Self::None),
+            _ => Err(format!("invalid mode {s}")),
+        }
+    }
+}
+
+impl std::fmt::Display for Mode {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Enforce => fmt.write_str("enforce"),
+            Self::Testing => fmt.write_str("testing"),
+            Self::None => fmt.write_str("none"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Policy {
+    pub mode: Mode,
+    /// The permitted MX host name patterns
+    pub mx: Vec<String>,
+    /// How long the policy may be cached, in seconds
+    pub max_age: u64,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+    fn from_str(text: &str) -> Result<Self, String> {
+        let mut version = None;
+        let mut mode = None;
+        let mut max_age = None;
+        let mut mx = vec![];
+
+        for line in text.lines() {
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| format!("invalid policy line {line}"))?;
+            let value = value.trim();
+            match key {
+                "version" => version = Some(value),
+                "mode" => mode = Some(value.parse()?),
+                "max_age" => {
+                    let age: u64 = value
+                        .parse()
+                        .map_err(|err| format!("invalid max_age {value}: {err}"))?;
+                    max_age = Some(age.min(MAX_MAX_AGE));
+                }
+                "mx" => mx.push(value.to_ascii_lowercase()),
+                // Extension fields are ignored
+                _ => {}
+            }
+        }
+
+        if version != Some("STSv1") {
+            return Err("not an STSv1 policy".to_string());
+        }
+        let mode = mode.ok_or_else(|| "missing mode".to_string())?;
+        if mx.is_empty() && mode != Mode::None {
+            return Err("missing mx".to_string());
+        }
+
+        Ok(Self {
+            mode,
+            mx,
+            max_age: max_age.ok_or_else(|| "missing max_age".to_string())?,
+        })
+    }
+}
+
+impl std::fmt::Display for Policy {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(fmt, "version: STSv1")?;
+        writeln!(fmt, "mode: {}", self.mode)?;
+        for mx in &self.mx {
+            writeln!(fmt, "mx: {mx}")?;
+        }
+        writeln!(fmt, "max_age: {}", self.max_age)
+    }
+}
+
+impl Policy {
+    /// Returns true if the MX host name is permitted by the policy.
+    /// A pattern of the form `*.example.com` matches a single
+    /// leftmost label; RFC 8461 section 4.1
+    pub fn mx_name_matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.mx.iter().any(|pattern| {
+            let pattern = pattern.trim_end_matches('.');
+            match pattern.strip_prefix("*.") {
+                Some(suffix) => match name.split_once('.') {
+                    Some((label, rest)) => !label.is_empty() && rest == suffix,
+                    None => false,
+                },
+                None => name == pattern,
+            }
+        })
+    }
+}
+
+/// Extracts the policy id from the `_mta-sts` TXT records.
+/// Returns None unless there is exactly one valid STSv1 record;
+/// RFC 8461 section 3.1
+pub fn parse_txt_records<S: AsRef<str>>(records: &[S]) -> Option<String> {
+    let mut records = records
+        .iter()
+        .map(|r| r.as_ref())
+        .filter(|r| r.starts_with("v=STSv1"));
+
+    let record = match (records.next(), records.next()) {
+        (Some(record), None) => record,
+        _ => return None,
+    };
+
+    let mut fields = record
+        .split(';')
+        .map(str::trim)
+        .filter(|field| !field.is_empty());
+    if fields.next() != Some("v=STSv1") {
+        return None;
+    }
+    let mut id = None;
+    for field in fields {
+        let (name, value) = field.split_once('=')?;
+        if name == "id" {
+            if value.is_empty()
+                || value.len() > 32
+                || !value.chars().all(|c| c.is_ascii_alphanumeric())
+            {
+                return None;
+            }
+            id = Some(value.to_string());
+        }
+    }
+    id
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_policy() {
+        let policy: Policy = "version: STSv1\r\n\
+                              mode: enforce\r\n\
+                              mx: mail.example.com\r\n\
+                              mx: *.Example.net\r\n\
+                              mx: backupmx.example.com\r\n\
+                              max_age: 604800\r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            policy,
+            Policy {
+                mode: Mode::Enforce,
+                mx: vec![
+                    "mail.example.com".to_string(),
+                    "*.example.net".to_string(),
+                    "backupmx.example.com".to_string()
+                ],
+                max_age: 604800,
+            }
+        );
+
+        let policy: Policy = "version: STSv1\nmode: none\nmax_age: 99999999999\nfoo: bar\n"
+            .parse()
+            .unwrap();
+        assert_eq!(policy.mode, Mode::None);
+        assert_eq!(policy.max_age, MAX_MAX_AGE);
+        assert_eq!(policy.to_string().parse::<Policy>().unwrap(), policy);
+
+        assert!("mode: enforce\nmx: a.example.com\nmax_age: 10"
+            .parse::<Policy>()
+            .is_err());
+        assert!("version: STSv1\nmode: enforce\nmax_age: 10"
+            .parse::<Policy>()
+            .is_err());
+        assert!("version: STSv1\nmode: strict\nmx: a\nmax_age: 10"
+            .parse::<Policy>()
+            .is_err());
+        assert!("<html>not found</html>".parse::<Policy>().is_err());
+    }
+
+    #[test]
+    fn mx_matching() {
+        let policy = Policy {
+            mode: Mode::Enforce,
+            mx: vec!["mail.example.com".to_string(), "*.example.net".to_string()],
+            max_age: 86400,
+        };
+        assert!(policy.mx_name_matches("mail.example.com"));
+        assert!(policy.mx_name_matches("MAIL.example.com."));
+        assert!(policy.mx_name_matches("mx1.example.net"));
+        assert!(!policy.mx_name_matches("example.net"));
+        assert!(!policy.mx_name_matches("a.mx1.example.net"));
+        assert!(!policy.mx_name_matches("other.example.com"));
+    }
+
+    #[test]
+    fn txt_records() {
+        assert_eq!(
+            parse_txt_records(&["v=STSv1; id=20160831085700Z;"]),
+            Some("20160831085700Z".to_string())
+        );
+        assert_eq!(
+            parse_txt_records(&["v=spf1 -all", "v=STSv1;id=abc"]),
+            Some("abc".to_string())
+        );
+        assert_eq!(parse_txt_records(&["v=STSv1; id=a", "v=STSv1; id=b"]), None);
+        assert_eq!(parse_txt_records(&["v=STSv1; id=not-valid"]), None);
+        assert_eq!(parse_txt_records(&["v=STSv1;"]), None);
+        assert_eq!(parse_txt_records::<&str>(&[]), None);
+    }
+}
@@ -1,4 +1,8 @@
-use crate::{AsyncReadAndWrite, BoxedAsyncReadAndWrite, Command, Domain, ForwardPath, ReversePath};
+use crate::{
+    build_dane_tls_connector, has_8bit, headers_have_8bit, AsyncReadAndWrite,
+    BoxedAsyncReadAndWrite, Command, Domain, DsnParameters, EsmtpParameter, ForwardPath,
+    ReversePath, TlsaRecord,
+};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -175,6 +179,10 @@ pub struct SmtpClient {
     capabilities: HashMap<String, EsmtpCapability>,
     read_buffer: Vec<u8>,
     timeouts: SmtpClientTimeouts,
+    /// Set once the session has been established via LHLO,
+    /// which changes how the response to the message
+    /// content is reported
+    use_lmtp: bool,
 }
 
 impl SmtpClient {
@@ -207,6 +215,7 @@ impl SmtpClient {
             capabilities: HashMap::new(),
             read_buffer: Vec::with_capacity(1024),
             timeouts,
+            use_lmtp: false,
         }
     }
 
@@ -369,9 +378,29 @@ impl SmtpClient {
         &mut self,
         ehlo_name: &str,
     ) -> Result<&HashMap<String, EsmtpCapability>, ClientError> {
-        let response = self
-            .send_command(&Command::Ehlo(Domain::Name(ehlo_name.to_string())))
+        self.use_lmtp = false;
+        self.greet(Command::Ehlo(Domain::Name(ehlo_name.to_string())))
+            .await
+    }
+
+    /// Greets an RFC 2033 LMTP server, rather than an SMTP server.
+    /// Once this has succeeded, the server provides a separate response
+    /// to the message content for each of the accepted recipients.
+    pub async fn lhlo(
+        &mut self,
+        lhlo_name: &str,
+    ) -> Result<&HashMap<String, EsmtpCapability>, ClientError> {
+        self.greet(Command::Lhlo(Domain::Name(lhlo_name.to_string())))
             .await?;
+        self.use_lmtp = true;
+        Ok(&self.capabilities)
+    }
+
+    async fn greet(
+        &mut self,
+        command: Command,
+    ) -> Result<&HashMap<String, EsmtpCapability>, ClientError> {
+        let response = self.send_command(&command).await?;
         if response.code != 250 {
             return Err(ClientError::Rejected(response));
         }
@@ -394,6 +423,12 @@ impl SmtpClient {
         Ok(&self.capabilities)
     }
 
+    /// Returns the capabilities advertised by the peer in response
+    /// to the most recent EHLO
+    pub fn capabilities(&self) -> &HashMap<String, EsmtpCapability> {
+        &self.capabilities
+    }
+
     pub async fn auth_plain(
         &mut self,
         username: &str,
@@ -425,12 +460,30 @@ impl SmtpClient {
     /// * Some(handshake_error) - if the handshake failed
     /// * None - if the handshake succeeded
     pub async fn starttls(&mut self, insecure: bool) -> Result<Option<String>, ClientError> {
+        self.starttls_with_connector(build_tls_connector(insecure))
+            .await
+    }
+
+    /// Attempt TLS handshake, authenticating the server using the
+    /// provided DANE TLSA records rather than the usual web PKI.
+    /// The return value has the same meaning as for `starttls`.
+    pub async fn starttls_dane(
+        &mut self,
+        records: &[TlsaRecord],
+    ) -> Result<Option<String>, ClientError> {
+        self.starttls_with_connector(build_dane_tls_connector(records))
+            .await
+    }
+
+    async fn starttls_with_connector(
+        &mut self,
+        connector: TlsConnector,
+    ) -> Result<Option<String>, ClientError> {
         let resp = self.send_command(&Command::StartTls).await?;
         if resp.code != 220 {
             return Err(ClientError::Rejected(resp));
         }
 
-        let connector = build_tls_connector(insecure);
         let mut handshake_error = None;
         let stream: BoxedAsyncReadAndWrite = match connector
             .connect(
@@ -454,49 +507,254 @@ impl SmtpClient {
         Ok(handshake_error)
     }
 
+    /// Send a message to a single recipient.
+    ///
+    /// The BODY=8BITMIME and SMTPUTF8 parameters are passed to MAIL FROM
+    /// when the message content or envelope requires them.  If the peer
+    /// doesn't advertise a required extension, the message is failed with
+    /// a permanent error, without sending anything to the peer.
+    ///
+    /// If the peer advertises CHUNKING, the message is sent using BDAT,
+    /// otherwise DATA is used.
     pub async fn send_mail<B: AsRef<[u8]>, SENDER: Into<ReversePath>, RECIP: Into<ForwardPath>>(
         &mut self,
         sender: SENDER,
         recipient: RECIP,
         data: B,
     ) -> Result<Response, ClientError> {
-        let mut responses = self
-            .pipeline_commands(vec![
-                Command::MailFrom {
-                    address: sender.into(),
-                    parameters: vec![],
-                },
-                Command::RcptTo {
-                    address: recipient.into(),
-                    parameters: vec![],
-                },
-                Command::Data,
-            ])
-            .await;
+        self.send_mail_with_dsn(sender, recipient, data, &DsnParameters::default())
+            .await
+    }
+
+    /// Like send_mail, but additionally passes the RFC 3461 DSN
+    /// parameters on to the peer if it advertises DSN support.
+    pub async fn send_mail_with_dsn<
+        B: AsRef<[u8]>,
+        SENDER: Into<ReversePath>,
+        RECIP: Into<ForwardPath>,
+    >(
+        &mut self,
+        sender: SENDER,
+        recipient: RECIP,
+        data: B,
+        dsn: &DsnParameters,
+    ) -> Result<Response, ClientError> {
+        let result = self
+            .send_mail_multi_recip(sender, vec![recipient.into()], data, dsn)
+            .await?;
+        let response = result.recipient_response(0).clone();
+        if !response.is_success() {
+            return Err(ClientError::Rejected(response));
+        }
+        Ok(response)
+    }
+
+    /// Send a message to several recipients in a single transaction,
+    /// pipelining the RCPT TO commands when the peer supports it.
+    /// A rejected MAIL FROM, or a message that cannot be sent to this
+    /// peer, is reported as an error that applies to all recipients.
+    /// Otherwise, the outcome for each recipient is returned.
+    pub async fn send_mail_multi_recip<B: AsRef<[u8]>, SENDER: Into<ReversePath>>(
+        &mut self,
+        sender: SENDER,
+        recipients: Vec<ForwardPath>,
+        data: B,
+        dsn: &DsnParameters,
+    ) -> Result<MultiRecipientResult, ClientError> {
+        let sender = sender.into();
+        let data: &[u8] = data.as_ref();
+
+        let needs_smtputf8 = !sender.to_string().is_ascii()
+            || recipients
+                .iter()
+                .any(|recipient| !recipient.to_string().is_ascii())
+            || headers_have_8bit(data);
+        let needs_8bitmime = has_8bit(data);
+
+        let mut parameters = vec![];
+
+        if needs_smtputf8 {
+            if !self.capabilities.contains_key("SMTPUTF8") {
+                return Err(ClientError::Rejected(Response {
+                    code: 554,
+                    enhanced_code: Some(EnhancedStatusCode {
+                        class: 5,
+                        subject: 6,
+                        detail: 7,
+                    }),
+                    content: "message requires SMTPUTF8 but the peer does not support it"
+                        .to_string(),
+                    command: None,
+                }));
+            }
+            parameters.push(EsmtpParameter {
+                name: "SMTPUTF8".to_string(),
+                value: None,
+            });
+        }
+
+        if needs_8bitmime {
+            if !self.capabilities.contains_key("8BITMIME") {
+                return Err(ClientError::Rejected(Response {
+                    code: 554,
+                    enhanced_code: Some(EnhancedStatusCode {
+                        class: 5,
+                        subject: 6,
+                        detail: 3,
+                    }),
+                    content: "message contains 8-bit data but the peer does not support 8BITMIME"
+                        .to_string(),
+                    command: None,
+                }));
+            }
+            parameters.push(EsmtpParameter {
+                name: "BODY".to_string(),
+                value: Some("8BITMIME".to_string()),
+            });
+        }
+
+        if self.capabilities.contains_key("SIZE") {
+            parameters.push(EsmtpParameter {
+                name: "SIZE".to_string(),
+                value: Some(data.len().to_string()),
+            });
+        }
+
+        let use_dsn = self.capabilities.contains_key("DSN");
+        if use_dsn {
+            parameters.extend(dsn.mail_parameters());
+        }
+        let rcpt_parameters = if use_dsn {
+            dsn.rcpt_parameters()
+        } else {
+            vec![]
+        };
 
-        if responses.is_empty() {
-            // Should be impossible to get here really, but if we do,
-            // assume that we aren't connected
-            return Err(ClientError::NotConnected);
+        let use_bdat = self.capabilities.contains_key("CHUNKING");
+        let num_recipients = recipients.len();
+
+        let mut commands = vec![Command::MailFrom {
+            address: sender,
+            parameters,
+        }];
+        for recipient in recipients {
+            commands.push(Command::RcptTo {
+                address: recipient,
+                parameters: rcpt_parameters.clone(),
+            });
         }
+        if !use_bdat {
+            commands.push(Command::Data);
+        }
+
+        let mut responses = self.pipeline_commands(commands).await.into_iter();
+        let mut next_response = || responses.next().unwrap_or(Err(ClientError::NotConnected));
 
-        let mail_resp = responses.remove(0)?;
-        if mail_resp.code != 250 {
+        let mail_resp = next_response()?;
+        if !mail_resp.is_success() {
             return Err(ClientError::Rejected(mail_resp));
         }
 
-        let rcpt_resp = responses.remove(0)?;
-        if rcpt_resp.code != 250 {
-            return Err(ClientError::Rejected(rcpt_resp));
+        let mut rcpt_responses = Vec::with_capacity(num_recipients);
+        for _ in 0..num_recipients {
+            rcpt_responses.push(next_response()?);
         }
 
-        let data_resp = responses.remove(0)?;
-        if data_resp.code != 354 {
-            return Err(ClientError::Rejected(data_resp));
+        if !rcpt_responses.iter().any(Response::is_success) {
+            // Nothing to send; the peer will have rejected DATA
+            self.reset_transaction().await;
+            return Ok(MultiRecipientResult {
+                rcpt_responses,
+                data_response: None,
+                lmtp_data_responses: vec![],
+            });
+        }
+
+        let data_response = if use_bdat {
+            let bdat_resp = self.send_bdat(data).await?;
+            // An LMTP server has more responses to send, one for
+            // each of the remaining recipients
+            if !bdat_resp.is_success() && !self.use_lmtp {
+                self.reset_transaction().await;
+            }
+            bdat_resp
+        } else {
+            let data_resp = next_response()?;
+            if data_resp.code != 354 {
+                self.reset_transaction().await;
+                return Ok(MultiRecipientResult {
+                    rcpt_responses,
+                    data_response: Some(data_resp),
+                    lmtp_data_responses: vec![],
+                });
+            }
+            self.send_data(data).await?
+        };
+
+        let lmtp_data_responses = if self.use_lmtp {
+            self.read_lmtp_data_responses(&rcpt_responses, &data_response)
+                .await?
+        } else {
+            vec![]
+        };
+
+        Ok(MultiRecipientResult {
+            rcpt_responses,
+            data_response: Some(data_response),
+            lmtp_data_responses,
+        })
+    }
+
+    /// Abandons the transaction after the peer refused to accept
+    /// the message, so that the connection can be used for another.
+    /// If the peer doesn't accept the RSET, the connection is closed.
+    async fn reset_transaction(&mut self) {
+        match self.send_command(&Command::Rset).await {
+            Ok(response) if response.is_success() => {}
+            _ => {
+                self.socket.take();
+            }
         }
+    }
 
+    /// An LMTP server responds to the message content once for
+    /// each accepted recipient, in the order that they were accepted.
+    /// first is the first of those responses, which has already
+    /// been read.
+    async fn read_lmtp_data_responses(
+        &mut self,
+        rcpt_responses: &[Response],
+        first: &Response,
+    ) -> Result<Vec<Option<Response>>, ClientError> {
+        let mut first = Some(first.clone());
+        let mut responses = Vec::with_capacity(rcpt_responses.len());
+
+        for rcpt in rcpt_responses {
+            if !rcpt.is_success() {
+                responses.push(None);
+                continue;
+            }
+            let response = match first.take() {
+                Some(response) => response,
+                None => match timeout(
+                    self.timeouts.data_dot_timeout,
+                    self.read_response(Some(".".to_string())),
+                )
+                .await
+                {
+                    Ok(res) => res?,
+                    Err(_) => return Err(ClientError::TimeOut),
+                },
+            };
+            responses.push(Some(response));
+        }
+
+        Ok(responses)
+    }
+
+    /// Send the message payload following a 354 response to DATA
+    async fn send_data(&mut self, data: &[u8]) -> Result<Response, ClientError> {
         let mut needs_stuffing = false;
-        let data: &[u8] = data.as_ref();
 
         for line in data.split(|&b| b == b'\n') {
             if line.starts_with(b".") {
@@ -541,15 +799,74 @@ impl SmtpClient {
             None => return Err(ClientError::NotConnected),
         }
 
-        let resp = self.read_response(Some(".".to_string())).await?;
-        if resp.code != 250 {
-            return Err(ClientError::Rejected(resp));
+        self.read_response(Some(".".to_string())).await
+    }
+
+    /// Send the message payload as a single RFC 3030 BDAT LAST chunk
+    async fn send_bdat(&mut self, data: &[u8]) -> Result<Response, ClientError> {
+        let command = Command::Bdat {
+            chunk_size: data.len(),
+            last: true,
+        };
+        let line = command.encode();
+
+        match self.socket.as_mut() {
+            Some(sock) => {
+                sock.write_all(line.as_bytes())
+                    .await
+                    .map_err(|_| ClientError::NotConnected)?;
+                sock.write_all(data)
+                    .await
+                    .map_err(|_| ClientError::NotConnected)?;
+            }
+            None => return Err(ClientError::NotConnected),
         }
 
+        let resp = match timeout(
+            self.timeouts.data_dot_timeout,
+            self.read_response(Some(line)),
+        )
+        .await
+        {
+            Ok(res) => res?,
+            Err(_) => return Err(ClientError::TimeOut),
+        };
+
         Ok(resp)
     }
 }
 
+/// The outcome of a transaction with several recipients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiRecipientResult {
+    /// The response to each RCPT TO, in the same order as the recipients
+    pub rcpt_responses: Vec<Response>,
+    /// The final response to the message content, or None if
+    /// none of the recipients were accepted
+    pub data_response: Option<Response>,
+    /// When using LMTP, the response to the message content for each
+    /// recipient, in the same order as the recipients; None for those
+    /// that were not accepted. Empty when using SMTP, in which case
+    /// data_response applies to all of the accepted recipients.
+    pub lmtp_data_responses: Vec<Option<Response>>,
+}
+
+impl MultiRecipientResult {
+    /// Returns the response that determines the outcome for the
+    /// recipient at the specified index: the RCPT TO response if it
+    /// was rejected, otherwise the response to the message content.
+    pub fn recipient_response(&self, idx: usize) -> &Response {
+        let rcpt = &self.rcpt_responses[idx];
+        if !rcpt.is_success() {
+            return rcpt;
+        }
+        if let Some(Some(response)) = self.lmtp_data_responses.get(idx) {
+            return response;
+        }
+        self.data_response.as_ref().unwrap_or(rcpt)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
 pub struct EnhancedStatusCode {
     pub class: u8,
@@ -608,6 +925,12 @@ impl Response {
         line
     }
 
+    /// Returns true for any 2xx reply, such as a 251 from a peer
+    /// that will forward the message to the recipient
+    pub fn is_success(&self) -> bool {
+        self.code >= 200 && self.code < 300
+    }
+
     pub fn is_transient(&self) -> bool {
         self.code >= 400 && self.code < 500
     }
@@ -759,4 +1082,221 @@ mod test {
             Err(ClientError::MalformedResponseLine(_))
         ));
     }
+
+    #[test]
+    fn multi_recipient_result() {
+        fn response(code: u16, content: &str) -> Response {
+            Response {
+                code,
+                enhanced_code: None,
+                content: content.to_string(),
+                command: None,
+            }
+        }
+
+        let result = MultiRecipientResult {
+            rcpt_responses: vec![
+                response(250, "ok"),
+                response(451, "try later"),
+                response(550, "no such user"),
+            ],
+            data_response: Some(response(250, "queued")),
+            lmtp_data_responses: vec![],
+        };
+        assert_eq!(result.recipient_response(0), &response(250, "queued"));
+        assert_eq!(result.recipient_response(1), &response(451, "try later"));
+        assert_eq!(result.recipient_response(2), &response(550, "no such user"));
+
+        let result = MultiRecipientResult {
+            rcpt_responses: vec![response(250, "ok"), response(550, "no such user")],
+            data_response: Some(response(554, "rejected")),
+            lmtp_data_responses: vec![],
+        };
+        assert_eq!(result.recipient_response(0), &response(554, "rejected"));
+        assert_eq!(result.recipient_response(1), &response(550, "no such user"));
+
+        let result = MultiRecipientResult {
+            rcpt_responses: vec![
+                response(250, "ok"),
+                response(550, "no such user"),
+                response(250, "ok"),
+            ],
+            data_response: Some(response(250, "delivered")),
+            lmtp_data_responses: vec![
+                Some(response(250, "delivered")),
+                None,
+                Some(response(452, "mailbox full")),
+            ],
+        };
+        assert_eq!(result.recipient_response(0), &response(250, "delivered"));
+        assert_eq!(result.recipient_response(1), &response(550, "no such user"));
+        assert_eq!(result.recipient_response(2), &response(452, "mailbox full"));
+
+        let result = MultiRecipientResult {
+            rcpt_responses: vec![response(251, "will forward"), response(250, "ok")],
+            data_response: Some(response(250, "queued")),
+            lmtp_data_responses: vec![],
+        };
+        assert_eq!(result.recipient_response(0), &response(250, "queued"));
+        assert_eq!(result.recipient_response(1), &response(250, "queued"));
+    }
+
+    #[tokio::test]
+    async fn lmtp_transaction() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+
+        let server = tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(server_stream);
+            let mut lines = BufReader::new(read).lines();
+            let mut commands = vec![];
+
+            write.write_all(b"220 lmtp ready\r\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let line = line.trim_end().to_string();
+                let reply: &[u8] = if line.starts_with("LHLO") {
+                    b"250-lmtp.example.com\r\n250 PIPELINING\r\n"
+                } else if line == "RCPT TO:<nobody@example.com>" {
+                    b"550 5.1.1 no such user\r\n"
+                } else if line == "DATA" {
+                    b"354 go ahead\r\n"
+                } else if line == "." {
+                    b"250 2.0.0 delivered to a\r\n452 4.2.2 mailbox of b is full\r\n"
+                } else if line == "QUIT" {
+                    commands.push(line);
+                    break;
+                } else if line.starts_with("MAIL") || line.starts_with("RCPT") {
+                    b"250 ok\r\n"
+                } else {
+                    b""
+                };
+                commands.push(line);
+                write.write_all(reply).await.unwrap();
+            }
+            commands
+        });
+
+        let mut client =
+            SmtpClient::with_stream(client_stream, "localhost", SmtpClientTimeouts::default());
+        client.read_response(None).await.unwrap();
+        client.lhlo("client.example.com").await.unwrap();
+
+        let result = client
+            .send_mail_multi_recip(
+                ReversePath::try_from("sender@example.com").unwrap(),
+                vec![
+                    ForwardPath::try_from("a@example.com").unwrap(),
+                    ForwardPath::try_from("nobody@example.com").unwrap(),
+                    ForwardPath::try_from("b@example.com").unwrap(),
+                ],
+                "Subject: hello\r\n\r\nwoot\r\n",
+                &DsnParameters::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(result.recipient_response(0).code, 250);
+        assert_eq!(result.recipient_response(1).code, 550);
+        assert_eq!(result.recipient_response(2).code, 452);
+        assert_eq!(
+            result.recipient_response(2).content,
+            "mailbox of b is full".to_string()
+        );
+
+        client.send_command(&Command::Quit).await.ok();
+        drop(client);
+
+        let commands = server.await.unwrap();
+        assert_eq!(commands[0], "LHLO client.example.com");
+    }
+
+    #[tokio::test]
+    async fn rset_after_refused_transaction() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+
+        let server = tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(server_stream);
+            let mut lines = BufReader::new(read).lines();
+            let mut commands = vec![];
+            let mut accepted = false;
+
+            write.write_all(b"220 smtp ready\r\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let line = line.trim_end().to_string();
+                let reply: &[u8] = if line.starts_with("EHLO") {
+                    b"250-smtp.example.com\r\n250 PIPELINING\r\n"
+                } else if line == "RCPT TO:<nobody@example.com>" {
+                    b"550 5.1.1 no such user\r\n"
+                } else if line.starts_with("RCPT") {
+                    accepted = true;
+                    b"250 ok\r\n"
+                } else if line == "DATA" && accepted {
+                    b"451 4.3.0 try again later\r\n"
+                } else if line == "DATA" {
+                    b"554 5.5.1 no valid recipients\r\n"
+                } else if line == "QUIT" {
+                    commands.push(line);
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                commands.push(line);
+                write.write_all(reply).await.unwrap();
+            }
+            commands
+        });
+
+        let mut client =
+            SmtpClient::with_stream(client_stream, "localhost", SmtpClientTimeouts::default());
+        client.read_response(None).await.unwrap();
+        client.ehlo("client.example.com").await.unwrap();
+
+        // Every recipient is rejected
+        let result = client
+            .send_mail_multi_recip(
+                ReversePath::try_from("sender@example.com").unwrap(),
+                vec![ForwardPath::try_from("nobody@example.com").unwrap()],
+                "Subject: hello\r\n\r\nwoot\r\n",
+                &DsnParameters::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.recipient_response(0).code, 550);
+        assert_eq!(result.data_response, None);
+
+        // The recipient is accepted, but DATA is refused
+        let result = client
+            .send_mail_multi_recip(
+                ReversePath::try_from("sender@example.com").unwrap(),
+                vec![ForwardPath::try_from("a@example.com").unwrap()],
+                "Subject: hello\r\n\r\nwoot\r\n",
+                &DsnParameters::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.recipient_response(0).code, 451);
+
+        client.send_command(&Command::Quit).await.ok();
+        drop(client);
+
+        let commands = server.await.unwrap();
+        assert_eq!(
+            commands,
+            vec![
+                "EHLO client.example.com",
+                "MAIL FROM:<sender@example.com>",
+                "RCPT TO:<nobody@example.com>",
+                "DATA",
+                "RSET",
+                "MAIL FROM:<sender@example.com>",
+                "RCPT TO:<a@example.com>",
+                "DATA",
+                "RSET",
+                "QUIT",
+            ]
+        );
+    }
 }
@@ -0,0 +1,306 @@
+//! Verification of server certificates against DANE TLSA
+//! records, as described by RFC 7672
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use tokio_rustls::rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+/// The certificate usage of a TLSA record that matches the
+/// trust anchor of the server's certificate chain
+pub const DANE_TA: u8 = 2;
+/// The certificate usage of a TLSA record that matches the
+/// server's own certificate
+pub const DANE_EE: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsaRecord {
+    pub usage: u8,
+    pub selector: u8,
+    pub matching: u8,
+    pub data: Vec<u8>,
+}
+
+impl TlsaRecord {
+    /// Returns true if this record can be used to authenticate an SMTP
+    /// server. Only the DANE-TA and DANE-EE usages apply to SMTP;
+    /// RFC 7672 section 3.1.3
+    pub fn is_usable(&self) -> bool {
+        matches!(self.usage, DANE_TA | DANE_EE) && self.selector <= 1 && self.matching <= 2
+    }
+
+    /// Returns true if the DER encoded certificate matches this record
+    pub fn matches(&self, cert: &[u8]) -> bool {
+        let content = match self.selector {
+            0 => cert,
+            1 => match subject_public_key_info(cert) {
+                Some(spki) => spki,
+                None => return false,
+            },
+            _ => return false,
+        };
+        match self.matching {
+            0 => content == self.data.as_slice(),
+            1 => Sha256::digest(content).as_slice() == self.data.as_slice(),
+            2 => Sha512::digest(content).as_slice() == self.data.as_slice(),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for TlsaRecord {
+    /// Formats the record in the zone file presentation format
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{} {} {} ", self.usage, self.selector, self.matching)?;
+        for b in &self.data {
+            write!(fmt, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A DER element split from the front of some input
+struct DerElement<'a> {
+    tag: u8,
+    /// The complete encoded element
+    encoded: &'a [u8],
+    content: &'a [u8],
+    /// The input that follows the element
+    rest: &'a [u8],
+}
+
+fn der_element(input: &[u8]) -> Option<DerElement<'_>> {
+    let tag = *input.first()?;
+    let first_len = *input.get(1)? as usize;
+    let (len, header_len) = if first_len < 0x80 {
+        (first_len, 2)
+    } else {
+        let num_bytes = first_len & 0x7f;
+        if num_bytes == 0 || num_bytes > 4 {
+            return None;
+        }
+        let mut len = 0usize;
+        for b in input.get(2..2 + num_bytes)? {
+            len = (len << 8) | *b as usize;
+        }
+        (len, 2 + num_bytes)
+    };
+    let end = header_len.checked_add(len)?;
+    let element = input.get(..end)?;
+    Some(DerElement {
+        tag,
+        encoded: element,
+        content: &element[header_len..],
+        rest: &input[end..],
+    })
+}
+
+const SEQUENCE: u8 = 0x30;
+const EXPLICIT_VERSION: u8 = 0xa0;
+
+/// Returns the DER encoded SubjectPublicKeyInfo of a DER
+/// encoded X.509 certificate; RFC 5280 section 4.1
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let cert = der_element(cert)?;
+    if cert.tag != SEQUENCE {
+        return None;
+    }
+    let tbs = der_element(cert.content)?;
+    if tbs.tag != SEQUENCE {
+        return None;
+    }
+
+    let first = der_element(tbs.content)?;
+    let mut rest = if first.tag == EXPLICIT_VERSION {
+        first.rest
+    } else {
+        // There is no version, so that was the serial number
+        tbs.content
+    };
+    // Skip the serial number, signature, issuer, validity and subject
+    for _ in 0..5 {
+        rest = der_element(rest)?.rest;
+    }
+    let spki = der_element(rest)?;
+    if spki.tag != SEQUENCE {
+        return None;
+    }
+    Some(spki.encoded)
+}
+
+struct DaneVerifier {
+    records: Vec<TlsaRecord>,
+}
+
+impl ServerCertVerifier for DaneVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        // The name and validity period are not checked for
+        // DANE-EE; RFC 7672 section 3.1.1
+        if self
+            .records
+            .iter()
+            .any(|r| r.usage == DANE_EE && r.matches(&end_entity.0))
+        {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        // For DANE-TA, the matching certificate must be present in the
+        // chain, and is used as the trust anchor when validating the
+        // rest of the chain, including the name; RFC 7672 section 3.1.2
+        for (idx, cert) in intermediates.iter().enumerate() {
+            if !self
+                .records
+                .iter()
+                .any(|r| r.usage == DANE_TA && r.matches(&cert.0))
+            {
+                continue;
+            }
+            let mut roots = RootCertStore::empty();
+            if roots.add(cert).is_err() {
+                continue;
+            }
+            if WebPkiVerifier::new(roots, None)
+                .verify_server_cert(
+                    end_entity,
+                    &intermediates[..idx],
+                    server_name,
+                    &mut *scts,
+                    ocsp_response,
+                    now,
+                )
+                .is_ok()
+            {
+                return Ok(ServerCertVerified::assertion());
+            }
+        }
+
+        Err(tokio_rustls::rustls::Error::General(
+            "server certificate does not match any TLSA record".to_string(),
+        ))
+    }
+}
+
+/// Build a connector that authenticates the server using the
+/// provided TLSA records. Records that are not usable for SMTP
+/// are ignored, so callers should check that at least one of
+/// them is usable.
+pub fn build_dane_tls_connector(records: &[TlsaRecord]) -> TlsConnector {
+    let records = records.iter().filter(|r| r.is_usable()).cloned().collect();
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(DaneVerifier { records }))
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::{PrivateKey, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    fn self_signed() -> rcgen::Certificate {
+        rcgen::generate_simple_self_signed(vec!["mx.example.com".to_string()]).unwrap()
+    }
+
+    #[test]
+    fn spki() {
+        let cert = self_signed();
+        let der = cert.serialize_der().unwrap();
+        assert_eq!(
+            subject_public_key_info(&der).unwrap(),
+            cert.get_key_pair().public_key_der().as_slice()
+        );
+        assert_eq!(subject_public_key_info(b"\x30\x03\x02\x01"), None);
+        assert_eq!(subject_public_key_info(b""), None);
+    }
+
+    #[test]
+    fn matching() {
+        let cert = self_signed();
+        let der = cert.serialize_der().unwrap();
+        let spki = cert.get_key_pair().public_key_der();
+
+        let record = |selector, matching, data: &[u8]| TlsaRecord {
+            usage: DANE_EE,
+            selector,
+            matching,
+            data: data.to_vec(),
+        };
+
+        assert!(record(0, 0, &der).matches(&der));
+        assert!(record(0, 1, &Sha256::digest(&der)).matches(&der));
+        assert!(record(1, 1, &Sha256::digest(&spki)).matches(&der));
+        assert!(record(1, 2, &Sha512::digest(&spki)).matches(&der));
+        assert!(!record(1, 1, &Sha256::digest(&der)).matches(&der));
+        assert!(!record(1, 3, &spki).is_usable());
+        assert_eq!(record(1, 0, &[0xab, 0x01]).to_string(), "3 1 0 ab01");
+        assert!(!TlsaRecord {
+            usage: 1,
+            ..record(1, 1, &[])
+        }
+        .is_usable());
+    }
+
+    async fn handshake(cert: &rcgen::Certificate, records: &[TlsaRecord]) -> bool {
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(cert.serialize_der().unwrap())],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            if let Ok(mut stream) = acceptor.accept(server).await {
+                stream.write_all(b"hello").await.ok();
+                stream.shutdown().await.ok();
+            }
+        });
+
+        let connector = build_dane_tls_connector(records);
+        let result = match connector
+            .connect(ServerName::try_from("mx.example.com").unwrap(), client)
+            .await
+        {
+            Ok(mut stream) => {
+                let mut buf = vec![];
+                stream.read_to_end(&mut buf).await.ok();
+                buf == b"hello"
+            }
+            Err(_) => false,
+        };
+        server.await.unwrap();
+        result
+    }
+
+    #[tokio::test]
+    async fn dane_ee() {
+        let cert = self_signed();
+        let spki = cert.get_key_pair().public_key_der();
+        let good = TlsaRecord {
+            usage: DANE_EE,
+            selector: 1,
+            matching: 1,
+            data: Sha256::digest(&spki).to_vec(),
+        };
+        assert!(handshake(&cert, std::slice::from_ref(&good)).await);
+
+        let other = self_signed();
+        assert!(!handshake(&other, &[good]).await);
+    }
+}
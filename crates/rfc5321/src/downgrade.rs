@@ -0,0 +1,381 @@
+//! Helpers for deciding whether a message requires the RFC 6152 8BITMIME
+//! or RFC 6531 SMTPUTF8 extensions, and for downgrading 8-bit MIME content
+//! to 7-bit quoted-printable when the next hop doesn't support 8BITMIME.
+
+/// Returns true if data contains any bytes with the high bit set
+pub fn has_8bit(data: &[u8]) -> bool {
+    data.iter().any(|&b| b >= 0x80)
+}
+
+/// Returns true if the header portion of the message contains bytes
+/// with the high bit set. Such a message requires SMTPUTF8 to transport.
+pub fn headers_have_8bit(data: &[u8]) -> bool {
+    let (headers, _body) = split_header_body(data);
+    has_8bit(headers)
+}
+
+/// Re-encode any 8-bit MIME parts of the message as quoted-printable,
+/// so that it can be relayed to a peer that doesn't support 8BITMIME.
+/// Content that doesn't contain 8-bit data is passed through unchanged.
+/// Returns an error if the message cannot be downgraded, for example,
+/// because it has 8-bit data in its headers.
+pub fn downgrade_to_7bit(data: &[u8]) -> Result<Vec<u8>, String> {
+    downgrade_entity(data)
+}
+
+/// Split a MIME entity into its header block (including the CRLF of the
+/// final header line) and its body
+fn split_header_body(data: &[u8]) -> (&[u8], &[u8]) {
+    if let Some(body) = data.strip_prefix(b"\r\n") {
+        return (&[], body);
+    }
+    match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(idx) => (&data[0..idx + 2], &data[idx + 4..]),
+        None => (data, &[]),
+    }
+}
+
+/// Returns the raw header fields, each including any folded
+/// continuation lines and the trailing CRLF
+fn header_fields(headers: &[u8]) -> Vec<&[u8]> {
+    let mut fields: Vec<&[u8]> = vec![];
+    let mut field_start = None;
+    let mut pos = 0;
+
+    while pos < headers.len() {
+        let next = match headers[pos..].windows(2).position(|w| w == b"\r\n") {
+            Some(idx) => pos + idx + 2,
+            None => headers.len(),
+        };
+        let is_continuation = matches!(headers[pos], b' ' | b'\t');
+        if !is_continuation {
+            if let Some(start) = field_start.take() {
+                fields.push(&headers[start..pos]);
+            }
+            field_start.replace(pos);
+        }
+        pos = next;
+    }
+    if let Some(start) = field_start {
+        fields.push(&headers[start..]);
+    }
+
+    fields
+}
+
+/// Returns the lowercased name of a header field
+fn field_name(field: &[u8]) -> String {
+    let colon = field.iter().position(|&b| b == b':').unwrap_or(0);
+    String::from_utf8_lossy(&field[0..colon])
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Returns the unfolded value of the first header field with the
+/// specified (lowercase) name
+fn field_value(fields: &[&[u8]], name: &str) -> Option<String> {
+    let field = fields.iter().find(|f| field_name(f) == name)?;
+    let colon = field.iter().position(|&b| b == b':')?;
+    let value = String::from_utf8_lossy(&field[colon + 1..]);
+    Some(value.replace("\r\n", "").trim().to_string())
+}
+
+/// Returns the lowercased media type and the boundary parameter
+fn parse_content_type(value: &str) -> (String, Option<String>) {
+    let mut params = value.split(';');
+    let media_type = params.next().unwrap_or("").trim().to_ascii_lowercase();
+    let mut boundary = None;
+
+    for param in params {
+        if let Some((name, value)) = param.split_once('=') {
+            if name.trim().eq_ignore_ascii_case("boundary") {
+                boundary.replace(value.trim().trim_matches('"').to_string());
+            }
+        }
+    }
+
+    (media_type, boundary)
+}
+
+/// Re-assemble a MIME entity, replacing any Content-Transfer-Encoding
+/// field with the specified encoding
+fn assemble(fields: &[&[u8]], encoding: &str, body: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(body.len() + 1024);
+    for field in fields {
+        if field_name(field) != "content-transfer-encoding" {
+            result.extend_from_slice(field);
+        }
+    }
+    result.extend_from_slice(format!("Content-Transfer-Encoding: {encoding}\r\n").as_bytes());
+    result.extend_from_slice(b"\r\n");
+    result.extend_from_slice(body);
+    result
+}
+
+fn downgrade_entity(data: &[u8]) -> Result<Vec<u8>, String> {
+    if !has_8bit(data) {
+        return Ok(data.to_vec());
+    }
+
+    let (headers, body) = split_header_body(data);
+    if has_8bit(headers) {
+        return Err("message headers contain 8-bit data".to_string());
+    }
+
+    let fields = header_fields(headers);
+    let content_type = field_value(&fields, "content-type");
+    let encoding = field_value(&fields, "content-transfer-encoding")
+        .unwrap_or_else(|| "7bit".to_string())
+        .to_ascii_lowercase();
+    let (media_type, boundary) =
+        parse_content_type(content_type.as_deref().unwrap_or("text/plain"));
+
+    if media_type.starts_with("multipart/") {
+        let boundary = boundary.ok_or_else(|| format!("{media_type} has no boundary parameter"))?;
+        let body = downgrade_multipart(body, &boundary)?;
+        return Ok(assemble(&fields, "7bit", &body));
+    }
+
+    if media_type == "message/rfc822" {
+        let body = downgrade_entity(body)?;
+        return Ok(assemble(&fields, "7bit", &body));
+    }
+
+    match encoding.as_str() {
+        "7bit" | "8bit" | "binary" => {}
+        other => {
+            return Err(format!(
+                "{media_type} part with {other} transfer encoding contains 8-bit data"
+            ))
+        }
+    }
+
+    let body = encode_quoted_printable(body);
+    Ok(assemble(&fields, "quoted-printable", &body))
+}
+
+fn downgrade_multipart(body: &[u8], boundary: &str) -> Result<Vec<u8>, String> {
+    let delimiter = format!("--{boundary}");
+    let close_delimiter = format!("--{boundary}--");
+    let mut result = Vec::with_capacity(body.len() + 1024);
+    let mut part_start: Option<usize> = None;
+    let mut epilogue_start = None;
+    let mut pos = 0;
+
+    while pos < body.len() {
+        let (line_end, next) = match body[pos..].windows(2).position(|w| w == b"\r\n") {
+            Some(idx) => (pos + idx, pos + idx + 2),
+            None => (body.len(), body.len()),
+        };
+        let mut line = &body[pos..line_end];
+        while let Some(trimmed) = line.strip_suffix(b" ").or_else(|| line.strip_suffix(b"\t")) {
+            line = trimmed;
+        }
+
+        let is_close = line == close_delimiter.as_bytes();
+        if is_close || line == delimiter.as_bytes() {
+            match part_start {
+                None => {
+                    let preamble = &body[0..pos];
+                    if has_8bit(preamble) {
+                        return Err("multipart preamble contains 8-bit data".to_string());
+                    }
+                    result.extend_from_slice(preamble);
+                }
+                Some(start) => {
+                    // The CRLF preceding the delimiter belongs to the delimiter
+                    let content = &body[start..pos];
+                    match content.strip_suffix(b"\r\n") {
+                        Some(content) => {
+                            result.extend_from_slice(&downgrade_entity(content)?);
+                            result.extend_from_slice(b"\r\n");
+                        }
+                        None => result.extend_from_slice(&downgrade_entity(content)?),
+                    }
+                }
+            }
+            result.extend_from_slice(&body[pos..next]);
+
+            if is_close {
+                epilogue_start.replace(next);
+                break;
+            }
+            part_start.replace(next);
+        }
+        pos = next;
+    }
+
+    match (epilogue_start, part_start) {
+        (Some(start), _) => {
+            let epilogue = &body[start..];
+            if has_8bit(epilogue) {
+                return Err("multipart epilogue contains 8-bit data".to_string());
+            }
+            result.extend_from_slice(epilogue);
+        }
+        (None, Some(start)) => {
+            // Missing close delimiter; treat the remainder as a part
+            result.extend_from_slice(&downgrade_entity(&body[start..])?);
+        }
+        (None, None) => {
+            return Err(format!("multipart boundary {boundary} not found"));
+        }
+    }
+
+    Ok(result)
+}
+
+/// Quoted-printable encode data as described by RFC 2045.
+/// Line breaks in the input are preserved as CRLF hard line breaks.
+fn encode_quoted_printable(data: &[u8]) -> Vec<u8> {
+    // Lines are limited to 76 characters, including the trailing "="
+    // of a soft line break
+    const MAX_LINE: usize = 75;
+
+    let mut result = Vec::with_capacity(data.len() * 3 / 2);
+    let mut lines = data.split(|&b| b == b'\n').peekable();
+
+    while let Some(line) = lines.next() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let mut line_len = 0;
+
+        for (idx, &b) in line.iter().enumerate() {
+            let is_last = idx == line.len() - 1;
+            let literal = match b {
+                b'=' => false,
+                // Trailing whitespace must be encoded
+                b' ' | b'\t' => !is_last,
+                33..=126 => true,
+                _ => false,
+            };
+            let width = if literal { 1 } else { 3 };
+
+            if line_len + width > MAX_LINE {
+                result.extend_from_slice(b"=\r\n");
+                line_len = 0;
+            }
+
+            if literal {
+                result.push(b);
+            } else {
+                result.extend_from_slice(format!("={b:02X}").as_bytes());
+            }
+            line_len += width;
+        }
+
+        if lines.peek().is_some() {
+            result.extend_from_slice(b"\r\n");
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detect_8bit() {
+        assert!(!has_8bit(b"Subject: hello\r\n\r\nhello\r\n"));
+        assert!(has_8bit("Subject: hello\r\n\r\nhéllo\r\n".as_bytes()));
+        assert!(!headers_have_8bit(
+            "Subject: hello\r\n\r\nhéllo\r\n".as_bytes()
+        ));
+        assert!(headers_have_8bit(
+            "Subject: héllo\r\n\r\nhello\r\n".as_bytes()
+        ));
+    }
+
+    #[test]
+    fn quoted_printable() {
+        assert_eq!(
+            encode_quoted_printable("héllo = there \r\nline two\t\r\n".as_bytes()),
+            b"h=C3=A9llo =3D there=20\r\nline two=09\r\n".to_vec()
+        );
+
+        let long = "é".repeat(40);
+        let encoded = encode_quoted_printable(long.as_bytes());
+        let encoded = String::from_utf8(encoded).unwrap();
+        for line in encoded.split("\r\n") {
+            assert!(line.len() <= 76, "{line} is too long");
+        }
+        assert_eq!(encoded.replace("=\r\n", ""), "=C3=A9".repeat(40));
+    }
+
+    #[test]
+    fn downgrade_7bit_is_unchanged() {
+        let msg = b"Subject: hello\r\n\r\nhello\r\n";
+        assert_eq!(downgrade_to_7bit(msg).unwrap(), msg.to_vec());
+    }
+
+    #[test]
+    fn downgrade_simple() {
+        let msg = "Subject: hello\r\n\
+                   Content-Type: text/plain; charset=utf-8\r\n\
+                   Content-Transfer-Encoding: 8bit\r\n\
+                   \r\n\
+                   héllo\r\n";
+        let result = downgrade_to_7bit(msg.as_bytes()).unwrap();
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            "Subject: hello\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: quoted-printable\r\n\
+             \r\n\
+             h=C3=A9llo\r\n"
+        );
+    }
+
+    #[test]
+    fn downgrade_multipart() {
+        let msg = "Subject: hello\r\n\
+                   Content-Type: multipart/alternative;\r\n  boundary=\"BOUNDARY\"\r\n\
+                   \r\n\
+                   preamble\r\n\
+                   --BOUNDARY\r\n\
+                   Content-Type: text/plain\r\n\
+                   \r\n\
+                   plain\r\n\
+                   --BOUNDARY\r\n\
+                   Content-Type: text/html; charset=utf-8\r\n\
+                   Content-Transfer-Encoding: 8bit\r\n\
+                   \r\n\
+                   <b>héllo</b>\r\n\
+                   --BOUNDARY--\r\n\
+                   epilogue\r\n";
+        let result = downgrade_to_7bit(msg.as_bytes()).unwrap();
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            "Subject: hello\r\n\
+             Content-Type: multipart/alternative;\r\n  boundary=\"BOUNDARY\"\r\n\
+             Content-Transfer-Encoding: 7bit\r\n\
+             \r\n\
+             preamble\r\n\
+             --BOUNDARY\r\n\
+             Content-Type: text/plain\r\n\
+             \r\n\
+             plain\r\n\
+             --BOUNDARY\r\n\
+             Content-Type: text/html; charset=utf-8\r\n\
+             Content-Transfer-Encoding: quoted-printable\r\n\
+             \r\n\
+             <b>h=C3=A9llo</b>\r\n\
+             --BOUNDARY--\r\n\
+             epilogue\r\n"
+        );
+    }
+
+    #[test]
+    fn downgrade_failures() {
+        assert_eq!(
+            downgrade_to_7bit("Subject: héllo\r\n\r\nhello\r\n".as_bytes()).unwrap_err(),
+            "message headers contain 8-bit data"
+        );
+        assert_eq!(
+            downgrade_to_7bit("Content-Transfer-Encoding: base64\r\n\r\nhéllo\r\n".as_bytes())
+                .unwrap_err(),
+            "text/plain part with base64 transfer encoding contains 8-bit data"
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! RFC 3461 Delivery Status Notification ESMTP parameters
+use crate::EsmtpParameter;
+use serde::{Deserialize, Serialize};
+
+/// How much of the message to return in a DSN
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum DsnRet {
+    Full,
+    Hdrs,
+}
+
+/// The conditions under which a DSN should be generated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum DsnNotify {
+    Never,
+    Success,
+    Failure,
+    Delay,
+}
+
+/// The DSN parameters that accompanied a message and recipient.
+/// RET and ENVID are passed via MAIL FROM, while NOTIFY and ORCPT
+/// are passed via RCPT TO.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DsnParameters {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ret: Option<DsnRet>,
+    /// The envelope identifier, in xtext encoded form
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notify: Option<Vec<DsnNotify>>,
+    /// The original recipient, in `addr-type;xtext` form
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub orcpt: Option<String>,
+}
+
+impl DsnParameters {
+    pub fn is_empty(&self) -> bool {
+        self.ret.is_none() && self.envid.is_none() && self.notify.is_none() && self.orcpt.is_none()
+    }
+
+    /// Returns true if the sender asked to be notified under the
+    /// specified condition. When NOTIFY was not specified, only
+    /// failures and delays are notified.
+    pub fn should_notify(&self, condition: DsnNotify) -> bool {
+        match &self.notify {
+            Some(notify) => notify.contains(&condition),
+            None => matches!(condition, DsnNotify::Failure | DsnNotify::Delay),
+        }
+    }
+
+    /// Extract RET and ENVID from the parameters of a MAIL FROM command.
+    /// Other parameters are ignored.
+    pub fn parse_mail_parameters(&mut self, parameters: &[EsmtpParameter]) -> Result<(), String> {
+        for param in parameters {
+            if param.name.eq_ignore_ascii_case("RET") {
+                if self.ret.is_some() {
+                    return Err("duplicate RET parameter".to_string());
+                }
+                let ret = match param.value.as_deref().map(|v| v.to_ascii_uppercase()) {
+                    Some(v) if v == "FULL" => DsnRet::Full,
+                    Some(v) if v == "HDRS" => DsnRet::Hdrs,
+                    _ => return Err(format!("invalid parameter {}", param.to_string())),
+                };
+                self.ret.replace(ret);
+            } else if param.name.eq_ignore_ascii_case("ENVID") {
+                if self.envid.is_some() {
+                    return Err("duplicate ENVID parameter".to_string());
+                }
+                match &param.value {
+                    Some(v) if v.len() <= 100 => {
+                        self.envid.replace(v.to_string());
+                    }
+                    _ => return Err(format!("invalid parameter {}", param.to_string())),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Extract NOTIFY and ORCPT from the parameters of a RCPT TO command.
+    /// Other parameters are ignored.
+    pub fn parse_rcpt_parameters(&mut self, parameters: &[EsmtpParameter]) -> Result<(), String> {
+        for param in parameters {
+            if param.name.eq_ignore_ascii_case("NOTIFY") {
+                if self.notify.is_some() {
+                    return Err("duplicate NOTIFY parameter".to_string());
+                }
+                let value = param
+                    .value
+                    .as_deref()
+                    .ok_or_else(|| format!("invalid parameter {}", param.to_string()))?;
+                let mut notify = vec![];
+                for item in value.split(',') {
+                    let item = match item.to_ascii_uppercase().as_str() {
+                        "NEVER" => DsnNotify::Never,
+                        "SUCCESS" => DsnNotify::Success,
+                        "FAILURE" => DsnNotify::Failure,
+                        "DELAY" => DsnNotify::Delay,
+                        _ => return Err(format!("invalid NOTIFY value {item}")),
+                    };
+                    if !notify.contains(&item) {
+                        notify.push(item);
+                    }
+                }
+                if notify.contains(&DsnNotify::Never) && notify.len() > 1 {
+                    return Err("NOTIFY=NEVER cannot be combined with other values".to_string());
+                }
+                self.notify.replace(notify);
+            } else if param.name.eq_ignore_ascii_case("ORCPT") {
+                if self.orcpt.is_some() {
+                    return Err("duplicate ORCPT parameter".to_string());
+                }
+                match &param.value {
+                    Some(v) if v.contains(';') && v.len() <= 500 => {
+                        self.orcpt.replace(v.to_string());
+                    }
+                    _ => return Err(format!("invalid parameter {}", param.to_string())),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the RET and ENVID parameters for use with MAIL FROM
+    pub fn mail_parameters(&self) -> Vec<EsmtpParameter> {
+        let mut parameters = vec![];
+        if let Some(ret) = self.ret {
+            parameters.push(EsmtpParameter {
+                name: "RET".to_string(),
+                value: Some(
+                    match ret {
+                        DsnRet::Full => "FULL",
+                        DsnRet::Hdrs => "HDRS",
+                    }
+                    .to_string(),
+                ),
+            });
+        }
+        if let Some(envid) = &self.envid {
+            parameters.push(EsmtpParameter {
+                name: "ENVID".to_string(),
+                value: Some(envid.to_string()),
+            });
+        }
+        parameters
+    }
+
+    /// Returns the NOTIFY and ORCPT parameters for use with RCPT TO
+    pub fn rcpt_parameters(&self) -> Vec<EsmtpParameter> {
+        let mut parameters = vec![];
+        if let Some(notify) = &self.notify {
+            let value: Vec<&str> = notify
+                .iter()
+                .map(|n| match n {
+                    DsnNotify::Never => "NEVER",
+                    DsnNotify::Success => "SUCCESS",
+                    DsnNotify::Failure => "FAILURE",
+                    DsnNotify::Delay => "DELAY",
+                })
+                .collect();
+            parameters.push(EsmtpParameter {
+                name: "NOTIFY".to_string(),
+                value: Some(value.join(",")),
+            });
+        }
+        if let Some(orcpt) = &self.orcpt {
+            parameters.push(EsmtpParameter {
+                name: "ORCPT".to_string(),
+                value: Some(orcpt.to_string()),
+            });
+        }
+        parameters
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Command;
+
+    fn parse_params(line: &str) -> Vec<EsmtpParameter> {
+        match Command::parse(line).unwrap() {
+            Command::MailFrom { parameters, .. } | Command::RcptTo { parameters, .. } => parameters,
+            wat => panic!("unexpected {wat:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_dsn() {
+        let mut dsn = DsnParameters::default();
+        dsn.parse_mail_parameters(&parse_params(
+            "MAIL FROM:<user@example.com> RET=hdrs ENVID=QQ314159 BODY=8BITMIME",
+        ))
+        .unwrap();
+        dsn.parse_rcpt_parameters(&parse_params(
+            "RCPT TO:<other@example.com> NOTIFY=SUCCESS,FAILURE ORCPT=rfc822;other@example.com",
+        ))
+        .unwrap();
+
+        assert_eq!(
+            dsn,
+            DsnParameters {
+                ret: Some(DsnRet::Hdrs),
+                envid: Some("QQ314159".to_string()),
+                notify: Some(vec![DsnNotify::Success, DsnNotify::Failure]),
+                orcpt: Some("rfc822;other@example.com".to_string()),
+            }
+        );
+        assert!(dsn.should_notify(DsnNotify::Success));
+        assert!(!dsn.should_notify(DsnNotify::Delay));
+
+        assert_eq!(
+            Command::MailFrom {
+                address: crate::ReversePath::NullSender,
+                parameters: dsn.mail_parameters(),
+            }
+            .encode(),
+            "MAIL FROM:<> RET=HDRS ENVID=QQ314159\r\n"
+        );
+        assert_eq!(
+            dsn.rcpt_parameters(),
+            vec![
+                EsmtpParameter {
+                    name: "NOTIFY".to_string(),
+                    value: Some("SUCCESS,FAILURE".to_string()),
+                },
+                EsmtpParameter {
+                    name: "ORCPT".to_string(),
+                    value: Some("rfc822;other@example.com".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_dsn_errors() {
+        let mut dsn = DsnParameters::default();
+        assert_eq!(
+            dsn.parse_mail_parameters(&parse_params("MAIL FROM:<user@example.com> RET=BODY"))
+                .unwrap_err(),
+            "invalid parameter RET=BODY"
+        );
+        assert_eq!(
+            dsn.parse_rcpt_parameters(&parse_params(
+                "RCPT TO:<user@example.com> NOTIFY=NEVER,DELAY"
+            ))
+            .unwrap_err(),
+            "NOTIFY=NEVER cannot be combined with other values"
+        );
+        assert!(DsnParameters::default().is_empty());
+        assert!(DsnParameters::default().should_notify(DsnNotify::Failure));
+        assert!(!DsnParameters::default().should_notify(DsnNotify::Success));
+    }
+}
@@ -1,7 +1,13 @@
 pub mod client;
+pub mod dane;
+pub mod downgrade;
+pub mod dsn;
 pub mod parser;
 pub mod traits;
 
 pub use client::*;
+pub use dane::*;
+pub use downgrade::*;
+pub use dsn::*;
 pub use parser::*;
 pub use traits::*;
@@ -20,7 +20,9 @@ impl Parser {
             Rule::rcpt => Self::parse_rcpt(result.into_inner()),
             Rule::ehlo => Self::parse_ehlo(result.into_inner()),
             Rule::helo => Self::parse_helo(result.into_inner()),
+            Rule::lhlo => Self::parse_lhlo(result.into_inner()),
             Rule::data => Ok(Command::Data),
+            Rule::bdat => Self::parse_bdat(result.into_inner()),
             Rule::rset => Ok(Command::Rset),
             Rule::quit => Ok(Command::Quit),
             Rule::starttls => Ok(Command::StartTls),
@@ -43,6 +45,20 @@ impl Parser {
         Ok(Command::Helo(Self::parse_domain(domain)?))
     }
 
+    fn parse_lhlo(mut pairs: Pairs<Rule>) -> Result<Command, String> {
+        let domain = pairs.next().unwrap();
+        Ok(Command::Lhlo(Self::parse_domain(domain)?))
+    }
+
+    fn parse_bdat(mut pairs: Pairs<Rule>) -> Result<Command, String> {
+        let chunk_size = pairs.next().unwrap().as_str();
+        let chunk_size = chunk_size
+            .parse::<usize>()
+            .map_err(|err| format!("invalid BDAT chunk size {chunk_size}: {err:#}"))?;
+        let last = pairs.next().is_some();
+        Ok(Command::Bdat { chunk_size, last })
+    }
+
     fn parse_vrfy(mut pairs: Pairs<Rule>) -> Result<Command, String> {
         let param = pairs.next().unwrap().as_str().to_string();
         Ok(Command::Vrfy(param))
@@ -343,6 +359,8 @@ impl ToString for EsmtpParameter {
 pub enum Command {
     Ehlo(Domain),
     Helo(Domain),
+    /// RFC 2033 LMTP greeting
+    Lhlo(Domain),
     MailFrom {
         address: ReversePath,
         parameters: Vec<EsmtpParameter>,
@@ -352,6 +370,12 @@ pub enum Command {
         parameters: Vec<EsmtpParameter>,
     },
     Data,
+    /// RFC 3030 CHUNKING. The chunk_size bytes of payload
+    /// immediately follow the command line.
+    Bdat {
+        chunk_size: usize,
+        last: bool,
+    },
     Rset,
     Quit,
     Vrfy(String),
@@ -374,6 +398,7 @@ impl Command {
         match self {
             Self::Ehlo(domain) => format!("EHLO {}\r\n", domain.to_string()),
             Self::Helo(domain) => format!("HELO {}\r\n", domain.to_string()),
+            Self::Lhlo(domain) => format!("LHLO {}\r\n", domain.to_string()),
             Self::MailFrom {
                 address,
                 parameters,
@@ -399,6 +424,14 @@ impl Command {
                 format!("RCPT TO:<{}>{params}\r\n", address.to_string())
             }
             Self::Data => "DATA\r\n".to_string(),
+            Self::Bdat {
+                chunk_size,
+                last: true,
+            } => format!("BDAT {chunk_size} LAST\r\n"),
+            Self::Bdat {
+                chunk_size,
+                last: false,
+            } => format!("BDAT {chunk_size}\r\n"),
             Self::Rset => "RSET\r\n".to_string(),
             Self::Quit => "QUIT\r\n".to_string(),
             Self::StartTls => "STARTTLS\r\n".to_string(),
@@ -421,10 +454,10 @@ impl Command {
 
     pub fn client_timeout(&self, timeouts: &SmtpClientTimeouts) -> Duration {
         match self {
-            Self::Helo(_) | Self::Ehlo(_) => timeouts.ehlo_timeout,
+            Self::Helo(_) | Self::Ehlo(_) | Self::Lhlo(_) => timeouts.ehlo_timeout,
             Self::MailFrom { .. } => timeouts.mail_from_timeout,
             Self::RcptTo { .. } => timeouts.rcpt_to_timeout,
-            Self::Data { .. } => timeouts.data_timeout,
+            Self::Data { .. } | Self::Bdat { .. } => timeouts.data_timeout,
             Self::Rset => timeouts.rset_timeout,
             Self::StartTls => timeouts.starttls_timeout,
             Self::Quit | Self::Vrfy(_) | Self::Expn(_) | Self::Help(_) | Self::Noop(_) => {
@@ -450,6 +483,36 @@ mod test {
         assert_eq!(Parser::parse_command("rset").unwrap(), Command::Rset,);
     }
 
+    #[test]
+    fn parse_bdat() {
+        assert_eq!(
+            Parser::parse_command("BDAT 1024").unwrap(),
+            Command::Bdat {
+                chunk_size: 1024,
+                last: false,
+            }
+        );
+        assert_eq!(
+            Parser::parse_command("bdat 0 last").unwrap(),
+            Command::Bdat {
+                chunk_size: 0,
+                last: true,
+            }
+        );
+        assert_eq!(
+            Command::Bdat {
+                chunk_size: 42,
+                last: true,
+            }
+            .encode(),
+            "BDAT 42 LAST\r\n".to_string()
+        );
+        assert!(Parser::parse_command("BDAT").is_err());
+        assert!(Parser::parse_command("BDAT LAST").is_err());
+        assert!(Parser::parse_command("BDAT 10 LAST garbage").is_err());
+        assert!(Parser::parse_command("BDAT 10 garbage").is_err());
+    }
+
     #[test]
     fn parse_vrfy() {
         assert_eq!(
@@ -506,6 +569,18 @@ mod test {
         assert!(Parser::parse_command("HELO [127.0.0.1]").is_err(),);
     }
 
+    #[test]
+    fn parse_lhlo() {
+        assert_eq!(
+            Parser::parse_command("LHLO there").unwrap(),
+            Command::Lhlo(Domain::Name("there".to_string()))
+        );
+        assert_eq!(
+            Command::Lhlo(Domain::Name("there".to_string())).encode(),
+            "LHLO there\r\n"
+        );
+    }
+
     #[test]
     fn parse_auth() {
         assert_eq!(
@@ -751,6 +826,45 @@ mod test {
         );
     }
 
+    #[test]
+    fn parse_smtputf8() {
+        assert_eq!(
+            Parser::parse_command("MAIL FROM:<用户@例子.广告> SMTPUTF8 BODY=8BITMIME").unwrap(),
+            Command::MailFrom {
+                address: ReversePath::Path(MailPath {
+                    at_domain_list: vec![],
+                    mailbox: Mailbox {
+                        local_part: "用户".to_string(),
+                        domain: Domain::Name("例子.广告".to_string())
+                    }
+                }),
+                parameters: vec![
+                    EsmtpParameter {
+                        name: "SMTPUTF8".to_string(),
+                        value: None,
+                    },
+                    EsmtpParameter {
+                        name: "BODY".to_string(),
+                        value: Some("8BITMIME".to_string()),
+                    }
+                ],
+            }
+        );
+        assert_eq!(
+            Parser::parse_command("RCPT TO:<\"jöran q\"@bücher.example>").unwrap(),
+            Command::RcptTo {
+                address: ForwardPath::Path(MailPath {
+                    at_domain_list: vec![],
+                    mailbox: Mailbox {
+                        local_part: "\"jöran q\"".to_string(),
+                        domain: Domain::Name("bücher.example".to_string())
+                    }
+                }),
+                parameters: vec![],
+            }
+        );
+    }
+
     #[test]
     fn parse_domain() {
         assert!(is_valid_domain("hello"));
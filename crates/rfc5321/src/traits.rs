@@ -1,6 +1,6 @@
 use std::fmt::Debug;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::{TcpStream, UnixStream};
 use tokio_rustls::client::TlsStream as TlsClientStream;
 use tokio_rustls::server::TlsStream as TlsServerStream;
 
@@ -10,5 +10,7 @@ impl AsyncReadAndWrite for TlsClientStream<BoxedAsyncReadAndWrite> {}
 impl AsyncReadAndWrite for TlsServerStream<TcpStream> {}
 impl AsyncReadAndWrite for TlsServerStream<BoxedAsyncReadAndWrite> {}
 impl AsyncReadAndWrite for TcpStream {}
+impl AsyncReadAndWrite for UnixStream {}
+impl AsyncReadAndWrite for DuplexStream {}
 
 pub type BoxedAsyncReadAndWrite = Box<dyn AsyncReadAndWrite>;
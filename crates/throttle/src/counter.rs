@@ -0,0 +1,195 @@
+//! This module implements counters that reset after a period, such
+//! as the number of messages that a source has sent to a site today.
+//! When redis is configured, the counts are shared with all of the
+//! nodes that use the same redis server, otherwise, or if the redis
+//! server cannot be reached, the count applies to the local process.
+use crate::limit::RedisQuery;
+use crate::{Error, REDIS};
+use mod_redis::{Cmd, FromRedisValue};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+static MEMORY: Lazy<Mutex<HashMap<String, (u64, Instant)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Increments the counter, setting its expiry when it is created.
+/// Returns the new count.
+const INCREMENT_SCRIPT: &str = r#"
+local key = KEYS[1]
+local duration = tonumber(ARGV[1])
+
+local count = redis.call("INCR", key)
+if count == 1 then
+  redis.call("EXPIRE", key, duration)
+end
+return count
+"#;
+
+fn redis() -> Option<Arc<dyn RedisQuery>> {
+    REDIS
+        .get()
+        .cloned()
+        .map(|conn| Arc::new(conn) as Arc<dyn RedisQuery>)
+}
+
+/// Returns the current count for key, which is 0 if it
+/// has never been incremented or has expired
+pub async fn get_count<S: AsRef<str>>(key: S) -> Result<u64, Error> {
+    get_count_with(redis(), key.as_ref()).await
+}
+
+/// Increments the count for key, returning the new count.
+/// If this creates the counter, it will expire after duration;
+/// all users of key are expected to use the same duration.
+pub async fn increment_count<S: AsRef<str>>(key: S, duration: Duration) -> Result<u64, Error> {
+    increment_count_with(redis(), key.as_ref(), duration).await
+}
+
+async fn get_count_with(redis: Option<Arc<dyn RedisQuery>>, key: &str) -> Result<u64, Error> {
+    if let Some(redis) = redis {
+        let mut cmd = Cmd::new();
+        cmd.arg("GET").arg(key);
+        match redis.query(cmd).await {
+            Ok(result) => {
+                return Ok(<Option<u64> as FromRedisValue>::from_redis_value(&result)?.unwrap_or(0))
+            }
+            Err(err) => {
+                tracing::error!("{key}: redis is unavailable, using local count: {err:#}");
+            }
+        }
+    }
+    let store = MEMORY.lock().unwrap();
+    Ok(match store.get(key) {
+        Some((count, expires)) if *expires > Instant::now() => *count,
+        _ => 0,
+    })
+}
+
+async fn increment_count_with(
+    redis: Option<Arc<dyn RedisQuery>>,
+    key: &str,
+    duration: Duration,
+) -> Result<u64, Error> {
+    if let Some(redis) = redis {
+        let mut cmd = Cmd::new();
+        cmd.arg("EVAL")
+            .arg(INCREMENT_SCRIPT)
+            .arg(1)
+            .arg(key)
+            .arg(duration.as_secs().max(1));
+        match redis.query(cmd).await {
+            Ok(result) => return Ok(<u64 as FromRedisValue>::from_redis_value(&result)?),
+            Err(err) => {
+                tracing::error!("{key}: redis is unavailable, using local count: {err:#}");
+            }
+        }
+    }
+    let mut store = MEMORY.lock().unwrap();
+    let now = Instant::now();
+    match store.get_mut(key) {
+        Some((count, expires)) if *expires > now => {
+            *count += 1;
+            Ok(*count)
+        }
+        _ => {
+            // New counters are created at most once per period for
+            // each key, so this is a good time to discard old ones
+            store.retain(|_, (_, expires)| *expires > now);
+            store.insert(key.to_string(), (1, now + duration));
+            Ok(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::limit::QueryFuture;
+    use mod_redis::{Arg, RedisValue};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Emulates the counter commands of a redis server
+    #[derive(Default)]
+    struct FakeRedis {
+        counts: Mutex<HashMap<String, u64>>,
+        unavailable: AtomicBool,
+    }
+
+    impl FakeRedis {
+        fn execute(&self, args: &[&str]) -> anyhow::Result<RedisValue> {
+            if self.unavailable.load(Ordering::SeqCst) {
+                anyhow::bail!("connection refused");
+            }
+            let mut counts = self.counts.lock().unwrap();
+            Ok(match args {
+                ["GET", key] => match counts.get(*key) {
+                    Some(count) => RedisValue::Data(count.to_string().into_bytes()),
+                    None => RedisValue::Nil,
+                },
+                ["EVAL", script, "1", key, _duration] if *script == INCREMENT_SCRIPT => {
+                    let count = counts.entry(key.to_string()).or_default();
+                    *count += 1;
+                    RedisValue::Int(*count as i64)
+                }
+                _ => anyhow::bail!("unexpected command {args:?}"),
+            })
+        }
+    }
+
+    impl RedisQuery for FakeRedis {
+        fn query(&self, cmd: Cmd) -> QueryFuture<'_> {
+            let args: Vec<String> = cmd
+                .args_iter()
+                .map(|arg| match arg {
+                    Arg::Simple(arg) => String::from_utf8_lossy(arg).to_string(),
+                    Arg::Cursor => String::new(),
+                })
+                .collect();
+            let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+            let result = self.execute(&args);
+            Box::pin(async move { result })
+        }
+    }
+
+    #[tokio::test]
+    async fn redis_count() {
+        let redis = Arc::new(FakeRedis::default());
+        let key = "test_redis_count";
+        let day = Duration::from_secs(86400);
+
+        assert_eq!(get_count_with(Some(redis.clone()), key).await.unwrap(), 0);
+        for expected in 1..=3 {
+            assert_eq!(
+                increment_count_with(Some(redis.clone()), key, day)
+                    .await
+                    .unwrap(),
+                expected
+            );
+        }
+        assert_eq!(get_count_with(Some(redis.clone()), key).await.unwrap(), 3);
+
+        // If redis is unavailable, the count is kept locally
+        redis.unavailable.store(true, Ordering::SeqCst);
+        assert_eq!(get_count_with(Some(redis.clone()), key).await.unwrap(), 0);
+        increment_count_with(Some(redis.clone()), key, day)
+            .await
+            .unwrap();
+        assert_eq!(get_count_with(Some(redis.clone()), key).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn memory_count_expiry() {
+        let key = "test_memory_count_expiry";
+        let duration = Duration::from_millis(50);
+
+        assert_eq!(increment_count(key, duration).await.unwrap(), 1);
+        assert_eq!(increment_count(key, duration).await.unwrap(), 2);
+        assert_eq!(get_count(key).await.unwrap(), 2);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(get_count(key).await.unwrap(), 0);
+        assert_eq!(increment_count(key, duration).await.unwrap(), 1);
+    }
+}
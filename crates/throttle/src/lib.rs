@@ -11,6 +11,9 @@ use std::sync::Mutex;
 use std::time::Duration;
 use thiserror::Error;
 
+pub mod counter;
+pub mod limit;
+
 static MEMORY: OnceCell<Mutex<MemoryStore>> = OnceCell::new();
 static REDIS: OnceCell<RedisConnection> = OnceCell::new();
 
@@ -22,6 +25,8 @@ pub enum Error {
     AnyHow(#[from] anyhow::Error),
     #[error("{0}")]
     Redis(#[from] RedisError),
+    #[error("too many leases, retry after {0:?}")]
+    TooManyLeases(Duration),
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
@@ -35,12 +40,39 @@ pub struct ThrottleSpec {
 
 impl ThrottleSpec {
     pub async fn throttle<S: AsRef<str>>(&self, key: S) -> Result<ThrottleResult, Error> {
-        let key = key.as_ref();
+        self.throttle_quantity(key.as_ref(), 1).await
+    }
+
+    /// Returns the state of the throttle for key without consuming
+    /// a token.  `throttled` and `retry_after` indicate whether, and
+    /// for how long, the next call to `throttle` would be limited.
+    pub async fn peek<S: AsRef<str>>(&self, key: S) -> Result<ThrottleResult, Error> {
+        let mut result = self.throttle_quantity(key.as_ref(), 0).await?;
+        if result.remaining == 0 {
+            // A token becomes available once the time to reset is
+            // no more than the time it takes to earn the rest of
+            // the burst capacity
+            let emission = Duration::from_secs(self.period).div_f64(self.limit as f64);
+            let burst = emission.mul_f64(result.limit.saturating_sub(1) as f64);
+            result.throttled = true;
+            result.retry_after = Some(result.reset_after.saturating_sub(burst));
+        }
+        Ok(result)
+    }
+
+    async fn throttle_quantity(&self, key: &str, quantity: u64) -> Result<ThrottleResult, Error> {
         let limit = self.limit;
         let period = self.period;
         let max_burst = self.max_burst.unwrap_or(limit);
         let key = format!("{key}:{limit}:{max_burst}:{period}");
-        throttle(&key, limit, Duration::from_secs(period), max_burst, Some(1)).await
+        throttle(
+            &key,
+            limit,
+            Duration::from_secs(period),
+            max_burst,
+            Some(quantity),
+        )
+        .await
     }
 }
 
@@ -316,4 +348,28 @@ mod test {
             "invalid limit 'three': invalid digit found in string".to_string()
         );
     }
+
+    #[tokio::test]
+    async fn peek_does_not_consume() {
+        let spec = ThrottleSpec {
+            limit: 2,
+            period: 3600,
+            max_burst: Some(0),
+        };
+        let key = "peek_does_not_consume";
+
+        for _ in 0..3 {
+            assert!(!spec.peek(key).await.unwrap().throttled);
+        }
+        assert!(!spec.throttle(key).await.unwrap().throttled);
+
+        let result = spec.peek(key).await.unwrap();
+        assert!(result.throttled);
+        let retry_after = result.retry_after.unwrap();
+        assert!(
+            retry_after > Duration::from_secs(1700) && retry_after <= Duration::from_secs(1800),
+            "{retry_after:?}"
+        );
+        assert!(spec.throttle(key).await.unwrap().throttled);
+    }
 }
@@ -0,0 +1,493 @@
+//! This module implements a limit on the number of concurrent leases
+//! for a given key, such as the number of connections to a site.
+//! When redis is configured, the leases are shared with all of the
+//! nodes that use the same redis server, otherwise, or if the redis
+//! server cannot be reached, the limit applies to the local process.
+use crate::{Error, REDIS};
+use mod_redis::{Cmd, FromRedisValue, RedisConnection, RedisValue};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+static MEMORY: Lazy<Mutex<HashMap<String, HashMap<Uuid, Instant>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Leases are held in a sorted set, scored by their expiry time.
+/// Returns 0 if the lease was acquired, otherwise the number of
+/// seconds until the oldest lease expires.
+const ACQUIRE_SCRIPT: &str = r#"
+local key = KEYS[1]
+local limit = tonumber(ARGV[1])
+local uuid = ARGV[2]
+local duration = tonumber(ARGV[3])
+local now = tonumber(redis.call("TIME")[1])
+
+redis.call("ZREMRANGEBYSCORE", key, "-inf", now)
+if redis.call("ZCARD", key) >= limit then
+  local oldest = redis.call("ZRANGE", key, 0, 0, "WITHSCORES")
+  return math.max(1, tonumber(oldest[2]) - now)
+end
+redis.call("ZADD", key, now + duration, uuid)
+redis.call("EXPIRE", key, duration)
+return 0
+"#;
+
+/// Returns 1 if the lease was extended, 0 if it had already expired
+const EXTEND_SCRIPT: &str = r#"
+local key = KEYS[1]
+local uuid = ARGV[1]
+local duration = tonumber(ARGV[2])
+local now = tonumber(redis.call("TIME")[1])
+
+local expires = redis.call("ZSCORE", key, uuid)
+if not expires or tonumber(expires) <= now then
+  return 0
+end
+redis.call("ZADD", key, "XX", now + duration, uuid)
+redis.call("EXPIRE", key, duration)
+return 1
+"#;
+
+pub(crate) type QueryFuture<'a> =
+    Pin<Box<dyn Future<Output = anyhow::Result<RedisValue>> + Send + 'a>>;
+
+/// The redis operations used to manage leases and counters,
+/// so that tests can substitute a fake server
+pub(crate) trait RedisQuery: Send + Sync {
+    fn query(&self, cmd: Cmd) -> QueryFuture<'_>;
+}
+
+impl RedisQuery for RedisConnection {
+    fn query(&self, cmd: Cmd) -> QueryFuture<'_> {
+        Box::pin(RedisConnection::query(self, cmd))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitSpec {
+    /// The maximum number of concurrent leases
+    pub limit: u64,
+    /// How long a lease remains valid unless it is extended.
+    /// This bounds how long a lease can outlive a crashed holder.
+    pub duration: Duration,
+}
+
+impl LimitSpec {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            duration: Duration::from_secs(60),
+        }
+    }
+
+    /// Attempts to acquire a lease for key. Returns Error::TooManyLeases
+    /// if limit leases are already held.
+    /// All users of key are expected to use the same limit.
+    pub async fn acquire_lease<S: AsRef<str>>(&self, key: S) -> Result<LimitLease, Error> {
+        let redis = REDIS
+            .get()
+            .cloned()
+            .map(|conn| Arc::new(conn) as Arc<dyn RedisQuery>);
+        self.acquire_lease_with(redis, key.as_ref()).await
+    }
+
+    async fn acquire_lease_with(
+        &self,
+        redis: Option<Arc<dyn RedisQuery>>,
+        key: &str,
+    ) -> Result<LimitLease, Error> {
+        if let Some(redis) = redis {
+            match self.acquire_lease_redis(redis, key).await {
+                Err(err @ (Error::Redis(_) | Error::AnyHow(_))) => {
+                    tracing::error!("{key}: redis is unavailable, using local limit: {err:#}");
+                }
+                result => return result,
+            }
+        }
+        self.acquire_lease_memory(key)
+    }
+
+    async fn acquire_lease_redis(
+        &self,
+        conn: Arc<dyn RedisQuery>,
+        key: &str,
+    ) -> Result<LimitLease, Error> {
+        let uuid = Uuid::new_v4();
+        let mut cmd = Cmd::new();
+        cmd.arg("EVAL")
+            .arg(ACQUIRE_SCRIPT)
+            .arg(1)
+            .arg(key)
+            .arg(self.limit)
+            .arg(uuid.to_string())
+            .arg(self.duration.as_secs().max(1));
+        let result = conn.query(cmd).await?;
+        match <u64 as FromRedisValue>::from_redis_value(&result)? {
+            0 => Ok(LimitLease {
+                key: key.to_string(),
+                uuid,
+                duration: self.duration,
+                backend: Backend::Redis(conn),
+                armed: true,
+                lost: Arc::new(AtomicBool::new(false)),
+            }),
+            retry_after => Err(Error::TooManyLeases(Duration::from_secs(retry_after))),
+        }
+    }
+
+    fn acquire_lease_memory(&self, key: &str) -> Result<LimitLease, Error> {
+        let mut store = MEMORY.lock().unwrap();
+        let leases = store.entry(key.to_string()).or_default();
+        let now = Instant::now();
+        leases.retain(|_, expires| *expires > now);
+
+        if leases.len() as u64 >= self.limit {
+            let oldest = leases.values().min().copied().unwrap_or(now);
+            return Err(Error::TooManyLeases(oldest.saturating_duration_since(now)));
+        }
+
+        let uuid = Uuid::new_v4();
+        leases.insert(uuid, now + self.duration);
+        Ok(LimitLease {
+            key: key.to_string(),
+            uuid,
+            duration: self.duration,
+            backend: Backend::Memory,
+            armed: true,
+            lost: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+enum Backend {
+    Memory,
+    Redis(Arc<dyn RedisQuery>),
+}
+
+/// A lease acquired via LimitSpec::acquire_lease.
+/// The lease is released when it is dropped.
+pub struct LimitLease {
+    key: String,
+    uuid: Uuid,
+    duration: Duration,
+    backend: Backend,
+    armed: bool,
+    lost: Arc<AtomicBool>,
+}
+
+impl LimitLease {
+    /// Extends the lease so that it remains valid for
+    /// its duration from now
+    pub async fn extend(&self) -> Result<(), Error> {
+        match &self.backend {
+            Backend::Memory => {
+                let mut store = MEMORY.lock().unwrap();
+                let now = Instant::now();
+                match store
+                    .get_mut(&self.key)
+                    .and_then(|leases| leases.get_mut(&self.uuid))
+                {
+                    Some(expires) if *expires > now => {
+                        *expires = now + self.duration;
+                        Ok(())
+                    }
+                    _ => Err(Error::Generic(format!(
+                        "lease {} for {} has expired",
+                        self.uuid, self.key
+                    ))),
+                }
+            }
+            Backend::Redis(conn) => {
+                let mut cmd = Cmd::new();
+                cmd.arg("EVAL")
+                    .arg(EXTEND_SCRIPT)
+                    .arg(1)
+                    .arg(&self.key)
+                    .arg(self.uuid.to_string())
+                    .arg(self.duration.as_secs().max(1));
+                let result = conn.query(cmd).await?;
+                if <u64 as FromRedisValue>::from_redis_value(&result)? == 1 {
+                    Ok(())
+                } else {
+                    Err(Error::Generic(format!(
+                        "lease {} for {} has expired",
+                        self.uuid, self.key
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Releases the lease
+    pub async fn release(mut self) {
+        self.armed = false;
+        match &self.backend {
+            Backend::Memory => self.release_memory(),
+            Backend::Redis(conn) => {
+                if let Err(err) = conn.query(self.release_cmd()).await {
+                    tracing::error!("releasing lease for {}: {err:#}", self.key);
+                }
+            }
+        }
+    }
+
+    /// Returns a flag that hold_while sets if the lease could
+    /// not be extended, and is therefore no longer counted
+    /// against the limit
+    pub fn lost_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.lost)
+    }
+
+    /// Runs fut, extending the lease as needed until it
+    /// completes, then releases the lease.
+    /// If the lease cannot be extended, it is lost: the lost_flag
+    /// is set and no further attempt is made to extend it, but fut
+    /// continues to run, so it should check the flag and wind down.
+    pub async fn hold_while<F: Future>(self, fut: F) -> F::Output {
+        tokio::pin!(fut);
+        let interval = (self.duration / 2).max(Duration::from_secs(1));
+        let mut extending = true;
+        loop {
+            tokio::select! {
+                result = &mut fut => {
+                    self.release().await;
+                    return result;
+                }
+                _ = tokio::time::sleep(interval), if extending => {
+                    if let Err(err) = self.extend().await {
+                        tracing::error!("extending lease for {}, the lease is lost: {err:#}", self.key);
+                        self.lost.store(true, Ordering::SeqCst);
+                        extending = false;
+                    }
+                }
+            }
+        }
+    }
+
+    fn release_memory(&self) {
+        let mut store = MEMORY.lock().unwrap();
+        if let Some(leases) = store.get_mut(&self.key) {
+            leases.remove(&self.uuid);
+            if leases.is_empty() {
+                store.remove(&self.key);
+            }
+        }
+    }
+
+    fn release_cmd(&self) -> Cmd {
+        let mut cmd = Cmd::new();
+        cmd.arg("ZREM").arg(&self.key).arg(self.uuid.to_string());
+        cmd
+    }
+}
+
+impl Drop for LimitLease {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        match &self.backend {
+            Backend::Memory => self.release_memory(),
+            Backend::Redis(conn) => {
+                // If there is no runtime, the lease will expire
+                // once its duration has elapsed
+                if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                    let conn = Arc::clone(conn);
+                    let cmd = self.release_cmd();
+                    handle.spawn(async move { conn.query(cmd).await });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mod_redis::Arg;
+    use std::collections::HashSet;
+
+    /// Emulates the lease commands of a redis server
+    #[derive(Default)]
+    struct FakeRedis {
+        leases: Mutex<HashMap<String, HashSet<String>>>,
+        unavailable: AtomicBool,
+    }
+
+    impl FakeRedis {
+        fn count(&self, key: &str) -> usize {
+            self.leases
+                .lock()
+                .unwrap()
+                .get(key)
+                .map_or(0, |set| set.len())
+        }
+
+        /// Simulates the expiry of all of the leases for key
+        fn expire(&self, key: &str) {
+            self.leases.lock().unwrap().remove(key);
+        }
+
+        fn execute(&self, args: &[&str]) -> anyhow::Result<RedisValue> {
+            if self.unavailable.load(Ordering::SeqCst) {
+                anyhow::bail!("connection refused");
+            }
+            let mut leases = self.leases.lock().unwrap();
+            let result = match args {
+                ["EVAL", script, "1", key, limit, uuid, _duration] if *script == ACQUIRE_SCRIPT => {
+                    let set = leases.entry(key.to_string()).or_default();
+                    if set.len() as u64 >= limit.parse::<u64>()? {
+                        30
+                    } else {
+                        set.insert(uuid.to_string());
+                        0
+                    }
+                }
+                ["EVAL", script, "1", key, uuid, _duration] if *script == EXTEND_SCRIPT => {
+                    matches!(leases.get(*key), Some(set) if set.contains(*uuid)).into()
+                }
+                ["ZREM", key, uuid] => match leases.get_mut(*key) {
+                    Some(set) => set.remove(*uuid).into(),
+                    None => 0,
+                },
+                _ => anyhow::bail!("unexpected command {args:?}"),
+            };
+            Ok(RedisValue::Int(result))
+        }
+    }
+
+    impl RedisQuery for FakeRedis {
+        fn query(&self, cmd: Cmd) -> QueryFuture<'_> {
+            let args: Vec<String> = cmd
+                .args_iter()
+                .map(|arg| match arg {
+                    Arg::Simple(arg) => String::from_utf8_lossy(arg).to_string(),
+                    Arg::Cursor => String::new(),
+                })
+                .collect();
+            let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+            let result = self.execute(&args);
+            Box::pin(async move { result })
+        }
+    }
+
+    #[tokio::test]
+    async fn redis_limit() {
+        let redis = Arc::new(FakeRedis::default());
+        let spec = LimitSpec::new(2);
+        let key = "test_redis_limit";
+        let acquire = || spec.acquire_lease_with(Some(redis.clone()), key);
+
+        let first = acquire().await.unwrap();
+        let second = acquire().await.unwrap();
+        assert!(matches!(
+            acquire().await,
+            Err(Error::TooManyLeases(delay)) if delay == Duration::from_secs(30)
+        ));
+        assert_eq!(redis.count(key), 2);
+        second.extend().await.unwrap();
+
+        first.release().await;
+        assert_eq!(redis.count(key), 1);
+        let third = acquire().await.unwrap();
+
+        // Dropping a lease releases it in the background
+        drop(second);
+        drop(third);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(redis.count(key), 0);
+
+        // If redis is unavailable, the limit is applied locally
+        redis.unavailable.store(true, Ordering::SeqCst);
+        let local = acquire().await.unwrap();
+        assert!(MEMORY.lock().unwrap().contains_key(key));
+        local.release().await;
+        assert!(!MEMORY.lock().unwrap().contains_key(key));
+    }
+
+    #[tokio::test]
+    async fn hold_while_releases() {
+        let redis = Arc::new(FakeRedis::default());
+        let spec = LimitSpec::new(1);
+        let key = "test_hold_while_releases";
+
+        let lease = spec
+            .acquire_lease_with(Some(redis.clone()), key)
+            .await
+            .unwrap();
+        let held = lease.hold_while(async { redis.count(key) }).await;
+        assert_eq!(held, 1);
+        assert_eq!(redis.count(key), 0);
+    }
+
+    #[tokio::test]
+    async fn hold_while_lost_lease() {
+        let redis = Arc::new(FakeRedis::default());
+        let spec = LimitSpec {
+            limit: 1,
+            duration: Duration::from_secs(1),
+        };
+        let key = "test_hold_while_lost_lease";
+
+        let lease = spec
+            .acquire_lease_with(Some(redis.clone()), key)
+            .await
+            .unwrap();
+        let lost = lease.lost_flag();
+        redis.expire(key);
+
+        // The flag is set once the lease fails to be extended
+        let wait_for_loss = async {
+            while !lost.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), lease.hold_while(wait_for_loss))
+            .await
+            .expect("lease to be lost");
+    }
+
+    #[tokio::test]
+    async fn memory_limit() {
+        let spec = LimitSpec::new(2);
+        let key = "test_memory_limit";
+
+        let first = spec.acquire_lease(key).await.unwrap();
+        let second = spec.acquire_lease(key).await.unwrap();
+        assert!(matches!(
+            spec.acquire_lease(key).await,
+            Err(Error::TooManyLeases(_))
+        ));
+
+        first.release().await;
+        let third = spec.acquire_lease(key).await.unwrap();
+
+        drop(second);
+        drop(third);
+        assert!(!MEMORY.lock().unwrap().contains_key("test_memory_limit"));
+    }
+
+    #[tokio::test]
+    async fn memory_lease_expiry() {
+        let spec = LimitSpec {
+            limit: 1,
+            duration: Duration::from_millis(50),
+        };
+        let key = "test_memory_lease_expiry";
+
+        let lease = spec.acquire_lease(key).await.unwrap();
+        assert!(spec.acquire_lease(key).await.is_err());
+
+        // An expired lease cannot be extended, and
+        // no longer counts against the limit
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(lease.extend().await.is_err());
+        let renewed = spec.acquire_lease(key).await.unwrap();
+        renewed.extend().await.unwrap();
+    }
+}
@@ -146,4 +146,15 @@ where
     pub fn can_skip(&self) -> Skip {
         self.wheel.can_skip()
     }
+
+    /// Returns the scheduled timer with the given `id`
+    pub fn get(&self, id: &EntryType::Id) -> Option<&Arc<EntryType>> {
+        self.timers.get(id)
+    }
+
+    /// Iterate over the scheduled, non-cancelled timers, in no
+    /// particular order
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<EntryType>> {
+        self.timers.values()
+    }
 }
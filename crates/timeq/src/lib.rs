@@ -110,6 +110,17 @@ impl<EntryType: CancellableTimerEntry + TimerEntryWithDelay> TimeQ<EntryType> {
         }
     }
 
+    /// Returns the item with the given id, if it is in the queue
+    pub fn get_by_id(&self, id: &EntryType::Id) -> Option<&Arc<EntryType>> {
+        self.wheel.get(id)
+    }
+
+    /// Iterates the contents of the queue without removing them.
+    /// The items are not returned in time order.
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<EntryType>> {
+        self.wheel.iter()
+    }
+
     /// Drains the entire contents of the queue, returning all of the
     /// contained items
     pub fn drain(&mut self) -> Vec<Arc<EntryType>> {
@@ -187,6 +198,32 @@ mod tests {
         assert_eq!(items, vec![item1, item3, item2]);
     }
 
+    #[test]
+    fn iterate() {
+        let item1 = Arc::new(Entry {
+            id: 1,
+            value: "foo",
+            delay: Duration::from_millis(1),
+        });
+        let item2 = Arc::new(Entry {
+            id: 2,
+            value: "bar",
+            delay: Duration::from_millis(10),
+        });
+
+        let mut queue = TimeQ::new();
+        queue.insert(Arc::clone(&item1)).unwrap();
+        queue.insert(Arc::clone(&item2)).unwrap();
+        queue.cancel_by_id(&item1.id).unwrap();
+
+        let items: Vec<_> = queue.iter().cloned().collect();
+        assert_eq!(items, vec![item2.clone()]);
+        assert_eq!(queue.len(), 1);
+
+        assert_eq!(queue.get_by_id(&item2.id), Some(&item2));
+        assert_eq!(queue.get_by_id(&item1.id), None);
+    }
+
     #[test]
     fn cancel() {
         let item1 = Arc::new(Entry {